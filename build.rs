@@ -8,12 +8,14 @@
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
 //!
-//! The build script also sets the linker flags to tell it which link script to use.
+//! The build script also sets the linker flags to tell it which link script to use,
+//! generates `$OUT_DIR/model.rs`, the F-RAM statics of the model in `model/`,
+//! and `$OUT_DIR/layout.rs`, the build hash in the F-RAM image header.
 
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -31,7 +33,38 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
-    // Specify linker arguments.
+    // Turn the model description and weight files into Rust statics.
+    let inputs = fram_modelgen::generate(Path::new("model/model.txt"), &out.join("model.rs"))
+        .unwrap_or_else(|e| panic!("{}", e));
+    for input in inputs {
+        println!("cargo:rerun-if-changed={}", input.display());
+    }
+
+    // Hash what decides where F-RAM statics go, for the image header. The
+    // sources are in because any of them can add, remove or reorder statics,
+    // or change their types, without changing the section sizes.
+    let mut hash = Fnv1a::new();
+    hash.update(include_bytes!("memory.x"));
+    hash.update(&fs::read(out.join("model.rs")).unwrap());
+    hash.update(env::var("CARGO_PKG_VERSION").unwrap().as_bytes());
+    let mut sources = Vec::new();
+    rust_files(Path::new("src"), &mut sources);
+    sources.sort();
+    for source in &sources {
+        hash.update(source.to_string_lossy().as_bytes());
+        hash.update(&fs::read(source).unwrap());
+    }
+    println!("cargo:rerun-if-changed=src");
+    File::create(out.join("layout.rs"))
+        .unwrap()
+        .write_all(format!("pub const FRAM_BUILD_HASH: u64 = {:#018x};\n", hash.0).as_bytes())
+        .unwrap();
+
+    // Specify linker arguments, for the board only: the host linker knows
+    // neither, and the library's unit tests are linked on the host.
+    if env::var("CARGO_CFG_TARGET_ARCH").as_deref() != Ok("arm") {
+        return;
+    }
 
     // `--nmagic` is required if memory section addresses are not aligned to 0x10000,
     // for example the FLASH and RAM sections in your `memory.x`.
@@ -41,3 +74,30 @@ fn main() {
     // Set the linker script to the one provided by cortex-m-rt.
     println!("cargo:rustc-link-arg=-Tlink.x");
 }
+
+// Every `.rs` file under `dir`.
+fn rust_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            rust_files(&path, files);
+        } else if path.extension() == Some("rs".as_ref()) {
+            files.push(path);
+        }
+    }
+}
+
+// 64-bit FNV-1a
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 = (self.0 ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
@@ -1,4 +1,6 @@
 STACK_SIZE = 12K;  
+/* Top of FRAM reserved for `.fram_noinit` */
+FRAM_NOINIT_SIZE = 8K;
 MEMORY
 {
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
@@ -35,12 +37,70 @@ MEMORY
    } INSERT AFTER .bss;
 */
 
-SECTIONS{
-    .fram_section :
+/* F-RAM statics. The initial contents of `.fram_rodata` and `.fram_data`
+   are stored in flash after the `.data` load image and copied to F-RAM by
+   `image::boot` when the image header there does not match this build.
+   `.fram_noinit` is never written by the startup code, and sits at a fixed
+   address at the top of FRAM so that it stays put when the sections below
+   it grow or shrink. */
+SECTIONS {
+    /* The image header, see `image`. Must stay at the start of FRAM. */
+    .fram_header (NOLOAD) :
     {
-        *(.fram_section*)  /* Place variables marked with .fram_section attribute here */
+        KEEP(*(.fram_header));
     } > FRAM
-}
+
+    /* Read-only F-RAM statics (model weights), covered by the header CRC */
+    .fram_rodata : AT(LOADADDR(.data) + SIZEOF(.data)) ALIGN(4)
+    {
+        _sfram_rodata = .;
+        *(.fram_rodata .fram_rodata.*);
+        . = ALIGN(4);
+        _efram_rodata = .;
+    } > FRAM
+
+    .fram_data : AT(LOADADDR(.fram_rodata) + SIZEOF(.fram_rodata)) ALIGN(4)
+    {
+        _sfram_data = .;
+        *(.fram_data .fram_data.*);
+        . = ALIGN(4);
+        _efram_data = .;
+    } > FRAM
+
+    .fram_noinit ORIGIN(FRAM) + LENGTH(FRAM) - FRAM_NOINIT_SIZE (NOLOAD) :
+    {
+        _sfram_noinit = .;
+        *(.fram_noinit .fram_noinit.*);
+        . = ALIGN(4);
+        _efram_noinit = .;
+    } > FRAM
+} INSERT AFTER .data;
+
+/* Load addresses of the F-RAM images in flash */
+_sifram_rodata = LOADADDR(.fram_rodata);
+_sifram_data = LOADADDR(.fram_data);
+ASSERT(_sifram_data + SIZEOF(.fram_data) <= ORIGIN(FLASH) + LENGTH(FLASH),
+       "F-RAM load images do not fit in FLASH");
+ASSERT(_efram_data <= _sfram_noinit,
+       ".fram_data runs into .fram_noinit, make FRAM_NOINIT_SIZE smaller");
+ASSERT(_efram_noinit <= ORIGIN(FRAM) + LENGTH(FRAM),
+       ".fram_noinit does not fit in FRAM_NOINIT_SIZE");
+
+/* SRAM globals saved with every checkpoint, see `checkpoint::checkpoint`.
+   Not initialized by the runtime. */
+SECTIONS {
+    .ckpt_ram (NOLOAD) : ALIGN(4)
+    {
+        _sckpt_ram = .;
+        *(.ckpt_ram .ckpt_ram.*);
+        . = ALIGN(4);
+        _eckpt_ram = .;
+    } > RAM
+} INSERT AFTER .bss;
+
+/* Bounds of the FRAM window, used by `fram::MappedFram` */
+_sfram = ORIGIN(FRAM);
+_efram = ORIGIN(FRAM) + LENGTH(FRAM);
 
 /* Define the stack section */
 _estack = ORIGIN(RAM) + LENGTH(RAM);
@@ -0,0 +1,288 @@
+//! Checkpoints for intermittent execution.
+//!
+//! A [`CheckpointStore`] keeps two slots in an F-RAM [`Region`] and a one
+//! half-word selector naming the slot that holds the latest complete
+//! checkpoint. A new checkpoint is written into the other slot and only
+//! becomes visible when the selector flips, which is a single bus write, so
+//! a power failure during `commit` leaves the previous checkpoint in place.
+//!
+//! On the board, `checkpoint()` saves the core registers, the live stack and
+//! the `.ckpt_ram` globals into the store, and `restore()` (called from `main`
+//! before any user code) resumes from the latest one.
+
+use crate::crc::Crc32;
+use crate::fram::{FramDevice, FramError, Region};
+
+#[cfg(target_arch = "arm")]
+mod runtime;
+#[cfg(target_arch = "arm")]
+pub use self::runtime::{checkpoint, restore, Resumed};
+
+// selector values; anything else (e.g. a blank F-RAM) means "no checkpoint"
+const SELECT_SLOT: [u16; 2] = [0xc4a0, 0xc4a1];
+const SELECTOR_LEN: usize = 4;
+// seq, payload length, payload CRC
+const HEADER_LEN: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckpointError {
+    /// The payload does not fit in a slot.
+    TooLarge { len: usize, capacity: usize },
+    Fram(FramError),
+}
+
+impl From<FramError> for CheckpointError {
+    fn from(e: FramError) -> Self {
+        CheckpointError::Fram(e)
+    }
+}
+
+/// A committed checkpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Increases by one with every commit.
+    pub seq: u32,
+    /// Payload location on the device.
+    pub payload: Region,
+}
+
+/// Double-buffered checkpoint storage in an F-RAM region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckpointStore {
+    region: Region,
+    slot_len: usize,
+}
+
+impl CheckpointStore {
+    /// `region` should be word aligned and is split into two equal slots.
+    pub const fn new(region: Region) -> Self {
+        CheckpointStore {
+            region,
+            slot_len: (region.len.saturating_sub(SELECTOR_LEN) / 2) & !3,
+        }
+    }
+
+    /// Largest payload a slot can hold.
+    pub const fn capacity(&self) -> usize {
+        self.slot_len.saturating_sub(HEADER_LEN)
+    }
+
+    fn slot(&self, index: usize) -> usize {
+        self.region.offset + SELECTOR_LEN + index * self.slot_len
+    }
+
+    fn selected<D: FramDevice>(&self, fram: &D) -> Result<Option<usize>, FramError> {
+        let selector = fram.read_u16(self.region.offset)?;
+        Ok(SELECT_SLOT.iter().position(|&s| s == selector))
+    }
+
+    /// The latest committed checkpoint, if there is one and it is intact.
+    pub fn latest<D: FramDevice>(&self, fram: &D) -> Result<Option<Checkpoint>, FramError> {
+        let slot = match self.selected(fram)? {
+            Some(index) => self.slot(index),
+            None => return Ok(None),
+        };
+        let seq = fram.read_u32(slot)?;
+        let len = fram.read_u32(slot + 4)? as usize;
+        let crc = fram.read_u32(slot + 8)?;
+        if len > self.capacity() {
+            return Ok(None);
+        }
+
+        let payload = Region::new(slot + HEADER_LEN, len);
+        if payload_crc(fram, payload)? != crc {
+            return Ok(None);
+        }
+        Ok(Some(Checkpoint { seq, payload }))
+    }
+
+    /// Writes `parts`, concatenated, as the new latest checkpoint.
+    pub fn commit<D: FramDevice>(
+        &self,
+        fram: &mut D,
+        parts: &[&[u8]],
+    ) -> Result<Checkpoint, CheckpointError> {
+        let len: usize = parts.iter().map(|p| p.len()).sum();
+        if len > self.capacity() {
+            return Err(CheckpointError::TooLarge {
+                len,
+                capacity: self.capacity(),
+            });
+        }
+
+        let (seq, index) = match (self.latest(fram)?, self.selected(fram)?) {
+            (Some(cp), Some(index)) => (cp.seq.wrapping_add(1), 1 - index),
+            _ => (1, 0),
+        };
+        let slot = self.slot(index);
+
+        let mut crc = Crc32::new();
+        let mut at = slot + HEADER_LEN;
+        for part in parts {
+            fram.write(at, part)?;
+            crc.update(part);
+            at += part.len();
+        }
+        fram.write_u32(slot, seq)?;
+        fram.write_u32(slot + 4, len as u32)?;
+        fram.write_u32(slot + 8, crc.finish())?;
+        fram.flush();
+
+        // commit point
+        fram.write_u16(self.region.offset, SELECT_SLOT[index])?;
+        fram.flush();
+
+        Ok(Checkpoint {
+            seq,
+            payload: Region::new(slot + HEADER_LEN, len),
+        })
+    }
+
+    /// Forgets every checkpoint, e.g. once the computation has finished.
+    pub fn clear<D: FramDevice>(&self, fram: &mut D) -> Result<(), FramError> {
+        fram.write_u16(self.region.offset, 0)?;
+        fram.flush();
+        Ok(())
+    }
+}
+
+fn payload_crc<D: FramDevice>(fram: &D, payload: Region) -> Result<u32, FramError> {
+    let mut crc = Crc32::new();
+    let mut buf = [0u8; 64];
+    let mut at = payload.offset;
+    while at < payload.end() {
+        let n = buf.len().min(payload.end() - at);
+        fram.read(at, &mut buf[..n])?;
+        crc.update(&buf[..n]);
+        at += n;
+    }
+    Ok(crc.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fram::sim::{SimFram, Tear};
+
+    fn payload<D: FramDevice>(fram: &D, cp: &Checkpoint) -> Vec<u8> {
+        let mut buf = vec![0; cp.payload.len];
+        fram.read(cp.payload.offset, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn commits_alternate_slots() {
+        let store = CheckpointStore::new(Region::new(16, 128));
+        let mut fram = SimFram::new(256);
+        assert_eq!(store.latest(&fram), Ok(None));
+
+        let a = store.commit(&mut fram, &[b"abc", b"def"]).unwrap();
+        let b = store.commit(&mut fram, &[b"ghi"]).unwrap();
+        assert_eq!((a.seq, b.seq), (1, 2));
+        assert_ne!(a.payload.offset, b.payload.offset);
+
+        let latest = store.latest(&fram).unwrap().unwrap();
+        assert_eq!(latest, b);
+        assert_eq!(payload(&fram, &latest), b"ghi");
+
+        store.clear(&mut fram).unwrap();
+        assert_eq!(store.latest(&fram), Ok(None));
+    }
+
+    #[test]
+    fn rejects_oversized_payloads() {
+        let store = CheckpointStore::new(Region::new(0, 64));
+        let mut fram = SimFram::new(64);
+        assert_eq!(store.capacity(), 16);
+        assert_eq!(
+            store.commit(&mut fram, &[&[0; 17]]),
+            Err(CheckpointError::TooLarge {
+                len: 17,
+                capacity: 16
+            })
+        );
+    }
+
+    #[test]
+    fn detects_corrupted_payload() {
+        let store = CheckpointStore::new(Region::new(0, 64));
+        let mut fram = SimFram::new(64);
+        let cp = store.commit(&mut fram, &[b"data"]).unwrap();
+        fram.write(cp.payload.offset, b"x").unwrap();
+        assert_eq!(store.latest(&fram), Ok(None));
+    }
+
+    // `main`'s boot: the per-boot count, then resume from the checkpoint or
+    // take one, the work, and `clear` once it is done. Returns whether it
+    // resumed.
+    fn boot(fram: &mut SimFram, store: &CheckpointStore) -> Result<bool, CheckpointError> {
+        let count = fram.read_u32(0)?;
+        fram.write_u32(0, count + 1)?;
+        let resumed = match store.latest(fram)? {
+            Some(_) => true,
+            None => {
+                store.commit(fram, &[b"state"])?;
+                false
+            }
+        };
+        fram.write_u32(4, 0x600d)?;
+        store.clear(fram)?;
+        Ok(resumed)
+    }
+
+    #[test]
+    fn boots_after_a_finished_run_start_fresh() {
+        let store = CheckpointStore::new(Region::new(16, 128));
+        let mut fram = SimFram::new(256);
+        assert_eq!(boot(&mut fram, &store), Ok(false));
+        assert_eq!(boot(&mut fram, &store), Ok(false));
+        assert_eq!(fram.read_u32(0), Ok(2));
+
+        // power fails during the work, after the checkpoint (the work and
+        // `clear` take the last three writes)
+        let mut probe = SimFram::from_image(fram.image().to_vec());
+        boot(&mut probe, &store).unwrap();
+        fram.fail_after(probe.writes() - 3);
+        assert!(boot(&mut fram, &store).is_err());
+        fram.reboot();
+        assert_eq!(boot(&mut fram, &store), Ok(true));
+        assert_eq!(boot(&mut fram, &store), Ok(false));
+        assert_eq!(fram.read_u32(0), Ok(5));
+    }
+
+    #[test]
+    fn never_exposes_a_torn_checkpoint() {
+        let store = CheckpointStore::new(Region::new(0, 256));
+        let old: Vec<u8> = (0..50).collect();
+        let new: Vec<u8> = (100..177).collect();
+
+        for &tear in [Tear::Lost, Tear::LowByte, Tear::Complete, Tear::Random].iter() {
+            let mut fram = SimFram::new(256);
+            store.commit(&mut fram, &[&old]).unwrap();
+            let image = fram.into_image();
+
+            let mut fram = SimFram::from_image(image.clone());
+            store.commit(&mut fram, &[&new]).unwrap();
+            let writes = fram.writes();
+
+            for n in 0..writes {
+                let mut fram = SimFram::from_image(image.clone());
+                fram.set_tear(tear);
+                fram.fail_after(n);
+                assert!(store.commit(&mut fram, &[&new]).is_err());
+                fram.reboot();
+
+                let cp = store.latest(&fram).unwrap().unwrap();
+                let data = payload(&fram, &cp);
+                match cp.seq {
+                    1 => assert_eq!(data, old),
+                    2 => assert_eq!(data, new),
+                    seq => panic!("unexpected seq {}", seq),
+                }
+                // the store keeps working after the failure
+                store.commit(&mut fram, &[b"next"]).unwrap();
+                assert_eq!(store.latest(&fram).unwrap().unwrap().seq, cp.seq + 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Register and stack capture for Cortex-M4F.
+//!
+//! A checkpoint payload is laid out as
+//!
+//! ```text
+//! | stack len: u32 | globals len: u32 | firmware: u32 | Context | .ckpt_ram | stack |
+//! ```
+//!
+//! where the stack part is everything between the saved SP and
+//! `_stack_start`. Only the registers a function call preserves are part of
+//! [`Context`], plus PRIMASK. `firmware` is a CRC-32 of the code and
+//! read-only data in flash: the saved PC and stack only make sense to the
+//! build that took the checkpoint, so `restore()` drops checkpoints of any
+//! other.
+//!
+//! `checkpoint()` is a plain call into `__ckpt_take`, which saves the
+//! registers and, still inside the same call, copies the stack and commits
+//! through `__ckpt_commit`. `__ckpt_resume` later puts the registers and
+//! stack back exactly as they were inside that call and returns from it, so
+//! to the compiler `__ckpt_take` is an ordinary external function that
+//! returns once per boot.
+//!
+//! Globals are only saved if they live in `.ckpt_ram`:
+//!
+//! ```ignore
+//! #[link_section = ".ckpt_ram"]
+//! static mut PROGRESS: u32 = 0;
+//! ```
+//!
+//! `.ckpt_ram` is not initialized by the runtime; `restore()` zeroes it when
+//! there is no checkpoint to resume from.
+
+use core::arch::global_asm;
+use core::ffi::c_void;
+use core::{mem, ptr, slice};
+
+use super::{CheckpointError, CheckpointStore};
+use crate::crc::Crc32;
+use crate::fram::{FramDevice, FramError, MappedFram};
+
+/// Callee-saved core state (AAPCS): r4-r11, sp, lr and s16-s31, and
+/// whether interrupts were masked.
+#[repr(C)]
+struct Context {
+    r4_r11: [u32; 8],
+    sp: u32,
+    lr: u32,
+    s16_s31: [u32; 16],
+    primask: u32,
+}
+
+const PREFIX_LEN: usize = 12;
+const CONTEXT_LEN: usize = mem::size_of::<Context>();
+
+// `__ckpt_take` return values
+const TAKEN: u32 = 0;
+const FAILED: u32 = 1;
+const RESUMED: u32 = 2;
+
+static mut CONTEXT: Context = Context {
+    r4_r11: [0; 8],
+    sp: 0,
+    lr: 0,
+    s16_s31: [0; 16],
+    primask: 0,
+};
+
+// why `__ckpt_commit` returned `FAILED`
+static mut ERROR: Option<CheckpointError> = None;
+
+// `firmware()`, once computed
+static mut FIRMWARE: Option<u32> = None;
+
+extern "C" {
+    // memory.x
+    static mut _sckpt_ram: u32;
+    static mut _eckpt_ram: u32;
+    static _stack_start: u32;
+    // cortex-m-rt's link.x: flash from `.text` to the end of `.rodata`
+    static _stext: u32;
+    static __erodata: u32;
+
+    /// Fills `ctx`, then returns `__ckpt_commit(fram, store)`; returns
+    /// `RESUMED` instead when a later boot resumes from the checkpoint.
+    fn __ckpt_take(ctx: *mut Context, fram: *mut c_void, store: *const c_void) -> u32;
+
+    /// Copies `len` bytes of stack from `src` to `dst` with interrupts
+    /// masked and without using the stack itself, then loads `ctx` and
+    /// returns `RESUMED` from the `__ckpt_take` call that filled it.
+    fn __ckpt_resume(ctx: *const Context, src: *const u32, dst: *mut u32, len: usize) -> !;
+}
+
+global_asm!(
+    // LTO assembles this without the target's FPU features
+    ".fpu fpv4-sp-d16",
+    ".section .text.__ckpt_take,\"ax\",%progbits",
+    ".global __ckpt_take",
+    ".type __ckpt_take,%function",
+    ".thumb_func",
+    "__ckpt_take:",
+    "    stmia r0, {{r4-r11}}",
+    "    mov r3, sp",
+    "    str r3, [r0, #32]",
+    "    str lr, [r0, #36]",
+    "    add r3, r0, #40",
+    "    vstmia r3, {{s16-s31}}",
+    "    mrs r3, primask",
+    "    str r3, [r0, #104]",
+    // the stack is copied by `__ckpt_commit`, below the saved SP
+    "    mov r4, r0",
+    "    mov r0, r1",
+    "    mov r1, r2",
+    "    bl __ckpt_commit",
+    "    ldr lr, [r4, #36]",
+    "    ldr r4, [r4]",
+    "    bx lr",
+    "",
+    ".section .text.__ckpt_resume,\"ax\",%progbits",
+    ".global __ckpt_resume",
+    ".type __ckpt_resume,%function",
+    ".thumb_func",
+    "__ckpt_resume:",
+    "    cpsid i",
+    ".Lcopy:",
+    "    cbz r3, .Lload",
+    "    ldr r12, [r1], #4",
+    "    str r12, [r2], #4",
+    "    subs r3, r3, #4",
+    "    b .Lcopy",
+    ".Lload:",
+    "    ldmia r0!, {{r4-r11}}",
+    "    ldr r1, [r0], #4",
+    "    mov sp, r1",
+    "    ldr lr, [r0], #4",
+    "    vldmia r0!, {{s16-s31}}",
+    "    ldr r1, [r0]",
+    "    msr primask, r1",
+    "    movs r0, #2",
+    "    bx lr",
+);
+
+/// How `checkpoint()` returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resumed {
+    /// A checkpoint was just taken; execution goes on normally.
+    No,
+    /// Execution continues here after a reboot, from the checkpoint.
+    Yes,
+}
+
+fn globals() -> &'static mut [u8] {
+    unsafe {
+        let start = ptr::addr_of_mut!(_sckpt_ram) as *mut u8;
+        let end = ptr::addr_of_mut!(_eckpt_ram) as usize;
+        slice::from_raw_parts_mut(start, end - start as usize)
+    }
+}
+
+/// Saves the current execution state into `store`.
+///
+/// Returns `Resumed::No` after the checkpoint has been committed. A later
+/// boot that calls [`restore`] continues from this call, which then returns
+/// `Resumed::Yes`. Anything outside the stack, `.ckpt_ram` and F-RAM is not
+/// part of the checkpoint, in particular peripheral state.
+#[inline(never)]
+pub fn checkpoint(
+    fram: &mut MappedFram,
+    store: &CheckpointStore,
+) -> Result<Resumed, CheckpointError> {
+    let fram: *mut MappedFram = fram;
+    let store: *const CheckpointStore = store;
+    match unsafe { __ckpt_take(ptr::addr_of_mut!(CONTEXT), fram.cast(), store.cast()) } {
+        TAKEN => Ok(Resumed::No),
+        RESUMED => Ok(Resumed::Yes),
+        _ => Err(unsafe { ptr::replace(ptr::addr_of_mut!(ERROR), None) }.unwrap()),
+    }
+}
+
+// Called by `__ckpt_take` once `CONTEXT` is filled in. Its frame lives below
+// the saved SP, so nothing it does ends up in the checkpoint.
+#[no_mangle]
+extern "C" fn __ckpt_commit(fram: &mut MappedFram, store: &CheckpointStore) -> u32 {
+    match persist(fram, store, unsafe { &*ptr::addr_of!(CONTEXT) }) {
+        Ok(()) => TAKEN,
+        Err(e) => {
+            unsafe { ERROR = Some(e) };
+            FAILED
+        }
+    }
+}
+
+// CRC-32 of this build's code and constants.
+fn firmware() -> u32 {
+    if let Some(crc) = unsafe { *ptr::addr_of!(FIRMWARE) } {
+        return crc;
+    }
+    let start = ptr::addr_of!(_stext) as usize;
+    let end = ptr::addr_of!(__erodata) as usize;
+    let mut crc = Crc32::new();
+    crc.update(unsafe { slice::from_raw_parts(start as *const u8, end - start) });
+    let crc = crc.finish();
+    unsafe { FIRMWARE = Some(crc) };
+    crc
+}
+
+fn persist(
+    fram: &mut MappedFram,
+    store: &CheckpointStore,
+    ctx: &Context,
+) -> Result<(), CheckpointError> {
+    let stack_top = ptr::addr_of!(_stack_start) as usize;
+    let stack = unsafe { slice::from_raw_parts(ctx.sp as *const u8, stack_top - ctx.sp as usize) };
+    let globals = globals();
+    let context = unsafe { slice::from_raw_parts(ctx as *const Context as *const u8, CONTEXT_LEN) };
+
+    let mut prefix = [0u8; PREFIX_LEN];
+    prefix[..4].copy_from_slice(&(stack.len() as u32).to_le_bytes());
+    prefix[4..8].copy_from_slice(&(globals.len() as u32).to_le_bytes());
+    prefix[8..].copy_from_slice(&firmware().to_le_bytes());
+
+    store.commit(fram, &[&prefix, context, globals, stack])?;
+    Ok(())
+}
+
+fn start_fresh(globals: &mut [u8]) {
+    for b in globals.iter_mut() {
+        *b = 0;
+    }
+}
+
+/// Resumes from the latest checkpoint in `store`, if there is one.
+///
+/// Call it from `main` once the FMC is up and before anything whose effect
+/// should not be repeated. Does not return when a checkpoint is restored;
+/// when there is none, zeroes `.ckpt_ram` and returns.
+pub fn restore(fram: &mut MappedFram, store: &CheckpointStore) -> Result<(), FramError> {
+    let globals = globals();
+    let cp = match store.latest(fram)? {
+        Some(cp) => cp,
+        None => {
+            start_fresh(globals);
+            return Ok(());
+        }
+    };
+
+    let stack_len = fram.read_u32(cp.payload.offset)? as usize;
+    let globals_len = fram.read_u32(cp.payload.offset + 4)? as usize;
+    let firmware_crc = fram.read_u32(cp.payload.offset + 8)?;
+    let stack_top = ptr::addr_of!(_stack_start) as usize;
+    let expected_len = PREFIX_LEN + CONTEXT_LEN + globals_len + stack_len;
+    if firmware_crc != firmware() || globals_len != globals.len() || expected_len != cp.payload.len
+    {
+        // taken by a different firmware build; cannot be resumed
+        start_fresh(globals);
+        return Ok(());
+    }
+
+    let context = cp.payload.offset + PREFIX_LEN;
+    let saved_globals = context + CONTEXT_LEN;
+    let saved_stack = saved_globals + globals_len;
+    fram.read(saved_globals, globals)?;
+
+    unsafe {
+        __ckpt_resume(
+            fram.as_ptr(context) as *const Context,
+            fram.as_ptr(saved_stack) as *const u32,
+            (stack_top - stack_len) as *mut u32,
+            stack_len,
+        )
+    }
+}
@@ -0,0 +1,512 @@
+//! Clock tree configuration for the STM32F303xE.
+//!
+//! Ask for the frequencies you want with [`ClockConfig`], [`ClockConfig::solve`]
+//! picks the PLL source, PREDIV, PLLMUL, bus prescalers and flash wait states,
+//! and `ClockPlan::apply` programs them and returns the frozen [`Clocks`]:
+//!
+//! ```ignore
+//! let clocks = ClockConfig::hsi()
+//!     .sysclk(16_000_000)
+//!     .pclk1(8_000_000)
+//!     .solve()?
+//!     .apply(&dp.RCC, &dp.FLASH);
+//! ```
+
+#[cfg(target_arch = "arm")]
+use stm32f3xx_hal_v2::pac;
+
+pub const HSI_HZ: u32 = 8_000_000;
+
+const SYSCLK_MAX: u32 = 72_000_000;
+const PCLK1_MAX: u32 = 36_000_000;
+const PCLK2_MAX: u32 = 72_000_000;
+const PLL_OUT_MIN: u32 = 16_000_000;
+const PLL_IN_MIN: u32 = 1_000_000;
+const PLL_IN_MAX: u32 = 24_000_000;
+const HSE_MIN: u32 = 4_000_000;
+const HSE_MAX: u32 = 32_000_000;
+
+// AHB prescaler divisors and their HPRE encodings (there is no /32)
+const HPRE: [(u32, u8); 9] = [
+    (1, 0b0000),
+    (2, 0b1000),
+    (4, 0b1001),
+    (8, 0b1010),
+    (16, 0b1011),
+    (64, 0b1100),
+    (128, 0b1101),
+    (256, 0b1110),
+    (512, 0b1111),
+];
+
+// APB prescaler divisors and their PPREx encodings
+const PPRE: [(u32, u8); 5] = [(1, 0b000), (2, 0b100), (4, 0b101), (8, 0b110), (16, 0b111)];
+
+/// Requested clock frequencies, in Hz.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockConfig {
+    hse: Option<u32>,
+    sysclk: u32,
+    hclk: Option<u32>,
+    pclk1: Option<u32>,
+    pclk2: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockError {
+    /// The HSE crystal is outside 4..=32 MHz.
+    HseOutOfRange(u32),
+    /// SYSCLK above 72 MHz.
+    SysclkTooFast(u32),
+    /// No oscillator/PLL combination produces exactly this SYSCLK.
+    SysclkUnreachable(u32),
+    /// HCLK is not SYSCLK divided by an AHB prescaler.
+    HclkUnreachable(u32),
+    /// PCLK1 is not HCLK divided by an APB prescaler.
+    Pclk1Unreachable(u32),
+    /// PCLK1 above 36 MHz.
+    Pclk1TooFast(u32),
+    /// PCLK2 is not HCLK divided by an APB prescaler.
+    Pclk2Unreachable(u32),
+}
+
+/// PLL input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PllSource {
+    HsiPrediv,
+    HsePrediv,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SysclkSource {
+    Hsi,
+    Hse,
+    Pll {
+        source: PllSource,
+        /// 1..=16
+        prediv: u8,
+        /// 2..=16
+        mul: u8,
+    },
+}
+
+/// Register values that produce a [`ClockConfig`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockPlan {
+    pub sysclk_source: SysclkSource,
+    /// AHB divisor
+    pub hpre: u32,
+    /// APB1 divisor
+    pub ppre1: u32,
+    /// APB2 divisor
+    pub ppre2: u32,
+    /// Flash wait states for SYSCLK
+    pub flash_latency: u8,
+    pub clocks: Clocks,
+}
+
+/// Frozen clock frequencies, in Hz.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Clocks {
+    sysclk: u32,
+    hclk: u32,
+    pclk1: u32,
+    pclk2: u32,
+}
+
+impl Clocks {
+    pub fn sysclk(&self) -> u32 {
+        self.sysclk
+    }
+
+    pub fn hclk(&self) -> u32 {
+        self.hclk
+    }
+
+    pub fn pclk1(&self) -> u32 {
+        self.pclk1
+    }
+
+    pub fn pclk2(&self) -> u32 {
+        self.pclk2
+    }
+
+    /// Core cycles in `us` microseconds, for busy-wait delays.
+    pub fn us_to_cycles(&self, us: u32) -> u32 {
+        (self.sysclk as u64 * us as u64 / 1_000_000) as u32
+    }
+}
+
+impl ClockConfig {
+    /// Run from the internal 8 MHz oscillator.
+    pub const fn hsi() -> Self {
+        ClockConfig {
+            hse: None,
+            sysclk: HSI_HZ,
+            hclk: None,
+            pclk1: None,
+            pclk2: None,
+        }
+    }
+
+    /// Run from an external crystal of `hz`.
+    pub const fn hse(hz: u32) -> Self {
+        ClockConfig {
+            hse: Some(hz),
+            sysclk: hz,
+            hclk: None,
+            pclk1: None,
+            pclk2: None,
+        }
+    }
+
+    pub const fn sysclk(mut self, hz: u32) -> Self {
+        self.sysclk = hz;
+        self
+    }
+
+    /// Defaults to SYSCLK.
+    pub const fn hclk(mut self, hz: u32) -> Self {
+        self.hclk = Some(hz);
+        self
+    }
+
+    /// Defaults to the fastest legal value (<= 36 MHz).
+    pub const fn pclk1(mut self, hz: u32) -> Self {
+        self.pclk1 = Some(hz);
+        self
+    }
+
+    /// Defaults to HCLK.
+    pub const fn pclk2(mut self, hz: u32) -> Self {
+        self.pclk2 = Some(hz);
+        self
+    }
+
+    pub fn solve(&self) -> Result<ClockPlan, ClockError> {
+        if let Some(hse) = self.hse {
+            if !(HSE_MIN..=HSE_MAX).contains(&hse) {
+                return Err(ClockError::HseOutOfRange(hse));
+            }
+        }
+        if self.sysclk > SYSCLK_MAX {
+            return Err(ClockError::SysclkTooFast(self.sysclk));
+        }
+
+        let sysclk = self.sysclk;
+        let sysclk_source = self
+            .sysclk_source()
+            .ok_or(ClockError::SysclkUnreachable(sysclk))?;
+
+        let hclk = self.hclk.unwrap_or(sysclk);
+        let hpre = divisor(&HPRE, sysclk, hclk).ok_or(ClockError::HclkUnreachable(hclk))?;
+
+        let (pclk1, ppre1) = match self.pclk1 {
+            Some(pclk1) => {
+                if pclk1 > PCLK1_MAX {
+                    return Err(ClockError::Pclk1TooFast(pclk1));
+                }
+                let ppre1 =
+                    divisor(&PPRE, hclk, pclk1).ok_or(ClockError::Pclk1Unreachable(pclk1))?;
+                (pclk1, ppre1)
+            }
+            None => {
+                let &(div, _) = PPRE.iter().find(|&&(div, _)| hclk / div <= PCLK1_MAX).unwrap();
+                (hclk / div, div)
+            }
+        };
+
+        let pclk2 = self.pclk2.unwrap_or(hclk);
+        if pclk2 > PCLK2_MAX {
+            return Err(ClockError::Pclk2Unreachable(pclk2));
+        }
+        let ppre2 = divisor(&PPRE, hclk, pclk2).ok_or(ClockError::Pclk2Unreachable(pclk2))?;
+
+        Ok(ClockPlan {
+            sysclk_source,
+            hpre,
+            ppre1,
+            ppre2,
+            flash_latency: flash_latency(sysclk),
+            clocks: Clocks {
+                sysclk,
+                hclk,
+                pclk1,
+                pclk2,
+            },
+        })
+    }
+
+    fn sysclk_source(&self) -> Option<SysclkSource> {
+        let sysclk = self.sysclk;
+        let (osc, source) = match self.hse {
+            Some(hse) if hse == sysclk => return Some(SysclkSource::Hse),
+            Some(hse) => (hse, PllSource::HsePrediv),
+            None if sysclk == HSI_HZ => return Some(SysclkSource::Hsi),
+            None => (HSI_HZ, PllSource::HsiPrediv),
+        };
+        if sysclk < PLL_OUT_MIN {
+            return None;
+        }
+
+        // smallest PREDIV first: the higher PLL input has less jitter
+        for prediv in 1..=16u32 {
+            let input = osc / prediv;
+            if !osc.is_multiple_of(prediv) || !(PLL_IN_MIN..=PLL_IN_MAX).contains(&input) {
+                continue;
+            }
+            for mul in 2..=16u32 {
+                if input * mul == sysclk {
+                    return Some(SysclkSource::Pll {
+                        source,
+                        prediv: prediv as u8,
+                        mul: mul as u8,
+                    });
+                }
+            }
+        }
+        None
+    }
+}
+
+fn divisor(table: &[(u32, u8)], from: u32, to: u32) -> Option<u32> {
+    table
+        .iter()
+        .map(|&(div, _)| div)
+        .find(|&div| from.is_multiple_of(div) && from / div == to)
+}
+
+#[cfg(target_arch = "arm")]
+fn encoding(table: &[(u32, u8)], divisor: u32) -> u8 {
+    table.iter().find(|&&(div, _)| div == divisor).unwrap().1
+}
+
+/// Wait states the flash needs at `sysclk` (RM0316, FLASH_ACR.LATENCY).
+pub fn flash_latency(sysclk: u32) -> u8 {
+    match sysclk {
+        0..=24_000_000 => 0,
+        24_000_001..=48_000_000 => 1,
+        _ => 2,
+    }
+}
+
+#[cfg(target_arch = "arm")]
+impl ClockPlan {
+    /// Programs RCC and FLASH. Must be called while running from HSI, i.e.
+    /// right after reset.
+    pub fn apply(&self, rcc: &pac::RCC, flash: &pac::FLASH) -> Clocks {
+        // more wait states before speeding up, never fewer than needed
+        flash.acr.modify(|r, w| unsafe {
+            w.prftbe().enabled();
+            w.latency().bits(r.latency().bits().max(self.flash_latency))
+        });
+
+        match self.sysclk_source {
+            SysclkSource::Hse
+            | SysclkSource::Pll {
+                source: PllSource::HsePrediv,
+                ..
+            } => {
+                rcc.cr.modify(|_, w| w.hseon().set_bit());
+                while rcc.cr.read().hserdy().bit_is_clear() {}
+            }
+            _ => {
+                rcc.cr.modify(|_, w| w.hsion().set_bit());
+                while rcc.cr.read().hsirdy().bit_is_clear() {}
+            }
+        }
+
+        if let SysclkSource::Pll {
+            source,
+            prediv,
+            mul,
+        } = self.sysclk_source
+        {
+            // the PLL can only be reconfigured while it is off
+            rcc.cr.modify(|_, w| w.pllon().clear_bit());
+            while rcc.cr.read().pllrdy().bit_is_set() {}
+
+            rcc.cfgr.modify(|_, w| match source {
+                PllSource::HsiPrediv => w.pllsrc().hsi_div_prediv(),
+                PllSource::HsePrediv => w.pllsrc().hse_div_prediv(),
+            });
+            rcc.cfgr2.modify(|_, w| w.prediv().bits(prediv - 1));
+            rcc.cfgr.modify(|_, w| w.pllmul().bits(mul - 2));
+
+            rcc.cr.modify(|_, w| w.pllon().on());
+            while rcc.cr.read().pllrdy().bit_is_clear() {}
+        }
+
+        rcc.cfgr.modify(|_, w| unsafe {
+            w.hpre().bits(encoding(&HPRE, self.hpre));
+            w.ppre1().bits(encoding(&PPRE, self.ppre1));
+            w.ppre2().bits(encoding(&PPRE, self.ppre2))
+        });
+
+        let sws = match self.sysclk_source {
+            SysclkSource::Hsi => {
+                rcc.cfgr.modify(|_, w| w.sw().hsi());
+                0b00
+            }
+            SysclkSource::Hse => {
+                rcc.cfgr.modify(|_, w| w.sw().hse());
+                0b01
+            }
+            SysclkSource::Pll { .. } => {
+                rcc.cfgr.modify(|_, w| w.sw().pll());
+                0b10
+            }
+        };
+        while rcc.cfgr.read().sws().bits() != sws {}
+
+        flash
+            .acr
+            .modify(|_, w| unsafe { w.latency().bits(self.flash_latency) });
+
+        self.clocks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MHZ: u32 = 1_000_000;
+
+    fn output(source: SysclkSource, osc: u32) -> u32 {
+        match source {
+            SysclkSource::Hsi => HSI_HZ,
+            SysclkSource::Hse => osc,
+            SysclkSource::Pll { prediv, mul, .. } => osc / prediv as u32 * mul as u32,
+        }
+    }
+
+    // Every SYSCLK the PLL can produce from `osc`, by brute force.
+    fn reachable(osc: u32) -> Vec<u32> {
+        let mut out = vec![osc];
+        for prediv in 1..=16 {
+            if !osc.is_multiple_of(prediv) || osc / prediv < PLL_IN_MIN || osc / prediv > PLL_IN_MAX {
+                continue;
+            }
+            for mul in 2..=16 {
+                let f = osc / prediv * mul;
+                if (PLL_OUT_MIN..=SYSCLK_MAX).contains(&f) && !out.contains(&f) {
+                    out.push(f);
+                }
+            }
+        }
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn every_reachable_hsi_frequency() {
+        let freqs = reachable(HSI_HZ);
+        for &f in freqs.iter() {
+            let plan = ClockConfig::hsi().sysclk(f).solve().unwrap();
+            assert_eq!(output(plan.sysclk_source, HSI_HZ), f);
+            assert_eq!(plan.clocks.sysclk(), f);
+            assert_eq!(plan.clocks.hclk(), f);
+            assert!(plan.clocks.pclk1() <= PCLK1_MAX);
+            assert_eq!(plan.flash_latency, flash_latency(f));
+        }
+        // and nothing else
+        for f in (1..=72).map(|m| m * MHZ) {
+            if !freqs.contains(&f) {
+                assert_eq!(
+                    ClockConfig::hsi().sysclk(f).solve(),
+                    Err(ClockError::SysclkUnreachable(f))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn every_reachable_hse_frequency() {
+        for &osc in [8 * MHZ, 12 * MHZ, 25 * MHZ].iter() {
+            for &f in reachable(osc).iter() {
+                let plan = ClockConfig::hse(osc).sysclk(f).solve().unwrap();
+                assert_eq!(output(plan.sysclk_source, osc), f);
+                if let SysclkSource::Pll { source, .. } = plan.sysclk_source {
+                    assert_eq!(source, PllSource::HsePrediv);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn current_board_setup() {
+        // HSI / 1 x 2, APB1 / 2
+        let plan = ClockConfig::hsi()
+            .sysclk(16 * MHZ)
+            .pclk1(8 * MHZ)
+            .solve()
+            .unwrap();
+        assert_eq!(
+            plan.sysclk_source,
+            SysclkSource::Pll {
+                source: PllSource::HsiPrediv,
+                prediv: 1,
+                mul: 2
+            }
+        );
+        assert_eq!((plan.hpre, plan.ppre1, plan.ppre2), (1, 2, 1));
+        assert_eq!(plan.flash_latency, 0);
+    }
+
+    #[test]
+    fn flash_wait_states() {
+        assert_eq!(flash_latency(8 * MHZ), 0);
+        assert_eq!(flash_latency(24 * MHZ), 0);
+        assert_eq!(flash_latency(32 * MHZ), 1);
+        assert_eq!(flash_latency(48 * MHZ), 1);
+        assert_eq!(flash_latency(56 * MHZ), 2);
+        assert_eq!(flash_latency(72 * MHZ), 2);
+    }
+
+    #[test]
+    fn bus_prescalers() {
+        let plan = ClockConfig::hsi().sysclk(72 * MHZ).solve().unwrap();
+        // APB1 defaults to the fastest legal clock
+        assert_eq!(plan.clocks.pclk1(), 36 * MHZ);
+        assert_eq!(plan.clocks.pclk2(), 72 * MHZ);
+
+        let plan = ClockConfig::hsi()
+            .sysclk(64 * MHZ)
+            .hclk(16 * MHZ)
+            .pclk2(4 * MHZ)
+            .solve()
+            .unwrap();
+        assert_eq!((plan.hpre, plan.ppre1, plan.ppre2), (4, 1, 4));
+
+        let c = ClockConfig::hsi().sysclk(64 * MHZ);
+        assert_eq!(
+            c.hclk(2 * MHZ).solve(),
+            Err(ClockError::HclkUnreachable(2 * MHZ))
+        );
+        assert_eq!(
+            c.pclk1(64 * MHZ).solve(),
+            Err(ClockError::Pclk1TooFast(64 * MHZ))
+        );
+        assert_eq!(
+            c.pclk1(20 * MHZ).solve(),
+            Err(ClockError::Pclk1Unreachable(20 * MHZ))
+        );
+        assert_eq!(
+            c.pclk2(3 * MHZ).solve(),
+            Err(ClockError::Pclk2Unreachable(3 * MHZ))
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range() {
+        assert_eq!(
+            ClockConfig::hsi().sysclk(80 * MHZ).solve(),
+            Err(ClockError::SysclkTooFast(80 * MHZ))
+        );
+        assert_eq!(
+            ClockConfig::hse(40 * MHZ).solve(),
+            Err(ClockError::HseOutOfRange(40 * MHZ))
+        );
+    }
+}
@@ -0,0 +1,499 @@
+//! Memory-to-memory DMA, for bulk copies between the F-RAM window and SRAM.
+//!
+//! Reading a tensor out of F-RAM with CPU loads keeps the core waiting for
+//! every FMC cycle. A DMA1 channel can do the copy while the core computes:
+//! [`Dma::start`] programs it and returns a [`Transfer`] to poll or
+//! [`wait`](Transfer::wait) on, and [`Dma::on_complete`] sets a callback run
+//! from the channel interrupt. [`TileStreamer`] builds on that to fetch the
+//! next tile of a tensor while a kernel works on the current one:
+//!
+//! ```ignore
+//! let mut dma = Dma::new(dp.DMA1, &dp.RCC, Channel::C1);
+//! let mut tiles = [[0; 2 * 50]; 2];
+//! TileStreamer::new(&mut dma, &mut tiles).run(PARAM_1.as_slice(), |at, tile| {
+//!     // rows at / 50 .. at / 50 + 2 of PARAM_1, now in SRAM
+//!     Ok::<_, DmaError>(())
+//! })?;
+//! ```
+//!
+//! Host builds copy with `copy_from_slice` when a transfer is started, so
+//! code using this module runs there unchanged.
+
+use core::marker::PhantomData;
+
+#[cfg(target_arch = "arm")]
+use core::sync::atomic::{compiler_fence, AtomicUsize, Ordering};
+#[cfg(target_arch = "arm")]
+use stm32f3xx_hal_v2::pac;
+
+/// Most elements one transfer can move (`CNDTR` is 16 bits wide).
+pub const MAX_LEN: usize = 0xffff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DmaError {
+    /// Source and destination differ in length.
+    Len { src: usize, dst: usize },
+    /// More than [`MAX_LEN`] elements.
+    TooLong { len: usize },
+    /// The channel reported a bus error, e.g. an address outside any memory.
+    Bus,
+}
+
+/// A DMA1 channel. Any of them can do memory-to-memory transfers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    C1 = 1,
+    C2,
+    C3,
+    C4,
+    C5,
+    C6,
+    C7,
+}
+
+impl Channel {
+    #[cfg(target_arch = "arm")]
+    fn index(self) -> usize {
+        self as usize - 1
+    }
+}
+
+/// Elements DMA can move: half-words and words only, because the F-RAM
+/// byte lanes are not wired and a byte store would clobber its neighbour.
+///
+/// # Safety
+///
+/// `SIZE` must be the `PSIZE`/`MSIZE` encoding of `size_of::<Self>()`.
+pub unsafe trait Word: Copy {
+    const SIZE: u32;
+}
+
+unsafe impl Word for u16 {
+    const SIZE: u32 = 0b01;
+}
+unsafe impl Word for i16 {
+    const SIZE: u32 = 0b01;
+}
+unsafe impl Word for u32 {
+    const SIZE: u32 = 0b10;
+}
+unsafe impl Word for i32 {
+    const SIZE: u32 = 0b10;
+}
+
+// Completion callbacks by channel, as `fn()` addresses; 0 for none.
+#[cfg(target_arch = "arm")]
+static CALLBACKS: [AtomicUsize; 7] = [const { AtomicUsize::new(0) }; 7];
+
+// DMA1 registers (RM0316, 13.6). Addressed by offset so the channel can be
+// picked at run time.
+#[cfg(target_arch = "arm")]
+mod reg {
+    use core::ptr;
+
+    use super::pac;
+
+    pub const ISR: usize = 0x00;
+    pub const IFCR: usize = 0x04;
+    pub const CCR: usize = 0x08;
+    pub const CNDTR: usize = 0x0c;
+    pub const CPAR: usize = 0x10;
+    pub const CMAR: usize = 0x14;
+    const CHANNEL_STRIDE: usize = 0x14;
+
+    // CCR bits
+    pub const EN: u32 = 1 << 0;
+    pub const TCIE: u32 = 1 << 1;
+    pub const PINC: u32 = 1 << 6;
+    pub const MINC: u32 = 1 << 7;
+    pub const PSIZE_SHIFT: u32 = 8;
+    pub const MSIZE_SHIFT: u32 = 10;
+    pub const PL_HIGH: u32 = 0b10 << 12;
+    pub const MEM2MEM: u32 = 1 << 14;
+
+    // ISR/IFCR bits, shifted by 4 per channel
+    pub const TCIF: u32 = 1 << 1;
+    pub const TEIF: u32 = 1 << 3;
+    pub const ALL: u32 = 0xf;
+
+    fn at(offset: usize) -> *mut u32 {
+        (pac::DMA1::ptr() as usize + offset) as *mut u32
+    }
+
+    pub fn read(offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(at(offset)) }
+    }
+
+    pub fn write(offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(at(offset), value) }
+    }
+
+    /// Offset of channel register `reg` of channel `index` (0-based).
+    pub fn channel(reg: usize, index: usize) -> usize {
+        reg + CHANNEL_STRIDE * index
+    }
+
+    pub fn flags(index: usize) -> u32 {
+        (read(ISR) >> (4 * index)) & ALL
+    }
+
+    pub fn clear(index: usize, flags: u32) {
+        write(IFCR, flags << (4 * index));
+    }
+}
+
+/// One DMA1 channel set up for memory-to-memory copies.
+pub struct Dma {
+    channel: Channel,
+    #[cfg(target_arch = "arm")]
+    _dma: pac::DMA1,
+    #[cfg(not(target_arch = "arm"))]
+    callback: Option<fn()>,
+}
+
+impl Dma {
+    /// Enables the DMA1 clock and takes `channel` for copies.
+    #[cfg(target_arch = "arm")]
+    pub fn new(dma: pac::DMA1, rcc: &pac::RCC, channel: Channel) -> Self {
+        rcc.ahbenr.modify(|_, w| w.dma1en().set_bit());
+        reg::write(reg::channel(reg::CCR, channel.index()), 0);
+        reg::clear(channel.index(), reg::ALL);
+        Dma { channel, _dma: dma }
+    }
+
+    /// Copies with the CPU.
+    #[cfg(not(target_arch = "arm"))]
+    pub fn new(channel: Channel) -> Self {
+        Dma {
+            channel,
+            callback: None,
+        }
+    }
+
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    /// Runs `callback` whenever a transfer completes, from the interrupt of
+    /// the channel, which must call [`on_interrupt`]. On the host it runs
+    /// before [`start`](Dma::start) returns.
+    pub fn on_complete(&mut self, callback: Option<fn()>) {
+        #[cfg(target_arch = "arm")]
+        CALLBACKS[self.channel.index()]
+            .store(callback.map_or(0, |f| f as usize), Ordering::Release);
+        #[cfg(not(target_arch = "arm"))]
+        {
+            self.callback = callback;
+        }
+    }
+
+    /// Copies `src` to `dst` and waits for it.
+    pub fn copy<T: Word>(&mut self, src: &[T], dst: &mut [T]) -> Result<(), DmaError> {
+        // waited for before the borrows end
+        unsafe { self.start(src, dst) }?.wait()
+    }
+
+    /// Starts copying `src` to `dst`, either of which may be in F-RAM.
+    ///
+    /// # Safety
+    ///
+    /// The [`Transfer`] must not be leaked (e.g. with `mem::forget`) before
+    /// it completes: the DMA would keep writing `dst` after the borrow ends.
+    /// Dropping it waits for the copy.
+    pub unsafe fn start<'a, T: Word>(
+        &'a mut self,
+        src: &'a [T],
+        dst: &'a mut [T],
+    ) -> Result<Transfer<'a, T>, DmaError> {
+        if src.len() != dst.len() {
+            return Err(DmaError::Len {
+                src: src.len(),
+                dst: dst.len(),
+            });
+        }
+        if src.len() > MAX_LEN {
+            return Err(DmaError::TooLong { len: src.len() });
+        }
+        self.begin(src, dst);
+        Ok(Transfer {
+            dma: self,
+            // `begin` does not start the channel for nothing to copy
+            result: if src.is_empty() { Some(Ok(())) } else { None },
+            buffers: PhantomData,
+        })
+    }
+
+    #[cfg(target_arch = "arm")]
+    fn begin<T: Word>(&mut self, src: &[T], dst: &mut [T]) {
+        let index = self.channel.index();
+        let callback = CALLBACKS[index].load(Ordering::Acquire);
+        if src.is_empty() {
+            if callback != 0 {
+                let callback: fn() = unsafe { core::mem::transmute(callback) };
+                callback();
+            }
+            return;
+        }
+        // stores to `src` must be done before the DMA reads it
+        compiler_fence(Ordering::SeqCst);
+        let ccr = reg::channel(reg::CCR, index);
+        reg::write(ccr, 0);
+        reg::clear(index, reg::ALL);
+        // in memory-to-memory mode with DIR = 0, CPAR is the source
+        reg::write(reg::channel(reg::CPAR, index), src.as_ptr() as u32);
+        reg::write(reg::channel(reg::CMAR, index), dst.as_mut_ptr() as u32);
+        reg::write(reg::channel(reg::CNDTR, index), src.len() as u32);
+        let mut config = reg::MEM2MEM
+            | reg::PL_HIGH
+            | T::SIZE << reg::MSIZE_SHIFT
+            | T::SIZE << reg::PSIZE_SHIFT
+            | reg::MINC
+            | reg::PINC;
+        if callback != 0 {
+            config |= reg::TCIE;
+        }
+        reg::write(ccr, config);
+        reg::write(ccr, config | reg::EN);
+    }
+
+    #[cfg(not(target_arch = "arm"))]
+    fn begin<T: Word>(&mut self, src: &[T], dst: &mut [T]) {
+        dst.copy_from_slice(src);
+        if let Some(callback) = self.callback {
+            callback();
+        }
+    }
+
+    #[cfg(target_arch = "arm")]
+    fn poll(&mut self) -> Option<Result<(), DmaError>> {
+        let index = self.channel.index();
+        let result = if reg::flags(index) & reg::TEIF != 0 {
+            Err(DmaError::Bus)
+        } else if reg::read(reg::channel(reg::CNDTR, index)) == 0 {
+            Ok(())
+        } else {
+            return None;
+        };
+        reg::write(reg::channel(reg::CCR, index), 0);
+        reg::clear(index, reg::ALL);
+        // loads from `dst` must not be moved before this
+        compiler_fence(Ordering::SeqCst);
+        Some(result)
+    }
+
+    #[cfg(not(target_arch = "arm"))]
+    fn poll(&mut self) -> Option<Result<(), DmaError>> {
+        Some(Ok(()))
+    }
+}
+
+/// Acknowledges the transfer-complete interrupt of `channel` and runs the
+/// callback set with [`Dma::on_complete`]. Call it from the `DMA1_CHx`
+/// handler; the transfer error flag is left for [`Transfer::wait`].
+#[cfg(target_arch = "arm")]
+pub fn on_interrupt(channel: Channel) {
+    let index = channel.index();
+    if reg::flags(index) & reg::TCIF == 0 {
+        return;
+    }
+    reg::clear(index, reg::TCIF);
+    let callback = CALLBACKS[index].load(Ordering::Acquire);
+    if callback != 0 {
+        let callback: fn() = unsafe { core::mem::transmute(callback) };
+        callback();
+    }
+}
+
+/// A copy in progress. Dropping it waits for the copy.
+pub struct Transfer<'a, T> {
+    dma: &'a mut Dma,
+    // once the copy is over: `Dma::poll` clears the flags and disables the
+    // channel, so it only reports a bus error once
+    result: Option<Result<(), DmaError>>,
+    buffers: PhantomData<&'a mut [T]>,
+}
+
+impl<T> Transfer<'_, T> {
+    pub fn is_done(&mut self) -> bool {
+        self.poll().is_some()
+    }
+
+    /// Waits until the destination holds the copy.
+    pub fn wait(mut self) -> Result<(), DmaError> {
+        self.finish()
+    }
+
+    fn poll(&mut self) -> Option<Result<(), DmaError>> {
+        if self.result.is_none() {
+            self.result = self.dma.poll();
+        }
+        self.result
+    }
+
+    fn finish(&mut self) -> Result<(), DmaError> {
+        loop {
+            if let Some(result) = self.poll() {
+                return result;
+            }
+        }
+    }
+}
+
+impl<T> Drop for Transfer<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+/// Streams a tensor through two SRAM buffers of `N` elements: while the
+/// kernel works on one tile, the next is copied into the other.
+pub struct TileStreamer<'a, T, const N: usize> {
+    dma: &'a mut Dma,
+    buffers: &'a mut [[T; N]; 2],
+}
+
+impl<'a, T: Word, const N: usize> TileStreamer<'a, T, N> {
+    pub fn new(dma: &'a mut Dma, buffers: &'a mut [[T; N]; 2]) -> Self {
+        assert!(N > 0 && N <= MAX_LEN, "tile size");
+        TileStreamer { dma, buffers }
+    }
+
+    /// Calls `kernel(at, tile)` for each tile of `src` in order, `at` being
+    /// the index of `tile[0]` in `src`. Tiles are `N` elements long, except
+    /// possibly the last. Stops at the first error, after the copy of the
+    /// next tile finished.
+    pub fn run<E, F>(&mut self, src: &[T], kernel: F) -> Result<(), E>
+    where
+        E: From<DmaError>,
+        F: FnMut(usize, &[T]) -> Result<(), E>,
+    {
+        let [first, second] = &mut *self.buffers;
+        stream(self.dma, src, first, second, kernel)
+    }
+}
+
+/// [`TileStreamer::run`] with buffers sized at run time: tiles are
+/// `first.len()` elements long, and `second` must be as long unless `src`
+/// fits in one tile.
+pub fn stream<T, E, F>(
+    dma: &mut Dma,
+    src: &[T],
+    first: &mut [T],
+    second: &mut [T],
+    mut kernel: F,
+) -> Result<(), E>
+where
+    T: Word,
+    E: From<DmaError>,
+    F: FnMut(usize, &[T]) -> Result<(), E>,
+{
+    let n = first.len();
+    assert!(n > 0 && (src.len() <= n || second.len() >= n), "tile size");
+    let mut tiles = src.chunks(n).enumerate().peekable();
+    if let Some(&(_, tile)) = tiles.peek() {
+        dma.copy(tile, &mut first[..tile.len()])?;
+    }
+    while let Some((i, tile)) = tiles.next() {
+        let (current, next) = if i % 2 == 0 {
+            (&*first, &mut *second)
+        } else {
+            (&*second, &mut *first)
+        };
+        let prefetch = match tiles.peek() {
+            // the transfer is waited for before `next` is used again
+            Some(&(_, following)) => {
+                Some(unsafe { dma.start(following, &mut next[..following.len()]) }?)
+            }
+            None => None,
+        };
+        let result = kernel(i * n, &current[..tile.len()]);
+        if let Some(prefetch) = prefetch {
+            prefetch.wait()?;
+        }
+        result?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, not(target_arch = "arm")))]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn copies_and_checks_lengths() {
+        let mut dma = Dma::new(Channel::C1);
+        let src = [1i32, -2, 3];
+        let mut dst = [0; 3];
+        dma.copy(&src, &mut dst).unwrap();
+        assert_eq!(dst, src);
+        assert_eq!(
+            dma.copy(&src, &mut [0; 2]),
+            Err(DmaError::Len { src: 3, dst: 2 })
+        );
+        let long = vec![0u16; MAX_LEN + 1];
+        assert_eq!(
+            dma.copy(&long, &mut long.clone()),
+            Err(DmaError::TooLong { len: MAX_LEN + 1 })
+        );
+
+        let mut transfer = unsafe { dma.start(&src[1..], &mut dst[..2]) }.unwrap();
+        assert!(transfer.is_done());
+        transfer.wait().unwrap();
+        assert_eq!(dst, [-2, 3, 3]);
+    }
+
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn runs_the_callback() {
+        let mut dma = Dma::new(Channel::C2);
+        dma.on_complete(Some(|| {
+            COMPLETED.fetch_add(1, Ordering::Relaxed);
+        }));
+        dma.copy(&[1u32, 2], &mut [0; 2]).unwrap();
+        dma.copy::<u32>(&[], &mut []).unwrap();
+        assert_eq!(COMPLETED.load(Ordering::Relaxed), 2);
+        dma.on_complete(None);
+        dma.copy(&[1u32, 2], &mut [0; 2]).unwrap();
+        assert_eq!(COMPLETED.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn streams_tiles_in_order() {
+        let mut dma = Dma::new(Channel::C1);
+        let mut buffers = [[0i16; 4]; 2];
+        let src: Vec<i16> = (0..10).collect();
+        let mut seen = Vec::new();
+        TileStreamer::new(&mut dma, &mut buffers)
+            .run(&src, |at, tile| {
+                seen.push((at, tile.to_vec()));
+                Ok::<_, DmaError>(())
+            })
+            .unwrap();
+        assert_eq!(
+            seen,
+            vec![
+                (0, vec![0, 1, 2, 3]),
+                (4, vec![4, 5, 6, 7]),
+                (8, vec![8, 9]),
+            ]
+        );
+        // the short last tile went over the start of the first one
+        assert_eq!(buffers, [[8, 9, 2, 3], [4, 5, 6, 7]]);
+
+        let mut calls = 0;
+        let result = TileStreamer::new(&mut dma, &mut buffers).run(&src, |at, _| {
+            calls += 1;
+            if at == 4 {
+                Err(DmaError::Bus)
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!((result, calls), (Err(DmaError::Bus), 2));
+        TileStreamer::new(&mut dma, &mut buffers)
+            .run(&[], |_, _| -> Result<(), DmaError> { unreachable!() })
+            .unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+//! Fixed-point numbers.
+//!
+//! `Fixed<FRAC, S>` is an `S` integer scaled by `2^-FRAC`, so `Fixed<8, i16>`
+//! is Q7.8. All arithmetic is done on integers, widened to `i64`, and
+//! saturates instead of wrapping; rounding is to nearest with ties towards
+//! positive infinity, like [`Requant`](crate::nn::Requant). Results are the
+//! same on the host and on the board.
+//!
+//! Constants can be written as floats and are converted when compiling:
+//!
+//! ```
+//! use parallel_fram::fixed::Fixed;
+//!
+//! type Q15 = Fixed<15, i16>;
+//! const HALF: Q15 = Q15::from_f32(0.5);
+//! assert_eq!(HALF.to_bits(), 0x4000);
+//! assert_eq!(HALF * HALF, Q15::from_f32(0.25));
+//! ```
+
+use core::fmt;
+use core::ops::{Add, Mul, Neg, Sub};
+
+use crate::tensor::{Element, Zero};
+
+/// Integer types a [`Fixed`] can be stored in.
+pub trait Storage: Copy + Ord + Zero {
+    const MIN: i64;
+    const MAX: i64;
+    /// Width in bits; `FRAC` must be smaller.
+    const BITS: u32;
+
+    fn to_i64(self) -> i64;
+
+    /// `x` clamped to `MIN..=MAX`.
+    fn saturate(x: i64) -> Self;
+}
+
+macro_rules! storage {
+    ($($t:ty),*) => {$(
+        impl Storage for $t {
+            const MIN: i64 = <$t>::MIN as i64;
+            const MAX: i64 = <$t>::MAX as i64;
+            const BITS: u32 = <$t>::BITS;
+
+            #[inline(always)]
+            fn to_i64(self) -> i64 {
+                self as i64
+            }
+
+            #[inline(always)]
+            fn saturate(x: i64) -> Self {
+                x.clamp(<Self as Storage>::MIN, <Self as Storage>::MAX) as $t
+            }
+        }
+    )*};
+}
+
+storage!(i8, i16, i32);
+
+/// `x / 2^shift`, rounded to nearest with ties towards positive infinity.
+#[inline(always)]
+pub fn round_shift(x: i64, shift: u32) -> i64 {
+    match shift {
+        0 => x,
+        // |x / 2^shift| is at most a half, rounded to 0
+        64.. => 0,
+        _ => x.saturating_add(1 << (shift - 1)) >> shift,
+    }
+}
+
+/// An `S` scaled by `2^-FRAC`. `FRAC` must be less than the bits of `S`,
+/// which fails the build as soon as such a number is made:
+///
+/// ```compile_fail
+/// use parallel_fram::fixed::Fixed;
+///
+/// let x = Fixed::<8, i8>::from_int(1);
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Fixed<const FRAC: u32, S = i16>(S);
+
+impl<const FRAC: u32, S: Storage> Fixed<FRAC, S> {
+    // Evaluated by everything that makes a number or scales by `FRAC`.
+    const FRAC_FITS: () = assert!(
+        FRAC < S::BITS,
+        "Fixed: FRAC must be less than the bits of S"
+    );
+
+    pub const ZERO: Self = Self::from_bits(S::ZERO);
+
+    /// The number whose raw representation is `bits`.
+    pub const fn from_bits(bits: S) -> Self {
+        let () = Self::FRAC_FITS;
+        Fixed(bits)
+    }
+
+    pub const fn to_bits(self) -> S {
+        self.0
+    }
+
+    /// Largest representable number.
+    pub fn max_value() -> Self {
+        Self::from_bits(S::saturate(S::MAX))
+    }
+
+    /// Smallest representable number.
+    pub fn min_value() -> Self {
+        Self::from_bits(S::saturate(S::MIN))
+    }
+
+    /// `n`, saturated.
+    pub fn from_int(n: i32) -> Self {
+        Self::from_bits(S::saturate((n as i64).saturating_mul(1 << FRAC)))
+    }
+
+    /// Converts to another format, rounding off and saturating.
+    pub fn convert<const TO: u32, T: Storage>(self) -> Fixed<TO, T> {
+        let () = Self::FRAC_FITS;
+        let x = self.0.to_i64();
+        let bits = if TO >= FRAC {
+            x.saturating_mul(1 << (TO - FRAC))
+        } else {
+            round_shift(x, FRAC - TO)
+        };
+        Fixed::from_bits(T::saturate(bits))
+    }
+
+    /// `self * 2^-shift`, rounded.
+    pub fn round_shift(self, shift: u32) -> Self {
+        Fixed(S::saturate(round_shift(self.0.to_i64(), shift)))
+    }
+
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Fixed(S::saturate(self.0.to_i64() + rhs.0.to_i64()))
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Fixed(S::saturate(self.0.to_i64() - rhs.0.to_i64()))
+    }
+
+    /// The product, rounded to `FRAC` bits.
+    pub fn saturating_mul(self, rhs: Self) -> Self {
+        Self::from_bits(S::saturate(round_shift(
+            self.0.to_i64() * rhs.0.to_i64(),
+            FRAC,
+        )))
+    }
+
+    /// For printing and host-side checks; not needed for the arithmetic.
+    pub fn to_f32(self) -> f32 {
+        let () = Self::FRAC_FITS;
+        self.0.to_i64() as f32 / (1u64 << FRAC) as f32
+    }
+}
+
+macro_rules! from_f32 {
+    ($($t:ty),*) => {$(
+        impl<const FRAC: u32> Fixed<FRAC, $t> {
+            /// `x` rounded to the nearest representable number, saturated.
+            /// Usable in constants.
+            pub const fn from_f32(x: f32) -> Self {
+                let scaled = x as f64 * (1u64 << FRAC) as f64 + 0.5;
+                // `as` truncates towards zero and saturates; floor instead
+                let mut bits = scaled as i64;
+                if (bits as f64) > scaled {
+                    bits -= 1;
+                }
+                let bits = if bits < <$t>::MIN as i64 {
+                    <$t>::MIN
+                } else if bits > <$t>::MAX as i64 {
+                    <$t>::MAX
+                } else {
+                    bits as $t
+                };
+                Self::from_bits(bits)
+            }
+        }
+    )*};
+}
+
+from_f32!(i8, i16, i32);
+
+impl<const FRAC: u32, S: Storage> Zero for Fixed<FRAC, S> {
+    const ZERO: Self = Self::from_bits(S::ZERO);
+}
+
+impl<const FRAC: u32, S: Storage> Default for Fixed<FRAC, S> {
+    fn default() -> Self {
+        Self::ZERO
+    }
+}
+
+impl<const FRAC: u32, S: Storage + fmt::Debug> Element for Fixed<FRAC, S> {
+    #[inline(always)]
+    fn widen(self) -> i64 {
+        self.0.to_i64()
+    }
+}
+
+impl<const FRAC: u32, S: Storage> Add for Fixed<FRAC, S> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        self.saturating_add(rhs)
+    }
+}
+
+impl<const FRAC: u32, S: Storage> Sub for Fixed<FRAC, S> {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        self.saturating_sub(rhs)
+    }
+}
+
+impl<const FRAC: u32, S: Storage> Mul for Fixed<FRAC, S> {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self {
+        self.saturating_mul(rhs)
+    }
+}
+
+impl<const FRAC: u32, S: Storage> Neg for Fixed<FRAC, S> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Fixed(S::saturate(-self.0.to_i64()))
+    }
+}
+
+impl<const FRAC: u32, S: Storage + fmt::Debug> fmt::Debug for Fixed<FRAC, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}/2^{}", self.0, FRAC)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type Q8 = Fixed<8, i16>;
+    type Q15 = Fixed<15, i16>;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(Q8::from_f32(1.0).to_bits(), 256);
+        assert_eq!(Q8::from_f32(-1.5).to_bits(), -384);
+        // ties go up
+        assert_eq!(Q8::from_f32(0.5 / 256.0).to_bits(), 1);
+        assert_eq!(Q8::from_f32(-0.5 / 256.0).to_bits(), 0);
+        assert_eq!(Q8::from_f32(-1.5 / 256.0).to_bits(), -1);
+        assert_eq!(Q15::from_f32(1.0), Q15::max_value());
+        assert_eq!(Q15::from_f32(-2.0), Q15::min_value());
+        assert_eq!(Q8::from_int(3).to_f32(), 3.0);
+        assert_eq!(Q8::from_int(1000), Q8::max_value());
+
+        const C: Fixed<4, i8> = Fixed::<4, i8>::from_f32(-0.3);
+        assert_eq!(C.to_bits(), -5);
+    }
+
+    #[test]
+    fn arithmetic_saturates() {
+        let a = Q8::from_f32(100.0);
+        assert_eq!((a + a), Q8::max_value());
+        assert_eq!((-a - a), Q8::min_value());
+        assert_eq!(-Q8::min_value(), Q8::max_value());
+        assert_eq!(
+            Q8::from_f32(1.5) * Q8::from_f32(-2.25),
+            Q8::from_f32(-3.375)
+        );
+        assert_eq!(a * a, Q8::max_value());
+        assert_eq!(Q15::from_f32(-1.0) * Q15::from_f32(-1.0), Q15::max_value());
+    }
+
+    #[test]
+    fn rounding() {
+        // 3/256 * 1/2 = 1.5/256 -> 2/256
+        let x = Q8::from_bits(3) * Q8::from_f32(0.5);
+        assert_eq!(x.to_bits(), 2);
+        let x = Q8::from_bits(-3) * Q8::from_f32(0.5);
+        assert_eq!(x.to_bits(), -1);
+        assert_eq!(Q8::from_bits(7).round_shift(1).to_bits(), 4);
+        assert_eq!(round_shift(-6, 2), -1);
+        assert_eq!(round_shift(i64::MIN, 63), -1);
+        assert_eq!(round_shift(i64::MIN, 64), 0);
+        assert_eq!(round_shift(i64::MAX, u32::MAX), 0);
+    }
+
+    #[test]
+    fn format_changes() {
+        let x = Q8::from_f32(1.7);
+        let wide: Fixed<16, i32> = x.convert();
+        assert_eq!(wide.to_bits(), x.to_bits() as i32 * 256);
+        assert_eq!(wide.convert::<8, i16>(), x);
+        let coarse: Fixed<2, i8> = x.convert();
+        assert_eq!(coarse.to_f32(), 1.75);
+        let small: Fixed<6, i8> = Q8::from_f32(3.0).convert();
+        assert_eq!(small, Fixed::<6, i8>::max_value());
+    }
+}
@@ -0,0 +1,483 @@
+//! FMC bank 1 (NOR/PSRAM/SRAM) driver.
+//!
+//! The FRAM is wired to bank 1 as an asynchronous SRAM. Instead of poking
+//! `BCRx`/`BTRx` by hand, describe the device with a [`NorSramBuilder`] and let
+//! [`NorSramBuilder::build`] check the combination before programming it:
+//!
+//! ```ignore
+//! let fram = NorSramBuilder::new(SubBank::Ne1)
+//!     .memory_type(MemoryType::Sram)
+//!     .bus_width(BusWidth::Bits16)
+//!     .write_enable(true)
+//!     .timing(Timing { addset: 1, addhld: 1, datast: 5, ..Timing::default() })
+//!     .build(dp.FMC)?;
+//! ```
+
+#[cfg(target_arch = "arm")]
+use stm32f3xx_hal_v2::pac;
+
+pub mod pins;
+pub mod timing;
+
+/// Base address of FMC bank 1; each sub-bank is a 64 MiB window above it.
+pub const BANK1_BASE: usize = 0x6000_0000;
+const SUB_BANK_SIZE: usize = 0x0400_0000;
+
+/// Chip select line (sub-bank) of bank 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubBank {
+    Ne1,
+    Ne2,
+    Ne3,
+    Ne4,
+}
+
+impl SubBank {
+    pub fn base_address(self) -> usize {
+        BANK1_BASE + SUB_BANK_SIZE * self as usize
+    }
+}
+
+/// `BCRx.MTYP`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryType {
+    Sram = 0b00,
+    Psram = 0b01,
+    Nor = 0b10,
+}
+
+/// `BCRx.MWID`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusWidth {
+    Bits8 = 0b00,
+    Bits16 = 0b01,
+}
+
+/// `BTRx.ACCMOD`, only used when extended mode is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessMode {
+    A = 0b00,
+    B = 0b01,
+    C = 0b10,
+    D = 0b11,
+}
+
+/// Access timings in HCLK cycles, as they end up in `BTRx`/`BWTRx`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    /// Address setup phase, 0..=15.
+    pub addset: u8,
+    /// Address hold phase, 1..=15 (only used by multiplexed and mode D accesses).
+    pub addhld: u8,
+    /// Data phase, 1..=255.
+    pub datast: u8,
+    /// Bus turnaround phase, 0..=15.
+    pub busturn: u8,
+    /// FMC_CLK divider for synchronous accesses, 2..=16 (stored as value - 1).
+    pub clkdiv: u8,
+    /// Data latency for synchronous NOR, 2..=17 (stored as value - 2).
+    pub datlat: u8,
+}
+
+impl Default for Timing {
+    // BTRx reset values: the slowest access the FMC can do.
+    fn default() -> Self {
+        Timing {
+            addset: 15,
+            addhld: 15,
+            datast: 255,
+            busturn: 15,
+            clkdiv: 16,
+            datlat: 17,
+        }
+    }
+}
+
+/// Reasons a configuration is refused by [`NorSramBuilder::build`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Burst mode needs a synchronous memory; SRAM is always asynchronous.
+    BurstOnAsyncSram,
+    /// Address/data multiplexing is only available for NOR and PSRAM.
+    MuxedSram,
+    /// Access modes B/C/D only exist in extended mode.
+    AccessModeWithoutExtendedMode,
+    /// Separate write timings only exist in extended mode.
+    WriteTimingWithoutExtendedMode,
+    /// A timing field is outside the range its register field can hold.
+    TimingOutOfRange(TimingField),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimingField {
+    AddressSetup,
+    AddressHold,
+    DataSetup,
+    BusTurnaround,
+    ClockDivision,
+    DataLatency,
+}
+
+/// Everything that goes into `BCRx`, `BTRx` and `BWTRx` for one sub-bank.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NorSramConfig {
+    pub sub_bank: SubBank,
+    pub memory_type: MemoryType,
+    pub bus_width: BusWidth,
+    pub muxed: bool,
+    pub write_enable: bool,
+    pub extended_mode: bool,
+    pub access_mode: AccessMode,
+    pub burst: bool,
+    pub async_wait: bool,
+    pub timing: Timing,
+    /// Write timings (`BWTRx`); reads and writes share `timing` when `None`.
+    pub write_timing: Option<Timing>,
+}
+
+impl NorSramConfig {
+    /// Checks the combinations the reference manual declares invalid.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.burst && self.memory_type == MemoryType::Sram {
+            return Err(Error::BurstOnAsyncSram);
+        }
+        if self.muxed && self.memory_type == MemoryType::Sram {
+            return Err(Error::MuxedSram);
+        }
+        if !self.extended_mode {
+            if self.access_mode != AccessMode::A {
+                return Err(Error::AccessModeWithoutExtendedMode);
+            }
+            if self.write_timing.is_some() {
+                return Err(Error::WriteTimingWithoutExtendedMode);
+            }
+        }
+        check_timing(&self.timing)?;
+        if let Some(ref write) = self.write_timing {
+            check_timing(write)?;
+        }
+        Ok(())
+    }
+}
+
+fn check_timing(t: &Timing) -> Result<(), Error> {
+    let checks = [
+        (t.addset <= 15, TimingField::AddressSetup),
+        (t.addhld >= 1 && t.addhld <= 15, TimingField::AddressHold),
+        (t.datast >= 1, TimingField::DataSetup),
+        (t.busturn <= 15, TimingField::BusTurnaround),
+        (t.clkdiv >= 2 && t.clkdiv <= 16, TimingField::ClockDivision),
+        (t.datlat >= 2 && t.datlat <= 17, TimingField::DataLatency),
+    ];
+    for &(ok, field) in checks.iter() {
+        if !ok {
+            return Err(Error::TimingOutOfRange(field));
+        }
+    }
+    Ok(())
+}
+
+/// Builder for a NOR/PSRAM/SRAM sub-bank of FMC bank 1.
+///
+/// Defaults describe a plain asynchronous 16-bit SRAM in mode A with writes
+/// enabled, which is what the FRAM needs apart from the timings.
+#[derive(Clone, Copy, Debug)]
+pub struct NorSramBuilder {
+    config: NorSramConfig,
+}
+
+impl NorSramBuilder {
+    pub fn new(sub_bank: SubBank) -> Self {
+        NorSramBuilder {
+            config: NorSramConfig {
+                sub_bank,
+                memory_type: MemoryType::Sram,
+                bus_width: BusWidth::Bits16,
+                muxed: false,
+                write_enable: true,
+                extended_mode: false,
+                access_mode: AccessMode::A,
+                burst: false,
+                async_wait: false,
+                timing: Timing::default(),
+                write_timing: None,
+            },
+        }
+    }
+
+    pub fn memory_type(mut self, memory_type: MemoryType) -> Self {
+        self.config.memory_type = memory_type;
+        self
+    }
+
+    pub fn bus_width(mut self, bus_width: BusWidth) -> Self {
+        self.config.bus_width = bus_width;
+        self
+    }
+
+    pub fn muxed(mut self, muxed: bool) -> Self {
+        self.config.muxed = muxed;
+        self
+    }
+
+    pub fn write_enable(mut self, enable: bool) -> Self {
+        self.config.write_enable = enable;
+        self
+    }
+
+    pub fn extended_mode(mut self, enable: bool) -> Self {
+        self.config.extended_mode = enable;
+        self
+    }
+
+    pub fn access_mode(mut self, mode: AccessMode) -> Self {
+        self.config.access_mode = mode;
+        self
+    }
+
+    pub fn burst(mut self, enable: bool) -> Self {
+        self.config.burst = enable;
+        self
+    }
+
+    pub fn async_wait(mut self, enable: bool) -> Self {
+        self.config.async_wait = enable;
+        self
+    }
+
+    pub fn timing(mut self, timing: Timing) -> Self {
+        self.config.timing = timing;
+        self
+    }
+
+    pub fn write_timing(mut self, timing: Timing) -> Self {
+        self.config.write_timing = Some(timing);
+        self
+    }
+
+    pub fn config(&self) -> &NorSramConfig {
+        &self.config
+    }
+
+    /// Validates the configuration and programs the sub-bank.
+    ///
+    /// The FMC clock must already be enabled in `RCC.AHBENR`.
+    #[cfg(target_arch = "arm")]
+    pub fn build(self, fmc: pac::FMC) -> Result<FmcNorSramBank, Error> {
+        self.config.validate()?;
+        program(&fmc, &self.config);
+        Ok(FmcNorSramBank {
+            fmc,
+            config: self.config,
+        })
+    }
+}
+
+/// An enabled, configured sub-bank. Owns the FMC peripheral.
+#[cfg(target_arch = "arm")]
+pub struct FmcNorSramBank {
+    fmc: pac::FMC,
+    config: NorSramConfig,
+}
+
+#[cfg(target_arch = "arm")]
+impl FmcNorSramBank {
+    pub fn config(&self) -> &NorSramConfig {
+        &self.config
+    }
+
+    /// Start of the memory window the device is mapped at.
+    pub fn base_address(&self) -> usize {
+        self.config.sub_bank.base_address()
+    }
+
+    /// Disables the sub-bank and gives the peripheral back.
+    pub fn release(self) -> pac::FMC {
+        macro_rules! disable {
+            ($bcr:ident) => {
+                self.fmc.$bcr.modify(|_, w| w.mbken().clear_bit())
+            };
+        }
+        match self.config.sub_bank {
+            SubBank::Ne1 => disable!(bcr1),
+            SubBank::Ne2 => disable!(bcr2),
+            SubBank::Ne3 => disable!(bcr3),
+            SubBank::Ne4 => disable!(bcr4),
+        }
+        self.fmc
+    }
+}
+
+#[cfg(target_arch = "arm")]
+fn program(fmc: &pac::FMC, c: &NorSramConfig) {
+    macro_rules! program {
+        ($bcr:ident, $btr:ident, $bwtr:ident) => {
+            unsafe {
+                fmc.$bcr.modify(|_, w| {
+                    w.mtyp().bits(c.memory_type as u8);
+                    w.mwid().bits(c.bus_width as u8);
+                    w.muxen().bit(c.muxed);
+                    // NOR flash needs the NOR flash memory access enabled
+                    w.faccen().bit(c.memory_type == MemoryType::Nor);
+                    w.bursten().bit(c.burst);
+                    w.wren().bit(c.write_enable);
+                    w.extmod().bit(c.extended_mode);
+                    w.asyncwait().bit(c.async_wait);
+                    w
+                });
+
+                fmc.$btr.modify(|_, w| {
+                    w.addset().bits(c.timing.addset);
+                    w.addhld().bits(c.timing.addhld);
+                    w.datast().bits(c.timing.datast);
+                    w.busturn().bits(c.timing.busturn);
+                    w.clkdiv().bits(c.timing.clkdiv - 1);
+                    w.datlat().bits(c.timing.datlat - 2);
+                    w.accmod().bits(c.access_mode as u8);
+                    w
+                });
+
+                if let Some(ref t) = c.write_timing {
+                    fmc.$bwtr.modify(|_, w| {
+                        w.addset().bits(t.addset);
+                        w.addhld().bits(t.addhld);
+                        w.datast().bits(t.datast);
+                        w.busturn().bits(t.busturn);
+                        w.accmod().bits(c.access_mode as u8);
+                        w
+                    });
+                }
+
+                // enable the bank last, once everything else is in place
+                fmc.$bcr.modify(|_, w| w.mbken().set_bit());
+            }
+        };
+    }
+
+    match c.sub_bank {
+        SubBank::Ne1 => program!(bcr1, btr1, bwtr1),
+        SubBank::Ne2 => program!(bcr2, btr2, bwtr2),
+        SubBank::Ne3 => program!(bcr3, btr3, bwtr3),
+        SubBank::Ne4 => program!(bcr4, btr4, bwtr4),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // the board's F-RAM, see `FRAM_TIMINGS` in `main.rs`
+    const FRAM: Timing = Timing {
+        addset: 0,
+        addhld: 1,
+        datast: 2,
+        busturn: 1,
+        clkdiv: 2,
+        datlat: 2,
+    };
+
+    fn fram() -> NorSramBuilder {
+        NorSramBuilder::new(SubBank::Ne1).timing(FRAM)
+    }
+
+    #[test]
+    fn sub_bank_windows() {
+        assert_eq!(SubBank::Ne1.base_address(), 0x6000_0000);
+        assert_eq!(SubBank::Ne2.base_address(), 0x6400_0000);
+        assert_eq!(SubBank::Ne4.base_address(), 0x6c00_0000);
+    }
+
+    #[test]
+    fn accepts_valid_configurations() {
+        assert_eq!(fram().config().validate(), Ok(()));
+        assert_eq!(
+            NorSramBuilder::new(SubBank::Ne1).config().validate(),
+            Ok(())
+        );
+        let nor = fram()
+            .memory_type(MemoryType::Nor)
+            .muxed(true)
+            .burst(true)
+            .extended_mode(true)
+            .access_mode(AccessMode::D)
+            .write_timing(FRAM);
+        assert_eq!(nor.config().validate(), Ok(()));
+        assert_eq!(nor.config().write_timing, Some(FRAM));
+    }
+
+    #[test]
+    fn rejects_invalid_combinations() {
+        let cases = [
+            (fram().burst(true), Error::BurstOnAsyncSram),
+            (fram().muxed(true), Error::MuxedSram),
+            (
+                fram().access_mode(AccessMode::B),
+                Error::AccessModeWithoutExtendedMode,
+            ),
+            (
+                fram().write_timing(FRAM),
+                Error::WriteTimingWithoutExtendedMode,
+            ),
+        ];
+        for (builder, error) in cases.iter() {
+            assert_eq!(builder.config().validate(), Err(*error));
+        }
+    }
+
+    #[test]
+    fn timing_field_ranges() {
+        use self::TimingField::*;
+
+        type Set = fn(&mut Timing, u8);
+        // (field, smallest legal, largest legal, setter)
+        let fields: [(TimingField, u8, u8, Set); 6] = [
+            (AddressSetup, 0, 15, |t, v| t.addset = v),
+            (AddressHold, 1, 15, |t, v| t.addhld = v),
+            (DataSetup, 1, 255, |t, v| t.datast = v),
+            (BusTurnaround, 0, 15, |t, v| t.busturn = v),
+            (ClockDivision, 2, 16, |t, v| t.clkdiv = v),
+            (DataLatency, 2, 17, |t, v| t.datlat = v),
+        ];
+        for &(field, min, max, set) in fields.iter() {
+            let mut t = FRAM;
+            for &v in [min, max].iter() {
+                set(&mut t, v);
+                assert_eq!(check_timing(&t), Ok(()), "{:?} = {}", field, v);
+            }
+            let mut out = Vec::new();
+            if min > 0 {
+                out.push(min - 1);
+            }
+            if max < u8::MAX {
+                out.push(max + 1);
+            }
+            for &v in out.iter() {
+                set(&mut t, v);
+                assert_eq!(
+                    check_timing(&t),
+                    Err(Error::TimingOutOfRange(field)),
+                    "{:?} = {}",
+                    field,
+                    v
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn checks_write_timings_too() {
+        let bad = Timing { datast: 0, ..FRAM };
+        assert_eq!(
+            fram()
+                .extended_mode(true)
+                .write_timing(bad)
+                .config()
+                .validate(),
+            Err(Error::TimingOutOfRange(TimingField::DataSetup))
+        );
+        assert_eq!(
+            fram().timing(bad).config().validate(),
+            Err(Error::TimingOutOfRange(TimingField::DataSetup))
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! FMC pin assignment.
+//!
+//! A board describes its wiring as a table of [`FmcPin`]s, [`check`] verifies
+//! it covers the bus exactly (usable in a `const` so a bad table fails the
+//! build) and `apply` puts every listed pin into AF12.
+
+#[cfg(target_arch = "arm")]
+use stm32f3xx_hal_v2::pac;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+    D,
+    E,
+    F,
+    G,
+    H,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+    /// Address line A0..=A25
+    A(u8),
+    /// Data line D0..=D15
+    D(u8),
+    /// Output enable
+    Noe,
+    /// Write enable
+    Nwe,
+    /// Chip select NE1..=NE4
+    Ne(u8),
+    /// Byte lane NBL0..=NBL1
+    Nbl(u8),
+}
+
+impl Signal {
+    // Unique index of every signal, used for the "seen" bitmask in `check`.
+    const fn index(self) -> Option<u32> {
+        match self {
+            Signal::A(n) if n <= 25 => Some(n as u32),
+            Signal::D(n) if n <= 15 => Some(26 + n as u32),
+            Signal::Noe => Some(42),
+            Signal::Nwe => Some(43),
+            Signal::Ne(n) if n >= 1 && n <= 4 => Some(43 + n as u32),
+            Signal::Nbl(n) if n <= 1 => Some(48 + n as u32),
+            _ => None,
+        }
+    }
+}
+
+/// One GPIO pin routed to an FMC signal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FmcPin {
+    pub port: Port,
+    pub pin: u8,
+    pub signal: Signal,
+}
+
+impl FmcPin {
+    pub const fn new(port: Port, pin: u8, signal: Signal) -> Self {
+        FmcPin { port, pin, signal }
+    }
+}
+
+/// What is wrong with a pin table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PinError {
+    /// Pin number above 15 or a signal that does not exist.
+    Invalid(FmcPin),
+    /// The same GPIO appears twice.
+    DuplicatePin(FmcPin),
+    /// The same FMC signal is routed to two pins.
+    DuplicateSignal(FmcPin),
+    /// A signal needed for the bus width is not routed.
+    MissingSignal(Signal),
+}
+
+impl PinError {
+    /// Panics with a message naming the problem; `const` friendly.
+    pub const fn panic(self) -> ! {
+        match self {
+            PinError::Invalid(_) => panic!("FMC pin map: invalid pin or signal"),
+            PinError::DuplicatePin(_) => panic!("FMC pin map: GPIO assigned twice"),
+            PinError::DuplicateSignal(_) => panic!("FMC pin map: signal assigned twice"),
+            PinError::MissingSignal(Signal::A(_)) => panic!("FMC pin map: address line missing"),
+            PinError::MissingSignal(Signal::D(_)) => panic!("FMC pin map: data line missing"),
+            PinError::MissingSignal(_) => panic!("FMC pin map: control signal missing"),
+        }
+    }
+}
+
+/// Checks that `pins` routes A0..A(`address_bits` - 1), D0..D(`data_bits` - 1),
+/// NOE, NWE and NE`chip_select` exactly once, and uses no GPIO twice.
+pub const fn check(
+    pins: &[FmcPin],
+    address_bits: u8,
+    data_bits: u8,
+    chip_select: u8,
+) -> Result<(), PinError> {
+    let mut used_pins: u128 = 0;
+    let mut seen: u64 = 0;
+
+    let mut i = 0;
+    while i < pins.len() {
+        let p = pins[i];
+        let signal = match p.signal.index() {
+            Some(index) if p.pin <= 15 => index,
+            _ => return Err(PinError::Invalid(p)),
+        };
+        let gpio = p.port as u32 * 16 + p.pin as u32;
+        if used_pins & (1 << gpio) != 0 {
+            return Err(PinError::DuplicatePin(p));
+        }
+        if seen & (1 << signal) != 0 {
+            return Err(PinError::DuplicateSignal(p));
+        }
+        used_pins |= 1 << gpio;
+        seen |= 1 << signal;
+        i += 1;
+    }
+
+    let mut n = 0;
+    while n < address_bits {
+        if !routed(seen, Signal::A(n)) {
+            return Err(PinError::MissingSignal(Signal::A(n)));
+        }
+        n += 1;
+    }
+    let mut n = 0;
+    while n < data_bits {
+        if !routed(seen, Signal::D(n)) {
+            return Err(PinError::MissingSignal(Signal::D(n)));
+        }
+        n += 1;
+    }
+    if !routed(seen, Signal::Noe) {
+        return Err(PinError::MissingSignal(Signal::Noe));
+    }
+    if !routed(seen, Signal::Nwe) {
+        return Err(PinError::MissingSignal(Signal::Nwe));
+    }
+    if !routed(seen, Signal::Ne(chip_select)) {
+        return Err(PinError::MissingSignal(Signal::Ne(chip_select)));
+    }
+    Ok(())
+}
+
+const fn routed(seen: u64, signal: Signal) -> bool {
+    match signal.index() {
+        Some(index) => seen & (1 << index) != 0,
+        None => false,
+    }
+}
+
+/// The GPIO ports that carry FMC signals on the STM32F303xE.
+///
+/// Their clocks must be enabled in `RCC.AHBENR` before calling [`apply`].
+#[cfg(target_arch = "arm")]
+pub struct FmcPorts {
+    pub gpiod: pac::GPIOD,
+    pub gpioe: pac::GPIOE,
+    pub gpiof: pac::GPIOF,
+    pub gpiog: pac::GPIOG,
+    pub gpioh: pac::GPIOH,
+}
+
+/// Switches every pin in `pins` to AF12 (FMC) at very high speed.
+#[cfg(target_arch = "arm")]
+pub fn apply(pins: &[FmcPin], ports: &FmcPorts) {
+    const AF12: u32 = 12;
+
+    macro_rules! configure {
+        ($gpio:expr, $pin:expr) => {{
+            let pin = $pin as u32;
+            unsafe {
+                $gpio.moder.modify(|r, w| {
+                    w.bits(r.bits() & !(0b11 << (2 * pin)) | (0b10 << (2 * pin)))
+                });
+                if pin < 8 {
+                    $gpio.afrl.modify(|r, w| {
+                        w.bits(r.bits() & !(0xf << (4 * pin)) | (AF12 << (4 * pin)))
+                    });
+                } else {
+                    let shift = 4 * (pin - 8);
+                    $gpio.afrh.modify(|r, w| {
+                        w.bits(r.bits() & !(0xf << shift) | (AF12 << shift))
+                    });
+                }
+                $gpio.ospeedr.modify(|r, w| w.bits(r.bits() | (0b11 << (2 * pin))));
+            }
+        }};
+    }
+
+    for p in pins {
+        match p.port {
+            Port::D => configure!(ports.gpiod, p.pin),
+            Port::E => configure!(ports.gpioe, p.pin),
+            Port::F => configure!(ports.gpiof, p.pin),
+            Port::G => configure!(ports.gpiog, p.pin),
+            Port::H => configure!(ports.gpioh, p.pin),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // two address and two data lines on NE1
+    const BUS: [FmcPin; 7] = [
+        FmcPin::new(Port::F, 0, Signal::A(0)),
+        FmcPin::new(Port::F, 1, Signal::A(1)),
+        FmcPin::new(Port::D, 14, Signal::D(0)),
+        FmcPin::new(Port::D, 15, Signal::D(1)),
+        FmcPin::new(Port::D, 4, Signal::Noe),
+        FmcPin::new(Port::D, 5, Signal::Nwe),
+        FmcPin::new(Port::D, 7, Signal::Ne(1)),
+    ];
+
+    const _: () = match check(&BUS, 2, 2, 1) {
+        Ok(()) => (),
+        Err(e) => e.panic(),
+    };
+
+    fn with(extra: FmcPin) -> Vec<FmcPin> {
+        let mut pins = BUS.to_vec();
+        pins.push(extra);
+        pins
+    }
+
+    #[test]
+    fn accepts_a_complete_table() {
+        assert_eq!(check(&BUS, 2, 2, 1), Ok(()));
+        // extra signals beyond the bus width are allowed
+        assert_eq!(check(&BUS, 1, 1, 1), Ok(()));
+        let nbl = with(FmcPin::new(Port::E, 0, Signal::Nbl(0)));
+        assert_eq!(check(&nbl, 2, 2, 1), Ok(()));
+    }
+
+    #[test]
+    fn rejects_conflicts() {
+        let twice = FmcPin::new(Port::F, 0, Signal::A(2));
+        assert_eq!(
+            check(&with(twice), 2, 2, 1),
+            Err(PinError::DuplicatePin(twice))
+        );
+        let twice = FmcPin::new(Port::G, 0, Signal::Noe);
+        assert_eq!(
+            check(&with(twice), 2, 2, 1),
+            Err(PinError::DuplicateSignal(twice))
+        );
+        // the same pin number on another port is another GPIO
+        let other_port = FmcPin::new(Port::E, 14, Signal::D(2));
+        assert_eq!(check(&with(other_port), 2, 3, 1), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_pins() {
+        for &pin in [
+            FmcPin::new(Port::F, 16, Signal::A(2)),
+            FmcPin::new(Port::F, 2, Signal::A(26)),
+            FmcPin::new(Port::F, 2, Signal::D(16)),
+            FmcPin::new(Port::F, 2, Signal::Ne(0)),
+            FmcPin::new(Port::F, 2, Signal::Ne(5)),
+            FmcPin::new(Port::F, 2, Signal::Nbl(2)),
+        ]
+        .iter()
+        {
+            assert_eq!(check(&with(pin), 2, 2, 1), Err(PinError::Invalid(pin)));
+        }
+    }
+
+    #[test]
+    fn reports_missing_signals() {
+        for i in 0..BUS.len() {
+            let mut pins = BUS.to_vec();
+            let missing = pins.remove(i);
+            assert_eq!(
+                check(&pins, 2, 2, 1),
+                Err(PinError::MissingSignal(missing.signal))
+            );
+        }
+        assert_eq!(
+            check(&BUS, 3, 2, 1),
+            Err(PinError::MissingSignal(Signal::A(2)))
+        );
+        assert_eq!(
+            check(&BUS, 2, 3, 1),
+            Err(PinError::MissingSignal(Signal::D(2)))
+        );
+        assert_eq!(
+            check(&BUS, 2, 2, 2),
+            Err(PinError::MissingSignal(Signal::Ne(2)))
+        );
+        assert_eq!(
+            check(&[], 0, 0, 1),
+            Err(PinError::MissingSignal(Signal::Noe))
+        );
+    }
+}
@@ -0,0 +1,291 @@
+//! Converts datasheet timings into FMC cycle counts.
+//!
+//! The `BTRx` fields count HCLK cycles, so the same part needs different
+//! values whenever the clock tree changes. [`DeviceTimings::to_timing`] takes
+//! the nanosecond figures from the memory datasheet and the HCLK frequency
+//! the FMC actually runs at, and rounds every phase up to whole cycles.
+
+use core::convert::TryFrom;
+
+use super::Timing;
+
+/// Asynchronous access timings of a memory part, in nanoseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceTimings {
+    /// Address valid to chip enable / write enable (tAS).
+    pub address_setup_ns: u32,
+    /// Address hold after the strobe (tAH).
+    pub address_hold_ns: u32,
+    /// Data setup to the end of the write strobe, or output valid for reads (tDS).
+    pub data_setup_ns: u32,
+    /// Minimum read/write cycle time (tRC/tWC).
+    pub cycle_time_ns: u32,
+    /// Time the part needs to release the bus between accesses.
+    pub bus_turnaround_ns: u32,
+}
+
+/// A datasheet constraint that cannot be met at the requested HCLK.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimingError {
+    ZeroClock,
+    /// ADDSET would need more than 15 cycles.
+    AddressSetupTooLong { cycles: u32 },
+    /// ADDHLD would need more than 15 cycles.
+    AddressHoldTooLong { cycles: u32 },
+    /// DATAST would need more than 255 cycles.
+    DataSetupTooLong { cycles: u32 },
+    /// BUSTURN would need more than 15 cycles.
+    BusTurnaroundTooLong { cycles: u32 },
+}
+
+const MAX_ADDSET: u32 = 15;
+const MAX_ADDHLD: u32 = 15;
+const MAX_DATAST: u32 = 255;
+const MAX_BUSTURN: u32 = 15;
+
+/// Number of whole HCLK cycles covering `ns` nanoseconds, `u32::MAX` if
+/// there are more than that (far beyond any `BTRx` field anyway).
+pub fn ns_to_cycles(ns: u32, hclk_hz: u32) -> u32 {
+    let cycles = (ns as u64 * hclk_hz as u64).div_ceil(1_000_000_000);
+    u32::try_from(cycles).unwrap_or(u32::MAX)
+}
+
+impl DeviceTimings {
+    /// Mode A timings for an asynchronous SRAM-like part at `hclk_hz`.
+    ///
+    /// A read takes ADDSET + DATAST cycles, so DATAST is stretched until the
+    /// whole access also covers the cycle time.
+    pub fn to_timing(&self, hclk_hz: u32) -> Result<Timing, TimingError> {
+        if hclk_hz == 0 {
+            return Err(TimingError::ZeroClock);
+        }
+
+        let addset = ns_to_cycles(self.address_setup_ns, hclk_hz);
+        if addset > MAX_ADDSET {
+            return Err(TimingError::AddressSetupTooLong { cycles: addset });
+        }
+
+        let addhld = ns_to_cycles(self.address_hold_ns, hclk_hz).max(1);
+        if addhld > MAX_ADDHLD {
+            return Err(TimingError::AddressHoldTooLong { cycles: addhld });
+        }
+
+        let cycle = ns_to_cycles(self.cycle_time_ns, hclk_hz);
+        let datast = ns_to_cycles(self.data_setup_ns, hclk_hz)
+            .max(cycle.saturating_sub(addset))
+            .max(1);
+        if datast > MAX_DATAST {
+            return Err(TimingError::DataSetupTooLong { cycles: datast });
+        }
+
+        let busturn = ns_to_cycles(self.bus_turnaround_ns, hclk_hz);
+        if busturn > MAX_BUSTURN {
+            return Err(TimingError::BusTurnaroundTooLong { cycles: busturn });
+        }
+
+        Ok(Timing {
+            addset: addset as u8,
+            addhld: addhld as u8,
+            datast: datast as u8,
+            busturn: busturn as u8,
+            // unused by asynchronous accesses, keep the smallest legal values
+            clkdiv: 2,
+            datlat: 2,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Cypress FM28V100, 128K x 8 parallel F-RAM
+    const FM28V100: DeviceTimings = DeviceTimings {
+        address_setup_ns: 0,
+        address_hold_ns: 10,
+        data_setup_ns: 60,
+        cycle_time_ns: 90,
+        bus_turnaround_ns: 10,
+    };
+
+    // Cypress FM22L16, 256K x 16 parallel F-RAM
+    const FM22L16: DeviceTimings = DeviceTimings {
+        address_setup_ns: 0,
+        address_hold_ns: 20,
+        data_setup_ns: 55,
+        cycle_time_ns: 110,
+        bus_turnaround_ns: 15,
+    };
+
+    // Cypress FM18W08, 32K x 8 wide-voltage F-RAM
+    const FM18W08: DeviceTimings = DeviceTimings {
+        address_setup_ns: 0,
+        address_hold_ns: 0,
+        data_setup_ns: 70,
+        cycle_time_ns: 130,
+        bus_turnaround_ns: 0,
+    };
+
+    fn access_ns(t: &Timing, hclk_hz: u32) -> u64 {
+        (t.addset as u64 + t.datast as u64) * 1_000_000_000 / hclk_hz as u64
+    }
+
+    #[test]
+    fn rounds_up_to_whole_cycles() {
+        assert_eq!(ns_to_cycles(0, 72_000_000), 0);
+        assert_eq!(ns_to_cycles(1, 72_000_000), 1);
+        // 62.5 ns period
+        assert_eq!(ns_to_cycles(62, 16_000_000), 1);
+        assert_eq!(ns_to_cycles(63, 16_000_000), 2);
+        assert_eq!(ns_to_cycles(125, 16_000_000), 2);
+        assert_eq!(ns_to_cycles(u32::MAX, u32::MAX), u32::MAX);
+    }
+
+    #[test]
+    fn fm28v100() {
+        let t = FM28V100.to_timing(72_000_000).unwrap();
+        // 13.9 ns period: 60 ns -> 5, 90 ns -> 7 cycles
+        assert_eq!((t.addset, t.addhld, t.datast, t.busturn), (0, 1, 7, 1));
+
+        let t = FM28V100.to_timing(16_000_000).unwrap();
+        assert_eq!((t.addset, t.addhld, t.datast, t.busturn), (0, 1, 2, 1));
+
+        let t = FM28V100.to_timing(8_000_000).unwrap();
+        assert_eq!((t.addset, t.addhld, t.datast, t.busturn), (0, 1, 1, 1));
+    }
+
+    #[test]
+    fn fm22l16() {
+        let t = FM22L16.to_timing(64_000_000).unwrap();
+        // 15.6 ns period: 20 ns -> 2, 55 ns -> 4, 110 ns -> 8 cycles
+        assert_eq!((t.addset, t.addhld, t.datast, t.busturn), (0, 2, 8, 1));
+
+        let t = FM22L16.to_timing(36_000_000).unwrap();
+        assert_eq!((t.addset, t.addhld, t.datast, t.busturn), (0, 1, 4, 1));
+
+        // the board's F-RAM at the `CLOCK_CONFIG` HCLK
+        let t = FM22L16.to_timing(16_000_000).unwrap();
+        assert_eq!((t.addset, t.addhld, t.datast, t.busturn), (0, 1, 2, 1));
+    }
+
+    #[test]
+    fn fm18w08() {
+        let t = FM18W08.to_timing(72_000_000).unwrap();
+        assert_eq!((t.addset, t.addhld, t.datast, t.busturn), (0, 1, 10, 0));
+    }
+
+    #[test]
+    fn access_always_covers_cycle_time() {
+        for part in [FM28V100, FM22L16, FM18W08].iter() {
+            for mhz in 1..=72 {
+                let hclk = mhz * 1_000_000;
+                let t = part.to_timing(hclk).unwrap();
+                assert!(access_ns(&t, hclk) >= part.cycle_time_ns as u64);
+                assert!(t.datast as u32 >= ns_to_cycles(part.data_setup_ns, hclk));
+            }
+        }
+    }
+
+    #[test]
+    fn reports_unreachable_constraints() {
+        let slow = DeviceTimings {
+            address_setup_ns: 250,
+            ..FM28V100
+        };
+        assert_eq!(
+            slow.to_timing(72_000_000),
+            Err(TimingError::AddressSetupTooLong { cycles: 18 })
+        );
+        assert!(slow.to_timing(8_000_000).is_ok());
+
+        let slow = DeviceTimings {
+            cycle_time_ns: 4_000,
+            ..FM28V100
+        };
+        assert_eq!(
+            slow.to_timing(72_000_000),
+            Err(TimingError::DataSetupTooLong { cycles: 288 })
+        );
+
+        let slow = DeviceTimings {
+            bus_turnaround_ns: 300,
+            ..FM28V100
+        };
+        assert_eq!(
+            slow.to_timing(72_000_000),
+            Err(TimingError::BusTurnaroundTooLong { cycles: 22 })
+        );
+
+        assert_eq!(FM28V100.to_timing(0), Err(TimingError::ZeroClock));
+    }
+
+    #[test]
+    fn accepts_up_to_each_field_maximum() {
+        // at 1 GHz every nanosecond is one cycle
+        const GHZ: u32 = 1_000_000_000;
+        let zero = DeviceTimings {
+            address_setup_ns: 0,
+            address_hold_ns: 0,
+            data_setup_ns: 0,
+            cycle_time_ns: 0,
+            bus_turnaround_ns: 0,
+        };
+        let t = zero.to_timing(GHZ).unwrap();
+        assert_eq!((t.addset, t.addhld, t.datast, t.busturn), (0, 1, 1, 0));
+
+        let max = DeviceTimings {
+            address_setup_ns: MAX_ADDSET,
+            address_hold_ns: MAX_ADDHLD,
+            data_setup_ns: MAX_DATAST,
+            cycle_time_ns: 0,
+            bus_turnaround_ns: MAX_BUSTURN,
+        };
+        let t = max.to_timing(GHZ).unwrap();
+        assert_eq!((t.addset, t.addhld, t.datast, t.busturn), (15, 15, 255, 15));
+
+        let over = |t: DeviceTimings| t.to_timing(GHZ);
+        assert_eq!(
+            over(DeviceTimings {
+                address_setup_ns: MAX_ADDSET + 1,
+                ..max
+            }),
+            Err(TimingError::AddressSetupTooLong { cycles: 16 })
+        );
+        assert_eq!(
+            over(DeviceTimings {
+                address_hold_ns: MAX_ADDHLD + 1,
+                ..max
+            }),
+            Err(TimingError::AddressHoldTooLong { cycles: 16 })
+        );
+        assert_eq!(
+            over(DeviceTimings {
+                data_setup_ns: MAX_DATAST + 1,
+                ..max
+            }),
+            Err(TimingError::DataSetupTooLong { cycles: 256 })
+        );
+        assert_eq!(
+            over(DeviceTimings {
+                bus_turnaround_ns: MAX_BUSTURN + 1,
+                ..max
+            }),
+            Err(TimingError::BusTurnaroundTooLong { cycles: 16 })
+        );
+        // the cycle time stretches DATAST past its maximum too
+        assert_eq!(
+            over(DeviceTimings {
+                cycle_time_ns: MAX_ADDSET + MAX_DATAST,
+                ..max
+            }),
+            Ok(t)
+        );
+        assert_eq!(
+            over(DeviceTimings {
+                cycle_time_ns: MAX_ADDSET + MAX_DATAST + 1,
+                ..max
+            }),
+            Err(TimingError::DataSetupTooLong { cycles: 256 })
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Access to the parallel F-RAM.
+//!
+//! Code that keeps state in F-RAM is written against [`FramDevice`], which
+//! addresses the memory by byte offset. On the board that is the FMC window
+//! ([`MappedFram`]); on the host it is a plain buffer ([`MemFram`]) so the
+//! same code can be unit tested, including against power failures with
+//! [`sim::SimFram`].
+
+pub mod heap;
+pub mod kv;
+pub mod log;
+#[cfg(target_arch = "arm")]
+mod mapped;
+#[cfg(not(target_arch = "arm"))]
+mod mem;
+pub mod memtest;
+#[cfg(not(target_arch = "arm"))]
+pub mod sim;
+pub mod undo;
+
+#[cfg(target_arch = "arm")]
+pub use self::mapped::MappedFram;
+#[cfg(not(target_arch = "arm"))]
+pub use self::mem::MemFram;
+#[cfg(target_arch = "arm")]
+pub use self::undo::{recover, transaction};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FramError {
+    /// `offset..offset + len` is not inside the device.
+    OutOfBounds { offset: usize, len: usize },
+    /// A half-word access at an odd offset.
+    Misaligned { offset: usize },
+    /// The device lost power; only [`sim::SimFram`] reports this.
+    PowerLost,
+}
+
+/// A byte range of a [`FramDevice`] set aside for one user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl Region {
+    pub const fn new(offset: usize, len: usize) -> Self {
+        Region { offset, len }
+    }
+
+    pub const fn end(&self) -> usize {
+        self.offset + self.len
+    }
+}
+
+/// Word-aligned backing storage for a [`Region`], to be placed in F-RAM:
+///
+/// ```ignore
+/// #[link_section = ".fram_data"]
+/// static mut LOG_AREA: FramArea<1024> = FramArea::new();
+/// ```
+#[repr(C, align(4))]
+pub struct FramArea<const N: usize>([u8; N]);
+
+impl<const N: usize> FramArea<N> {
+    pub const fn new() -> Self {
+        FramArea([0; N])
+    }
+}
+
+impl<const N: usize> Default for FramArea<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A byte-addressed non-volatile memory on a 16-bit bus.
+///
+/// Half-word accesses are single bus transactions and therefore the unit of
+/// atomicity; wider values are split into half-words, low half first.
+pub trait FramDevice {
+    /// Size in bytes.
+    fn size(&self) -> usize;
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), FramError>;
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FramError>;
+
+    fn read_u16(&self, offset: usize) -> Result<u16, FramError>;
+
+    fn write_u16(&mut self, offset: usize, value: u16) -> Result<(), FramError>;
+
+    /// Waits until every write issued so far has reached the memory.
+    fn flush(&mut self) {}
+
+    fn read_u32(&self, offset: usize) -> Result<u32, FramError> {
+        check_access(self.size(), offset, 4, 2)?;
+        let lo = self.read_u16(offset)? as u32;
+        let hi = self.read_u16(offset + 2)? as u32;
+        Ok(lo | hi << 16)
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) -> Result<(), FramError> {
+        check_access(self.size(), offset, 4, 2)?;
+        self.write_u16(offset, value as u16)?;
+        self.write_u16(offset + 2, (value >> 16) as u16)
+    }
+}
+
+/// The bytes of `value`. `T` should have no padding (integers, arrays of
+/// them, `#[repr(C)]` structs of them).
+pub fn bytes_of<T: Copy>(value: &T) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>())
+    }
+}
+
+/// Reads a `T` stored with [`bytes_of`]. Every bit pattern must be a valid `T`.
+pub fn read_value<T: Copy, D: FramDevice>(fram: &D, offset: usize) -> Result<T, FramError> {
+    let mut value = core::mem::MaybeUninit::<T>::uninit();
+    let bytes = unsafe {
+        core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, core::mem::size_of::<T>())
+    };
+    fram.read(offset, bytes)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Checks that `len` bytes at `offset` fit in `size` and are `align`-aligned.
+pub fn check_access(size: usize, offset: usize, len: usize, align: usize) -> Result<(), FramError> {
+    if !offset.is_multiple_of(align) {
+        return Err(FramError::Misaligned { offset });
+    }
+    match offset.checked_add(len) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(FramError::OutOfBounds { offset, len }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn words_are_little_endian_half_words() {
+        let mut fram = MemFram::new(16);
+        fram.write_u32(4, 0x1234_5678).unwrap();
+        assert_eq!(fram.read_u16(4), Ok(0x5678));
+        assert_eq!(fram.read_u16(6), Ok(0x1234));
+        assert_eq!(fram.read_u32(4), Ok(0x1234_5678));
+        assert_eq!(&fram.image()[4..8], &[0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn bytes_at_any_offset() {
+        let mut fram = MemFram::new(8);
+        fram.write(1, &[1, 2, 3]).unwrap();
+        let mut buf = [0; 5];
+        fram.read(0, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn rejects_bad_accesses() {
+        let mut fram = MemFram::new(8);
+        assert_eq!(fram.read_u16(3), Err(FramError::Misaligned { offset: 3 }));
+        assert_eq!(
+            fram.write_u32(6, 0),
+            Err(FramError::OutOfBounds { offset: 6, len: 4 })
+        );
+        assert_eq!(
+            fram.write(7, &[0, 0]),
+            Err(FramError::OutOfBounds { offset: 7, len: 2 })
+        );
+        assert_eq!(
+            fram.read(usize::MAX, &mut [0]),
+            Err(FramError::OutOfBounds {
+                offset: usize::MAX,
+                len: 1
+            })
+        );
+    }
+}
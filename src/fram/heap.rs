@@ -0,0 +1,754 @@
+//! A persistent heap in an F-RAM region.
+//!
+//! Blocks are allocated and freed inside an undo-log [`Transaction`], so an
+//! allocation and the write that links the new block into a persistent data
+//! structure either both survive a power failure or neither does. After a
+//! reboot, [`FramHeap::recover`] rolls back the interrupted transaction and
+//! rebuilds the free list from the block headers, merging neighbouring free
+//! blocks and releasing every transient block.
+//!
+//! Heap layout:
+//!
+//! ```text
+//! | magic: u32 | free: u32 | root: u32 | pad: u32 | block | block | ...
+//! block: | size: u32 | next: u32 | payload ... |
+//! ```
+//!
+//! Offsets are device offsets, with 0 for none. `size` covers the whole
+//! block and is a multiple of 8; its low bits flag used and transient
+//! blocks. `next` links free blocks in address order and is the start of the
+//! payload of a used one, so payloads are 8-byte aligned.
+//!
+//! [`PBox`] and [`PVec`] are handles to values on the heap, small enough to
+//! be stored in F-RAM themselves. The heap's root slot holds the offset of
+//! the first object, to find everything else from after a reboot:
+//!
+//! ```ignore
+//! heap::recover()?;
+//! let count = fram::transaction(|tx| {
+//!     let heap = heap::heap(tx.fram());
+//!     let count = PBox::new_in(&heap, tx, 0u32)?;
+//!     heap.set_root(tx, count.offset())?;
+//!     Ok::<_, HeapError>(count)
+//! })?;
+//! ```
+//!
+//! There is deliberately no `GlobalAlloc` on the heap. Compiled Rust stores
+//! single bytes, which [`MappedFram`](super::MappedFram) cannot do without
+//! clobbering the neighbouring byte, so a `Vec<u8>` or a struct with a `u8`
+//! field in F-RAM would be corrupted by its own writes; `PBox` and `PVec`
+//! write through the [`FramDevice`] instead.
+
+use core::marker::PhantomData;
+use core::mem;
+
+use super::undo::{Transaction, TxError, UndoLog};
+use super::{bytes_of, read_value, FramDevice, FramError, Region};
+
+/// `"HEAP"`
+const MAGIC: u32 = u32::from_le_bytes(*b"HEAP");
+const HEADER_LEN: usize = 16;
+const BLOCK_HEADER_LEN: usize = 8;
+const MIN_BLOCK: usize = 16;
+const USED: u32 = 1;
+const TRANSIENT: u32 = 2;
+const FLAGS: u32 = 7;
+
+/// Largest alignment the heap can give.
+pub const MAX_ALIGN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapError {
+    /// [`FramHeap::format`] has not run on the region.
+    NotFormatted,
+    OutOfMemory,
+    /// An alignment above [`MAX_ALIGN`].
+    Alignment,
+    /// Freeing something that is not an allocated block.
+    InvalidPointer,
+    /// The block at `offset` has a size that does not fit the heap.
+    Corrupt {
+        offset: usize,
+    },
+    Tx(TxError),
+    Fram(FramError),
+}
+
+impl From<TxError> for HeapError {
+    fn from(e: TxError) -> Self {
+        match e {
+            TxError::Fram(e) => HeapError::Fram(e),
+            e => HeapError::Tx(e),
+        }
+    }
+}
+
+impl From<FramError> for HeapError {
+    fn from(e: FramError) -> Self {
+        HeapError::Fram(e)
+    }
+}
+
+/// Bytes in used and free blocks, headers included.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub used: usize,
+    pub free: usize,
+    pub largest_free: usize,
+    pub blocks: usize,
+}
+
+/// A heap in an F-RAM region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FramHeap {
+    region: Region,
+}
+
+impl FramHeap {
+    pub const fn new(region: Region) -> Self {
+        FramHeap { region }
+    }
+
+    // first and one-past-last block offsets
+    fn bounds(&self) -> (usize, usize) {
+        let start = (self.region.offset + HEADER_LEN).next_multiple_of(8);
+        (start, self.region.end() & !7)
+    }
+
+    fn free_link(&self) -> usize {
+        self.region.offset + 4
+    }
+
+    pub fn is_formatted<D: FramDevice>(&self, fram: &D) -> Result<bool, FramError> {
+        Ok(fram.read_u32(self.region.offset)? == MAGIC)
+    }
+
+    /// Makes the region one empty heap, forgetting everything on it.
+    pub fn format<D: FramDevice>(&self, tx: &mut Transaction<'_, D>) -> Result<(), HeapError> {
+        let (start, end) = self.bounds();
+        if end < start + MIN_BLOCK {
+            return Err(HeapError::OutOfMemory);
+        }
+        tx.init(start, bytes_of(&[(end - start) as u32, 0]))?;
+        tx.write_value(self.region.offset + 4, &[start as u32, 0, 0])?;
+        tx.write_u32(self.region.offset, MAGIC)?;
+        Ok(())
+    }
+
+    /// Offset of the object stored with [`set_root`](Self::set_root), 0 if
+    /// none.
+    pub fn root<D: FramDevice>(&self, fram: &D) -> Result<usize, FramError> {
+        Ok(fram.read_u32(self.region.offset + 8)? as usize)
+    }
+
+    pub fn set_root<D: FramDevice>(
+        &self,
+        tx: &mut Transaction<'_, D>,
+        offset: usize,
+    ) -> Result<(), TxError> {
+        tx.write_u32(self.region.offset + 8, offset as u32)
+    }
+
+    /// Allocates `size` bytes aligned to `align` and returns their offset.
+    pub fn alloc<D: FramDevice>(
+        &self,
+        tx: &mut Transaction<'_, D>,
+        size: usize,
+        align: usize,
+    ) -> Result<usize, HeapError> {
+        self.alloc_block(tx, size, align, USED)
+    }
+
+    /// Like [`alloc`](Self::alloc), for a block that only lives until the
+    /// next [`recover`](Self::recover), e.g. one whose offset is only kept
+    /// in SRAM.
+    pub fn alloc_transient<D: FramDevice>(
+        &self,
+        tx: &mut Transaction<'_, D>,
+        size: usize,
+        align: usize,
+    ) -> Result<usize, HeapError> {
+        self.alloc_block(tx, size, align, USED | TRANSIENT)
+    }
+
+    // First fit, splitting off the rest of the block if it is big enough.
+    fn alloc_block<D: FramDevice>(
+        &self,
+        tx: &mut Transaction<'_, D>,
+        size: usize,
+        align: usize,
+        flags: u32,
+    ) -> Result<usize, HeapError> {
+        if !self.is_formatted(tx.fram())? {
+            return Err(HeapError::NotFormatted);
+        }
+        if align > MAX_ALIGN {
+            return Err(HeapError::Alignment);
+        }
+        let need = (size.next_multiple_of(8) + BLOCK_HEADER_LEN).max(MIN_BLOCK);
+
+        let mut link = self.free_link();
+        let mut block = tx.fram().read_u32(link)? as usize;
+        while block != 0 {
+            let len = self.block_len(tx.fram(), block)?;
+            let next = tx.fram().read_u32(block + 4)?;
+            if len >= need {
+                if len - need >= MIN_BLOCK {
+                    // not reachable unless the transaction commits
+                    let rest = block + need;
+                    tx.init(rest, bytes_of(&[(len - need) as u32, next]))?;
+                    tx.write_u32(link, rest as u32)?;
+                    tx.write_u32(block, need as u32 | flags)?;
+                } else {
+                    tx.write_u32(link, next)?;
+                    tx.write_u32(block, len as u32 | flags)?;
+                }
+                return Ok(block + BLOCK_HEADER_LEN);
+            }
+            link = block + 4;
+            block = next as usize;
+        }
+        Err(HeapError::OutOfMemory)
+    }
+
+    /// Frees the block at `offset`, as returned by [`alloc`](Self::alloc),
+    /// merging it with free neighbours.
+    pub fn free<D: FramDevice>(
+        &self,
+        tx: &mut Transaction<'_, D>,
+        offset: usize,
+    ) -> Result<(), HeapError> {
+        let (start, end) = self.bounds();
+        let block = offset.wrapping_sub(BLOCK_HEADER_LEN);
+        if block < start || block >= end || !block.is_multiple_of(8) {
+            return Err(HeapError::InvalidPointer);
+        }
+        // a real block header, not payload bytes that look like one
+        let mut at = start;
+        while at < block {
+            at += self.block_len(tx.fram(), at)?;
+        }
+        if at != block || tx.fram().read_u32(block)? & USED == 0 {
+            return Err(HeapError::InvalidPointer);
+        }
+        let mut len = self.block_len(tx.fram(), block)?;
+
+        // the free blocks before and after
+        let mut link = self.free_link();
+        let mut prev = None;
+        let mut next = tx.fram().read_u32(link)? as usize;
+        while next != 0 && next < block {
+            prev = Some(next);
+            link = next + 4;
+            next = tx.fram().read_u32(link)? as usize;
+        }
+
+        if next != 0 && block + len == next {
+            len += self.block_len(tx.fram(), next)?;
+            next = tx.fram().read_u32(next + 4)? as usize;
+        }
+        match prev {
+            Some(prev) if prev + self.block_len(tx.fram(), prev)? == block => {
+                let prev_len = self.block_len(tx.fram(), prev)?;
+                tx.write_value(prev, &[(prev_len + len) as u32, next as u32])?;
+            }
+            _ => {
+                tx.write_value(block, &[len as u32, next as u32])?;
+                tx.write_u32(link, block as u32)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn block_len<D: FramDevice>(&self, fram: &D, block: usize) -> Result<usize, HeapError> {
+        let len = (fram.read_u32(block)? & !FLAGS) as usize;
+        if len < MIN_BLOCK || block + len > self.bounds().1 {
+            return Err(HeapError::Corrupt { offset: block });
+        }
+        Ok(len)
+    }
+
+    /// Rolls back a transaction cut short by a power failure, then rebuilds
+    /// the free list (see [`rebuild`](Self::rebuild)). Returns whether there
+    /// was a transaction to roll back. Safe to interrupt and run again.
+    pub fn recover<D: FramDevice>(&self, fram: &mut D, log: &UndoLog) -> Result<bool, HeapError> {
+        let rolled_back = log.recover(fram)?;
+        if self.is_formatted(fram)? {
+            log.run(fram, |tx| self.rebuild(tx))?;
+        }
+        Ok(rolled_back)
+    }
+
+    /// Relinks the free list from the block headers alone: merges adjacent
+    /// free blocks and frees transient ones. Only writes what differs.
+    pub fn rebuild<D: FramDevice>(&self, tx: &mut Transaction<'_, D>) -> Result<(), HeapError> {
+        let (start, end) = self.bounds();
+        let mut link = self.free_link();
+        // the free run being merged: start and length
+        let mut run: Option<(usize, usize)> = None;
+        let mut block = start;
+        while block < end {
+            let flags = tx.fram().read_u32(block)? & FLAGS;
+            let len = self.block_len(tx.fram(), block)?;
+            if flags == USED {
+                if let Some((at, len)) = run.take() {
+                    link = self.relink(tx, link, at, len)?;
+                }
+            } else {
+                run = match run {
+                    Some((at, run_len)) => Some((at, run_len + len)),
+                    None => Some((block, len)),
+                };
+            }
+            block += len;
+        }
+        if let Some((at, len)) = run {
+            link = self.relink(tx, link, at, len)?;
+        }
+        update_u32(tx, link, 0)?;
+        Ok(())
+    }
+
+    // Makes `at` a free block of `len` bytes linked from `link`, and returns
+    // its own link.
+    fn relink<D: FramDevice>(
+        &self,
+        tx: &mut Transaction<'_, D>,
+        link: usize,
+        at: usize,
+        len: usize,
+    ) -> Result<usize, TxError> {
+        update_u32(tx, at, len as u32)?;
+        update_u32(tx, link, at as u32)?;
+        Ok(at + 4)
+    }
+
+    /// Walks all blocks.
+    pub fn usage<D: FramDevice>(&self, fram: &D) -> Result<Usage, HeapError> {
+        if !self.is_formatted(fram)? {
+            return Err(HeapError::NotFormatted);
+        }
+        let (mut block, end) = self.bounds();
+        let mut usage = Usage::default();
+        while block < end {
+            let len = self.block_len(fram, block)?;
+            if fram.read_u32(block)? & USED != 0 {
+                usage.used += len;
+            } else {
+                usage.free += len;
+                usage.largest_free = usage.largest_free.max(len);
+            }
+            usage.blocks += 1;
+            block += len;
+        }
+        Ok(usage)
+    }
+}
+
+// Writes `value` unless it is already there, to keep the log short.
+fn update_u32<D: FramDevice>(
+    tx: &mut Transaction<'_, D>,
+    offset: usize,
+    value: u32,
+) -> Result<(), TxError> {
+    if tx.fram().read_u32(offset)? != value {
+        tx.write_u32(offset, value)?;
+    }
+    Ok(())
+}
+
+fn check_type<T>() -> Result<(), HeapError> {
+    if mem::align_of::<T>() > MAX_ALIGN {
+        return Err(HeapError::Alignment);
+    }
+    Ok(())
+}
+
+/// A `T` on a [`FramHeap`]. Only the offset is kept, so a `PBox` can itself
+/// be stored in F-RAM, and is `Copy`; freeing it twice is an error.
+#[repr(transparent)]
+pub struct PBox<T> {
+    offset: u32,
+    _type: PhantomData<T>,
+}
+
+impl<T> Clone for PBox<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PBox<T> {}
+
+impl<T: Copy> PBox<T> {
+    /// The box at `offset`, e.g. the heap root.
+    pub const fn at(offset: usize) -> Self {
+        PBox {
+            offset: offset as u32,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset as usize
+    }
+
+    pub fn new_in<D: FramDevice>(
+        heap: &FramHeap,
+        tx: &mut Transaction<'_, D>,
+        value: T,
+    ) -> Result<Self, HeapError> {
+        check_type::<T>()?;
+        let offset = heap.alloc(tx, mem::size_of::<T>(), mem::align_of::<T>())?;
+        tx.init(offset, bytes_of(&value))?;
+        Ok(PBox::at(offset))
+    }
+
+    pub fn get<D: FramDevice>(&self, fram: &D) -> Result<T, FramError> {
+        read_value(fram, self.offset())
+    }
+
+    pub fn set<D: FramDevice>(&self, tx: &mut Transaction<'_, D>, value: T) -> Result<(), TxError> {
+        tx.write_value(self.offset(), &value)
+    }
+
+    pub fn free<D: FramDevice>(
+        self,
+        heap: &FramHeap,
+        tx: &mut Transaction<'_, D>,
+    ) -> Result<(), HeapError> {
+        heap.free(tx, self.offset())
+    }
+}
+
+// | len: u32 | capacity: u32 | data: u32 |
+const VEC_HEADER_LEN: usize = 12;
+
+/// A growable array of `T` on a [`FramHeap`]: a header block pointing at a
+/// data block, which is reallocated at twice the size when full.
+#[repr(transparent)]
+pub struct PVec<T> {
+    header: u32,
+    _type: PhantomData<T>,
+}
+
+impl<T> Clone for PVec<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for PVec<T> {}
+
+impl<T: Copy> PVec<T> {
+    /// The vector whose header is at `offset`, e.g. the heap root.
+    pub const fn at(offset: usize) -> Self {
+        PVec {
+            header: offset as u32,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.header as usize
+    }
+
+    pub fn new_in<D: FramDevice>(
+        heap: &FramHeap,
+        tx: &mut Transaction<'_, D>,
+    ) -> Result<Self, HeapError> {
+        check_type::<T>()?;
+        let header = heap.alloc(tx, VEC_HEADER_LEN, 4)?;
+        tx.init(header, bytes_of(&[0u32; 3]))?;
+        Ok(PVec::at(header))
+    }
+
+    // len, capacity, data
+    fn header<D: FramDevice>(&self, fram: &D) -> Result<[u32; 3], FramError> {
+        read_value(fram, self.offset())
+    }
+
+    pub fn len<D: FramDevice>(&self, fram: &D) -> Result<usize, FramError> {
+        Ok(self.header(fram)?[0] as usize)
+    }
+
+    pub fn is_empty<D: FramDevice>(&self, fram: &D) -> Result<bool, FramError> {
+        Ok(self.len(fram)? == 0)
+    }
+
+    pub fn capacity<D: FramDevice>(&self, fram: &D) -> Result<usize, FramError> {
+        Ok(self.header(fram)?[1] as usize)
+    }
+
+    fn element(data: u32, index: usize) -> usize {
+        data as usize + index * mem::size_of::<T>()
+    }
+
+    pub fn get<D: FramDevice>(&self, fram: &D, index: usize) -> Result<T, FramError> {
+        let [len, _, data] = self.header(fram)?;
+        assert!(index < len as usize, "index out of bounds");
+        read_value(fram, Self::element(data, index))
+    }
+
+    pub fn set<D: FramDevice>(
+        &self,
+        tx: &mut Transaction<'_, D>,
+        index: usize,
+        value: T,
+    ) -> Result<(), TxError> {
+        let [len, _, data] = self.header(tx.fram())?;
+        assert!(index < len as usize, "index out of bounds");
+        tx.write_value(Self::element(data, index), &value)
+    }
+
+    pub fn push<D: FramDevice>(
+        &self,
+        heap: &FramHeap,
+        tx: &mut Transaction<'_, D>,
+        value: T,
+    ) -> Result<(), HeapError> {
+        let [len, mut capacity, mut data] = self.header(tx.fram())?;
+        if len == capacity {
+            capacity = (capacity * 2).max(4);
+            let size = capacity as usize * mem::size_of::<T>();
+            let grown = heap.alloc(tx, size, mem::align_of::<T>())?;
+            copy(tx, data as usize, grown, len as usize * mem::size_of::<T>())?;
+            if data != 0 {
+                heap.free(tx, data as usize)?;
+            }
+            data = grown as u32;
+        }
+        tx.init(Self::element(data, len as usize), bytes_of(&value))?;
+        tx.write_value(self.offset(), &[len + 1, capacity, data])?;
+        Ok(())
+    }
+
+    pub fn pop<D: FramDevice>(&self, tx: &mut Transaction<'_, D>) -> Result<Option<T>, TxError> {
+        let len = self.len(tx.fram())?;
+        if len == 0 {
+            return Ok(None);
+        }
+        let value = self.get(tx.fram(), len - 1)?;
+        tx.write_u32(self.offset(), len as u32 - 1)?;
+        Ok(Some(value))
+    }
+
+    /// Frees the elements and the header.
+    pub fn free<D: FramDevice>(
+        self,
+        heap: &FramHeap,
+        tx: &mut Transaction<'_, D>,
+    ) -> Result<(), HeapError> {
+        let data = self.header(tx.fram())?[2];
+        if data != 0 {
+            heap.free(tx, data as usize)?;
+        }
+        heap.free(tx, self.offset())
+    }
+}
+
+// Copies into a block allocated in the same transaction.
+fn copy<D: FramDevice>(
+    tx: &mut Transaction<'_, D>,
+    from: usize,
+    to: usize,
+    len: usize,
+) -> Result<(), TxError> {
+    let mut buf = [0u8; 32];
+    let mut done = 0;
+    while done < len {
+        let n = buf.len().min(len - done);
+        tx.fram().read(from + done, &mut buf[..n])?;
+        tx.init(to + done, &buf[..n])?;
+        done += n;
+    }
+    Ok(())
+}
+
+#[cfg(target_arch = "arm")]
+mod global {
+    use core::ptr;
+
+    use super::{FramHeap, HeapError};
+    use crate::fram::undo::transaction;
+    use crate::fram::{FramArea, MappedFram};
+
+    /// Size of the region managed by [`heap`].
+    pub const HEAP_SIZE: usize = 8 * 1024;
+
+    #[link_section = ".fram_data"]
+    static mut HEAP_AREA: FramArea<HEAP_SIZE> = FramArea::new();
+
+    /// The heap in F-RAM. Transactions on it go through
+    /// [`fram::transaction`](crate::fram::transaction).
+    pub fn heap(fram: &MappedFram) -> FramHeap {
+        FramHeap::new(fram.region_of(ptr::addr_of!(HEAP_AREA)).unwrap())
+    }
+
+    /// Formats the heap on first boot, and afterwards rebuilds it, freeing
+    /// the transient blocks of the last run. Call once at boot, after
+    /// [`fram::recover`](crate::fram::recover).
+    pub fn recover() -> Result<(), HeapError> {
+        transaction(|tx| {
+            let heap = heap(tx.fram());
+            if heap.is_formatted(tx.fram())? {
+                heap.rebuild(tx)
+            } else {
+                heap.format(tx)
+            }
+        })
+    }
+}
+
+#[cfg(target_arch = "arm")]
+pub use self::global::{heap, recover, HEAP_SIZE};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fram::sim::{self, SimFram, Tear};
+
+    const LOG: UndoLog = UndoLog::new(Region::new(0, 512));
+    const HEAP: FramHeap = FramHeap::new(Region::new(512, 512));
+
+    fn formatted() -> SimFram {
+        let mut fram = SimFram::new(1024);
+        LOG.run(&mut fram, |tx| HEAP.format(tx)).unwrap();
+        fram
+    }
+
+    fn alloc(fram: &mut SimFram, size: usize) -> Result<usize, HeapError> {
+        LOG.run(fram, |tx| HEAP.alloc(tx, size, 4))
+    }
+
+    fn free(fram: &mut SimFram, offset: usize) -> Result<(), HeapError> {
+        LOG.run(fram, |tx| HEAP.free(tx, offset))
+    }
+
+    // 512 - 16 bytes of header
+    const SPACE: usize = 496;
+
+    #[test]
+    fn alloc_and_free() {
+        let mut fram = SimFram::new(1024);
+        assert_eq!(alloc(&mut fram, 4), Err(HeapError::NotFormatted));
+        let mut fram = formatted();
+        let a = alloc(&mut fram, 10).unwrap();
+        let b = alloc(&mut fram, 100).unwrap();
+        let c = alloc(&mut fram, 1).unwrap();
+        assert_eq!(a % 8, 0);
+        assert_eq!(b - a, 24);
+        assert_eq!(c - b, 112);
+        let usage = HEAP.usage(&fram).unwrap();
+        assert_eq!(
+            (usage.used, usage.free, usage.blocks),
+            (24 + 112 + 16, SPACE - 152, 4)
+        );
+
+        // a used block header inside b's payload
+        fram.write_u32(b + 8, 24 | USED).unwrap();
+        assert_eq!(free(&mut fram, b + 16), Err(HeapError::InvalidPointer));
+
+        free(&mut fram, a).unwrap();
+        free(&mut fram, c).unwrap();
+        assert_eq!(free(&mut fram, c), Err(HeapError::InvalidPointer));
+        // c merged with the free rest
+        assert_eq!(HEAP.usage(&fram).unwrap().blocks, 3);
+        // b with both neighbours, back to one block
+        free(&mut fram, b).unwrap();
+        let usage = HEAP.usage(&fram).unwrap();
+        assert_eq!(
+            (usage.free, usage.largest_free, usage.blocks),
+            (SPACE, SPACE, 1)
+        );
+
+        assert_eq!(alloc(&mut fram, SPACE), Err(HeapError::OutOfMemory));
+        assert_eq!(alloc(&mut fram, SPACE - 8).map(|a| a - 8), Ok(528));
+        assert_eq!(
+            LOG.run(&mut fram, |tx| HEAP.alloc(tx, 4, 16)),
+            Err(HeapError::Alignment)
+        );
+    }
+
+    #[test]
+    fn boxes_and_vectors() {
+        let mut fram = formatted();
+        let (count, _) = LOG
+            .run(&mut fram, |tx| {
+                let count = PBox::new_in(&HEAP, tx, 7u32)?;
+                let squares = PVec::<i16>::new_in(&HEAP, tx)?;
+                for i in 0..10 {
+                    squares.push(&HEAP, tx, i * i)?;
+                }
+                HEAP.set_root(tx, squares.offset())?;
+                Ok::<_, HeapError>((count, squares))
+            })
+            .unwrap();
+        assert_eq!(count.get(&fram), Ok(7));
+        let squares = PVec::<i16>::at(HEAP.root(&fram).unwrap());
+        assert_eq!(squares.len(&fram), Ok(10));
+        assert_eq!(squares.capacity(&fram), Ok(16));
+        assert_eq!(squares.get(&fram, 9), Ok(81));
+
+        LOG.run(&mut fram, |tx| {
+            count.set(tx, 8)?;
+            squares.set(tx, 0, -1)?;
+            assert_eq!(squares.pop(tx)?, Some(81));
+            count.free(&HEAP, tx)
+        })
+        .unwrap();
+        assert_eq!(squares.get(&fram, 0), Ok(-1));
+        assert_eq!(squares.len(&fram), Ok(9));
+
+        LOG.run(&mut fram, |tx| squares.free(&HEAP, tx)).unwrap();
+        assert_eq!(HEAP.usage(&fram).unwrap().blocks, 1);
+    }
+
+    #[test]
+    fn recovery_frees_transient_blocks() {
+        let mut fram = formatted();
+        let a = alloc(&mut fram, 8).unwrap();
+        LOG.run(&mut fram, |tx| HEAP.alloc_transient(tx, 8, 4))
+            .unwrap();
+        LOG.run(&mut fram, |tx| HEAP.alloc_transient(tx, 8, 4))
+            .unwrap();
+        alloc(&mut fram, 8).unwrap();
+        assert_eq!(HEAP.usage(&fram).unwrap().blocks, 5);
+
+        assert_eq!(HEAP.recover(&mut fram, &LOG), Ok(false));
+        let usage = HEAP.usage(&fram).unwrap();
+        assert_eq!((usage.used, usage.blocks), (32, 4));
+        // the two freed blocks are one again
+        assert_eq!(alloc(&mut fram, 24), Ok(a + 16));
+    }
+
+    // Allocates and links a box from the root, one transaction per boot.
+    fn boot(fram: &mut SimFram) -> Result<(), FramError> {
+        let result = HEAP.recover(fram, &LOG).and_then(|_| {
+            LOG.run(fram, |tx| {
+                if HEAP.root(tx.fram())? == 0 {
+                    let value = PBox::new_in(&HEAP, tx, [0x55u8; 40])?;
+                    HEAP.set_root(tx, value.offset())?;
+                }
+                Ok(())
+            })
+        });
+        result.map_err(|e| match e {
+            HeapError::Fram(e) => e,
+            e => panic!("{:?}", e),
+        })
+    }
+
+    #[test]
+    fn allocation_survives_power_failure() {
+        for &tear in [Tear::Lost, Tear::LowByte].iter() {
+            let fram = formatted();
+            sim::for_each_failure(fram.image(), tear, boot, |fram, n| {
+                let root = HEAP.root(fram).unwrap();
+                assert_eq!(PBox::<[u8; 40]>::at(root).get(fram), Ok([0x55; 40]));
+                let usage = HEAP.usage(fram).unwrap();
+                assert_eq!((usage.used, usage.blocks), (48, 2), "write {}", n);
+            });
+        }
+    }
+}
@@ -0,0 +1,768 @@
+//! A small key-value store in an F-RAM region, for configuration and
+//! calibration data.
+//!
+//! The region holds two superblock slots and two equal areas. Entries are
+//! appended to the active area and never overwritten: setting a key writes
+//! the new value to a fresh slot after the last entry, and only the
+//! superblock write that moves the end of the area past it makes the new
+//! value current. Until then the old value is what `get` returns, so a
+//! power failure leaves every key at its old or its new value. Removing a
+//! key appends a tombstone the same way.
+//!
+//! When the active area is full, the latest value of every key is copied to
+//! the other area, which becomes the active one with the next superblock.
+//! Compaction finds those values with a table of [`MAX_KEYS`] entries, so no
+//! more keys than that can be set at once. A key being removed is left out
+//! of the compacted area, so `remove` works even when no tombstone fits.
+//!
+//! Superblocks are written alternately, each with a generation number and a
+//! CRC; the newer valid one counts.
+//!
+//! Layout:
+//!
+//! ```text
+//! | superblock 0 | superblock 1 | area 0 | area 1 |
+//! superblock: | magic: u32 | generation: u32 | area: u32 | used: u32 | crc: u32 |
+//! entry: | key: [u8; 8] | len: u16 | flags: u16 | crc: u32 | value (len, padded to 4) |
+//! ```
+
+use super::{bytes_of, read_value, FramDevice, FramError, Region};
+use crate::crc::{crc32, Crc32};
+
+/// `"KVST"`
+const MAGIC: u32 = u32::from_le_bytes(*b"KVST");
+const SUPERBLOCK_LEN: usize = 20;
+const AREAS: usize = 2 * SUPERBLOCK_LEN;
+const ENTRY_HEADER_LEN: usize = 16;
+const TOMBSTONE: u16 = 1;
+
+pub const KEY_LEN: usize = 8;
+/// Longest value [`Entry`] can hold.
+pub const MAX_VALUE_LEN: usize = 128;
+/// Most keys that can be set at once.
+pub const MAX_KEYS: usize = 32;
+
+/// A key: up to [`KEY_LEN`] bytes, zero-padded, see [`key`].
+pub type Key = [u8; KEY_LEN];
+
+/// `name` as a key.
+pub const fn key(name: &str) -> Key {
+    let bytes = name.as_bytes();
+    assert!(bytes.len() <= KEY_LEN, "key too long");
+    let mut key = [0; KEY_LEN];
+    let mut i = 0;
+    while i < bytes.len() {
+        key[i] = bytes[i];
+        i += 1;
+    }
+    key
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KvError {
+    /// No room for the value even after compaction, or a new key when
+    /// [`MAX_KEYS`] are already set.
+    Full,
+    /// A value longer than [`MAX_VALUE_LEN`], or than the buffer.
+    ValueLen {
+        len: usize,
+    },
+    /// No valid superblock, see [`KvStore::open`].
+    NotFormatted,
+    /// The entry at `offset` does not check out.
+    Corrupt {
+        offset: usize,
+    },
+    Fram(FramError),
+}
+
+impl From<FramError> for KvError {
+    fn from(e: FramError) -> Self {
+        KvError::Fram(e)
+    }
+}
+
+// The contents of a superblock.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct State {
+    generation: u32,
+    area: u32,
+    used: u32,
+}
+
+// An entry header.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Header {
+    key: Key,
+    len: u16,
+    flags: u16,
+    crc: u32,
+}
+
+fn entry_len(len: usize) -> usize {
+    ENTRY_HEADER_LEN + len.next_multiple_of(4)
+}
+
+/// A key and its value, as returned by [`KvStore::iter`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub key: Key,
+    len: usize,
+    data: [u8; MAX_VALUE_LEN],
+}
+
+impl Entry {
+    pub fn value(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+/// A key-value store in an F-RAM region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KvStore {
+    region: Region,
+}
+
+impl KvStore {
+    pub const fn new(region: Region) -> Self {
+        KvStore { region }
+    }
+
+    fn area_len(&self) -> usize {
+        (self.region.len.saturating_sub(AREAS) / 2) & !3
+    }
+
+    fn area_offset(&self, area: u32) -> usize {
+        self.region.offset + AREAS + area as usize * self.area_len()
+    }
+
+    fn superblock_offset(&self, generation: u32) -> usize {
+        self.region.offset + (generation as usize % 2) * SUPERBLOCK_LEN
+    }
+
+    fn state<D: FramDevice>(&self, fram: &D) -> Result<State, KvError> {
+        let mut best: Option<State> = None;
+        for slot in 0..2 {
+            let fields: [u32; 5] = read_value(fram, self.region.offset + slot * SUPERBLOCK_LEN)?;
+            let [magic, generation, area, used, crc] = fields;
+            if magic != MAGIC || crc != crc32(bytes_of(&[magic, generation, area, used])) {
+                continue;
+            }
+            if area > 1 || used as usize > self.area_len() {
+                continue;
+            }
+            let state = State {
+                generation,
+                area,
+                used,
+            };
+            best = match best {
+                Some(b) if (b.generation.wrapping_sub(generation) as i32) > 0 => Some(b),
+                _ => Some(state),
+            };
+        }
+        best.ok_or(KvError::NotFormatted)
+    }
+
+    // The commit point of every change.
+    fn write_state<D: FramDevice>(&self, fram: &mut D, state: State) -> Result<(), FramError> {
+        let fields = [MAGIC, state.generation, state.area, state.used];
+        let offset = self.superblock_offset(state.generation);
+        fram.flush();
+        fram.write(offset, bytes_of(&fields))?;
+        fram.write_u32(offset + 16, crc32(bytes_of(&fields)))?;
+        fram.flush();
+        Ok(())
+    }
+
+    /// Empties the store.
+    pub fn format<D: FramDevice>(&self, fram: &mut D) -> Result<(), KvError> {
+        if self.area_len() < entry_len(0) {
+            return Err(KvError::Full);
+        }
+        let generation = match self.state(fram) {
+            Ok(state) => state.generation.wrapping_add(1),
+            Err(_) => 0,
+        };
+        let state = State {
+            generation,
+            area: 0,
+            used: 0,
+        };
+        self.write_state(fram, state).map_err(KvError::from)
+    }
+
+    /// Formats the store unless it holds one. Returns whether it did.
+    pub fn open<D: FramDevice>(&self, fram: &mut D) -> Result<bool, KvError> {
+        match self.state(fram) {
+            Ok(_) => Ok(false),
+            Err(KvError::NotFormatted) => {
+                self.format(fram)?;
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    // Calls `f` with the offset and header of every committed entry.
+    fn scan<D, F>(&self, fram: &D, state: State, mut f: F) -> Result<(), KvError>
+    where
+        D: FramDevice,
+        F: FnMut(usize, &Header) -> Result<(), KvError>,
+    {
+        let start = self.area_offset(state.area);
+        let end = start + state.used as usize;
+        let mut at = start;
+        while at < end {
+            let header: Header = read_value(fram, at)?;
+            let len = entry_len(header.len as usize);
+            if at + len > end {
+                return Err(KvError::Corrupt { offset: at });
+            }
+            f(at, &header)?;
+            at += len;
+        }
+        Ok(())
+    }
+
+    // The offset of the current entry of `key`, tombstone or not.
+    fn find<D: FramDevice>(
+        &self,
+        fram: &D,
+        state: State,
+        key: &Key,
+    ) -> Result<Option<(usize, Header)>, KvError> {
+        let mut found = None;
+        self.scan(fram, state, |at, header| {
+            if header.key == *key {
+                found = Some((at, *header));
+            }
+            Ok(())
+        })?;
+        Ok(found)
+    }
+
+    fn read_entry<D: FramDevice>(
+        &self,
+        fram: &D,
+        at: usize,
+        header: &Header,
+        buf: &mut [u8],
+    ) -> Result<usize, KvError> {
+        let len = header.len as usize;
+        if len > buf.len() {
+            return Err(KvError::ValueLen { len });
+        }
+        fram.read(at + ENTRY_HEADER_LEN, &mut buf[..len])?;
+        if header.crc != entry_crc(&header.key, header.flags, &buf[..len]) {
+            return Err(KvError::Corrupt { offset: at });
+        }
+        Ok(len)
+    }
+
+    /// Copies the value of `key` into `buf` and returns its length, or
+    /// `None` if the key is not set.
+    pub fn get<D: FramDevice>(
+        &self,
+        fram: &D,
+        key: &Key,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, KvError> {
+        let state = self.state(fram)?;
+        match self.find(fram, state, key)? {
+            Some((at, header)) if header.flags & TOMBSTONE == 0 => {
+                self.read_entry(fram, at, &header, buf).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The value of `key` as a `T`, stored with [`set_value`](Self::set_value).
+    pub fn get_value<D: FramDevice, T: Copy>(
+        &self,
+        fram: &D,
+        key: &Key,
+    ) -> Result<Option<T>, KvError> {
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        match self.get(fram, key, &mut buf)? {
+            Some(len) if len == core::mem::size_of::<T>() => Ok(Some(unsafe {
+                core::ptr::read_unaligned(buf.as_ptr() as *const T)
+            })),
+            Some(len) => Err(KvError::ValueLen { len }),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets `key` to `value`, atomically.
+    pub fn set<D: FramDevice>(&self, fram: &mut D, key: &Key, value: &[u8]) -> Result<(), KvError> {
+        if value.len() > MAX_VALUE_LEN {
+            return Err(KvError::ValueLen { len: value.len() });
+        }
+        self.append(fram, key, 0, value)
+    }
+
+    /// Sets `key` to the bytes of `value`, see [`bytes_of`].
+    pub fn set_value<D: FramDevice, T: Copy>(
+        &self,
+        fram: &mut D,
+        key: &Key,
+        value: &T,
+    ) -> Result<(), KvError> {
+        self.set(fram, key, bytes_of(value))
+    }
+
+    /// Removes `key`. Returns whether it was set.
+    pub fn remove<D: FramDevice>(&self, fram: &mut D, key: &Key) -> Result<bool, KvError> {
+        let state = self.state(fram)?;
+        match self.find(fram, state, key)? {
+            Some((_, header)) if header.flags & TOMBSTONE == 0 => {
+                self.append(fram, key, TOMBSTONE, &[])?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn append<D: FramDevice>(
+        &self,
+        fram: &mut D,
+        key: &Key,
+        flags: u16,
+        value: &[u8],
+    ) -> Result<(), KvError> {
+        let mut state = self.state(fram)?;
+        if flags & TOMBSTONE == 0 {
+            let mut current = [0; MAX_KEYS];
+            let count = self.current(fram, state, &mut current)?;
+            if count == MAX_KEYS && !self.is_set(fram, &current, key)? {
+                return Err(KvError::Full);
+            }
+        }
+        let len = entry_len(value.len());
+        if state.used as usize + len > self.area_len() {
+            if flags & TOMBSTONE != 0 {
+                // compacting without the key removes it
+                self.compact(fram, state, Some(key))?;
+                return Ok(());
+            }
+            state = self.compact(fram, state, None)?;
+            if state.used as usize + len > self.area_len() {
+                return Err(KvError::Full);
+            }
+        }
+        let at = self.area_offset(state.area) + state.used as usize;
+        write_entry(fram, at, key, flags, value)?;
+        self.write_state(
+            fram,
+            State {
+                generation: state.generation.wrapping_add(1),
+                area: state.area,
+                used: state.used + len as u32,
+            },
+        )?;
+        Ok(())
+    }
+
+    // Fills `current` with the offsets of the current entries of the keys
+    // that are set, in one scan, and returns how many there are.
+    fn current<D: FramDevice>(
+        &self,
+        fram: &D,
+        state: State,
+        current: &mut [usize; MAX_KEYS],
+    ) -> Result<usize, KvError> {
+        let mut keys = [[0; KEY_LEN]; MAX_KEYS];
+        let mut count = 0;
+        self.scan(fram, state, |at, header| {
+            let removed = header.flags & TOMBSTONE != 0;
+            match keys[..count].iter().position(|k| *k == header.key) {
+                Some(i) if removed => {
+                    count -= 1;
+                    keys[i] = keys[count];
+                    current[i] = current[count];
+                }
+                Some(i) => current[i] = at,
+                None if removed => {}
+                None if count < MAX_KEYS => {
+                    keys[count] = header.key;
+                    current[count] = at;
+                    count += 1;
+                }
+                None => return Err(KvError::Full),
+            }
+            Ok(())
+        })?;
+        Ok(count)
+    }
+
+    fn is_set<D: FramDevice>(
+        &self,
+        fram: &D,
+        current: &[usize],
+        key: &Key,
+    ) -> Result<bool, KvError> {
+        for &at in current {
+            let header: Header = read_value(fram, at)?;
+            if header.key == *key {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Copies the current value of every key but `without` to the other area
+    // and switches to it.
+    fn compact<D: FramDevice>(
+        &self,
+        fram: &mut D,
+        state: State,
+        without: Option<&Key>,
+    ) -> Result<State, KvError> {
+        let mut current = [0; MAX_KEYS];
+        let count = self.current(fram, state, &mut current)?;
+        // oldest first, like the entries they came from
+        let current = &mut current[..count];
+        current.sort_unstable();
+
+        let area = 1 - state.area;
+        let start = self.area_offset(area);
+        let mut used = 0;
+        let mut buf = [0u8; MAX_VALUE_LEN];
+        for &at in current.iter() {
+            let header: Header = read_value(fram, at)?;
+            if Some(&header.key) != without {
+                let len = self.read_entry(fram, at, &header, &mut buf)?;
+                write_entry(fram, start + used, &header.key, 0, &buf[..len])?;
+                used += entry_len(len);
+            }
+        }
+        let compacted = State {
+            generation: state.generation.wrapping_add(1),
+            area,
+            used: used as u32,
+        };
+        self.write_state(fram, compacted)?;
+        Ok(compacted)
+    }
+
+    /// The keys that are set and their values, oldest first.
+    pub fn iter<'a, D: FramDevice>(&'a self, fram: &'a D) -> Result<Iter<'a, D>, KvError> {
+        let state = self.state(fram)?;
+        Ok(Iter {
+            store: self,
+            fram,
+            state,
+            at: self.area_offset(state.area),
+        })
+    }
+
+    /// Bytes used in the active area, and its size.
+    pub fn usage<D: FramDevice>(&self, fram: &D) -> Result<(usize, usize), KvError> {
+        Ok((self.state(fram)?.used as usize, self.area_len()))
+    }
+}
+
+fn entry_crc(key: &Key, flags: u16, value: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(key);
+    crc.update(&(value.len() as u16).to_le_bytes());
+    crc.update(&flags.to_le_bytes());
+    crc.update(value);
+    crc.finish()
+}
+
+fn write_entry<D: FramDevice>(
+    fram: &mut D,
+    at: usize,
+    key: &Key,
+    flags: u16,
+    value: &[u8],
+) -> Result<(), FramError> {
+    let header = Header {
+        key: *key,
+        len: value.len() as u16,
+        flags,
+        crc: entry_crc(key, flags, value),
+    };
+    fram.write(at, bytes_of(&header))?;
+    fram.write(at + ENTRY_HEADER_LEN, value)
+}
+
+/// Iterator over a [`KvStore`], see [`KvStore::iter`].
+pub struct Iter<'a, D> {
+    store: &'a KvStore,
+    fram: &'a D,
+    state: State,
+    at: usize,
+}
+
+impl<D: FramDevice> Iter<'_, D> {
+    fn next_entry(&mut self) -> Result<Option<Entry>, KvError> {
+        let end = self.store.area_offset(self.state.area) + self.state.used as usize;
+        while self.at < end {
+            let at = self.at;
+            let header: Header = read_value(self.fram, at)?;
+            self.at += entry_len(header.len as usize);
+            if header.flags & TOMBSTONE != 0 {
+                continue;
+            }
+            let current = self.store.find(self.fram, self.state, &header.key)?;
+            if current.map(|(at, _)| at) != Some(at) {
+                continue;
+            }
+            let mut entry = Entry {
+                key: header.key,
+                len: 0,
+                data: [0; MAX_VALUE_LEN],
+            };
+            entry.len = self
+                .store
+                .read_entry(self.fram, at, &header, &mut entry.data)?;
+            return Ok(Some(entry));
+        }
+        Ok(None)
+    }
+}
+
+impl<D: FramDevice> Iterator for Iter<'_, D> {
+    type Item = Result<Entry, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                // stop after an error
+                self.at = usize::MAX;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "arm")]
+mod global {
+    use core::ptr;
+
+    use super::KvStore;
+    use crate::fram::{FramArea, MappedFram};
+
+    /// Size of the region of [`store`].
+    pub const KV_STORE_SIZE: usize = 2048;
+
+    // kept across firmware updates
+    #[link_section = ".fram_noinit"]
+    static mut KV_AREA: FramArea<KV_STORE_SIZE> = FramArea::new();
+
+    /// The firmware's store; [`KvStore::open`] it once at boot.
+    pub fn store(fram: &MappedFram) -> KvStore {
+        KvStore::new(fram.region_of(ptr::addr_of!(KV_AREA)).unwrap())
+    }
+}
+
+#[cfg(target_arch = "arm")]
+pub use self::global::{store, KV_STORE_SIZE};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fram::sim::{self, Tear};
+    use crate::fram::MemFram;
+
+    // areas of 100 bytes
+    const STORE: KvStore = KvStore::new(Region::new(16, 240));
+    const GAIN: Key = key("gain");
+    const OFFSET: Key = key("offset");
+
+    fn value(fram: &impl FramDevice, key: &Key) -> Option<Vec<u8>> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        STORE
+            .get(fram, key, &mut buf)
+            .unwrap()
+            .map(|len| buf[..len].to_vec())
+    }
+
+    fn keys(fram: &impl FramDevice) -> Vec<Key> {
+        STORE.iter(fram).unwrap().map(|e| e.unwrap().key).collect()
+    }
+
+    #[test]
+    fn get_set_remove() {
+        let mut fram = MemFram::new(256);
+        assert_eq!(STORE.get(&fram, &GAIN, &mut []), Err(KvError::NotFormatted));
+        assert_eq!(STORE.open(&mut fram), Ok(true));
+        assert_eq!(STORE.open(&mut fram), Ok(false));
+        assert_eq!(value(&fram, &GAIN), None);
+
+        STORE.set(&mut fram, &GAIN, b"1.25").unwrap();
+        STORE.set_value(&mut fram, &OFFSET, &-3i32).unwrap();
+        STORE.set(&mut fram, &GAIN, b"1.5").unwrap();
+        assert_eq!(value(&fram, &GAIN).as_deref(), Some(&b"1.5"[..]));
+        assert_eq!(STORE.get_value::<_, i32>(&fram, &OFFSET), Ok(Some(-3)));
+        assert_eq!(
+            STORE.get_value::<_, u16>(&fram, &OFFSET),
+            Err(KvError::ValueLen { len: 4 })
+        );
+        assert_eq!(keys(&fram), [OFFSET, GAIN]);
+
+        assert_eq!(STORE.remove(&mut fram, &GAIN), Ok(true));
+        assert_eq!(STORE.remove(&mut fram, &GAIN), Ok(false));
+        assert_eq!(value(&fram, &GAIN), None);
+        assert_eq!(keys(&fram), [OFFSET]);
+        let values: Vec<Vec<u8>> = STORE
+            .iter(&fram)
+            .unwrap()
+            .map(|e| e.unwrap().value().to_vec())
+            .collect();
+        assert_eq!(values, [(-3i32).to_le_bytes().to_vec()]);
+    }
+
+    #[test]
+    fn compacts_when_full() {
+        let mut fram = MemFram::new(256);
+        STORE.open(&mut fram).unwrap();
+        // 20-byte entries, 5 to an area
+        for i in 0..20u32 {
+            STORE.set_value(&mut fram, &GAIN, &i).unwrap();
+            STORE.set_value(&mut fram, &OFFSET, &(i * 2)).unwrap();
+        }
+        assert_eq!(STORE.get_value(&fram, &GAIN), Ok(Some(19u32)));
+        assert_eq!(STORE.get_value(&fram, &OFFSET), Ok(Some(38u32)));
+        // compacted to 2 entries every 3 sets, and 2 sets since
+        assert_eq!(STORE.usage(&fram), Ok((80, 100)));
+
+        assert_eq!(
+            STORE.set(&mut fram, &key("big"), &[0; 90]),
+            Err(KvError::Full)
+        );
+        assert_eq!(
+            STORE.set(&mut fram, &key("big"), &[0; 200]),
+            Err(KvError::ValueLen { len: 200 })
+        );
+        assert_eq!(keys(&fram), [GAIN, OFFSET]);
+    }
+
+    #[test]
+    fn sets_at_most_max_keys() {
+        // 35 empty entries to an area
+        let store = KvStore::new(Region::new(0, 40 + 2 * 35 * 16));
+        let mut fram = MemFram::new(40 + 2 * 35 * 16);
+        store.open(&mut fram).unwrap();
+        let name = |i: usize| key(&format!("k{}", i));
+        for i in 0..MAX_KEYS {
+            store.set(&mut fram, &name(i), &[]).unwrap();
+        }
+        assert_eq!(
+            store.set(&mut fram, &name(MAX_KEYS), &[]),
+            Err(KvError::Full)
+        );
+
+        // the keys that are set can still change, through compactions, and
+        // removing one makes room for another
+        for _ in 0..10 {
+            store.set(&mut fram, &name(0), &[]).unwrap();
+        }
+        assert_eq!(store.remove(&mut fram, &name(1)), Ok(true));
+        store.set(&mut fram, &name(MAX_KEYS), &[]).unwrap();
+        for i in (0..=MAX_KEYS).filter(|&i| i != 1) {
+            assert_eq!(store.remove(&mut fram, &name(i)), Ok(true));
+            store.set(&mut fram, &name(i + 100), &[]).unwrap();
+        }
+        assert_eq!(store.iter(&fram).unwrap().count(), MAX_KEYS);
+    }
+
+    #[test]
+    fn removes_from_a_full_area() {
+        let mut fram = MemFram::new(256);
+        STORE.open(&mut fram).unwrap();
+        // 88 of 100 bytes, with no room for a tombstone even after compaction
+        STORE.set(&mut fram, &GAIN, &[1; 52]).unwrap();
+        STORE.set(&mut fram, &OFFSET, &[2; 4]).unwrap();
+        assert_eq!(STORE.usage(&fram), Ok((88, 100)));
+        assert_eq!(STORE.set(&mut fram, &key("x"), &[0; 4]), Err(KvError::Full));
+
+        let full = fram.image().to_vec();
+        sim::for_each_failure(
+            &full,
+            Tear::LowByte,
+            |fram| {
+                STORE
+                    .remove(fram, &OFFSET)
+                    .map(|_| ())
+                    .map_err(|e| match e {
+                        KvError::Fram(e) => e,
+                        e => panic!("{:?}", e),
+                    })
+            },
+            |fram, _| {
+                assert_eq!(keys(fram), [GAIN]);
+                assert_eq!(value(fram, &GAIN), Some(vec![1; 52]));
+            },
+        );
+
+        assert_eq!(STORE.remove(&mut fram, &OFFSET), Ok(true));
+        assert_eq!(keys(&fram), [GAIN]);
+        assert_eq!(value(&fram, &GAIN), Some(vec![1; 52]));
+        assert_eq!(STORE.usage(&fram), Ok((68, 100)));
+        STORE.set(&mut fram, &key("x"), &[0; 4]).unwrap();
+    }
+
+    #[test]
+    fn detects_damaged_entries() {
+        let mut fram = MemFram::new(256);
+        STORE.open(&mut fram).unwrap();
+        STORE.set(&mut fram, &GAIN, b"abc").unwrap();
+        // the first value byte of area 0
+        fram.write(16 + 40 + 16, b"x").unwrap();
+        assert_eq!(
+            STORE.get(&fram, &GAIN, &mut [0; 8]),
+            Err(KvError::Corrupt { offset: 56 })
+        );
+    }
+
+    // Every key is at its old or new value after power fails anywhere in
+    // an update, compaction included.
+    #[test]
+    fn updates_are_atomic() {
+        let mut old = MemFram::new(256);
+        STORE.open(&mut old).unwrap();
+        for i in 0..4u32 {
+            STORE.set_value(&mut old, &GAIN, &i).unwrap();
+        }
+        STORE.set_value(&mut old, &OFFSET, &7u32).unwrap();
+        let old = old.into_image();
+
+        for &tear in [Tear::Lost, Tear::LowByte, Tear::Complete].iter() {
+            sim::for_each_failure(
+                &old,
+                tear,
+                |fram| {
+                    // what the boot before left
+                    let gain = STORE.get_value::<_, u32>(fram, &GAIN).unwrap();
+                    let offset = STORE.get_value::<_, u32>(fram, &OFFSET).unwrap();
+                    assert!(
+                        matches!(
+                            (gain, offset),
+                            (Some(3), Some(7)) | (Some(100), Some(7)) | (Some(100), None)
+                        ),
+                        "{:?} {:?}",
+                        gain,
+                        offset
+                    );
+                    STORE
+                        .set_value(fram, &GAIN, &100u32)
+                        .and_then(|()| STORE.remove(fram, &OFFSET))
+                        .map(|_| ())
+                        .map_err(|e| match e {
+                            KvError::Fram(e) => e,
+                            e => panic!("{:?}", e),
+                        })
+                },
+                |fram, _| {
+                    assert_eq!(STORE.get_value(fram, &GAIN), Ok(Some(100u32)));
+                    assert_eq!(STORE.get_value::<_, u32>(fram, &OFFSET), Ok(None));
+                },
+            );
+        }
+    }
+}
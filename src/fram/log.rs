@@ -0,0 +1,519 @@
+//! An append-only circular log of fixed-size records in an F-RAM region.
+//!
+//! Every record carries its sequence number and a CRC-32, so readers (and
+//! the `fram-drain` host tool) can tell a record from stale or torn bytes.
+//! Which sequence numbers are in the log is kept in two pointer slots that
+//! are written alternately, each with a generation number and a CRC; the
+//! newer valid one counts. A record is written before the pointer slot that
+//! makes it part of the log, so a power failure loses at most the record
+//! being appended. Once the log is full, appending first drops the oldest
+//! record, then overwrites it.
+//!
+//! Log layout:
+//!
+//! ```text
+//! | magic: u32 | record_len: u16 | pad: u16 | capacity: u32 | crc: u32 |
+//! | pointer slot 0 | pointer slot 1 | record 0 | record 1 | ...
+//! pointer slot: | generation: u32 | head: u32 | tail: u32 | crc: u32 |
+//! record: | seq: u32 | crc: u32 | data (record_len, padded to 4) |
+//! ```
+//!
+//! `tail` is the sequence number of the oldest record and `head` the one the
+//! next record gets; record `seq` is stored in slot `seq % capacity`.
+
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::Range;
+
+use super::{bytes_of, read_value, FramDevice, FramError, Region};
+use crate::crc::{crc32, Crc32};
+
+/// `"FLOG"`
+pub const MAGIC: u32 = u32::from_le_bytes(*b"FLOG");
+const HEADER_LEN: usize = 16;
+const POINTER_LEN: usize = 16;
+const RECORDS: usize = HEADER_LEN + 2 * POINTER_LEN;
+const RECORD_HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogError {
+    /// No valid log header, see [`RawLog::open`].
+    NotFormatted,
+    /// The header is for records of another length.
+    RecordLen {
+        found: usize,
+    },
+    /// The region cannot hold a single record.
+    TooSmall,
+    /// Record `seq` is in the log but does not check out.
+    Corrupt {
+        seq: u32,
+    },
+    Fram(FramError),
+}
+
+impl From<FramError> for LogError {
+    fn from(e: FramError) -> Self {
+        LogError::Fram(e)
+    }
+}
+
+// generation, head, tail
+type Pointers = [u32; 3];
+
+/// A log of `record_len`-byte records, untyped. See [`FramLog`] for
+/// records of a type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawLog {
+    region: Region,
+    record_len: usize,
+}
+
+impl RawLog {
+    pub const fn new(region: Region, record_len: usize) -> Self {
+        RawLog { region, record_len }
+    }
+
+    /// The log whose header is at `offset`, if there is a valid one. Its
+    /// region is as long as the header says.
+    pub fn at<D: FramDevice>(fram: &D, offset: usize) -> Result<Option<RawLog>, FramError> {
+        if offset + HEADER_LEN > fram.size() {
+            return Ok(None);
+        }
+        let header: [u32; 4] = read_value(fram, offset)?;
+        if header[0] != MAGIC || header[3] != crc32(bytes_of(&[header[0], header[1], header[2]])) {
+            return Ok(None);
+        }
+        let record_len = (header[1] & 0xffff) as usize;
+        let capacity = header[2] as usize;
+        let log = RawLog::new(Region::new(offset, 0), record_len);
+        let len = RECORDS + capacity * log.slot_len();
+        if offset + len > fram.size() {
+            return Ok(None);
+        }
+        Ok(Some(RawLog::new(Region::new(offset, len), record_len)))
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn record_len(&self) -> usize {
+        self.record_len
+    }
+
+    fn slot_len(&self) -> usize {
+        RECORD_HEADER_LEN + self.record_len.next_multiple_of(4)
+    }
+
+    /// Records the region holds.
+    pub fn capacity(&self) -> usize {
+        self.region.len.saturating_sub(RECORDS) / self.slot_len()
+    }
+
+    fn header(&self) -> [u32; 4] {
+        let fields = [MAGIC, self.record_len as u32, self.capacity() as u32];
+        [fields[0], fields[1], fields[2], crc32(bytes_of(&fields))]
+    }
+
+    /// Empties the log. Safe to interrupt and run again.
+    pub fn format<D: FramDevice>(&self, fram: &mut D) -> Result<(), LogError> {
+        if self.capacity() == 0 || self.record_len > u16::MAX as usize {
+            return Err(LogError::TooSmall);
+        }
+        // invalid until the header is complete
+        fram.write_u32(self.region.offset, 0)?;
+        fram.flush();
+        self.write_pointers(fram, 0, [0, 0, 0])?;
+        self.write_pointers(fram, 1, [1, 0, 0])?;
+        fram.write(self.region.offset, bytes_of(&self.header()))?;
+        fram.flush();
+        Ok(())
+    }
+
+    /// Checks the header, formatting the log if it is missing or for other
+    /// records. Returns whether it was formatted.
+    pub fn open<D: FramDevice>(&self, fram: &mut D) -> Result<bool, LogError> {
+        match self.check(fram) {
+            Ok(()) => Ok(false),
+            Err(LogError::NotFormatted) | Err(LogError::RecordLen { .. }) => {
+                self.format(fram)?;
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn check<D: FramDevice>(&self, fram: &D) -> Result<(), LogError> {
+        let found: [u32; 4] = read_value(fram, self.region.offset)?;
+        let expected = self.header();
+        if found == expected {
+            return Ok(());
+        }
+        match RawLog::at(fram, self.region.offset)? {
+            Some(log) if log.record_len != self.record_len => Err(LogError::RecordLen {
+                found: log.record_len,
+            }),
+            _ => Err(LogError::NotFormatted),
+        }
+    }
+
+    fn pointer_offset(&self, slot: u32) -> usize {
+        self.region.offset + HEADER_LEN + (slot as usize % 2) * POINTER_LEN
+    }
+
+    fn write_pointers<D: FramDevice>(
+        &self,
+        fram: &mut D,
+        slot: u32,
+        pointers: Pointers,
+    ) -> Result<(), FramError> {
+        let crc = crc32(bytes_of(&pointers));
+        let offset = self.pointer_offset(slot);
+        fram.write(offset, bytes_of(&pointers))?;
+        fram.write_u32(offset + 12, crc)?;
+        fram.flush();
+        Ok(())
+    }
+
+    // The newer valid pointer slot.
+    fn pointers<D: FramDevice>(&self, fram: &D) -> Result<Pointers, LogError> {
+        self.check(fram)?;
+        let mut best: Option<Pointers> = None;
+        for slot in 0..2 {
+            let [generation, head, tail, crc]: [u32; 4] =
+                read_value(fram, self.pointer_offset(slot))?;
+            let pointers = [generation, head, tail];
+            if crc != crc32(bytes_of(&pointers)) {
+                continue;
+            }
+            best = match best {
+                Some(b) if (b[0].wrapping_sub(generation) as i32) > 0 => Some(b),
+                _ => Some(pointers),
+            };
+        }
+        // both slots are only ever torn one at a time
+        best.ok_or(LogError::NotFormatted)
+    }
+
+    fn update<D: FramDevice>(
+        &self,
+        fram: &mut D,
+        [generation, _, _]: Pointers,
+        head: u32,
+        tail: u32,
+    ) -> Result<Pointers, FramError> {
+        let next = generation.wrapping_add(1);
+        let pointers = [next, head, tail];
+        self.write_pointers(fram, next, pointers)?;
+        Ok(pointers)
+    }
+
+    /// Sequence numbers of the records in the log, oldest first. Empty once
+    /// they wrap around after 2^32 records.
+    pub fn seqs<D: FramDevice>(&self, fram: &D) -> Result<Range<u32>, LogError> {
+        let [_, head, tail] = self.pointers(fram)?;
+        Ok(tail..head)
+    }
+
+    pub fn len<D: FramDevice>(&self, fram: &D) -> Result<usize, LogError> {
+        let [_, head, tail] = self.pointers(fram)?;
+        Ok(head.wrapping_sub(tail) as usize)
+    }
+
+    pub fn is_empty<D: FramDevice>(&self, fram: &D) -> Result<bool, LogError> {
+        Ok(self.len(fram)? == 0)
+    }
+
+    fn slot_offset(&self, seq: u32) -> usize {
+        self.region.offset + RECORDS + (seq as usize % self.capacity()) * self.slot_len()
+    }
+
+    /// Appends `data` (of `record_len` bytes) and returns its sequence
+    /// number.
+    pub fn push<D: FramDevice>(&self, fram: &mut D, data: &[u8]) -> Result<u32, LogError> {
+        assert_eq!(data.len(), self.record_len, "record length");
+        let mut pointers = self.pointers(fram)?;
+        let [_, head, tail] = pointers;
+        if head.wrapping_sub(tail) as usize == self.capacity() {
+            // drop the oldest record before overwriting it
+            pointers = self.update(fram, pointers, head, tail.wrapping_add(1))?;
+        }
+
+        let offset = self.slot_offset(head);
+        let mut crc = Crc32::new();
+        crc.update(&head.to_le_bytes());
+        crc.update(data);
+        fram.write_u32(offset, head)?;
+        fram.write_u32(offset + 4, crc.finish())?;
+        fram.write(offset + RECORD_HEADER_LEN, data)?;
+        fram.flush();
+
+        self.update(fram, pointers, head.wrapping_add(1), pointers[2])?;
+        Ok(head)
+    }
+
+    /// Reads record `seq` into `buf` (of `record_len` bytes).
+    pub fn read<D: FramDevice>(&self, fram: &D, seq: u32, buf: &mut [u8]) -> Result<(), LogError> {
+        assert_eq!(buf.len(), self.record_len, "record length");
+        let offset = self.slot_offset(seq);
+        let [stored, stored_crc]: [u32; 2] = read_value(fram, offset)?;
+        fram.read(offset + RECORD_HEADER_LEN, buf)?;
+        let mut crc = Crc32::new();
+        crc.update(&seq.to_le_bytes());
+        crc.update(buf);
+        if stored != seq || stored_crc != crc.finish() {
+            return Err(LogError::Corrupt { seq });
+        }
+        Ok(())
+    }
+
+    /// Drops the `count` oldest records, e.g. once they have been sent.
+    pub fn consume<D: FramDevice>(&self, fram: &mut D, count: usize) -> Result<(), LogError> {
+        let pointers = self.pointers(fram)?;
+        let [_, head, tail] = pointers;
+        let count = count.min(head.wrapping_sub(tail) as usize) as u32;
+        if count > 0 {
+            self.update(fram, pointers, head, tail.wrapping_add(count))?;
+        }
+        Ok(())
+    }
+
+    pub fn clear<D: FramDevice>(&self, fram: &mut D) -> Result<(), LogError> {
+        self.consume(fram, usize::MAX)
+    }
+}
+
+/// A record and its sequence number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record<T> {
+    pub seq: u32,
+    pub value: T,
+}
+
+/// A log of `T` records. `T` is stored with [`bytes_of`], so it should have
+/// no padding, and every bit pattern must be a valid `T`.
+///
+/// ```ignore
+/// #[link_section = ".fram_noinit"]
+/// static mut SAMPLE_AREA: FramArea<1024> = FramArea::new();
+///
+/// let log = FramLog::<Sample>::new(fram.region_of(ptr::addr_of!(SAMPLE_AREA)).unwrap());
+/// log.open(&mut fram)?;
+/// log.push(&mut fram, &sample)?;
+/// for record in log.iter(&fram)? {
+///     let Record { seq, value } = record?;
+/// }
+/// ```
+pub struct FramLog<T> {
+    raw: RawLog,
+    _type: PhantomData<T>,
+}
+
+impl<T: Copy> FramLog<T> {
+    pub const fn new(region: Region) -> Self {
+        FramLog {
+            raw: RawLog::new(region, mem::size_of::<T>()),
+            _type: PhantomData,
+        }
+    }
+
+    pub fn raw(&self) -> &RawLog {
+        &self.raw
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.raw.capacity()
+    }
+
+    /// See [`RawLog::open`].
+    pub fn open<D: FramDevice>(&self, fram: &mut D) -> Result<bool, LogError> {
+        self.raw.open(fram)
+    }
+
+    pub fn format<D: FramDevice>(&self, fram: &mut D) -> Result<(), LogError> {
+        self.raw.format(fram)
+    }
+
+    pub fn push<D: FramDevice>(&self, fram: &mut D, value: &T) -> Result<u32, LogError> {
+        self.raw.push(fram, bytes_of(value))
+    }
+
+    pub fn get<D: FramDevice>(&self, fram: &D, seq: u32) -> Result<T, LogError> {
+        // zeroed, so the bytes are initialized before they are borrowed
+        let mut value = mem::MaybeUninit::<T>::zeroed();
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, mem::size_of::<T>())
+        };
+        self.raw.read(fram, seq, buf)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn len<D: FramDevice>(&self, fram: &D) -> Result<usize, LogError> {
+        self.raw.len(fram)
+    }
+
+    pub fn is_empty<D: FramDevice>(&self, fram: &D) -> Result<bool, LogError> {
+        self.raw.is_empty(fram)
+    }
+
+    /// The records from oldest to newest.
+    pub fn iter<'a, D: FramDevice>(&'a self, fram: &'a D) -> Result<Iter<'a, D, T>, LogError> {
+        Ok(Iter {
+            log: self,
+            fram,
+            seqs: self.raw.seqs(fram)?,
+        })
+    }
+
+    /// See [`RawLog::consume`].
+    pub fn consume<D: FramDevice>(&self, fram: &mut D, count: usize) -> Result<(), LogError> {
+        self.raw.consume(fram, count)
+    }
+
+    pub fn clear<D: FramDevice>(&self, fram: &mut D) -> Result<(), LogError> {
+        self.raw.clear(fram)
+    }
+}
+
+/// Iterator over a [`FramLog`], see [`FramLog::iter`].
+pub struct Iter<'a, D, T> {
+    log: &'a FramLog<T>,
+    fram: &'a D,
+    seqs: Range<u32>,
+}
+
+impl<D: FramDevice, T: Copy> Iterator for Iter<'_, D, T> {
+    type Item = Result<Record<T>, LogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let seq = self.seqs.next()?;
+        Some(
+            self.log
+                .get(self.fram, seq)
+                .map(|value| Record { seq, value }),
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.seqs.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fram::sim::{self, SimFram, Tear};
+    use crate::fram::MemFram;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    #[repr(C)]
+    struct Sample {
+        time: u32,
+        value: i16,
+        channel: u16,
+    }
+
+    // 3 records of 16 bytes after 48 bytes of header and pointers
+    const LOG: FramLog<Sample> = FramLog::new(Region::new(64, 100));
+
+    fn sample(time: u32) -> Sample {
+        Sample {
+            time,
+            value: -(time as i16),
+            channel: 1,
+        }
+    }
+
+    fn contents<D: FramDevice>(fram: &D) -> Vec<(u32, u32)> {
+        LOG.iter(fram)
+            .unwrap()
+            .map(|r| r.map(|r| (r.seq, r.value.time)).unwrap())
+            .collect()
+    }
+
+    fn newest<D: FramDevice>(fram: &D) -> Result<Sample, LogError> {
+        LOG.get(fram, LOG.raw().seqs(fram)?.end - 1)
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut fram = MemFram::new(256);
+        assert_eq!(LOG.len(&fram), Err(LogError::NotFormatted));
+        assert_eq!(LOG.open(&mut fram), Ok(true));
+        assert_eq!(LOG.open(&mut fram), Ok(false));
+        assert_eq!(LOG.capacity(), 3);
+        assert!(LOG.is_empty(&fram).unwrap());
+
+        for time in 10..15 {
+            LOG.push(&mut fram, &sample(time)).unwrap();
+        }
+        assert_eq!(contents(&fram), [(2, 12), (3, 13), (4, 14)]);
+        assert_eq!(LOG.get(&fram, 3), Ok(sample(13)));
+
+        LOG.consume(&mut fram, 2).unwrap();
+        assert_eq!(contents(&fram), [(4, 14)]);
+        LOG.clear(&mut fram).unwrap();
+        assert_eq!(LOG.push(&mut fram, &sample(15)), Ok(5));
+        assert_eq!(contents(&fram), [(5, 15)]);
+
+        // a log of other records is replaced
+        let other = FramLog::<u32>::new(Region::new(64, 100));
+        assert_eq!(
+            other.len(&fram),
+            Err(LogError::RecordLen {
+                found: mem::size_of::<Sample>()
+            })
+        );
+        assert_eq!(other.open(&mut fram), Ok(true));
+        assert_eq!(other.capacity(), 4);
+    }
+
+    #[test]
+    fn detects_damaged_records() {
+        let mut fram = MemFram::new(256);
+        LOG.open(&mut fram).unwrap();
+        LOG.push(&mut fram, &sample(1)).unwrap();
+        LOG.push(&mut fram, &sample(2)).unwrap();
+        // in the data of record 1
+        fram.write(64 + 48 + 16 + 9, &[0xee]).unwrap();
+        let records: Vec<_> = LOG.iter(&fram).unwrap().collect();
+        assert_eq!(records[0].map(|r| r.seq), Ok(0));
+        assert_eq!(records[1], Err(LogError::Corrupt { seq: 1 }));
+    }
+
+    #[test]
+    fn loses_at_most_the_record_being_appended() {
+        let mut fram = SimFram::new(256);
+        LOG.open(&mut fram).unwrap();
+        for time in 0..3 {
+            LOG.push(&mut fram, &sample(time)).unwrap();
+        }
+        for &tear in [Tear::Lost, Tear::LowByte, Tear::Complete].iter() {
+            sim::for_each_failure(
+                fram.image(),
+                tear,
+                |fram| {
+                    let fram_error = |e| match e {
+                        LogError::Fram(e) => e,
+                        e => panic!("{:?}", e),
+                    };
+                    LOG.open(fram).map_err(fram_error)?;
+                    // append 3 and 4 once, across reboots
+                    loop {
+                        let newest = newest(fram).map_err(fram_error)?;
+                        if newest.time == 4 {
+                            return Ok(());
+                        }
+                        LOG.push(fram, &sample(newest.time + 1))
+                            .map_err(fram_error)?;
+                    }
+                },
+                |fram, n| {
+                    let times: Vec<u32> = contents(fram).iter().map(|&(_, t)| t).collect();
+                    assert_eq!(times, [2, 3, 4], "write {}", n);
+                },
+            );
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use core::ptr;
+
+use super::{check_access, FramDevice, FramError, Region};
+
+extern "C" {
+    // bounds of the FRAM region, defined in memory.x
+    static mut _sfram: u8;
+    static _efram: u8;
+}
+
+/// The F-RAM as seen through the FMC bank 1 window.
+///
+/// The byte lane signals (NBL0/NBL1) are not wired, so a byte store would
+/// also overwrite its neighbour; `write` turns partial half-words into
+/// read-modify-write cycles instead.
+///
+/// With the `profile` feature every read and write is counted against the
+/// [`profile`](crate::profile) object at the mapped address.
+pub struct MappedFram {
+    base: *mut u8,
+    size: usize,
+}
+
+impl MappedFram {
+    /// The `FRAM` region of `memory.x`.
+    ///
+    /// # Safety
+    ///
+    /// The FMC must be configured, and nothing else may write the region
+    /// (other than through `.fram_data` statics the caller knows about).
+    pub unsafe fn new() -> Self {
+        let base = ptr::addr_of_mut!(_sfram);
+        let end = ptr::addr_of!(_efram);
+        MappedFram {
+            base,
+            size: end as usize - base as usize,
+        }
+    }
+
+    /// Address of `offset` in the memory map.
+    pub fn as_ptr(&self, offset: usize) -> *mut u8 {
+        self.base.wrapping_add(offset)
+    }
+
+    /// Offset of `ptr` if it points into the F-RAM, e.g. a `.fram_data` static.
+    pub fn offset_of<T>(&self, ptr: *const T) -> Option<usize> {
+        let addr = ptr as usize;
+        let base = self.base as usize;
+        if addr >= base && addr + core::mem::size_of::<T>() <= base + self.size {
+            Some(addr - base)
+        } else {
+            None
+        }
+    }
+
+    /// The region occupied by `obj`, e.g. a [`super::FramArea`] static.
+    pub fn region_of<T>(&self, obj: *const T) -> Option<Region> {
+        self.offset_of(obj)
+            .map(|offset| Region::new(offset, core::mem::size_of::<T>()))
+    }
+
+    fn half(&self, offset: usize) -> *mut u16 {
+        self.as_ptr(offset) as *mut u16
+    }
+}
+
+impl FramDevice for MappedFram {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), FramError> {
+        check_access(self.size, offset, buf.len(), 1)?;
+        #[cfg(feature = "profile")]
+        crate::profile::bytes(false, self.as_ptr(offset), buf.len());
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = unsafe { ptr::read_volatile(self.as_ptr(offset + i)) };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FramError> {
+        check_access(self.size, offset, data.len(), 1)?;
+        #[cfg(feature = "profile")]
+        crate::profile::bytes(true, self.as_ptr(offset), data.len());
+        let mut i = 0;
+        while i < data.len() {
+            let at = offset + i;
+            let aligned = at & !1;
+            let value = if at & 1 == 0 && i + 1 < data.len() {
+                i += 2;
+                u16::from_le_bytes([data[i - 2], data[i - 1]])
+            } else {
+                // only one byte of this half-word changes
+                let mut bytes = unsafe { ptr::read_volatile(self.half(aligned)) }.to_le_bytes();
+                bytes[at & 1] = data[i];
+                i += 1;
+                u16::from_le_bytes(bytes)
+            };
+            unsafe { ptr::write_volatile(self.half(aligned), value) };
+        }
+        Ok(())
+    }
+
+    fn read_u16(&self, offset: usize) -> Result<u16, FramError> {
+        check_access(self.size, offset, 2, 2)?;
+        #[cfg(feature = "profile")]
+        crate::profile::bytes(false, self.as_ptr(offset), 2);
+        Ok(unsafe { ptr::read_volatile(self.half(offset)) })
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) -> Result<(), FramError> {
+        check_access(self.size, offset, 2, 2)?;
+        #[cfg(feature = "profile")]
+        crate::profile::bytes(true, self.as_ptr(offset), 2);
+        unsafe { ptr::write_volatile(self.half(offset), value) };
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        // the FMC has a write FIFO; wait until it drained
+        cortex_m::asm::dsb();
+    }
+}
@@ -0,0 +1,58 @@
+use super::{check_access, FramDevice, FramError};
+
+/// F-RAM stand-in for host builds, backed by a `Vec<u8>`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemFram {
+    data: Vec<u8>,
+}
+
+impl MemFram {
+    /// A zero-filled device of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        MemFram {
+            data: vec![0; size],
+        }
+    }
+
+    /// A device holding a previously dumped image.
+    pub fn from_image(data: Vec<u8>) -> Self {
+        MemFram { data }
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_image(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl FramDevice for MemFram {
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), FramError> {
+        check_access(self.data.len(), offset, buf.len(), 1)?;
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FramError> {
+        check_access(self.data.len(), offset, data.len(), 1)?;
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_u16(&self, offset: usize) -> Result<u16, FramError> {
+        check_access(self.data.len(), offset, 2, 2)?;
+        Ok(u16::from_le_bytes([self.data[offset], self.data[offset + 1]]))
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) -> Result<(), FramError> {
+        check_access(self.data.len(), offset, 2, 2)?;
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+}
@@ -0,0 +1,514 @@
+//! F-RAM wiring and cell tests.
+//!
+//! The tests go straight to the bus rather than through [`FramDevice`]
+//! (`super::FramDevice`), so that a byte store really is a byte store:
+//!
+//! * data lines: walking ones and zeros through one half-word;
+//! * address lines: each of A0..=A15 driven alone, looking for lines that
+//!   are stuck or shorted to another one (on a 16-bit bus, FMC A0 selects
+//!   half-words, so A`n` is byte offset `2 << n`);
+//! * byte lanes: an 8-bit store to each half of a half-word must leave the
+//!   other half alone, on buses that have NBL0/NBL1 (see
+//!   [`Bus::has_byte_lanes`]). The board does not wire them, so on
+//!   `MappedFram` both are reported as [`Outcome::Skipped`];
+//! * March C- over the whole memory.
+//!
+//! Address lines beyond the size of the bus are reported as
+//! [`Outcome::Skipped`]; with the 32K `FRAM` region that is A14 and A15.
+//!
+//! With [`Mode::Preserving`] every location is saved before it is tested and
+//! restored afterwards, March C- working through the memory a block at a
+//! time. A power failure during the test can still lose the block being
+//! tested.
+
+use core::fmt;
+
+/// Half-word and byte access to the memory under test.
+pub trait Bus {
+    /// In bytes.
+    fn size(&self) -> usize;
+    fn read16(&self, offset: usize) -> u16;
+    fn write16(&mut self, offset: usize, value: u16);
+    /// A single byte store, using the byte lane signals.
+    fn write8(&mut self, offset: usize, value: u8);
+
+    /// Whether NBL0/NBL1 are wired; if not, the byte lane test is skipped.
+    fn has_byte_lanes(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Leaves the test patterns behind.
+    Destructive,
+    /// Restores everything it overwrites.
+    Preserving,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// Not testable on this bus.
+    Skipped,
+}
+
+/// Result of March C-.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum March {
+    Pass,
+    /// The first mismatch.
+    Fail {
+        offset: usize,
+        expected: u16,
+        found: u16,
+    },
+}
+
+/// Outcome per signal, plus the cell test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// D0..=D15
+    pub data: [Outcome; 16],
+    /// A0..=A15
+    pub address: [Outcome; 16],
+    /// NBL0, NBL1; skipped without byte lane signals
+    pub byte_lanes: [Outcome; 2],
+    pub march: March,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        let mut lines = self
+            .data
+            .iter()
+            .chain(&self.address)
+            .chain(&self.byte_lanes);
+        lines.all(|&o| o != Outcome::Fail) && self.march == March::Pass
+    }
+}
+
+impl fmt::Display for Report {
+    /// One line per signal, e.g. `D3 FAIL`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let groups: [(&str, &[Outcome]); 3] = [
+            ("D", &self.data),
+            ("A", &self.address),
+            ("NBL", &self.byte_lanes),
+        ];
+        for (name, outcomes) in groups.iter() {
+            for (n, outcome) in outcomes.iter().enumerate() {
+                let outcome = match outcome {
+                    Outcome::Pass => "pass",
+                    Outcome::Fail => "FAIL",
+                    Outcome::Skipped => "skipped",
+                };
+                writeln!(f, "{}{} {}", name, n, outcome)?;
+            }
+        }
+        match self.march {
+            March::Pass => writeln!(f, "march pass"),
+            March::Fail {
+                offset,
+                expected,
+                found,
+            } => writeln!(
+                f,
+                "march FAIL at {:#06x}: expected {:#06x}, found {:#06x}",
+                offset, expected, found
+            ),
+        }
+    }
+}
+
+/// All tests, in the order that makes later failures easiest to read:
+/// data lines, byte lanes, address lines, cells.
+pub fn run<B: Bus>(bus: &mut B, mode: Mode) -> Report {
+    Report {
+        data: data_lines(bus, 0, mode),
+        byte_lanes: if bus.has_byte_lanes() {
+            byte_lanes(bus, 2, mode)
+        } else {
+            [Outcome::Skipped; 2]
+        },
+        address: address_lines(bus, mode),
+        march: march_c(bus, mode),
+    }
+}
+
+/// Walking ones and zeros at `offset`.
+pub fn data_lines<B: Bus>(bus: &mut B, offset: usize, mode: Mode) -> [Outcome; 16] {
+    let saved = bus.read16(offset);
+    let mut bad = 0u16;
+    for bit in 0..16 {
+        for &pattern in [1u16 << bit, !(1u16 << bit)].iter() {
+            bus.write16(offset, pattern);
+            bad |= bus.read16(offset) ^ pattern;
+        }
+    }
+    if mode == Mode::Preserving {
+        bus.write16(offset, saved);
+    }
+    let mut outcomes = [Outcome::Pass; 16];
+    for (bit, outcome) in outcomes.iter_mut().enumerate() {
+        if bad & (1 << bit) != 0 {
+            *outcome = Outcome::Fail;
+        }
+    }
+    outcomes
+}
+
+/// Byte stores into either half of the half-word at `offset`.
+pub fn byte_lanes<B: Bus>(bus: &mut B, offset: usize, mode: Mode) -> [Outcome; 2] {
+    let saved = bus.read16(offset);
+    let mut outcomes = [Outcome::Pass; 2];
+    for lane in 0..2 {
+        for &(background, byte) in [(0xffffu16, 0x00u8), (0x0000, 0xa5)].iter() {
+            bus.write16(offset, background);
+            bus.write8(offset + lane, byte);
+            let mut expected = background.to_le_bytes();
+            expected[lane] = byte;
+            let found = bus.read16(offset).to_le_bytes();
+            // the lane that was written must change, the other must not
+            if found[lane] != expected[lane] {
+                outcomes[lane] = Outcome::Fail;
+            }
+            if found[1 - lane] != expected[1 - lane] {
+                outcomes[1 - lane] = Outcome::Fail;
+            }
+        }
+    }
+    if mode == Mode::Preserving {
+        bus.write16(offset, saved);
+    }
+    outcomes
+}
+
+const PATTERN: u16 = 0xaaaa;
+const ANTI: u16 = 0x5555;
+
+/// Stuck and shorted address lines, by writing to offset 0 and to the
+/// offset each line selects alone.
+pub fn address_lines<B: Bus>(bus: &mut B, mode: Mode) -> [Outcome; 16] {
+    let lines = (0..16).take_while(|&n| 2 << n < bus.size()).count();
+    let at = |n: usize| 2 << n;
+    let zero = bus.read16(0);
+    let mut saved = [0u16; 16];
+    for (n, s) in saved.iter_mut().enumerate().take(lines) {
+        *s = bus.read16(at(n));
+    }
+
+    let mut outcomes = [Outcome::Skipped; 16];
+    for outcome in outcomes.iter_mut().take(lines) {
+        *outcome = Outcome::Pass;
+    }
+    for n in 0..lines {
+        bus.write16(at(n), PATTERN);
+    }
+    // a line stuck high makes offset 0 alias its own offset
+    bus.write16(0, ANTI);
+    for (n, outcome) in outcomes.iter_mut().enumerate().take(lines) {
+        if bus.read16(at(n)) != PATTERN {
+            *outcome = Outcome::Fail;
+        }
+    }
+    bus.write16(0, PATTERN);
+    // a line stuck low aliases offset 0, two shorted lines each other
+    for n in 0..lines {
+        bus.write16(at(n), ANTI);
+        if bus.read16(0) != PATTERN {
+            outcomes[n] = Outcome::Fail;
+        }
+        for m in (0..lines).filter(|&m| m != n) {
+            if bus.read16(at(m)) != PATTERN {
+                outcomes[n] = Outcome::Fail;
+                outcomes[m] = Outcome::Fail;
+            }
+        }
+        bus.write16(at(n), PATTERN);
+    }
+
+    if mode == Mode::Preserving {
+        for (n, &s) in saved.iter().enumerate().take(lines) {
+            bus.write16(at(n), s);
+        }
+        bus.write16(0, zero);
+    }
+    outcomes
+}
+
+// Half-words March C- covers at a time in preserving mode.
+const BLOCK: usize = 128;
+
+/// March C- with all-zero and all-one half-words:
+/// ⇕(w0) ⇑(r0,w1) ⇑(r1,w0) ⇓(r0,w1) ⇓(r1,w0) ⇕(r0).
+pub fn march_c<B: Bus>(bus: &mut B, mode: Mode) -> March {
+    let words = bus.size() / 2;
+    match mode {
+        Mode::Destructive => march_range(bus, 0, words),
+        Mode::Preserving => {
+            let mut saved = [0u16; BLOCK];
+            for start in (0..words).step_by(BLOCK) {
+                let end = (start + BLOCK).min(words);
+                for (i, s) in (start..end).zip(saved.iter_mut()) {
+                    *s = bus.read16(2 * i);
+                }
+                let result = march_range(bus, start, end);
+                for (i, &s) in (start..end).zip(saved.iter()) {
+                    bus.write16(2 * i, s);
+                }
+                if result != March::Pass {
+                    return result;
+                }
+            }
+            March::Pass
+        }
+    }
+}
+
+// March C- over half-words `start..end`.
+fn march_range<B: Bus>(bus: &mut B, start: usize, end: usize) -> March {
+    match march_elements(bus, start, end) {
+        Ok(()) => March::Pass,
+        Err(fail) => fail,
+    }
+}
+
+fn march_elements<B: Bus>(bus: &mut B, start: usize, end: usize) -> Result<(), March> {
+    let (zero, one) = (0x0000, 0xffff);
+    for i in start..end {
+        bus.write16(2 * i, zero);
+    }
+    let elements = [
+        (true, zero, one),
+        (true, one, zero),
+        (false, zero, one),
+        (false, one, zero),
+    ];
+    for &(up, read, write) in elements.iter() {
+        for j in 0..end - start {
+            let i = if up { start + j } else { end - 1 - j };
+            check(bus, i, read)?;
+            bus.write16(2 * i, write);
+        }
+    }
+    for i in start..end {
+        check(bus, i, zero)?;
+    }
+    Ok(())
+}
+
+fn check<B: Bus>(bus: &B, word: usize, expected: u16) -> Result<(), March> {
+    let found = bus.read16(2 * word);
+    if found == expected {
+        Ok(())
+    } else {
+        Err(March::Fail {
+            offset: 2 * word,
+            expected,
+            found,
+        })
+    }
+}
+
+#[cfg(target_arch = "arm")]
+impl Bus for super::MappedFram {
+    fn size(&self) -> usize {
+        super::FramDevice::size(self)
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        unsafe { core::ptr::read_volatile(self.as_ptr(offset) as *const u16) }
+    }
+
+    fn write16(&mut self, offset: usize, value: u16) {
+        unsafe { core::ptr::write_volatile(self.as_ptr(offset) as *mut u16, value) }
+    }
+
+    fn write8(&mut self, offset: usize, value: u8) {
+        unsafe { core::ptr::write_volatile(self.as_ptr(offset), value) }
+    }
+
+    fn has_byte_lanes(&self) -> bool {
+        // see `MappedFram`
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A 16-bit memory behind wiring faults.
+    struct Wired {
+        cells: Vec<u16>,
+        // data lines that always read as the bit in `stuck_value`
+        stuck: u16,
+        stuck_value: u16,
+        // address lines (half-word index bits) tied low / high
+        low: usize,
+        high: usize,
+        // two address lines that always carry the same level
+        short: Option<(usize, usize)>,
+        // byte lane signals not connected: byte stores hit both bytes
+        no_byte_lanes: bool,
+    }
+
+    impl Wired {
+        fn new(size: usize) -> Self {
+            Wired {
+                cells: vec![0x1234; size / 2],
+                stuck: 0,
+                stuck_value: 0,
+                low: 0,
+                high: 0,
+                short: None,
+                no_byte_lanes: false,
+            }
+        }
+
+        fn index(&self, offset: usize) -> usize {
+            let mut i = ((offset / 2) & !self.low) | self.high;
+            if let Some((a, b)) = self.short {
+                // wired-AND of the two lines
+                let level = (i >> a) & (i >> b) & 1;
+                i = i & !(1 << a) & !(1 << b) | level << a | level << b;
+            }
+            i % self.cells.len()
+        }
+    }
+
+    impl Bus for Wired {
+        fn size(&self) -> usize {
+            self.cells.len() * 2
+        }
+
+        fn read16(&self, offset: usize) -> u16 {
+            let value = self.cells[self.index(offset)];
+            value & !self.stuck | self.stuck_value & self.stuck
+        }
+
+        fn write16(&mut self, offset: usize, value: u16) {
+            let i = self.index(offset);
+            self.cells[i] = value & !self.stuck | self.stuck_value & self.stuck;
+        }
+
+        fn write8(&mut self, offset: usize, value: u8) {
+            let i = self.index(offset);
+            let mut bytes = self.cells[i].to_le_bytes();
+            if self.no_byte_lanes {
+                bytes = [value, value];
+            } else {
+                bytes[offset & 1] = value;
+            }
+            self.cells[i] = u16::from_le_bytes(bytes);
+        }
+    }
+
+    fn failing(outcomes: &[Outcome]) -> Vec<usize> {
+        (0..outcomes.len())
+            .filter(|&n| outcomes[n] == Outcome::Fail)
+            .collect()
+    }
+
+    #[test]
+    fn good_wiring_passes_and_is_preserved() {
+        let mut bus = Wired::new(32 * 1024);
+        for (i, c) in bus.cells.iter_mut().enumerate() {
+            *c = (i as u16).wrapping_mul(7919);
+        }
+        let before = bus.cells.clone();
+        let report = run(&mut bus, Mode::Preserving);
+        assert!(report.passed(), "{}", report);
+        assert_eq!(bus.cells, before);
+        // 16K half-words need A0..=A13
+        assert_eq!(report.address[13], Outcome::Pass);
+        assert_eq!(report.address[14..], [Outcome::Skipped; 2]);
+        assert!(report.to_string().contains("A15 skipped\n"));
+
+        assert!(run(&mut bus, Mode::Destructive).passed());
+        assert!(bus.cells.iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn stuck_data_lines() {
+        let mut bus = Wired::new(1024);
+        bus.stuck = 1 << 3 | 1 << 12;
+        bus.stuck_value = 1 << 12;
+        let report = run(&mut bus, Mode::Preserving);
+        assert_eq!(failing(&report.data), vec![3, 12]);
+        assert!(!report.passed());
+        assert!(report.to_string().contains("D3 FAIL\n"));
+    }
+
+    #[test]
+    fn bad_address_lines() {
+        let mut bus = Wired::new(1024);
+        bus.low = 1 << 4;
+        assert_eq!(
+            failing(&address_lines(&mut bus, Mode::Destructive)),
+            vec![4]
+        );
+
+        let mut bus = Wired::new(1024);
+        bus.high = 1 << 2;
+        assert_eq!(
+            failing(&address_lines(&mut bus, Mode::Destructive)),
+            vec![2]
+        );
+
+        let mut bus = Wired::new(1024);
+        bus.short = Some((1, 6));
+        assert_eq!(
+            failing(&address_lines(&mut bus, Mode::Destructive)),
+            vec![1, 6]
+        );
+        assert!(matches!(
+            march_c(&mut bus, Mode::Destructive),
+            March::Fail { .. }
+        ));
+    }
+
+    #[test]
+    fn missing_byte_lanes() {
+        let mut bus = Wired::new(64);
+        bus.no_byte_lanes = true;
+        assert_eq!(
+            byte_lanes(&mut bus, 2, Mode::Preserving),
+            [Outcome::Fail; 2]
+        );
+        assert_eq!(bus.cells[1], 0x1234);
+    }
+
+    #[test]
+    fn march_finds_a_bad_cell() {
+        struct Stuck(Wired);
+        impl Bus for Stuck {
+            fn size(&self) -> usize {
+                self.0.size()
+            }
+            fn read16(&self, offset: usize) -> u16 {
+                self.0.read16(offset) | if offset == 0x2a { 0x0100 } else { 0 }
+            }
+            fn write16(&mut self, offset: usize, value: u16) {
+                self.0.write16(offset, value)
+            }
+            fn write8(&mut self, offset: usize, value: u8) {
+                self.0.write8(offset, value)
+            }
+        }
+
+        let mut bus = Stuck(Wired::new(512));
+        assert_eq!(
+            march_c(&mut bus, Mode::Preserving),
+            March::Fail {
+                offset: 0x2a,
+                expected: 0,
+                found: 0x0100
+            }
+        );
+        // the block was put back
+        assert_eq!(bus.0.cells[0x14], 0x1234);
+    }
+}
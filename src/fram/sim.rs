@@ -0,0 +1,337 @@
+//! Simulated F-RAM that can lose power in the middle of a write.
+//!
+//! Every half-word store is one FMC transaction and counts as one write.
+//! Once the configured write is reached the device "loses power": that store
+//! is torn according to [`Tear`], and every later access fails with
+//! [`FramError::PowerLost`] until [`SimFram::reboot`]. The code under test is
+//! expected to bail out with `?` and be started again, like after a real
+//! brown-out.
+
+use super::{check_access, FramDevice, FramError};
+
+/// What an interrupted half-word store leaves in the memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tear {
+    /// Nothing of the store lands.
+    Lost,
+    /// Only the low byte is written.
+    LowByte,
+    /// The store completes, but nothing after it does.
+    Complete,
+    /// Every bit ends up either old or new, at random.
+    Random,
+}
+
+#[derive(Clone, Debug)]
+pub struct SimFram {
+    data: Vec<u8>,
+    writes: u64,
+    fail_at: Option<u64>,
+    tear: Tear,
+    powered: bool,
+    rng: u64,
+}
+
+impl SimFram {
+    /// A zero-filled device of `size` bytes that never loses power.
+    pub fn new(size: usize) -> Self {
+        Self::from_image(vec![0; size])
+    }
+
+    pub fn from_image(data: Vec<u8>) -> Self {
+        SimFram {
+            data,
+            writes: 0,
+            fail_at: None,
+            tear: Tear::Lost,
+            powered: true,
+            rng: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+
+    /// Lose power during the `writes`-th half-word store from now on
+    /// (0 = the very next one).
+    pub fn fail_after(&mut self, writes: u64) {
+        self.fail_at = Some(self.writes + writes);
+    }
+
+    /// Lose power at a random store among the next `within` ones.
+    pub fn fail_randomly(&mut self, within: u64) {
+        let n = self.next_random() % within.max(1);
+        self.fail_after(n);
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        // xorshift must not start from zero
+        self.rng = seed | 1;
+    }
+
+    pub fn set_tear(&mut self, tear: Tear) {
+        self.tear = tear;
+    }
+
+    pub fn power_lost(&self) -> bool {
+        !self.powered
+    }
+
+    /// Half-word stores completed since the last reboot.
+    pub fn writes(&self) -> u64 {
+        self.writes
+    }
+
+    /// Powers the device back up with whatever survived in it.
+    pub fn reboot(&mut self) {
+        self.powered = true;
+        self.fail_at = None;
+        self.writes = 0;
+    }
+
+    pub fn image(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_image(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn powered(&self) -> Result<(), FramError> {
+        if self.powered {
+            Ok(())
+        } else {
+            Err(FramError::PowerLost)
+        }
+    }
+
+    // One bus transaction; `offset` is even.
+    fn store(&mut self, offset: usize, value: u16) -> Result<(), FramError> {
+        self.powered()?;
+        let new = value.to_le_bytes();
+        if self.fail_at == Some(self.writes) {
+            self.powered = false;
+            let old = [self.data[offset], self.data[offset + 1]];
+            let torn = match self.tear {
+                Tear::Lost => old,
+                Tear::LowByte => [new[0], old[1]],
+                Tear::Complete => new,
+                Tear::Random => {
+                    let mask = self.next_random() as u16;
+                    let old = u16::from_le_bytes(old);
+                    (old & !mask | value & mask).to_le_bytes()
+                }
+            };
+            self.data[offset..offset + 2].copy_from_slice(&torn);
+            return Err(FramError::PowerLost);
+        }
+        self.data[offset..offset + 2].copy_from_slice(&new);
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+impl FramDevice for SimFram {
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), FramError> {
+        self.powered()?;
+        check_access(self.data.len(), offset, buf.len(), 1)?;
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FramError> {
+        self.powered()?;
+        check_access(self.data.len(), offset, data.len(), 1)?;
+        // half-word transactions, like the FMC without byte lanes
+        let end = offset + data.len();
+        let mut at = offset & !1;
+        while at < end {
+            let mut half = [self.data[at], *self.data.get(at + 1).unwrap_or(&0)];
+            for (i, b) in half.iter_mut().enumerate() {
+                if (offset..end).contains(&(at + i)) {
+                    *b = data[at + i - offset];
+                }
+            }
+            self.store(at, u16::from_le_bytes(half))?;
+            at += 2;
+        }
+        Ok(())
+    }
+
+    fn read_u16(&self, offset: usize) -> Result<u16, FramError> {
+        self.powered()?;
+        check_access(self.data.len(), offset, 2, 2)?;
+        Ok(u16::from_le_bytes([self.data[offset], self.data[offset + 1]]))
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) -> Result<(), FramError> {
+        self.powered()?;
+        check_access(self.data.len(), offset, 2, 2)?;
+        self.store(offset, value)
+    }
+}
+
+/// Crashes `program` at every one of its writes in turn.
+///
+/// `program` plays one boot: it runs against the device from the start,
+/// including any recovery it does, and returns the first error. It is run
+/// once without failures to count its writes; then, for every write `n`, a
+/// fresh device holding `image` loses power at write `n`, is rebooted, and
+/// `program` runs again to completion before `check(&device, n)`.
+pub fn for_each_failure<P, C>(image: &[u8], tear: Tear, mut program: P, mut check: C)
+where
+    P: FnMut(&mut SimFram) -> Result<(), FramError>,
+    C: FnMut(&SimFram, u64),
+{
+    let mut fram = SimFram::from_image(image.to_vec());
+    program(&mut fram).expect("program fails without power loss");
+    let writes = fram.writes();
+
+    for n in 0..writes {
+        let mut fram = SimFram::from_image(image.to_vec());
+        fram.set_tear(tear);
+        fram.fail_after(n);
+        assert_eq!(program(&mut fram), Err(FramError::PowerLost));
+        fram.reboot();
+        program(&mut fram).expect("program fails after reboot");
+        check(&fram, n);
+    }
+}
+
+/// Runs `program` until it completes, cutting power at a random write
+/// within the next `within` ones on every boot. Returns the number of
+/// power failures it took.
+pub fn run_intermittently<P>(fram: &mut SimFram, within: u64, mut program: P) -> u64
+where
+    P: FnMut(&mut SimFram) -> Result<(), FramError>,
+{
+    let mut failures = 0;
+    loop {
+        fram.fail_randomly(within);
+        match program(fram) {
+            Ok(()) => {
+                fram.reboot();
+                return failures;
+            }
+            Err(FramError::PowerLost) => {
+                failures += 1;
+                fram.reboot();
+            }
+            Err(e) => panic!("program failed: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn power_dies_at_the_chosen_write() {
+        let mut fram = SimFram::new(8);
+        fram.fail_after(2);
+        fram.write_u16(0, 1).unwrap();
+        fram.write_u16(2, 2).unwrap();
+        assert_eq!(fram.write_u16(4, 3), Err(FramError::PowerLost));
+        assert!(fram.power_lost());
+        assert_eq!(fram.read_u16(0), Err(FramError::PowerLost));
+        assert_eq!(fram.write_u16(6, 4), Err(FramError::PowerLost));
+
+        fram.reboot();
+        assert_eq!(fram.image(), &[1, 0, 2, 0, 0, 0, 0, 0]);
+        fram.write_u16(6, 4).unwrap();
+        assert_eq!(fram.writes(), 1);
+    }
+
+    #[test]
+    fn tears_the_interrupted_store() {
+        for &(tear, expected) in [
+            (Tear::Lost, 0x1111),
+            (Tear::LowByte, 0x11ff),
+            (Tear::Complete, 0xeeff),
+        ]
+        .iter()
+        {
+            let mut fram = SimFram::new(2);
+            fram.write_u16(0, 0x1111).unwrap();
+            fram.set_tear(tear);
+            fram.fail_after(0);
+            assert!(fram.write_u16(0, 0xeeff).is_err());
+            fram.reboot();
+            assert_eq!(fram.read_u16(0), Ok(expected));
+        }
+
+        let mut fram = SimFram::new(2);
+        fram.set_tear(Tear::Random);
+        fram.fail_after(0);
+        assert!(fram.write_u16(0, 0xffff).is_err());
+        fram.reboot();
+        let torn = fram.read_u16(0).unwrap();
+        assert!(torn != 0 && torn != 0xffff);
+    }
+
+    #[test]
+    fn words_tear_between_half_words() {
+        let mut fram = SimFram::new(4);
+        fram.fail_after(1);
+        assert!(fram.write_u32(0, 0xaaaa_bbbb).is_err());
+        fram.reboot();
+        assert_eq!(fram.read_u32(0), Ok(0x0000_bbbb));
+    }
+
+    #[test]
+    fn byte_writes_are_half_word_transactions() {
+        let mut fram = SimFram::from_image(vec![9; 6]);
+        fram.write(1, &[1, 2, 3]).unwrap();
+        assert_eq!(fram.image(), &[9, 1, 2, 3, 9, 9]);
+        assert_eq!(fram.writes(), 2);
+    }
+
+    #[test]
+    fn crashes_at_every_write() {
+        let mut seen = Vec::new();
+        for_each_failure(
+            &[0; 8],
+            Tear::Lost,
+            |fram| {
+                for i in 0..4 {
+                    fram.write_u16(i * 2, 1)?;
+                }
+                Ok(())
+            },
+            |fram, n| {
+                assert_eq!(fram.image(), &[1, 0, 1, 0, 1, 0, 1, 0]);
+                seen.push(n);
+            },
+        );
+        assert_eq!(seen, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn eventually_completes() {
+        let mut fram = SimFram::new(64);
+        fram.seed(7);
+        let failures = run_intermittently(&mut fram, 40, |fram| {
+            // resumes where the last boot got to
+            let mut i = fram.read_u16(0)? as usize;
+            while i < 31 {
+                fram.write_u16(2 + i * 2, i as u16)?;
+                i += 1;
+                fram.write_u16(0, i as u16)?;
+            }
+            Ok(())
+        });
+        assert!(failures > 0);
+        for i in 0..31 {
+            assert_eq!(fram.read_u16(2 + i * 2), Ok(i as u16));
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Failure-atomic updates through an undo log.
+//!
+//! Before a transaction overwrites F-RAM it appends the old bytes to an
+//! [`UndoLog`] kept in F-RAM itself. If power fails before the transaction
+//! commits, [`UndoLog::recover`] at the next boot puts the old bytes back, so
+//! either all or none of a transaction's writes survive.
+//!
+//! Log layout:
+//!
+//! ```text
+//! | state: u16 | pad: u16 | count slot 0 | count slot 1 | entry 0 | entry 1 | ...
+//! count slot: | count: u16 | !count: u16 |
+//! entry: | offset: u32 | len: u16 | pad: u16 | old bytes (len, padded to 2) |
+//! ```
+//!
+//! An entry only counts once the count has been bumped past it, and the data
+//! it covers is written after that, so a torn entry never guards a write.
+//! Count `n` goes to slot `n % 2`, leaving `n - 1` whole in the other one: a
+//! torn slot fails its check and the count read is the larger valid one, so
+//! it can't move backwards when a store carries into the high byte.
+
+use super::{bytes_of, FramDevice, FramError, Region};
+
+const ACTIVE: u16 = 0x7a55;
+const IDLE: u16 = 0;
+const LOG_HEADER_LEN: usize = 12;
+const ENTRY_HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxError {
+    /// The undo log has no room for another entry.
+    LogFull,
+    /// An interrupted transaction has not been rolled back yet.
+    NeedsRecovery,
+    /// The value to update is not in F-RAM.
+    NotInFram,
+    Fram(FramError),
+}
+
+impl From<FramError> for TxError {
+    fn from(e: FramError) -> Self {
+        TxError::Fram(e)
+    }
+}
+
+/// An undo log in an F-RAM region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UndoLog {
+    region: Region,
+}
+
+impl UndoLog {
+    pub const fn new(region: Region) -> Self {
+        UndoLog { region }
+    }
+
+    fn state<D: FramDevice>(&self, fram: &D) -> Result<u16, FramError> {
+        fram.read_u16(self.region.offset)
+    }
+
+    /// Rolls back a transaction interrupted by a power failure. Returns
+    /// whether there was one. Safe to interrupt and run again.
+    pub fn recover<D: FramDevice>(&self, fram: &mut D) -> Result<bool, FramError> {
+        if self.state(fram)? != ACTIVE {
+            return Ok(false);
+        }
+        let count = self.count(fram)? as usize;
+        for i in (0..count).rev() {
+            self.undo_entry(fram, i)?;
+        }
+        fram.flush();
+        fram.write_u16(self.region.offset, IDLE)?;
+        fram.flush();
+        Ok(true)
+    }
+
+    // The larger of the two counts that pass their check.
+    fn count<D: FramDevice>(&self, fram: &D) -> Result<u16, FramError> {
+        let mut count = 0;
+        for slot in 0..2 {
+            let at = self.region.offset + 4 + 4 * slot;
+            let n = fram.read_u16(at)?;
+            if fram.read_u16(at + 2)? == !n {
+                count = count.max(n);
+            }
+        }
+        Ok(count)
+    }
+
+    fn write_count<D: FramDevice>(
+        &self,
+        fram: &mut D,
+        slot: usize,
+        count: u16,
+    ) -> Result<(), FramError> {
+        let at = self.region.offset + 4 + 4 * slot;
+        fram.write_u16(at, count)?;
+        fram.write_u16(at + 2, !count)
+    }
+
+    // Entries are variable length: walk to the `index`-th one.
+    fn undo_entry<D: FramDevice>(&self, fram: &mut D, index: usize) -> Result<(), FramError> {
+        let mut at = self.region.offset + LOG_HEADER_LEN;
+        for _ in 0..index {
+            at += entry_len(fram.read_u16(at + 4)? as usize);
+        }
+        let target = fram.read_u32(at)? as usize;
+        let len = fram.read_u16(at + 4)? as usize;
+
+        let mut buf = [0u8; 32];
+        let mut done = 0;
+        while done < len {
+            let n = buf.len().min(len - done);
+            fram.read(at + ENTRY_HEADER_LEN + done, &mut buf[..n])?;
+            fram.write(target + done, &buf[..n])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Starts a transaction. Only one can be open at a time.
+    pub fn begin<'a, D: FramDevice>(
+        &'a self,
+        fram: &'a mut D,
+    ) -> Result<Transaction<'a, D>, TxError> {
+        if self.state(fram)? == ACTIVE {
+            return Err(TxError::NeedsRecovery);
+        }
+        // both slots, or a count left from the last transaction would win
+        self.write_count(fram, 0, 0)?;
+        self.write_count(fram, 1, 0)?;
+        fram.write_u16(self.region.offset, ACTIVE)?;
+        Ok(Transaction {
+            log: self,
+            fram,
+            count: 0,
+            tail: self.region.offset + LOG_HEADER_LEN,
+        })
+    }
+
+    /// Runs `f` as one transaction: committed if it returns `Ok`, rolled
+    /// back if it returns `Err` (or power fails before it returns).
+    pub fn run<D, F, R, E>(&self, fram: &mut D, f: F) -> Result<R, E>
+    where
+        D: FramDevice,
+        F: FnOnce(&mut Transaction<'_, D>) -> Result<R, E>,
+        E: From<TxError>,
+    {
+        let mut tx = self.begin(fram)?;
+        match f(&mut tx) {
+            Ok(r) => {
+                tx.commit()?;
+                Ok(r)
+            }
+            Err(e) => {
+                tx.abort()?;
+                Err(e)
+            }
+        }
+    }
+}
+
+fn entry_len(len: usize) -> usize {
+    ENTRY_HEADER_LEN + len.div_ceil(2) * 2
+}
+
+/// An open transaction, see [`UndoLog::begin`].
+pub struct Transaction<'a, D: FramDevice> {
+    log: &'a UndoLog,
+    fram: &'a mut D,
+    count: u16,
+    tail: usize,
+}
+
+impl<'a, D: FramDevice> Transaction<'a, D> {
+    /// Reads through to the device.
+    pub fn fram(&self) -> &D {
+        self.fram
+    }
+
+    /// Undoably writes `data` at `offset`.
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), TxError> {
+        let entry = entry_len(data.len());
+        if self.tail + entry > self.log.region.end() || data.len() > u16::MAX as usize {
+            return Err(TxError::LogFull);
+        }
+
+        // copy the old bytes into the log
+        self.fram.write_u32(self.tail, offset as u32)?;
+        self.fram.write_u32(self.tail + 4, data.len() as u32)?;
+        let mut buf = [0u8; 32];
+        let mut done = 0;
+        while done < data.len() {
+            let n = buf.len().min(data.len() - done);
+            self.fram.read(offset + done, &mut buf[..n])?;
+            self.fram.write(self.tail + ENTRY_HEADER_LEN + done, &buf[..n])?;
+            done += n;
+        }
+        self.fram.flush();
+
+        // the entry is valid from here on
+        self.count += 1;
+        self.log.write_count(self.fram, self.count as usize % 2, self.count)?;
+        self.tail += entry;
+        self.fram.flush();
+
+        self.fram.write(offset, data)?;
+        Ok(())
+    }
+
+    pub fn write_u16(&mut self, offset: usize, value: u16) -> Result<(), TxError> {
+        self.write(offset, &value.to_le_bytes())
+    }
+
+    pub fn write_u32(&mut self, offset: usize, value: u32) -> Result<(), TxError> {
+        self.write(offset, &value.to_le_bytes())
+    }
+
+    /// Writes `data` at `offset` without logging the old bytes, for memory
+    /// that nothing refers to unless the transaction commits, such as a heap
+    /// block allocated in it.
+    pub fn init(&mut self, offset: usize, data: &[u8]) -> Result<(), TxError> {
+        self.fram.write(offset, data)?;
+        Ok(())
+    }
+
+    /// Undoably writes the bytes of `value` at `offset`, see [`bytes_of`].
+    pub fn write_value<T: Copy>(&mut self, offset: usize, value: &T) -> Result<(), TxError> {
+        self.write(offset, bytes_of(value))
+    }
+
+    /// Makes every write of the transaction permanent.
+    pub fn commit(self) -> Result<(), TxError> {
+        self.fram.flush();
+        self.fram.write_u16(self.log.region.offset, IDLE)?;
+        self.fram.flush();
+        Ok(())
+    }
+
+    /// Undoes every write of the transaction.
+    pub fn abort(self) -> Result<(), TxError> {
+        self.log.recover(self.fram)?;
+        Ok(())
+    }
+}
+
+#[cfg(target_arch = "arm")]
+impl<'a> Transaction<'a, super::MappedFram> {
+    /// Undoably `*place = value` for a value that lives in F-RAM, such as an
+    /// element of a `.fram_data` tensor. `place` is a pointer, e.g. from
+    /// `ptr::addr_of!`, as no reference may be held across the write.
+    pub fn set<T: Copy>(&mut self, place: *const T, value: T) -> Result<(), TxError> {
+        let offset = self.fram.offset_of(place).ok_or(TxError::NotInFram)?;
+        self.write_value(offset, &value)
+    }
+}
+
+#[cfg(target_arch = "arm")]
+mod global {
+    use core::ptr;
+
+    use super::{Transaction, TxError, UndoLog};
+    use crate::fram::{FramArea, FramError, MappedFram};
+
+    /// Size of the log used by [`transaction`].
+    pub const UNDO_LOG_SIZE: usize = 1024;
+
+    #[link_section = ".fram_data"]
+    static mut UNDO_AREA: FramArea<UNDO_LOG_SIZE> = FramArea::new();
+
+    fn log(fram: &MappedFram) -> UndoLog {
+        UndoLog::new(fram.region_of(ptr::addr_of!(UNDO_AREA)).unwrap())
+    }
+
+    /// Runs `f` as a failure-atomic transaction on the F-RAM:
+    ///
+    /// ```ignore
+    /// #[link_section = ".fram_data"]
+    /// static mut LIMITS: [u16; 2] = [0; 2];
+    ///
+    /// fram::transaction(|tx| {
+    ///     let limits = ptr::addr_of!(LIMITS) as *const u16;
+    ///     tx.set(limits, 1)?;
+    ///     tx.set(limits.wrapping_add(1), 2)
+    /// })?;
+    /// ```
+    ///
+    /// [`recover`] must have run since boot.
+    pub fn transaction<F, R, E>(f: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut Transaction<'_, MappedFram>) -> Result<R, E>,
+        E: From<TxError>,
+    {
+        let mut fram = unsafe { MappedFram::new() };
+        log(&fram).run(&mut fram, f)
+    }
+
+    /// Rolls back a [`transaction`] cut short by a power failure. Call once at
+    /// boot, after the FMC is configured.
+    pub fn recover() -> Result<bool, FramError> {
+        let mut fram = unsafe { MappedFram::new() };
+        log(&fram).recover(&mut fram)
+    }
+}
+
+#[cfg(target_arch = "arm")]
+pub use self::global::{recover, transaction, UNDO_LOG_SIZE};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fram::sim::{SimFram, Tear};
+
+    const LOG: UndoLog = UndoLog::new(Region::new(256, 256));
+
+    fn update<D: FramDevice>(fram: &mut D) -> Result<(), TxError> {
+        LOG.run(fram, |tx| {
+            tx.write_u32(0, 0xaaaa_aaaa)?;
+            tx.write(10, b"new bytes")?;
+            tx.write_u16(0, 0xbbbb)?;
+            tx.write_value(40, &[7i16; 5])
+        })
+    }
+
+    fn old_image() -> Vec<u8> {
+        let mut image = vec![0u8; 512];
+        for (i, b) in image[..128].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        image
+    }
+
+    #[test]
+    fn commit_keeps_the_writes() {
+        let mut fram = SimFram::from_image(old_image());
+        update(&mut fram).unwrap();
+        assert_eq!(fram.read_u32(0), Ok(0xaaaa_bbbb));
+        assert_eq!(&fram.image()[10..19], b"new bytes");
+        assert_eq!(fram.read_u16(48), Ok(7));
+        assert_eq!(LOG.recover(&mut fram), Ok(false));
+    }
+
+    #[test]
+    fn error_rolls_back() {
+        let mut fram = SimFram::from_image(old_image());
+        let r: Result<(), _> = LOG.run(&mut fram, |tx| {
+            tx.write(0, b"xyz")?;
+            Err(TxError::NotInFram)
+        });
+        assert_eq!(r, Err(TxError::NotInFram));
+        assert_eq!(&fram.image()[..256], &old_image()[..256]);
+    }
+
+    #[test]
+    fn log_overflow() {
+        let log = UndoLog::new(Region::new(256, 32));
+        let mut fram = SimFram::from_image(old_image());
+        let r = log.run(&mut fram, |tx| {
+            tx.write(0, &[1; 16])?;
+            tx.write(16, &[1; 16])
+        });
+        assert_eq!(r, Err(TxError::LogFull));
+        assert_eq!(&fram.image()[..256], &old_image()[..256]);
+    }
+
+    #[test]
+    fn refuses_to_start_before_recovery() {
+        let mut fram = SimFram::from_image(old_image());
+        fram.fail_after(10);
+        assert!(update(&mut fram).is_err());
+        fram.reboot();
+        assert_eq!(update(&mut fram), Err(TxError::NeedsRecovery));
+        assert_eq!(LOG.recover(&mut fram), Ok(true));
+        update(&mut fram).unwrap();
+    }
+
+    #[test]
+    fn count_carries_into_the_high_byte() {
+        // the 256th entry bumps the count from 0x00ff to 0x0100
+        let log = UndoLog::new(Region::new(1024, 3072));
+        let update = |fram: &mut SimFram| -> Result<(), TxError> {
+            log.run(fram, |tx| {
+                for i in 0..260 {
+                    tx.write_u16(2 * i, 0xffff)?;
+                }
+                Ok(())
+            })
+        };
+        let mut old = vec![0u8; 4096];
+        for (i, b) in old[..1024].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut probe = SimFram::from_image(old.clone());
+        update(&mut probe).unwrap();
+        let new = probe.image()[..1024].to_vec();
+
+        for n in 0..probe.writes() {
+            let mut fram = SimFram::from_image(old.clone());
+            fram.set_tear(Tear::LowByte);
+            fram.fail_after(n);
+            assert!(update(&mut fram).is_err());
+            fram.reboot();
+            log.recover(&mut fram).unwrap();
+            let data = &fram.image()[..1024];
+            assert!(data == &old[..1024] || data == &new[..], "torn at write {}", n);
+        }
+    }
+
+    #[test]
+    fn all_or_nothing_under_power_failure() {
+        let old = old_image();
+        let mut new = SimFram::from_image(old.clone());
+        update(&mut new).unwrap();
+        let new = new.into_image();
+
+        for &tear in [Tear::Lost, Tear::LowByte, Tear::Complete].iter() {
+            let mut probe = SimFram::from_image(old.clone());
+            update(&mut probe).unwrap();
+            for n in 0..probe.writes() {
+                let mut fram = SimFram::from_image(old.clone());
+                fram.set_tear(tear);
+                fram.fail_after(n);
+                assert!(update(&mut fram).is_err());
+                fram.reboot();
+
+                // and power fails again during recovery
+                fram.fail_after(n % 7);
+                if LOG.recover(&mut fram).is_err() {
+                    fram.reboot();
+                    LOG.recover(&mut fram).unwrap();
+                }
+                let data = &fram.image()[..256];
+                assert!(data == &old[..256] || data == &new[..256], "torn at write {}", n);
+            }
+        }
+    }
+}
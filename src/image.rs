@@ -0,0 +1,776 @@
+//! The F-RAM image header.
+//!
+//! F-RAM keeps its contents across firmware updates, so after flashing a
+//! build whose statics are laid out differently, the old contents must not
+//! be taken for the new ones. The first bytes of the `FRAM` region hold a
+//! [`Header`] describing the image: a magic number, the layout version the
+//! firmware declares, a hash of the build inputs that determine the layout,
+//! and the location of each section with a CRC-32 of its contents.
+//!
+//! Only read-only sections (`.fram_rodata`, the model weights) have their
+//! contents checked; writable ones change all the time and are only checked
+//! for their location.
+//!
+//! At boot, [`FramImage::boot`] compares the header with the running
+//! firmware's [`Layout`] and asks a [`Policy`] what to do about any
+//! mismatch: reinitialize the sections, migrate the old contents, accept
+//! them anyway, or halt. Reinitializing gives each section its [`Init`]
+//! contents; on the board, `.fram_rodata` and `.fram_data` are copied from
+//! their load images in flash and `.fram_noinit` is left alone, so a board
+//! programmed without a debugger gets its weights on first boot. After
+//! reinitializing or migrating the header is rewritten. Its CRC is written
+//! last, so a power failure while rewriting shows up as a corrupt header on
+//! the next boot.
+
+use core::mem;
+
+use crate::crc::Crc32;
+use crate::fram::{bytes_of, read_value, FramDevice, FramError, Region};
+
+/// `"FRAM"`
+pub const MAGIC: u32 = u32::from_le_bytes(*b"FRAM");
+/// Version of the header format itself.
+pub const FORMAT: u32 = 1;
+/// Sections a header can describe.
+pub const MAX_SECTIONS: usize = 3;
+pub const HEADER_LEN: usize = mem::size_of::<Header>();
+
+/// The initial contents of a section.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Init<'a> {
+    /// Left as they are.
+    Keep,
+    /// All zeros.
+    Zero,
+    /// A copy of a load image of the section's length, e.g. in flash.
+    Copy(&'a [u8]),
+}
+
+/// One section of the image as the firmware sees it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Section<'a> {
+    pub name: &'static str,
+    pub region: Region,
+    /// Whether the contents are covered by the CRC.
+    pub read_only: bool,
+    pub init: Init<'a>,
+}
+
+impl Section<'_> {
+    /// Gives the section its initial contents.
+    pub fn initialize<D: FramDevice>(&self, fram: &mut D) -> Result<(), FramError> {
+        match self.init {
+            Init::Keep => Ok(()),
+            Init::Zero => fill(fram, self.region, 0),
+            Init::Copy(image) => {
+                assert_eq!(image.len(), self.region.len, "load image size");
+                fram.write(self.region.offset, image)?;
+                fram.flush();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What the running firmware expects in F-RAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout<'a> {
+    /// Bumped by hand whenever F-RAM statics change incompatibly.
+    pub version: u32,
+    /// Hash of the build inputs that place statics: `memory.x`, the
+    /// generated model, and the crate's sources and version, so any code
+    /// change counts as a new layout. Data that must survive updates
+    /// belongs in `.fram_noinit`.
+    pub build_hash: u64,
+    pub sections: &'a [Section<'a>],
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct SectionEntry {
+    pub offset: u32,
+    pub len: u32,
+    /// CRC-32 of the contents, 0 for writable sections.
+    pub crc: u32,
+}
+
+/// The header as stored, without padding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Header {
+    pub magic: u32,
+    pub format: u32,
+    pub count: u32,
+    pub version: u32,
+    pub build_hash: u64,
+    pub sections: [SectionEntry; MAX_SECTIONS],
+    /// CRC-32 of the fields above.
+    pub crc: u32,
+}
+
+impl Header {
+    fn compute_crc(&self) -> u32 {
+        let bytes = bytes_of(self);
+        let mut crc = Crc32::new();
+        crc.update(&bytes[..HEADER_LEN - 4]);
+        crc.finish()
+    }
+}
+
+/// Why the F-RAM contents do not belong to this firmware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// No header at all: a new part, or one used for something else.
+    Blank,
+    /// The header CRC is wrong.
+    Corrupt,
+    /// A header format this firmware does not know.
+    Format(u32),
+    Version {
+        found: u32,
+        expected: u32,
+    },
+    Build {
+        found: u64,
+        expected: u64,
+    },
+    /// Sections moved, were resized, added or removed.
+    Sections,
+    /// The contents of read-only section `index` changed.
+    Contents {
+        index: usize,
+    },
+}
+
+/// What to do about a [`Mismatch`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Give the sections their initial contents.
+    Reinitialize,
+    /// Convert the contents with [`Policy::migrate`].
+    Migrate,
+    /// Use the contents as they are, and describe them in a new header.
+    Accept,
+    /// Give up; [`FramImage::boot`] returns [`ImageError::Halted`].
+    Halt,
+}
+
+/// Decides what happens to F-RAM contents that do not match the firmware.
+pub trait Policy<D: FramDevice> {
+    /// `found` is the header in F-RAM, if there is a readable one.
+    fn decide(&mut self, mismatch: Mismatch, found: Option<&Header>) -> Action;
+
+    /// Gives every section its initial contents, see [`Section::initialize`].
+    fn reinitialize(&mut self, fram: &mut D, layout: &Layout<'_>) -> Result<(), FramError> {
+        for section in layout.sections {
+            section.initialize(fram)?;
+        }
+        Ok(())
+    }
+
+    /// Converts contents described by `from` to `layout`. The default
+    /// cannot, so choosing [`Action::Migrate`] without overriding this halts
+    /// with `mismatch`.
+    ///
+    /// Must be idempotent: the header is only rewritten after this returns,
+    /// so a power failure during the migration runs it again, from the start,
+    /// on contents it may already have partly converted. Copy to where the
+    /// source is not overwritten, for instance.
+    fn migrate(
+        &mut self,
+        _fram: &mut D,
+        mismatch: Mismatch,
+        _from: &Header,
+        _layout: &Layout<'_>,
+    ) -> Result<(), ImageError> {
+        Err(ImageError::Halted(mismatch))
+    }
+}
+
+/// Reinitializes on any mismatch.
+pub struct Reinitialize;
+
+impl<D: FramDevice> Policy<D> for Reinitialize {
+    fn decide(&mut self, _mismatch: Mismatch, _found: Option<&Header>) -> Action {
+        Action::Reinitialize
+    }
+}
+
+/// Halts on any mismatch.
+pub struct Halt;
+
+impl<D: FramDevice> Policy<D> for Halt {
+    fn decide(&mut self, _mismatch: Mismatch, _found: Option<&Header>) -> Action {
+        Action::Halt
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageError {
+    /// The policy chose to halt, or could not migrate.
+    Halted(Mismatch),
+    /// More than [`MAX_SECTIONS`] sections, or one beyond 4G.
+    Layout,
+    Fram(FramError),
+}
+
+impl From<FramError> for ImageError {
+    fn from(e: FramError) -> Self {
+        ImageError::Fram(e)
+    }
+}
+
+/// How [`FramImage::boot`] went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boot {
+    Valid,
+    Reinitialized(Mismatch),
+    Migrated(Mismatch),
+    Accepted(Mismatch),
+}
+
+/// The header at a fixed offset of a device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FramImage {
+    offset: usize,
+}
+
+impl FramImage {
+    pub const fn new(offset: usize) -> Self {
+        FramImage { offset }
+    }
+
+    pub fn read_header<D: FramDevice>(&self, fram: &D) -> Result<Header, FramError> {
+        read_value(fram, self.offset)
+    }
+
+    /// The first mismatch between the F-RAM and `layout`, if any.
+    pub fn check<D: FramDevice>(
+        &self,
+        fram: &D,
+        layout: &Layout<'_>,
+    ) -> Result<Option<Mismatch>, ImageError> {
+        let expected = header(fram, layout, false)?;
+        let found = self.read_header(fram)?;
+        let mismatch = if found.magic != MAGIC {
+            Mismatch::Blank
+        } else if found.crc != found.compute_crc() {
+            Mismatch::Corrupt
+        } else if found.format != FORMAT {
+            Mismatch::Format(found.format)
+        } else if found.version != layout.version {
+            Mismatch::Version {
+                found: found.version,
+                expected: layout.version,
+            }
+        } else if found.build_hash != layout.build_hash {
+            Mismatch::Build {
+                found: found.build_hash,
+                expected: layout.build_hash,
+            }
+        } else if found.count != expected.count || !same_places(&found, &expected) {
+            Mismatch::Sections
+        } else {
+            match self.changed_section(fram, &found, layout)? {
+                Some(index) => Mismatch::Contents { index },
+                None => return Ok(None),
+            }
+        };
+        Ok(Some(mismatch))
+    }
+
+    fn changed_section<D: FramDevice>(
+        &self,
+        fram: &D,
+        found: &Header,
+        layout: &Layout<'_>,
+    ) -> Result<Option<usize>, FramError> {
+        for (index, section) in layout.sections.iter().enumerate() {
+            if section.read_only && section_crc(fram, section.region)? != found.sections[index].crc
+            {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Writes a header describing the current contents as `layout`.
+    pub fn seal<D: FramDevice>(&self, fram: &mut D, layout: &Layout<'_>) -> Result<(), ImageError> {
+        let header = header(fram, layout, true)?;
+        let bytes = bytes_of(&header);
+        // invalidate first, then everything but the CRC, then the CRC
+        fram.write_u32(self.offset + HEADER_LEN - 4, !header.crc)?;
+        fram.flush();
+        fram.write(self.offset, &bytes[..HEADER_LEN - 4])?;
+        fram.flush();
+        fram.write_u32(self.offset + HEADER_LEN - 4, header.crc)?;
+        fram.flush();
+        Ok(())
+    }
+
+    /// Checks the image and applies `policy` to a mismatch. Call once at
+    /// boot, before anything else reads F-RAM statics.
+    pub fn boot<D: FramDevice, P: Policy<D>>(
+        &self,
+        fram: &mut D,
+        layout: &Layout<'_>,
+        policy: &mut P,
+    ) -> Result<Boot, ImageError> {
+        let mismatch = match self.check(fram, layout)? {
+            None => return Ok(Boot::Valid),
+            Some(mismatch) => mismatch,
+        };
+        let found = self.read_header(fram)?;
+        let readable = !matches!(mismatch, Mismatch::Blank | Mismatch::Corrupt);
+        let found = if readable { Some(&found) } else { None };
+        let boot = match policy.decide(mismatch, found) {
+            Action::Halt => return Err(ImageError::Halted(mismatch)),
+            Action::Reinitialize => {
+                policy.reinitialize(fram, layout)?;
+                Boot::Reinitialized(mismatch)
+            }
+            Action::Migrate => match found {
+                Some(found) => {
+                    policy.migrate(fram, mismatch, found, layout)?;
+                    Boot::Migrated(mismatch)
+                }
+                None => return Err(ImageError::Halted(mismatch)),
+            },
+            Action::Accept => Boot::Accepted(mismatch),
+        };
+        self.seal(fram, layout)?;
+        Ok(boot)
+    }
+}
+
+// The header for `layout`, with CRCs of the read-only sections if `crcs`.
+fn header<D: FramDevice>(fram: &D, layout: &Layout<'_>, crcs: bool) -> Result<Header, ImageError> {
+    if layout.sections.len() > MAX_SECTIONS {
+        return Err(ImageError::Layout);
+    }
+    let mut header = Header {
+        magic: MAGIC,
+        format: FORMAT,
+        count: layout.sections.len() as u32,
+        version: layout.version,
+        build_hash: layout.build_hash,
+        sections: [SectionEntry::default(); MAX_SECTIONS],
+        crc: 0,
+    };
+    for (entry, section) in header.sections.iter_mut().zip(layout.sections) {
+        let region = section.region;
+        if region.end() > u32::MAX as usize {
+            return Err(ImageError::Layout);
+        }
+        entry.offset = region.offset as u32;
+        entry.len = region.len as u32;
+        if crcs && section.read_only {
+            entry.crc = section_crc(fram, region)?;
+        }
+    }
+    header.crc = header.compute_crc();
+    Ok(header)
+}
+
+fn same_places(a: &Header, b: &Header) -> bool {
+    a.sections
+        .iter()
+        .zip(b.sections.iter())
+        .all(|(a, b)| a.offset == b.offset && a.len == b.len)
+}
+
+fn section_crc<D: FramDevice>(fram: &D, region: Region) -> Result<u32, FramError> {
+    let mut crc = Crc32::new();
+    let mut buf = [0u8; 64];
+    let mut at = region.offset;
+    while at < region.end() {
+        let n = buf.len().min(region.end() - at);
+        fram.read(at, &mut buf[..n])?;
+        crc.update(&buf[..n]);
+        at += n;
+    }
+    Ok(crc.finish())
+}
+
+/// Sets every byte of `region` to `value`.
+pub fn fill<D: FramDevice>(fram: &mut D, region: Region, value: u8) -> Result<(), FramError> {
+    let buf = [value; 64];
+    let mut at = region.offset;
+    while at < region.end() {
+        let n = buf.len().min(region.end() - at);
+        fram.write(at, &buf[..n])?;
+        at += n;
+    }
+    fram.flush();
+    Ok(())
+}
+
+#[cfg(target_arch = "arm")]
+mod global {
+    use core::{ptr, slice};
+
+    use super::{Boot, FramImage, ImageError, Init, Layout, Policy, Section, HEADER_LEN};
+    use crate::fram::{FramArea, MappedFram, Region};
+
+    extern "C" {
+        // bounds of the F-RAM output sections and their load images in
+        // flash, defined in memory.x
+        static _sfram_rodata: u8;
+        static _efram_rodata: u8;
+        static _sifram_rodata: u8;
+        static _sfram_data: u8;
+        static _efram_data: u8;
+        static _sifram_data: u8;
+        static _sfram_noinit: u8;
+        static _efram_noinit: u8;
+    }
+
+    // first in the `FRAM` region, see memory.x
+    #[link_section = ".fram_header"]
+    static mut HEADER: FramArea<HEADER_LEN> = FramArea::new();
+
+    fn region(fram: &MappedFram, start: *const u8, end: *const u8) -> Region {
+        let offset = fram.offset_of(start).unwrap();
+        Region::new(offset, end as usize - start as usize)
+    }
+
+    // the load image of a section that starts at `start`
+    unsafe fn load_image(load: *const u8, start: *const u8, end: *const u8) -> &'static [u8] {
+        slice::from_raw_parts(load, end as usize - start as usize)
+    }
+
+    /// The image sections in memory.x: `.fram_rodata` and `.fram_data`,
+    /// initialized from flash, and `.fram_noinit`.
+    pub fn sections(fram: &MappedFram) -> [Section<'static>; 3] {
+        unsafe {
+            let rodata = (ptr::addr_of!(_sfram_rodata), ptr::addr_of!(_efram_rodata));
+            let data = (ptr::addr_of!(_sfram_data), ptr::addr_of!(_efram_data));
+            let noinit = (ptr::addr_of!(_sfram_noinit), ptr::addr_of!(_efram_noinit));
+            [
+                Section {
+                    name: ".fram_rodata",
+                    region: region(fram, rodata.0, rodata.1),
+                    read_only: true,
+                    init: Init::Copy(load_image(
+                        ptr::addr_of!(_sifram_rodata),
+                        rodata.0,
+                        rodata.1,
+                    )),
+                },
+                Section {
+                    name: ".fram_data",
+                    region: region(fram, data.0, data.1),
+                    read_only: false,
+                    init: Init::Copy(load_image(ptr::addr_of!(_sifram_data), data.0, data.1)),
+                },
+                Section {
+                    name: ".fram_noinit",
+                    region: region(fram, noinit.0, noinit.1),
+                    read_only: false,
+                    init: Init::Keep,
+                },
+            ]
+        }
+    }
+
+    /// [`FramImage::boot`] for the linked image:
+    ///
+    /// ```ignore
+    /// image::boot(LAYOUT_VERSION, FRAM_BUILD_HASH, &mut image::Reinitialize)?;
+    /// ```
+    pub fn boot<P: Policy<MappedFram>>(
+        version: u32,
+        build_hash: u64,
+        policy: &mut P,
+    ) -> Result<Boot, ImageError> {
+        let mut fram = unsafe { MappedFram::new() };
+        let image = FramImage::new(fram.offset_of(ptr::addr_of!(HEADER)).unwrap());
+        let sections = sections(&fram);
+        let layout = Layout {
+            version,
+            build_hash,
+            sections: &sections,
+        };
+        image.boot(&mut fram, &layout, policy)
+    }
+}
+
+#[cfg(target_arch = "arm")]
+pub use self::global::{boot, sections};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fram::sim::{self, SimFram, Tear};
+
+    const IMAGE: FramImage = FramImage::new(0);
+    const SECTIONS: [Section<'static>; 2] = [
+        Section {
+            name: "rodata",
+            region: Region::new(128, 64),
+            read_only: true,
+            init: Init::Keep,
+        },
+        Section {
+            name: "data",
+            region: Region::new(192, 64),
+            read_only: false,
+            init: Init::Zero,
+        },
+    ];
+    const LAYOUT: Layout<'static> = Layout {
+        version: 3,
+        build_hash: 0x0123_4567_89ab_cdef,
+        sections: &SECTIONS,
+    };
+
+    fn sealed() -> SimFram {
+        let mut fram = SimFram::new(256);
+        fram.write(128, &[7; 64]).unwrap();
+        fram.write(192, &[9; 64]).unwrap();
+        IMAGE.seal(&mut fram, &LAYOUT).unwrap();
+        fram
+    }
+
+    #[test]
+    fn header_has_no_padding() {
+        assert_eq!(HEADER_LEN, 4 * 4 + 8 + 12 * MAX_SECTIONS + 4);
+    }
+
+    #[test]
+    fn detects_mismatches() {
+        let mut fram = SimFram::new(256);
+        assert_eq!(IMAGE.check(&fram, &LAYOUT), Ok(Some(Mismatch::Blank)));
+
+        fram = sealed();
+        assert_eq!(IMAGE.check(&fram, &LAYOUT), Ok(None));
+        // writable sections may change
+        fram.write(200, &[1, 2, 3]).unwrap();
+        assert_eq!(IMAGE.check(&fram, &LAYOUT), Ok(None));
+        fram.write(130, &[1]).unwrap();
+        assert_eq!(
+            IMAGE.check(&fram, &LAYOUT),
+            Ok(Some(Mismatch::Contents { index: 0 }))
+        );
+
+        let fram = sealed();
+        let newer = Layout {
+            version: 4,
+            ..LAYOUT
+        };
+        assert_eq!(
+            IMAGE.check(&fram, &newer),
+            Ok(Some(Mismatch::Version {
+                found: 3,
+                expected: 4
+            }))
+        );
+        let rebuilt = Layout {
+            build_hash: 1,
+            ..LAYOUT
+        };
+        assert!(matches!(
+            IMAGE.check(&fram, &rebuilt),
+            Ok(Some(Mismatch::Build {
+                found: 0x0123_4567_89ab_cdef,
+                expected: 1
+            }))
+        ));
+        let moved = [
+            SECTIONS[0],
+            Section {
+                region: Region::new(196, 60),
+                ..SECTIONS[1]
+            },
+        ];
+        let moved = Layout {
+            sections: &moved,
+            ..LAYOUT
+        };
+        assert_eq!(IMAGE.check(&fram, &moved), Ok(Some(Mismatch::Sections)));
+
+        let mut fram = sealed();
+        fram.write(20, &[0xff]).unwrap();
+        assert_eq!(IMAGE.check(&fram, &LAYOUT), Ok(Some(Mismatch::Corrupt)));
+    }
+
+    #[test]
+    fn copies_load_images() {
+        let weights = [5; 64];
+        let sections = [
+            Section {
+                init: Init::Copy(&weights),
+                ..SECTIONS[0]
+            },
+            SECTIONS[1],
+            Section {
+                name: "noinit",
+                region: Region::new(256, 64),
+                read_only: false,
+                init: Init::Keep,
+            },
+        ];
+        let layout = Layout {
+            sections: &sections,
+            ..LAYOUT
+        };
+        let mut fram = SimFram::new(320);
+        fram.write(128, &[0xff; 192]).unwrap();
+        assert_eq!(
+            IMAGE.boot(&mut fram, &layout, &mut Reinitialize),
+            Ok(Boot::Reinitialized(Mismatch::Blank))
+        );
+        assert_eq!(&fram.image()[128..192], &weights[..]);
+        assert_eq!(&fram.image()[192..256], &[0; 64][..]);
+        assert_eq!(&fram.image()[256..320], &[0xff; 64][..]);
+
+        // damaged weights come back from the load image
+        fram.write(160, &[0]).unwrap();
+        fram.write(300, &[0]).unwrap();
+        assert_eq!(
+            IMAGE.boot(&mut fram, &layout, &mut Reinitialize),
+            Ok(Boot::Reinitialized(Mismatch::Contents { index: 0 }))
+        );
+        assert_eq!(&fram.image()[128..192], &weights[..]);
+        assert_eq!(fram.image()[300], 0);
+    }
+
+    #[test]
+    fn policies() {
+        let mut fram = SimFram::new(256);
+        fram.write(192, &[9; 64]).unwrap();
+        assert_eq!(
+            IMAGE.boot(&mut fram, &LAYOUT, &mut Halt),
+            Err(ImageError::Halted(Mismatch::Blank))
+        );
+        assert_eq!(
+            IMAGE.boot(&mut fram, &LAYOUT, &mut Reinitialize),
+            Ok(Boot::Reinitialized(Mismatch::Blank))
+        );
+        assert_eq!(&fram.image()[192..256], &[0; 64][..]);
+        assert_eq!(IMAGE.boot(&mut fram, &LAYOUT, &mut Halt), Ok(Boot::Valid));
+
+        // the default migration halts with the mismatch
+        struct Unsupported;
+        impl Policy<SimFram> for Unsupported {
+            fn decide(&mut self, _mismatch: Mismatch, _found: Option<&Header>) -> Action {
+                Action::Migrate
+            }
+        }
+        let newer = Layout {
+            version: 4,
+            ..LAYOUT
+        };
+        assert_eq!(
+            IMAGE.boot(&mut sealed(), &newer, &mut Unsupported),
+            Err(ImageError::Halted(Mismatch::Version {
+                found: 3,
+                expected: 4
+            }))
+        );
+
+        let mut fram = sealed();
+        assert_eq!(
+            IMAGE.boot(&mut fram, &MOVED, &mut Move),
+            Ok(Boot::Migrated(Mismatch::Sections))
+        );
+        assert_eq!(IMAGE.check(&fram, &MOVED), Ok(None));
+        assert_eq!(&fram.image()[224..256], &[9; 32][..]);
+        assert_eq!(
+            IMAGE.boot(&mut fram, &MOVED, &mut Reinitialize),
+            Ok(Boot::Valid)
+        );
+    }
+
+    // the data section moved to the upper half of where it was
+    const MOVED_SECTIONS: [Section<'static>; 2] = [
+        SECTIONS[0],
+        Section {
+            region: Region::new(224, 32),
+            ..SECTIONS[1]
+        },
+    ];
+    const MOVED: Layout<'static> = Layout {
+        sections: &MOVED_SECTIONS,
+        ..LAYOUT
+    };
+
+    // Copies the data section into its new place, which does not overlap
+    // the old one, so running it again after a power failure is harmless.
+    struct Move;
+
+    impl Policy<SimFram> for Move {
+        fn decide(&mut self, mismatch: Mismatch, found: Option<&Header>) -> Action {
+            match (mismatch, found) {
+                (Mismatch::Sections, Some(h)) if h.sections[1].offset == 192 => Action::Migrate,
+                _ => Action::Halt,
+            }
+        }
+
+        fn migrate(
+            &mut self,
+            fram: &mut SimFram,
+            _mismatch: Mismatch,
+            _from: &Header,
+            _layout: &Layout<'_>,
+        ) -> Result<(), ImageError> {
+            let mut data = [0; 32];
+            fram.read(192, &mut data)?;
+            fram.write(224, &data)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interrupted_migration_is_redone() {
+        let mut image = sealed();
+        // different bytes everywhere, so a misplaced copy shows
+        for (i, b) in (192..256).enumerate() {
+            image.write(b, &[i as u8]).unwrap();
+        }
+        let from = IMAGE.read_header(&image).unwrap();
+        // the migration runs again until the header is rewritten
+        sim::for_each_failure(
+            image.image(),
+            Tear::LowByte,
+            |fram| {
+                Move.migrate(fram, Mismatch::Sections, &from, &MOVED)
+                    .map_err(|e| match e {
+                        ImageError::Fram(e) => e,
+                        e => panic!("{:?}", e),
+                    })
+            },
+            |fram, _| {
+                let expected: Vec<u8> = (0..32).collect();
+                assert_eq!(&fram.image()[224..256], &expected[..]);
+            },
+        );
+    }
+
+    #[test]
+    fn interrupted_reinitialization_is_redone() {
+        let newer = Layout {
+            version: 4,
+            ..LAYOUT
+        };
+        sim::for_each_failure(
+            sealed().image(),
+            Tear::LowByte,
+            |fram| {
+                IMAGE
+                    .boot(fram, &newer, &mut Reinitialize)
+                    .map(|_| ())
+                    .map_err(|e| match e {
+                        ImageError::Fram(e) => e,
+                        e => panic!("{:?}", e),
+                    })
+            },
+            |fram, _| {
+                // the header is only valid once the sections are cleared
+                assert_eq!(IMAGE.check(fram, &newer), Ok(None));
+                assert_eq!(&fram.image()[192..256], &[0; 64][..]);
+                assert_eq!(&fram.image()[128..192], &[7; 64][..]);
+            },
+        );
+    }
+}
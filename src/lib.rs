@@ -0,0 +1,24 @@
+//! Board support shared by the parallel FRAM firmware.
+//!
+//! Everything that touches the STM32F303 peripherals is only compiled for
+//! `target_arch = "arm"`; the rest builds on the host as well.
+
+#![cfg_attr(target_arch = "arm", no_std)]
+#![allow(unsafe_code)]
+
+pub mod checkpoint;
+pub mod clocks;
+pub mod crc;
+pub mod dma;
+pub mod fixed;
+pub mod fmc;
+pub mod fram;
+pub mod image;
+pub mod nn;
+pub mod plan;
+#[cfg(feature = "profile")]
+pub mod profile;
+pub mod task;
+pub mod tensor;
+#[cfg(feature = "trace")]
+pub mod trace;
@@ -68,153 +68,261 @@ use panic_halt as _;
 use cortex_m_rt::entry;
 use stm32f3xx_hal_v2::delay;
 use::core::arch::asm;
-use cortex_m_semihosting::{debug, hprintln};
+use cortex_m_semihosting::{debug, hio, hprintln};
 use stm32f3xx_hal_v2::{self as hal, 
                         pac,
                         prelude::*,
                         flash::ACR, 
                         pac::Peripherals,
                         pac::FLASH};
-
-// #[link_section = ".fram_section"]
+use parallel_fram::checkpoint::{self, CheckpointStore, Resumed};
+use parallel_fram::clocks::{ClockConfig, Clocks};
+use parallel_fram::dma::{Channel, Dma, DmaError};
+use parallel_fram::fmc::{AccessMode, BusWidth, MemoryType, NorSramBuilder, SubBank};
+use parallel_fram::fmc::timing::DeviceTimings;
+use parallel_fram::fmc::pins::{self, FmcPin, FmcPorts, Port, Signal};
+use parallel_fram::image::{self, Action, Boot, Header, Layout, Mismatch, Policy};
+use parallel_fram::nn;
+#[cfg(feature = "profile")]
+use parallel_fram::profile;
+use parallel_fram::nn::resume::{Resumable, PROGRESS_LEN};
+use parallel_fram::plan::{self, Arenas, Layer, Memory, Op, Plan};
+use parallel_fram::tensor::{Numeric, Tensor1D};
+use parallel_fram::fram::{heap, memtest, read_value, undo, FramArea, FramError, MappedFram};
+use parallel_fram::fram::kv;
+use parallel_fram::fram::log::FramLog;
+use parallel_fram::task::{self, Next, Task, TaskCtx, TaskError, Var};
+
+// #[link_section = ".fram_data"]
 // static mut DATA_ARRAY: [u32; 5] = [0x341234, 0x3FF4, 0xCDAB, 0x12CD, 0x45EF];
 
 
-#[cfg(target_arch = "arm")]
-type Numeric = i32;
-#[cfg(not(target_arch = "arm"))]
-type Numeric = i16;
+// PARAM_1, PARAM_2 and their layers, generated by build.rs from model/
+mod model {
+    include!(concat!(env!("OUT_DIR"), "/model.rs"));
+}
+use model::{PARAM_1_LAYER, PARAM_2, PARAM_2_LAYER};
 
-#[derive(Debug)]
-pub struct Tensor2D<const H: usize, const W: usize> {
-    tensor: [[Numeric; W]; H],
+// FRAM_BUILD_HASH, generated by build.rs from memory.x, the model and the
+// sources
+mod layout {
+    include!(concat!(env!("OUT_DIR"), "/layout.rs"));
 }
 
-impl<const H: usize, const W: usize> Tensor2D<H, W> {
-    pub const fn new(tensor: [[Numeric; W]; H]) -> Self {
-        Self { tensor }
-    }
+// Bump when F-RAM statics change incompatibly. The build hash changes with
+// any edit to the sources; this tells a migration which layout it reads.
+const LAYOUT_VERSION: u32 = 1;
+
+// bumped once per boot, failure-atomically
+#[link_section=".fram_data"]
+static mut BOOT_COUNT: u32 = 0;
+
+// two checkpoint slots of 4K each
+#[link_section=".fram_data"]
+static mut CHECKPOINTS: FramArea<8196> = FramArea::new();
+
+// activations of the last inference, kept across power failures
+#[link_section=".fram_data"]
+static mut HIDDEN: Tensor1D<10> = Tensor1D::zeros();
+#[link_section=".fram_data"]
+static mut LOGITS: Tensor1D<2> = Tensor1D::zeros();
+#[link_section=".fram_data"]
+static mut LAYER_PROGRESS: FramArea<PROGRESS_LEN> = FramArea::new();
+
+const INPUT: Tensor1D<50> = Tensor1D::new([1; 50]);
+
+// one record per inference, read out with tools/fram-drain:
+// `fram-drain fram.bin --fields boot:u32,class:u32,logit0:i32,logit1:i32`
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Inference {
+    boot: u32,
+    class: u32,
+    logits: [Numeric; 2],
+}
 
-    #[inline(always)]
-    pub fn at(&self, rol: usize, col: usize) -> &Numeric {
-        hprintln!("{:p}", &self.tensor[rol][col]);
-        &self.tensor[rol][col]
-    }
+// kept across firmware updates
+#[link_section=".fram_noinit"]
+static mut INFERENCE_LOG: FramArea<1024> = FramArea::new();
+
+const RUNS: kv::Key = kv::key("runs");
+
+// PARAM_1 and PARAM_2 as a 50-10-2 classifier. The 500 MACs of the first
+// layer resume where they were after a power failure.
+fn classify(fram: &mut MappedFram, input: &Tensor1D<50>, logits: &mut Tensor1D<2>) -> usize {
+    let progress = fram.region_of(unsafe { ptr::addr_of!(LAYER_PROGRESS) }).unwrap();
+    let hidden = fram.offset_of(unsafe { ptr::addr_of!(HIDDEN) }).unwrap();
+    let layer = Resumable::new(progress, 10);
+    layer.matvec(fram, &PARAM_1_LAYER, input, hidden).unwrap();
+
+    let hidden = unsafe { &mut *ptr::addr_of_mut!(HIDDEN) };
+    nn::relu(hidden);
+    PARAM_2_LAYER.forward(hidden, logits);
+    // the next inference starts over
+    layer.reset(fram).unwrap();
+    nn::argmax(logits)
+}
 
-    #[inline(always)]
-    pub fn mut_at(&mut self, rol: usize, col: usize) -> &mut Numeric {
-        hprintln!("{:p}", &self.tensor[rol][col]);
-        &mut self.tensor[rol][col]
+// The same classifier as a static plan, as if PARAM_1 did not fit in SRAM:
+// 1280 bytes of scratch take it in tiles of two rows, copied by DMA while the
+// rows before are used. `fram-plan model/model.txt --sram 1280 --fram 0`
+// prints the plan.
+const PLAN_LAYERS: [Layer; 2] = [
+    Layer::new("param_1", Op::Dense { inputs: 50, outputs: 10 }, false),
+    Layer::new("param_2", Op::Dense { inputs: 10, outputs: 2 }, false),
+];
+const PLAN_SRAM: usize = 1280;
+static PLAN: Plan<2> = match plan::plan(&PLAN_LAYERS, Memory { sram: PLAN_SRAM, fram: 0 }) {
+    Ok(plan) => plan,
+    Err(e) => e.panic(),
+};
+static mut PLAN_SCRATCH: [Numeric; PLAN_SRAM / plan::ELEM] = [0; PLAN_SRAM / plan::ELEM];
+
+fn classify_planned(dma: &mut Dma, input: &Tensor1D<50>, logits: &mut Tensor1D<2>) -> usize {
+    let arenas = Arenas {
+        sram: unsafe { &mut *ptr::addr_of_mut!(PLAN_SCRATCH) },
+        fram: &mut [],
+    };
+    let weights = [PARAM_1_LAYER.weights.as_slice(), PARAM_2_LAYER.weights.as_slice()];
+    PLAN.run(dma, weights, arenas, input.as_array(), logits.as_mut_array(), |step, ops| {
+        let rows = ops.units.clone();
+        if step == 0 {
+            PARAM_1_LAYER.forward_rows(rows.start, ops.weights, ops.input, ops.output);
+            // relu
+            for v in &mut ops.output[rows] {
+                *v = (*v).max(0);
+            }
+        } else {
+            PARAM_2_LAYER.forward_rows(rows.start, ops.weights, ops.input, ops.output);
+        }
+        Ok::<_, DmaError>(())
+    })
+    .unwrap();
+    nn::argmax(logits)
+}
+
+// what `classify` touches, with the `profile` feature
+#[cfg(feature = "profile")]
+mod profiled {
+    use parallel_fram::profile::{self, Location, Object};
+
+    static PARAM_1: Object = Object::new("PARAM_1", Location::Fram);
+    static PARAM_2: Object = Object::new("PARAM_2", Location::Fram);
+    static HIDDEN: Object = Object::new("HIDDEN", Location::Fram);
+    static LOGITS: Object = Object::new("LOGITS", Location::Fram);
+    static INPUT: Object = Object::new("INPUT", Location::Sram);
+
+    pub fn register(input: &super::Tensor1D<50>) {
+        profile::register(&PARAM_1, &super::model::PARAM_1);
+        profile::register(&PARAM_2, &super::model::PARAM_2);
+        unsafe {
+            profile::register(&HIDDEN, &*core::ptr::addr_of!(super::HIDDEN));
+            profile::register(&LOGITS, &*core::ptr::addr_of!(super::LOGITS));
+        }
+        profile::register(&INPUT, input);
     }
 }
 
-pub struct Tensor1D<const W: usize> {
-    tensor: [Numeric; W],
+// task graph state: the row being summed and the sums so far
+#[link_section=".fram_data"]
+static mut ROW: u16 = 0;
+#[link_section=".fram_data"]
+static mut ROW_SUMS: [Numeric; 2] = [0; 2];
+
+static TASKS: [Task<MappedFram>; 1] = [sum_row];
+
+// Adds up one row of PARAM_2 per task, so a power failure loses at most a row.
+fn sum_row(ctx: &mut TaskCtx<MappedFram>) -> Result<Next, TaskError> {
+    let row_var = Var::of(ctx.fram(), unsafe { ptr::addr_of!(ROW) })?;
+    let sums_var = Var::of(ctx.fram(), unsafe { ptr::addr_of!(ROW_SUMS) })?;
+    let row = ctx.get(row_var)? as usize;
+    if row == 2 {
+        return Ok(Next::Done);
+    }
+
+    let first = ctx.fram().offset_of(PARAM_2.at(row, 0)).ok_or(TaskError::NotInFram)?;
+    let mut sum: Numeric = 0;
+    for col in 0..10 {
+        let x: Numeric = read_value(ctx.fram(), first + col * mem::size_of::<Numeric>())?;
+        sum = sum.wrapping_add(x);
+    }
+    let mut sums = ctx.get(sums_var)?;
+    sums[row] = sum;
+    ctx.set(sums_var, sums)?;
+    ctx.set(row_var, row as u16 + 1)?;
+    Ok(Next::Task(0))
 }
 
-#[link_section=".fram_section"]
-static mut PARAM_1: Tensor2D<10, 50> = Tensor2D::new([
-    [
-        7, 0, 2, 5, 4, 4, 5, 7, 9, 2, 9, 4, 9, 3, 0, 8, 4, 0, 2, 9, 3, 8, 1, 6, 6, 6, 5, 3, 3, 2,
-        4, 0, 6, 9, 3, 7, 6, 3, 4, 9, 2, 5, 0, 5, 7, 3, 5, 8, 7, 5,
-    ],
-    [
-        8, 0, 6, 0, 3, 6, 0, 6, 0, 0, 6, 3, 3, 0, 0, 0, 5, 4, 5, 9, 8, 4, 5, 8, 8, 5, 5, 9, 1, 7,
-        0, 3, 8, 8, 5, 9, 5, 5, 2, 4, 2, 7, 1, 7, 2, 5, 0, 7, 6, 8,
-    ],
-    [
-        2, 0, 6, 9, 4, 9, 8, 7, 0, 6, 4, 8, 1, 5, 5, 3, 6, 8, 4, 8, 8, 4, 7, 8, 4, 2, 4, 8, 0, 7,
-        0, 7, 5, 3, 9, 7, 1, 6, 2, 1, 5, 8, 5, 9, 1, 8, 7, 5, 8, 9,
-    ],
-    [
-        9, 1, 9, 7, 4, 1, 8, 3, 2, 5, 3, 9, 2, 8, 3, 1, 8, 8, 1, 4, 1, 3, 2, 4, 0, 5, 9, 5, 3, 9,
-        2, 9, 1, 9, 5, 0, 2, 7, 0, 7, 3, 9, 1, 4, 6, 0, 2, 4, 6, 7,
-    ],
-    [
-        4, 9, 0, 4, 7, 8, 3, 4, 4, 2, 2, 0, 5, 7, 0, 2, 7, 2, 3, 5, 0, 3, 2, 0, 3, 0, 4, 8, 1, 9,
-        8, 2, 4, 5, 3, 1, 8, 0, 7, 1, 8, 1, 9, 1, 6, 8, 9, 3, 8, 5,
-    ],
-    [
-        4, 4, 0, 3, 5, 7, 1, 9, 2, 2, 6, 6, 5, 0, 6, 5, 0, 3, 0, 9, 2, 6, 0, 0, 6, 6, 2, 5, 4, 8,
-        7, 9, 4, 5, 6, 4, 8, 9, 3, 6, 3, 4, 3, 4, 4, 4, 6, 8, 6, 1,
-    ],
-    [
-        5, 7, 8, 4, 6, 2, 0, 7, 9, 1, 3, 6, 0, 6, 8, 3, 4, 8, 9, 1, 9, 0, 3, 4, 6, 6, 7, 4, 5, 1,
-        6, 0, 9, 9, 8, 6, 5, 5, 4, 8, 6, 4, 5, 9, 6, 7, 9, 8, 7, 8,
-    ],
-    [
-        5, 0, 8, 2, 6, 3, 0, 1, 9, 9, 4, 9, 6, 0, 6, 6, 5, 8, 3, 4, 5, 5, 7, 9, 0, 8, 2, 8, 9, 4,
-        0, 1, 7, 6, 7, 8, 8, 7, 7, 9, 1, 4, 9, 7, 2, 9, 0, 7, 8, 7,
-    ],
-    [
-        3, 0, 0, 1, 0, 4, 7, 2, 9, 5, 6, 8, 6, 4, 3, 6, 2, 1, 5, 4, 5, 1, 4, 8, 6, 3, 5, 8, 0, 8,
-        0, 3, 0, 1, 9, 0, 9, 8, 0, 9, 0, 5, 2, 8, 1, 6, 1, 9, 5, 9,
-    ],
-    [
-        3, 7, 8, 5, 9, 8, 7, 4, 6, 9, 9, 1, 4, 1, 6, 2, 3, 4, 8, 9, 8, 0, 5, 6, 5, 3, 8, 2, 1, 4,
-        3, 1, 6, 9, 5, 9, 1, 1, 9, 3, 0, 9, 6, 3, 3, 0, 8, 5, 6, 6,
-    ],
-]);
-
-#[link_section=".fram_section"]
-static PARAM_2: Tensor2D<2, 10> = Tensor2D::new([
-    [ 0xDDDDDu32 as i32, 0xFFDCFF, 0xCBCD, 0x4567, 0xAADDDDD, 4, 9, 0, 1, 4],
-    [2, 9, 2, 3, 2, 2, 8, 0, 8, 4],
-]);
-
-fn initialization(){
+const CLOCK_CONFIG: ClockConfig = ClockConfig::hsi().sysclk(16_000_000).pclk1(8_000_000);
+
+// Cypress FM22L16-55-TG datasheet, read and write cycle AC parameters:
+//   tAS         0 ns   address setup to CE low      -> address_setup_ns
+//   tAH        20 ns   address hold after CE high   -> address_hold_ns
+//   tCE        55 ns   chip enable access time      -> data_setup_ns
+//   tRC, tWC  110 ns   read / write cycle time      -> cycle_time_ns
+//   tOHZ, tHZ 15 ns   output disable to high-Z     -> bus_turnaround_ns
+// The FMC drops NE1 for every access, so reads wait for tCE; tAA and tOE
+// are shorter and start no earlier. tPC (page mode cycle) does not apply,
+// as mode A never changes the address with NE1 held low.
+const FRAM_TIMINGS: DeviceTimings = DeviceTimings {
+    address_setup_ns: 0,
+    address_hold_ns: 20,
+    data_setup_ns: 55,
+    cycle_time_ns: 110,
+    bus_turnaround_ns: 15,
+};
+
+// FMC wiring of the F-RAM, see the table at the top of this file
+const FRAM_PINS: &[FmcPin] = &[
+    FmcPin::new(Port::H, 0, Signal::A(0)),
+    FmcPin::new(Port::H, 1, Signal::A(1)),
+    FmcPin::new(Port::F, 2, Signal::A(2)),
+    FmcPin::new(Port::F, 3, Signal::A(3)),
+    FmcPin::new(Port::F, 4, Signal::A(4)),
+    FmcPin::new(Port::F, 5, Signal::A(5)),
+    FmcPin::new(Port::F, 12, Signal::A(6)),
+    FmcPin::new(Port::F, 13, Signal::A(7)),
+    FmcPin::new(Port::F, 14, Signal::A(8)),
+    FmcPin::new(Port::F, 15, Signal::A(9)),
+    FmcPin::new(Port::G, 0, Signal::A(10)),
+    FmcPin::new(Port::G, 1, Signal::A(11)),
+    FmcPin::new(Port::G, 2, Signal::A(12)),
+    FmcPin::new(Port::G, 3, Signal::A(13)),
+    FmcPin::new(Port::G, 4, Signal::A(14)),
+    FmcPin::new(Port::G, 5, Signal::A(15)),
+    FmcPin::new(Port::D, 14, Signal::D(0)),
+    FmcPin::new(Port::D, 15, Signal::D(1)),
+    FmcPin::new(Port::D, 0, Signal::D(2)),
+    FmcPin::new(Port::D, 1, Signal::D(3)),
+    FmcPin::new(Port::E, 7, Signal::D(4)),
+    FmcPin::new(Port::E, 8, Signal::D(5)),
+    FmcPin::new(Port::E, 9, Signal::D(6)),
+    FmcPin::new(Port::E, 10, Signal::D(7)),
+    FmcPin::new(Port::E, 11, Signal::D(8)),
+    FmcPin::new(Port::E, 12, Signal::D(9)),
+    FmcPin::new(Port::E, 13, Signal::D(10)),
+    FmcPin::new(Port::E, 14, Signal::D(11)),
+    FmcPin::new(Port::E, 15, Signal::D(12)),
+    FmcPin::new(Port::D, 8, Signal::D(13)),
+    FmcPin::new(Port::D, 9, Signal::D(14)),
+    FmcPin::new(Port::D, 10, Signal::D(15)),
+    FmcPin::new(Port::D, 4, Signal::Noe),
+    FmcPin::new(Port::D, 5, Signal::Nwe),
+    FmcPin::new(Port::D, 7, Signal::Ne(1)),
+];
+
+// 16 address lines, 16-bit data bus, chip select NE1
+const _: () = match pins::check(FRAM_PINS, 16, 16, 1) {
+    Ok(()) => (),
+    Err(e) => e.panic(),
+};
+
+fn initialization() -> (Clocks, Dma) {
     let dp  = Peripherals::take().unwrap();
     
-     //enable HSI
-    dp.RCC.cr.write(|w| w.hsion().set_bit());
-    while dp.RCC.cr.read().hsirdy().bit_is_clear() {}
- 
-     //configure PLL
-     // Step 1: Disable the PLL by setting PLLON to 0
-     dp.RCC.cr.modify(|_r, w| w.pllon().clear_bit());
- 
-     // Step 2: Wait until PLLRDY is cleared
-     while dp.RCC.cr.read().pllrdy().bit_is_set() {}
- 
-     // Step 3: Change the desired parameter
-     // For example, modify PLL multiplier (PLLMUL)
- 
-     dp.RCC.cfgr.modify(|_, w| w.pllsrc().hsi_div_prediv());
- 
-     // Set PLL Prediv to /1
-     dp.RCC.cfgr2.modify(|_, w| w.prediv().div1());
- 
-     // Set PLL MUL to x9
-     dp.RCC.cfgr.modify(|_, w| w.pllmul().mul2());  //changed from x9 to x2
- 
-     // Step 4: Enable the PLL again by setting PLLON to 1
-    // dp.RCC.cr.modify(|_r, w| w.pllon().set_bit());
- 
-     dp.RCC.cr.modify(|_, w| w.pllon().on());
- 
-     while dp.RCC.cr.read().pllrdy().bit_is_clear(){}
- 
-        // Configure prescalar values for HCLK, PCLK1, and PCLK2
-    dp.RCC.cfgr.modify(|_, w| {
-         w.hpre().div1() // HCLK prescaler: no division
-         .ppre1().div2() // PCLK1 prescaler: divide by 2
-         .ppre2().div1() // PCLK2 prescaler: no division
-     });
- 
- 
-     // Enable FLASH Prefetch Buffer and set Flash Latency (required for high speed)
-     // was crashing just because this was missing
-     dp.FLASH.acr
-         .modify(|_, w| w.prftbe().enabled().latency().ws1());
- 
-      // Select PLL as system clock source
-      dp.RCC.cfgr.modify(|_, w| w.sw().pll());
- 
-      while dp.RCC.cfgr.read().sw().bits() != 0b10 {}
- 
-       // Wait for system clock to stabilize
-       while dp.RCC.cfgr.read().sws().bits() != 0b10 {}
- 
+    // SYSCLK 16 MHz from HSI through the PLL, APB1 / 2
+    let clocks = CLOCK_CONFIG.solve().unwrap().apply(&dp.RCC, &dp.FLASH);
+
     //   dp.RCC.ahbenr.modify(|_, w| w.iopden().set_bit());
     //   dp.RCC.ahbenr.modify(|_, w| w.iopeen().set_bit());
     //   dp.RCC.ahbenr.modify(|_, w| w.iopfen().set_bit());
@@ -237,245 +345,94 @@ fn initialization(){
     dp.RCC.apb2enr.modify(|_, w| w.syscfgen().set_bit());
     dp.RCC.apb1enr.modify(|_, w| w.pwren().set_bit());
 
-  let mut gpiod = dp.GPIOD;
-  let mut gpioe = dp.GPIOE;
-  let mut gpiof = dp.GPIOF;
-  let mut gpiog = dp.GPIOG;
-  let mut gpioh = dp.GPIOH;
-
-  //    PH0   ------> FMC_A0
-    gpioh.moder.modify(|_, w| {w.moder0().alternate()});
-    gpioh.afrl.modify(|_, w| {  w.afrl0().af12()});
-    gpioh.ospeedr.modify(|_, w| w.ospeedr0().very_high_speed());
-
-// PH1   ------> FMC_A1
-    gpioh.moder.modify(|_, w| {w.moder1().alternate()});
-    gpioh.afrl.modify(|_, w| {  w.afrl1().af12()});
-    gpioh.ospeedr.modify(|_, w| w.ospeedr1().very_high_speed());
-
-//  PF2   ------> FMC_A2
-    gpiof.moder.modify(|_, w| {w.moder2().alternate()});
-    gpiof.afrl.modify(|_, w| {  w.afrl2().af12()});
-    gpiof.ospeedr.modify(|_, w| w.ospeedr2().very_high_speed());
-
-//   PF3   ------> FMC_A3
-    gpiof.moder.modify(|_, w| {w.moder3().alternate()});
-    gpiof.afrl.modify(|_, w| {  w.afrl3().af12()});
-    gpiof.ospeedr.modify(|_, w| w.ospeedr3().very_high_speed());
-
-    //   PF4   ------> FMC_A4
-    gpiof.moder.modify(|_, w| {w.moder4().alternate()});
-    gpiof.afrl.modify(|_, w| {  w.afrl4().af12()});
-    gpiof.ospeedr.modify(|_, w| w.ospeedr4().very_high_speed());
-
-// PF5   ------> FMC_A5
-    gpiof.moder.modify(|_, w| {w.moder5().alternate()});
-    gpiof.afrl.modify(|_, w| {  w.afrl5().af12()});
-    gpiof.ospeedr.modify(|_, w| w.ospeedr5().very_high_speed());
-
-
-    //    PF12   ------> FMC_A6
-    gpiof.moder.modify(|_, w| {w.moder12().alternate()});
-    gpiof.afrh.modify(|_, w| {  w.afrh12().af12()});
-    gpiof.ospeedr.modify(|_, w| w.ospeedr12().very_high_speed());
-
-//   PF13   ------> FMC_A7
-    gpiof.moder.modify(|_, w| {w.moder13().alternate()});
-    gpiof.afrh.modify(|_, w| {  w.afrh13().af12()});
-    gpiof.ospeedr.modify(|_, w| w.ospeedr13().very_high_speed());
-
-//   PF14   ------> FMC_A8
-    gpiof.moder.modify(|_, w| {w.moder14().alternate()});
-    gpiof.afrh.modify(|_, w| {  w.afrh14().af12()});
-    gpiof.ospeedr.modify(|_, w| w.ospeedr14().very_high_speed());
-
-    //PF15   ------> FMC_A9
-    gpiof.moder.modify(|_, w| {w.moder15().alternate()});
-    gpiof.afrh.modify(|_, w| {  w.afrh15().af12()});
-    gpiof.ospeedr.modify(|_, w| w.ospeedr15().very_high_speed());
-
-    // PG0   ------> FMC_A10
-    gpiog.moder.modify(|_, w| {w.moder0().alternate()});
-    gpiog.afrl.modify(|_, w| {  w.afrl0().af12()});
-    gpiog.ospeedr.modify(|_, w| w.ospeedr0().very_high_speed());
-
-    //  PG1   ------> FMC_A11
-    gpiog.moder.modify(|_, w| {w.moder1().alternate()});
-    gpiog.afrl.modify(|_, w| {  w.afrl1().af12()});
-    gpiog.ospeedr.modify(|_, w| w.ospeedr1().very_high_speed());
-
-    //  PG2   ------> FMC_A12
-    gpiog.moder.modify(|_, w| {w.moder2().alternate()});
-    gpiog.afrl.modify(|_, w| {  w.afrl2().af12()});
-    gpiog.ospeedr.modify(|_, w| w.ospeedr2().very_high_speed());
-
-    //    PG3   ------> FMC_A13
-    gpiog.moder.modify(|_, w| {w.moder3().alternate()});
-    gpiog.afrl.modify(|_, w| {  w.afrl3().af12()});
-    gpiog.ospeedr.modify(|_, w| w.ospeedr3().very_high_speed());
-
-    //   PG4   ------> FMC_A14
-    gpiog.moder.modify(|_, w| {w.moder4().alternate()});
-    gpiog.afrl.modify(|_, w| {  w.afrl4().af12()});
-    gpiog.ospeedr.modify(|_, w| w.ospeedr4().very_high_speed());
-
-    
-     //PG5   ------> FMC_A15
-     gpiog.moder.modify(|_, w| {w.moder5().alternate()});
-     gpiog.afrl.modify(|_, w| {  w.afrl5().af12()});
-     gpiog.ospeedr.modify(|_, w| w.ospeedr5().very_high_speed());
-
-
-     //  PD14   ------> FMC_D0
-    gpiod.moder.modify(|_, w| {w.moder14().alternate()});
-    gpiod.afrh.modify(|_, w| {  w.afrh14().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr14().very_high_speed());
-
-    //  PD15   ------> FMC_D1
-    gpiod.moder.modify(|_, w| {w.moder15().alternate()});
-    gpiod.afrh.modify(|_, w| {  w.afrh15().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr15().very_high_speed());
-
-    // PD0   ------> FMC_D2
-    gpiod.moder.modify(|_, w| {w.moder0().alternate()});
-    gpiod.afrl.modify(|_, w| {  w.afrl0().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr0().very_high_speed());
-
-    // PD1   ------> FMC_D3
-    gpiod.moder.modify(|_, w| {w.moder1().alternate()});
-    gpiod.afrl.modify(|_, w| {  w.afrl1().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr1().very_high_speed());
-
-    //PE7   ------> FMC_D4
-    gpioe.moder.modify(|_, w| {w.moder7().alternate()});
-    gpioe.afrl.modify(|_, w| {  w.afrl7().af12()});
-    gpioe.ospeedr.modify(|_, w| w.ospeedr7().very_high_speed());
-
-    //PE8   ------> FMC_D5
-    gpioe.moder.modify(|_, w| {w.moder8().alternate()});
-    gpioe.afrh.modify(|_, w| {  w.afrh8().af12()});
-    gpioe.ospeedr.modify(|_, w| w.ospeedr8().very_high_speed());
-
-    // PE9   ------> FMC_D6
-    gpioe.moder.modify(|_, w| {w.moder9().alternate()});
-    gpioe.afrh.modify(|_, w| {  w.afrh9().af12()});
-    gpioe.ospeedr.modify(|_, w| w.ospeedr9().very_high_speed());
-
-    //PE10   ------> FMC_D7
-    gpioe.moder.modify(|_, w| {w.moder10().alternate()});
-    gpioe.afrh.modify(|_, w| {  w.afrh10().af12()});
-    gpioe.ospeedr.modify(|_, w| w.ospeedr10().very_high_speed());
-
-    //PE11   ------> FMC_D8
-    gpioe.moder.modify(|_, w| {w.moder11().alternate()});
-    gpioe.afrh.modify(|_, w| {  w.afrh11().af12()});
-    gpioe.ospeedr.modify(|_, w| w.ospeedr11().very_high_speed());
-
-
-    //PE12   ------> FMC_D9
-    gpioe.moder.modify(|_, w| {w.moder12().alternate()});
-    gpioe.afrh.modify(|_, w| {  w.afrh12().af12()});
-    gpioe.ospeedr.modify(|_, w| w.ospeedr12().very_high_speed());
+    let ports = FmcPorts {
+        gpiod: dp.GPIOD,
+        gpioe: dp.GPIOE,
+        gpiof: dp.GPIOF,
+        gpiog: dp.GPIOG,
+        gpioh: dp.GPIOH,
+    };
+    pins::apply(FRAM_PINS, &ports);
 
-    //PE13   ------> FMC_D10
-    gpioe.moder.modify(|_, w| {w.moder13().alternate()});
-    gpioe.afrh.modify(|_, w| {  w.afrh13().af12()});
-    gpioe.ospeedr.modify(|_, w| w.ospeedr13().very_high_speed());
-
-    //PE14   ------> FMC_D11
-    gpioe.moder.modify(|_, w| {w.moder14().alternate()});
-    gpioe.afrh.modify(|_, w| {  w.afrh14().af12()});
-    gpioe.ospeedr.modify(|_, w| w.ospeedr14().very_high_speed());
-
-    //PE15   ------> FMC_D12
-    gpioe.moder.modify(|_, w| {w.moder15().alternate()});
-    gpioe.afrh.modify(|_, w| {  w.afrh15().af12()});
-    gpioe.ospeedr.modify(|_, w| w.ospeedr15().very_high_speed());
-
-    //PD8   ------> FMC_D13
-    gpiod.moder.modify(|_, w| {w.moder8().alternate()});
-    gpiod.afrh.modify(|_, w| {  w.afrh8().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr8().very_high_speed());
-
-    //PD9   ------> FMC_D14
-    gpiod.moder.modify(|_, w| {w.moder9().alternate()});
-    gpiod.afrh.modify(|_, w| {  w.afrh9().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr9().very_high_speed());
-
-    //PD10   ------> FMC_D15
-    gpiod.moder.modify(|_, w| {w.moder10().alternate()});
-    gpiod.afrh.modify(|_, w| {  w.afrh10().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr10().very_high_speed());
-
-
-    // PD4   ------> FMC_NOE
-    // PD5   ------> FMC_NWE
-    // PD7   ------> FMC_NE1
+     // Configure FMC for SRAM memory(in our case F-RAM)
+    let timing = FRAM_TIMINGS.to_timing(clocks.hclk()).unwrap();
+    let _fram = NorSramBuilder::new(SubBank::Ne1)
+        .memory_type(MemoryType::Sram) // FRAM behaves like an asynchronous SRAM
+        .bus_width(BusWidth::Bits16)
+        .muxed(false)
+        .write_enable(true)
+        .extended_mode(false)
+        .access_mode(AccessMode::A)
+        .timing(timing)
+        .build(dp.FMC)
+        .unwrap();
+
+    // F-RAM <-> SRAM copies for the tiled plan
+    let dma = Dma::new(dp.DMA1, &dp.RCC, Channel::C1);
+
+    (clocks, dma)
+}
 
-    gpiod.moder.modify(|_, w| {w.moder7().alternate()});
-    gpiod.afrl.modify(|_, w| {  w.afrl7().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr7().very_high_speed());
+// Reinitializes on any mismatch, testing the FMC wiring first if F-RAM
+// holds no readable image: on first boot, or when a bad joint garbled the
+// header. Any other boot leaves F-RAM alone, as a power failure during the
+// test can lose the block being tested.
+struct TestBlank {
+    blank: bool,
+}
 
+impl Policy<MappedFram> for TestBlank {
+    fn decide(&mut self, _mismatch: Mismatch, found: Option<&Header>) -> Action {
+        self.blank = found.is_none();
+        Action::Reinitialize
+    }
 
-    gpiod.moder.modify(|_, w| {w.moder4().alternate()});
-    gpiod.afrl.modify(|_, w| {  w.afrl4().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr4().very_high_speed());
+    fn reinitialize(&mut self, fram: &mut MappedFram, layout: &Layout<'_>) -> Result<(), FramError> {
+        if self.blank {
+            let report = memtest::run(fram, memtest::Mode::Preserving);
+            if !report.passed() {
+                hprintln!("F-RAM test failed:\n{}", report).unwrap();
+            }
+        }
+        image::Reinitialize.reinitialize(fram, layout)
+    }
+}
 
+#[entry]
+fn main() -> ! {
 
-    gpiod.moder.modify(|_, w| {w.moder5().alternate()});
-    gpiod.afrl.modify(|_, w| {  w.afrl5().af12()});
-    gpiod.ospeedr.modify(|_, w| w.ospeedr5().very_high_speed());
+    let (clocks, mut dma) = initialization();
+
+    // make sure F-RAM holds this firmware's image before using any of it;
+    // on first boot, or if it was damaged, it is copied from flash
+    match image::boot(LAYOUT_VERSION, layout::FRAM_BUILD_HASH, &mut TestBlank { blank: false }) {
+        Ok(Boot::Valid) => {}
+        Ok(boot) => hprintln!("F-RAM image: {:?}", boot).unwrap(),
+        Err(e) => {
+            hprintln!("F-RAM image unusable: {:?}", e).unwrap();
+            loop {
+                asm::wfi();
+            }
+        }
+    }
 
+    let mut fram = unsafe { MappedFram::new() };
 
-   
-     // Configure FMC for SRAM memory(in our case F-RAM)
-       unsafe{
-           dp.FMC.bcr1.modify(|_, w| {
-           w.mbken().set_bit(); // Enable FRAM bank 1
-           w.mtyp().bits(0b00); // FRAM memory type
-           w.mwid().bits(0b01); // 16-bit width
-           w.bursten().clear_bit(); //disable brust access mode
-           w.wren().clear_bit(); // wrap disable
-           w.muxen().clear_bit(); // Non-multiplexed
-           w.extmod().clear_bit(); // extended mode
-           w.asyncwait().clear_bit(); //disable async wait
-           w
-        });
-   
-        /*
-           Timing.AddressSetupTime = 1;
-           Timing.AddressHoldTime = 1;
-           Timing.DataSetupTime = 5;
-           Timing.BusTurnAroundDuration = 0;
-           Timing.CLKDivision = 0;
-           Timing.DataLatency = 0;
-           Timing.AccessMode = FMC_ACCESS_MODE_A;
-      */
-        dp.FMC.btr1.modify(|_,w|  {
-          // Set address setup time to 1 cycle
-           w.addset().bits(0x1);
-           // Set data setup time to 5 cycle
-           w.datast().bits(0x5);
-           // address hold time
-           w.addhld().bits(0x1);
-           // bus turn around
-           w.busturn().bits(0x0);
-           // clock division
-           w.clkdiv().bits(0x4);
-           //data latency
-           w.datlat().bits(0x0);
-           //access mode
-           w.accmod().bits(0x0);
-   
-           w
-       });
-   }
-}
+    // roll back F-RAM updates cut short by the last power failure
+    undo::recover().unwrap();
+    // formats the persistent heap on first boot
+    heap::recover().unwrap();
 
-#[entry]
-fn main() -> ! {
+    // before `restore`, which does not return when it resumes
+    undo::transaction(|tx| {
+        let count = ptr::addr_of!(BOOT_COUNT);
+        tx.set(count, unsafe { *count } + 1)
+    })
+    .unwrap();
 
-    initialization();
+    let store = CheckpointStore::new(fram.region_of(unsafe { ptr::addr_of!(CHECKPOINTS) }).unwrap());
+    // does not return if there is a checkpoint to resume from
+    checkpoint::restore(&mut fram, &store).unwrap();
 
     // Use the `at` method to access the last element (9th row, 49th column)
     // let last_element = PARAM_1.at(9, 49);
@@ -493,19 +450,60 @@ fn main() -> ! {
     //hprintln!("{:p}", &PARAM_1).unwrap();
 
     unsafe {
-        *PARAM_1.mut_at(0, 0) = 32345678 as i32;
         hprintln!("{:?}", PARAM_2);
     }
 
+    if checkpoint::checkpoint(&mut fram, &store).unwrap() == Resumed::Yes {
+        hprintln!("resumed from checkpoint").unwrap();
+    }
+
+    // resumes at the row that was being summed when power failed
+    task::run(&TASKS).unwrap();
+    let row_sums = unsafe { *ptr::addr_of!(ROW_SUMS) };
+    hprintln!("row sums: {:?}", row_sums).unwrap();
+
+    let input = INPUT;
+    #[cfg(feature = "profile")]
+    {
+        let mut cp = cortex_m::Peripherals::take().unwrap();
+        profile::enable(&mut cp.DCB, &mut cp.DWT);
+        profiled::register(&input);
+        profile::start();
+    }
+    let class = classify(&mut fram, &input, unsafe { &mut *ptr::addr_of_mut!(LOGITS) });
+    hprintln!("class {}", class).unwrap();
+    let mut planned = Tensor1D::zeros();
+    hprintln!("class {} with the tiled plan", classify_planned(&mut dma, &input, &mut planned)).unwrap();
+
+    let log = FramLog::<Inference>::new(fram.region_of(unsafe { ptr::addr_of!(INFERENCE_LOG) }).unwrap());
+    log.open(&mut fram).unwrap();
+    let logits = unsafe { &*ptr::addr_of!(LOGITS) };
+    let record = Inference {
+        boot: unsafe { BOOT_COUNT },
+        class: class as u32,
+        logits: [*logits.at(0), *logits.at(1)],
+    };
+    log.push(&mut fram, &record).unwrap();
+
+    // settings and calibration, kept across firmware updates
+    let settings = kv::store(&fram);
+    settings.open(&mut fram).unwrap();
+    let runs: u32 = settings.get_value(&fram, &RUNS).unwrap().unwrap_or(0);
+    settings.set_value(&mut fram, &RUNS, &(runs + 1)).unwrap();
+    hprintln!("{} inferences since the store was formatted", runs + 1).unwrap();
+
+    // done: the next boot starts over instead of resuming at the checkpoint
+    store.clear(&mut fram).unwrap();
+    #[cfg(feature = "profile")]
+    profile::report(&mut hio::hstdout().unwrap()).unwrap();
+
     loop {
         // your code goes here
+        asm::wfi();
     }
 }
 
 
-fn delay(duration: u32) {
-    for _ in 0..duration {
-        // Perform some NOP operation or just loop
-        asm::nop(); // Assembly NOP instruction
-    }
+fn delay_us(clocks: &Clocks, us: u32) {
+    asm::delay(clocks.us_to_cycles(us));
 }
\ No newline at end of file
@@ -0,0 +1,368 @@
+//! Integer neural network inference.
+//!
+//! Layers read their weights straight out of the [`Tensor2D`]/[`Tensor1D`]
+//! they are given, so F-RAM weights are used in place, and write
+//! their activations to whatever tensor the caller passes, in SRAM or F-RAM.
+//!
+//! Products are summed in a saturating `i64` accumulator and every activation
+//! a layer produces is rounded and saturated into the `i16` range, so results
+//! are bit-identical on the host and on the board.
+
+use crate::dma::{DmaError, TileStreamer};
+use crate::fram::FramError;
+use crate::tensor::{Element, Matrix, Numeric, Tensor1D, Tensor2D, Vector};
+
+mod conv;
+mod pool;
+pub mod resume;
+
+pub use self::conv::{Conv2D, DepthwiseConv2D};
+pub use self::pool::{avg_pool2d, flatten, flatten_mut, max_pool2d};
+
+/// Accumulator of a dot product.
+pub type Acc = i64;
+
+/// Smallest activation a layer produces.
+pub const ACT_MIN: Numeric = i16::MIN as Numeric;
+/// Largest activation a layer produces.
+pub const ACT_MAX: Numeric = i16::MAX as Numeric;
+
+/// Rescales an accumulator to an activation: `acc * multiplier / 2^shift`,
+/// rounded to nearest (ties towards positive infinity) and saturated to
+/// [`ACT_MIN`]..=[`ACT_MAX`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Requant {
+    multiplier: i32,
+    shift: u8,
+}
+
+impl Requant {
+    /// Only saturates.
+    pub const IDENTITY: Requant = Requant::new(1, 0);
+    /// Largest `shift`.
+    pub const MAX_SHIFT: u8 = 62;
+
+    /// Panics if `shift` is more than [`MAX_SHIFT`](Self::MAX_SHIFT), which
+    /// fails the build for a `Requant` in a `const` or `static`.
+    pub const fn new(multiplier: i32, shift: u8) -> Self {
+        assert!(shift <= Self::MAX_SHIFT, "requant shift");
+        Requant { multiplier, shift }
+    }
+
+    pub const fn multiplier(self) -> i32 {
+        self.multiplier
+    }
+
+    pub const fn shift(self) -> u8 {
+        self.shift
+    }
+
+    pub fn apply(self, acc: Acc) -> Numeric {
+        let scaled = acc.saturating_mul(self.multiplier as Acc);
+        let rounded = match self.shift {
+            0 => scaled,
+            shift => scaled.saturating_add(1 << (shift - 1)) >> shift,
+        };
+        saturate(rounded)
+    }
+}
+
+// One multiply-add; the same everywhere so all kernels round alike.
+fn mac<W: Element, X: Element>(acc: Acc, w: W, x: X) -> Acc {
+    acc.saturating_add(w.widen().saturating_mul(x.widen()))
+}
+
+fn saturate(x: Acc) -> Numeric {
+    x.clamp(ACT_MIN as Acc, ACT_MAX as Acc) as Numeric
+}
+
+/// `output = requant(weights * input + bias)`, with `bias` in accumulator
+/// units (the scale of `weights * input`).
+pub fn dense<const I: usize, const O: usize>(
+    weights: &Tensor2D<O, I>,
+    bias: Option<&Tensor1D<O>>,
+    requant: Requant,
+    input: &Tensor1D<I>,
+    output: &mut Tensor1D<O>,
+) {
+    let input = input.as_array();
+    for (o, out) in output.as_mut_array().iter_mut().enumerate() {
+        let mut acc: Acc = bias.map_or(0, |b| *b.at(o) as Acc);
+        for (&w, &x) in weights.row(o).iter().zip(input.iter()) {
+            acc = mac(acc, w, x);
+        }
+        *out = requant.apply(acc);
+    }
+}
+
+/// [`dense`] with the weights and input in any backend, e.g. weights read
+/// through a [`FramDevice`](crate::fram::FramDevice) and input in SRAM, and
+/// of any element type. Slower than `dense` on in-memory tensors, as every
+/// element goes through [`Matrix::get`].
+pub fn matvec<M: Matrix, V: Vector, const O: usize>(
+    weights: &M,
+    bias: Option<&Tensor1D<O>>,
+    requant: Requant,
+    input: &V,
+    output: &mut Tensor1D<O>,
+) -> Result<(), FramError> {
+    assert!(M::ROWS == O && M::COLS == V::LEN, "matvec shapes");
+    for (o, out) in output.iter_mut().enumerate() {
+        let mut acc: Acc = bias.map_or(0, |b| *b.at(o) as Acc);
+        for (i, x) in input.elements().enumerate() {
+            acc = mac(acc, weights.get(o, i)?, x?);
+        }
+        *out = requant.apply(acc);
+    }
+    Ok(())
+}
+
+/// A fully connected layer.
+pub struct Dense<'a, const I: usize, const O: usize> {
+    /// One row per output.
+    pub weights: &'a Tensor2D<O, I>,
+    pub bias: Option<&'a Tensor1D<O>>,
+    pub requant: Requant,
+}
+
+impl<'a, const I: usize, const O: usize> Dense<'a, I, O> {
+    pub fn forward(&self, input: &Tensor1D<I>, output: &mut Tensor1D<O>) {
+        dense(self.weights, self.bias, self.requant, input, output)
+    }
+
+    /// [`forward`](Self::forward) with the weights copied to SRAM by
+    /// `streamer`, `N / I` rows at a time, the next rows while the current
+    /// ones are used. `N` must be a multiple of `I`.
+    pub fn forward_streamed<const N: usize>(
+        &self,
+        streamer: &mut TileStreamer<'_, Numeric, N>,
+        input: &Tensor1D<I>,
+        output: &mut Tensor1D<O>,
+    ) -> Result<(), DmaError> {
+        assert!(N.is_multiple_of(I), "tiles of whole rows");
+        streamer.run(self.weights.as_slice(), |at, tile| {
+            self.forward_rows(at / I, tile, input.as_array(), output.as_mut_array());
+            Ok(())
+        })
+    }
+
+    /// Outputs `first..first + rows.len() / I` given only those rows of the
+    /// weights, wherever they were copied to. `output` has all `O` elements.
+    pub fn forward_rows(
+        &self,
+        first: usize,
+        rows: &[Numeric],
+        input: &[Numeric],
+        output: &mut [Numeric],
+    ) {
+        for (k, row) in rows.chunks(I).enumerate() {
+            let o = first + k;
+            let mut acc: Acc = self.bias.map_or(0, |b| *b.at(o) as Acc);
+            for (&w, &x) in row.iter().zip(input.iter()) {
+                acc = mac(acc, w, x);
+            }
+            output[o] = self.requant.apply(acc);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerKind {
+    Dense,
+    Conv2D,
+    DepthwiseConv2D,
+}
+
+/// One entry of the layer table of a generated model.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LayerInfo {
+    pub name: &'static str,
+    pub kind: LayerKind,
+    /// Shape of the weights, outermost dimension first.
+    pub shape: &'static [usize],
+    pub has_bias: bool,
+    pub requant: Requant,
+}
+
+/// `x += bias`, saturated like a layer output.
+pub fn bias_add<const W: usize>(x: &mut Tensor1D<W>, bias: &Tensor1D<W>) {
+    for (v, &b) in x.as_mut_array().iter_mut().zip(bias.as_array().iter()) {
+        *v = saturate(*v as Acc + b as Acc);
+    }
+}
+
+/// Rescales activations in place.
+pub fn requantize<const W: usize>(x: &mut Tensor1D<W>, requant: Requant) {
+    for v in x.as_mut_array().iter_mut() {
+        *v = requant.apply(*v as Acc);
+    }
+}
+
+pub fn relu<const W: usize>(x: &mut Tensor1D<W>) {
+    for v in x.as_mut_array().iter_mut() {
+        if *v < 0 {
+            *v = 0;
+        }
+    }
+}
+
+/// Index of the largest element; the first one if there are several.
+pub fn argmax<const W: usize>(x: &Tensor1D<W>) -> usize {
+    let mut best = 0;
+    for (i, v) in x.as_array().iter().enumerate() {
+        if *v > *x.at(best) {
+            best = i;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requant_rounds_and_saturates() {
+        let q = Requant::new(3, 2); // * 0.75
+        assert_eq!(q.apply(4), 3);
+        assert_eq!(q.apply(2), 2); // 1.5
+        assert_eq!(q.apply(-2), -1); // -1.5
+        assert_eq!(q.apply(-3), -2); // -2.25
+        assert_eq!(q.apply(100_000), ACT_MAX);
+        assert_eq!(q.apply(-100_000), ACT_MIN);
+        assert_eq!(Requant::IDENTITY.apply(Acc::MAX), ACT_MAX);
+        assert_eq!(Requant::new(i32::MAX, 62).apply(Acc::MIN), -2);
+    }
+
+    #[test]
+    #[should_panic(expected = "requant shift")]
+    fn requant_rejects_long_shifts() {
+        Requant::new(1, Requant::MAX_SHIFT + 1);
+    }
+
+    #[test]
+    fn dense_layer() {
+        let weights = Tensor2D::new([[1, 2, 3], [-1, 0, 1]]);
+        let bias = Tensor1D::new([10, -10]);
+        let input = Tensor1D::new([4, 5, 6]);
+        let mut out = Tensor1D::zeros();
+
+        dense(&weights, None, Requant::IDENTITY, &input, &mut out);
+        assert_eq!(out, Tensor1D::new([32, 2]));
+
+        let layer = Dense {
+            weights: &weights,
+            bias: Some(&bias),
+            requant: Requant::new(1, 1),
+        };
+        layer.forward(&input, &mut out);
+        assert_eq!(out, Tensor1D::new([21, -4]));
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "arm"))]
+    fn dense_with_streamed_weights() {
+        use crate::dma::{Channel, Dma};
+
+        let weights = Tensor2D::new([[3, -1, 2, 0], [1, 1, 1, 1], [-2, 4, 0, 1]]);
+        let bias = Tensor1D::new([4, 0, -8]);
+        let layer = Dense {
+            weights: &weights,
+            bias: Some(&bias),
+            requant: Requant::new(5, 3),
+        };
+        let input = Tensor1D::new([120, -7, 33, 90]);
+        let mut expected = Tensor1D::zeros();
+        layer.forward(&input, &mut expected);
+
+        let mut dma = Dma::new(Channel::C1);
+        // two rows, then the last one
+        let mut tiles = [[0; 8]; 2];
+        let mut out = Tensor1D::zeros();
+        layer
+            .forward_streamed(
+                &mut TileStreamer::new(&mut dma, &mut tiles),
+                &input,
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    #[cfg(not(target_arch = "arm"))]
+    fn matvec_over_any_backend() {
+        use crate::fram::{FramDevice, MemFram};
+        use crate::tensor::FramTensor2D;
+
+        // i8 weights read through the device, the transposed weights of
+        // `dense_layer` in SRAM
+        let mut fram = MemFram::new(16);
+        fram.write(4, &[1, 2, 3, 0xff, 0, 1]).unwrap();
+        let weights = FramTensor2D::<_, 2, 3, i8>::new(&fram, 4).unwrap();
+        let input = Tensor1D::new([4, 5, 6]);
+        let mut out = Tensor1D::zeros();
+        matvec(&weights, None, Requant::IDENTITY, &input, &mut out).unwrap();
+        assert_eq!(out, Tensor1D::new([32, 2]));
+
+        let transposed = Tensor2D::<3, 2>::new([[1, -1], [2, 0], [3, 1]]);
+        let mut again = Tensor1D::zeros();
+        matvec(
+            &transposed.transposed(),
+            None,
+            Requant::IDENTITY,
+            &input,
+            &mut again,
+        )
+        .unwrap();
+        assert_eq!(again, out);
+    }
+
+    #[test]
+    fn dense_saturates_the_output() {
+        let weights = Tensor2D::new([[ACT_MAX; 4], [ACT_MIN; 4]]);
+        let input = Tensor1D::new([ACT_MAX; 4]);
+        let mut out = Tensor1D::zeros();
+        dense(&weights, None, Requant::IDENTITY, &input, &mut out);
+        assert_eq!(out, Tensor1D::new([ACT_MAX, ACT_MIN]));
+        dense(&weights, None, Requant::new(1, 20), &input, &mut out);
+        assert_eq!(out, Tensor1D::new([4096, -4096]));
+    }
+
+    #[test]
+    fn elementwise() {
+        let mut x = Tensor1D::new([-5, 0, 7, ACT_MAX, 7]);
+        bias_add(&mut x, &Tensor1D::new([1, 1, 1, 1, 1]));
+        assert_eq!(x, Tensor1D::new([-4, 1, 8, ACT_MAX, 8]));
+        relu(&mut x);
+        assert_eq!(x, Tensor1D::new([0, 1, 8, ACT_MAX, 8]));
+        requantize(&mut x, Requant::new(1, 3));
+        assert_eq!(x, Tensor1D::new([0, 0, 1, 4096, 1]));
+        assert_eq!(argmax(&x), 3);
+        assert_eq!(argmax(&Tensor1D::new([2, 5, 5])), 1);
+    }
+
+    // A two layer model; the expected outputs are the same on the board.
+    #[test]
+    fn small_model() {
+        let w1 = Tensor2D::new([[3, -1, 2, 0], [1, 1, 1, 1], [-2, 4, 0, 1]]);
+        let b1 = Tensor1D::new([4, 0, -8]);
+        let w2 = Tensor2D::new([[1, -1, 2], [-3, 2, 1]]);
+        let input = Tensor1D::new([120, -7, 33, 90]);
+
+        let mut hidden = Tensor1D::zeros();
+        Dense {
+            weights: &w1,
+            bias: Some(&b1),
+            requant: Requant::new(5, 3),
+        }
+        .forward(&input, &mut hidden);
+        assert_eq!(hidden, Tensor1D::new([273, 148, -116]));
+        relu(&mut hidden);
+
+        let mut logits = Tensor1D::zeros();
+        dense(&w2, None, Requant::new(1, 1), &hidden, &mut logits);
+        assert_eq!(logits, Tensor1D::new([63, -261]));
+        assert_eq!(argmax(&logits), 0);
+    }
+}
@@ -0,0 +1,315 @@
+//! 2D convolutions over CHW tensors.
+//!
+//! Output sizes cannot be computed from the input ones in a const generic
+//! yet, so the caller spells them out and the layer checks them:
+//! `OH = (H + 2 * padding - KH) / stride + 1`, likewise for `OW`.
+
+use super::{mac, Acc, Requant};
+use crate::tensor::{Tensor1D, Tensor3D, Tensor4D};
+
+fn check_shape(len: usize, kernel: usize, stride: usize, padding: usize, out: usize) {
+    assert!(stride > 0 && len + 2 * padding >= kernel);
+    assert_eq!(
+        (len + 2 * padding - kernel) / stride + 1,
+        out,
+        "output size"
+    );
+}
+
+// Input position under kernel tap `k` of output `o`, `None` in the padding.
+fn tap(o: usize, k: usize, stride: usize, padding: usize, len: usize) -> Option<usize> {
+    (o * stride + k).checked_sub(padding).filter(|&i| i < len)
+}
+
+/// A convolution with `CO` filters over `CI` input channels and zero padding.
+pub struct Conv2D<'a, const CO: usize, const CI: usize, const KH: usize, const KW: usize> {
+    pub weights: &'a Tensor4D<CO, CI, KH, KW>,
+    /// In accumulator units, like [`dense`](super::dense).
+    pub bias: Option<&'a Tensor1D<CO>>,
+    pub stride: usize,
+    pub padding: usize,
+    pub requant: Requant,
+}
+
+impl<'a, const CO: usize, const CI: usize, const KH: usize, const KW: usize>
+    Conv2D<'a, CO, CI, KH, KW>
+{
+    /// Panics if `OH`/`OW` do not match the input size, see the module docs.
+    pub fn forward<const H: usize, const W: usize, const OH: usize, const OW: usize>(
+        &self,
+        input: &Tensor3D<CI, H, W>,
+        output: &mut Tensor3D<CO, OH, OW>,
+    ) {
+        check_shape(H, KH, self.stride, self.padding, OH);
+        check_shape(W, KW, self.stride, self.padding, OW);
+        let weights = self.weights.as_array();
+        let input = input.as_array();
+
+        for (co, out) in output.as_mut_array().iter_mut().enumerate() {
+            for (oy, out) in out.iter_mut().enumerate() {
+                for (ox, out) in out.iter_mut().enumerate() {
+                    let mut acc: Acc = self.bias.map_or(0, |b| *b.at(co) as Acc);
+                    for (ci, kernel) in weights[co].iter().enumerate() {
+                        for (ky, kernel) in kernel.iter().enumerate() {
+                            let iy = match tap(oy, ky, self.stride, self.padding, H) {
+                                Some(iy) => iy,
+                                None => continue,
+                            };
+                            for (kx, &w) in kernel.iter().enumerate() {
+                                if let Some(ix) = tap(ox, kx, self.stride, self.padding, W) {
+                                    acc = mac(acc, w, input[ci][iy][ix]);
+                                }
+                            }
+                        }
+                    }
+                    *out = self.requant.apply(acc);
+                }
+            }
+        }
+    }
+}
+
+/// A convolution with one `KH` x `KW` filter per channel (depth multiplier 1).
+pub struct DepthwiseConv2D<'a, const C: usize, const KH: usize, const KW: usize> {
+    pub weights: &'a Tensor3D<C, KH, KW>,
+    /// In accumulator units, like [`dense`](super::dense).
+    pub bias: Option<&'a Tensor1D<C>>,
+    pub stride: usize,
+    pub padding: usize,
+    pub requant: Requant,
+}
+
+impl<'a, const C: usize, const KH: usize, const KW: usize> DepthwiseConv2D<'a, C, KH, KW> {
+    /// Panics if `OH`/`OW` do not match the input size, see the module docs.
+    pub fn forward<const H: usize, const W: usize, const OH: usize, const OW: usize>(
+        &self,
+        input: &Tensor3D<C, H, W>,
+        output: &mut Tensor3D<C, OH, OW>,
+    ) {
+        check_shape(H, KH, self.stride, self.padding, OH);
+        check_shape(W, KW, self.stride, self.padding, OW);
+        let weights = self.weights.as_array();
+        let input = input.as_array();
+
+        for (c, out) in output.as_mut_array().iter_mut().enumerate() {
+            for (oy, out) in out.iter_mut().enumerate() {
+                for (ox, out) in out.iter_mut().enumerate() {
+                    let mut acc: Acc = self.bias.map_or(0, |b| *b.at(c) as Acc);
+                    for (ky, kernel) in weights[c].iter().enumerate() {
+                        let iy = match tap(oy, ky, self.stride, self.padding, H) {
+                            Some(iy) => iy,
+                            None => continue,
+                        };
+                        for (kx, &w) in kernel.iter().enumerate() {
+                            if let Some(ix) = tap(ox, kx, self.stride, self.padding, W) {
+                                acc = mac(acc, w, input[c][iy][ix]);
+                            }
+                        }
+                    }
+                    *out = self.requant.apply(acc);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tensor::Numeric;
+
+    // Deterministic test data in -20..20.
+    fn fill(seed: u32, data: &mut [Numeric]) {
+        let mut x = seed.wrapping_mul(2_654_435_761) | 1;
+        for v in data {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *v = (x % 40) as Numeric - 20;
+        }
+    }
+
+    // All elements of a tensor, in memory order.
+    fn values<T>(t: &T) -> &[Numeric] {
+        let len = core::mem::size_of::<T>() / core::mem::size_of::<Numeric>();
+        unsafe { core::slice::from_raw_parts(t as *const T as *const Numeric, len) }
+    }
+
+    fn values_mut<T>(t: &mut T) -> &mut [Numeric] {
+        let len = core::mem::size_of::<T>() / core::mem::size_of::<Numeric>();
+        unsafe { core::slice::from_raw_parts_mut(t as *mut T as *mut Numeric, len) }
+    }
+
+    // Straightforward convolution over an explicitly zero-padded copy of the
+    // input. `groups` is 1 for a full convolution and the channel count for a
+    // depthwise one.
+    #[allow(clippy::too_many_arguments)]
+    fn reference(
+        input: &[Numeric],
+        (ci, h, w): (usize, usize, usize),
+        weights: &[Numeric],
+        (co, kh, kw): (usize, usize, usize),
+        bias: &[Numeric],
+        stride: usize,
+        padding: usize,
+        groups: usize,
+        requant: Requant,
+    ) -> Vec<Numeric> {
+        let (ph, pw) = (h + 2 * padding, w + 2 * padding);
+        let mut padded = vec![0 as Acc; ci * ph * pw];
+        for c in 0..ci {
+            for y in 0..h {
+                for x in 0..w {
+                    padded[(c * ph + y + padding) * pw + x + padding] =
+                        input[(c * h + y) * w + x] as Acc;
+                }
+            }
+        }
+        let (oh, ow) = ((ph - kh) / stride + 1, (pw - kw) / stride + 1);
+        let per_group = ci / groups;
+        let mut out = Vec::new();
+        for o in 0..co {
+            let first = o / (co / groups) * per_group;
+            for y in 0..oh {
+                for x in 0..ow {
+                    let mut acc = bias[o] as Acc;
+                    for c in 0..per_group {
+                        for ky in 0..kh {
+                            for kx in 0..kw {
+                                let wt = weights[((o * per_group + c) * kh + ky) * kw + kx] as Acc;
+                                acc += wt
+                                    * padded[((first + c) * ph + y * stride + ky) * pw
+                                        + x * stride
+                                        + kx];
+                            }
+                        }
+                    }
+                    out.push(requant.apply(acc));
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn conv2d_matches_reference() {
+        let mut input = Tensor3D::<3, 6, 5>::zeros();
+        let mut weights = Tensor4D::<4, 3, 3, 3>::zeros();
+        let mut bias = Tensor1D::<4>::zeros();
+        fill(1, values_mut(&mut input));
+        fill(2, values_mut(&mut weights));
+        fill(3, values_mut(&mut bias));
+        let requant = Requant::new(3, 4);
+
+        // no padding, stride 1
+        let mut out = Tensor3D::<4, 4, 3>::zeros();
+        let layer = Conv2D {
+            weights: &weights,
+            bias: Some(&bias),
+            stride: 1,
+            padding: 0,
+            requant,
+        };
+        layer.forward(&input, &mut out);
+        let expected = reference(
+            values(&input),
+            (3, 6, 5),
+            values(&weights),
+            (4, 3, 3),
+            bias.as_array(),
+            1,
+            0,
+            1,
+            requant,
+        );
+        assert_eq!(values(&out), &expected[..]);
+
+        // "same" padding, stride 2
+        let mut out = Tensor3D::<4, 3, 3>::zeros();
+        let layer = Conv2D {
+            weights: &weights,
+            bias: None,
+            stride: 2,
+            padding: 1,
+            requant,
+        };
+        layer.forward(&input, &mut out);
+        let expected = reference(
+            values(&input),
+            (3, 6, 5),
+            values(&weights),
+            (4, 3, 3),
+            &[0; 4],
+            2,
+            1,
+            1,
+            requant,
+        );
+        assert_eq!(values(&out), &expected[..]);
+    }
+
+    #[test]
+    fn depthwise_matches_reference() {
+        let mut input = Tensor3D::<2, 5, 5>::zeros();
+        let mut weights = Tensor3D::<2, 3, 3>::zeros();
+        fill(4, values_mut(&mut input));
+        fill(5, values_mut(&mut weights));
+        let bias = Tensor1D::new([7, -7]);
+        let requant = Requant::new(1, 2);
+
+        for &(stride, padding) in [(1, 0), (1, 1), (2, 1)].iter() {
+            let expected = reference(
+                values(&input),
+                (2, 5, 5),
+                values(&weights),
+                (2, 3, 3),
+                bias.as_array(),
+                stride,
+                padding,
+                2,
+                requant,
+            );
+            let layer = DepthwiseConv2D {
+                weights: &weights,
+                bias: Some(&bias),
+                stride,
+                padding,
+                requant,
+            };
+            match (stride, padding) {
+                (1, 0) => {
+                    let mut out = Tensor3D::<2, 3, 3>::zeros();
+                    layer.forward(&input, &mut out);
+                    assert_eq!(values(&out), &expected[..]);
+                }
+                (1, 1) => {
+                    let mut out = Tensor3D::<2, 5, 5>::zeros();
+                    layer.forward(&input, &mut out);
+                    assert_eq!(values(&out), &expected[..]);
+                }
+                _ => {
+                    let mut out = Tensor3D::<2, 3, 3>::zeros();
+                    layer.forward(&input, &mut out);
+                    assert_eq!(values(&out), &expected[..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "output size")]
+    fn wrong_output_size() {
+        let weights = Tensor4D::<1, 1, 3, 3>::zeros();
+        let layer = Conv2D {
+            weights: &weights,
+            bias: None,
+            stride: 1,
+            padding: 0,
+            requant: Requant::IDENTITY,
+        };
+        layer.forward(
+            &Tensor3D::<1, 5, 5>::zeros(),
+            &mut Tensor3D::<1, 5, 5>::zeros(),
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Pooling and flattening of CHW tensors.
+//!
+//! Pooling windows are `size` x `size`, move by `stride` and never extend
+//! past the input: `OH = (H - size) / stride + 1`, likewise for `OW`.
+
+use super::Acc;
+use crate::tensor::{Numeric, Tensor1D, Tensor3D};
+
+fn check_shape(len: usize, size: usize, stride: usize, out: usize) {
+    assert!(stride > 0 && size > 0 && len >= size);
+    assert_eq!((len - size) / stride + 1, out, "output size");
+}
+
+fn pool<const C: usize, const H: usize, const W: usize, const OH: usize, const OW: usize, F>(
+    input: &Tensor3D<C, H, W>,
+    size: usize,
+    stride: usize,
+    output: &mut Tensor3D<C, OH, OW>,
+    mut reduce: F,
+) where
+    F: FnMut(&mut dyn Iterator<Item = Numeric>) -> Numeric,
+{
+    check_shape(H, size, stride, OH);
+    check_shape(W, size, stride, OW);
+    let input = input.as_array();
+
+    for (c, out) in output.as_mut_array().iter_mut().enumerate() {
+        for (oy, out) in out.iter_mut().enumerate() {
+            for (ox, out) in out.iter_mut().enumerate() {
+                let rows = &input[c][oy * stride..oy * stride + size];
+                let mut window = rows
+                    .iter()
+                    .flat_map(|row| row[ox * stride..ox * stride + size].iter().copied());
+                *out = reduce(&mut window);
+            }
+        }
+    }
+}
+
+/// Largest value of every window.
+pub fn max_pool2d<
+    const C: usize,
+    const H: usize,
+    const W: usize,
+    const OH: usize,
+    const OW: usize,
+>(
+    input: &Tensor3D<C, H, W>,
+    size: usize,
+    stride: usize,
+    output: &mut Tensor3D<C, OH, OW>,
+) {
+    pool(input, size, stride, output, |window| window.max().unwrap());
+}
+
+/// Mean of every window, rounded to nearest with ties towards positive
+/// infinity like [`Requant`](super::Requant).
+pub fn avg_pool2d<
+    const C: usize,
+    const H: usize,
+    const W: usize,
+    const OH: usize,
+    const OW: usize,
+>(
+    input: &Tensor3D<C, H, W>,
+    size: usize,
+    stride: usize,
+    output: &mut Tensor3D<C, OH, OW>,
+) {
+    let count = (size * size) as Acc;
+    pool(input, size, stride, output, |window| {
+        let sum: Acc = window.map(|v| v as Acc).sum();
+        (sum * 2 + count).div_euclid(count * 2) as Numeric
+    });
+}
+
+/// `input` as a vector of its `C * H * W` elements, without copying; the
+/// input of a dense layer after the convolutions.
+pub fn flatten<const C: usize, const H: usize, const W: usize, const N: usize>(
+    input: &Tensor3D<C, H, W>,
+) -> &Tensor1D<N> {
+    assert_eq!(C * H * W, N, "flattened size");
+    // both are `repr(C)` arrays of `Numeric`
+    unsafe { &*(input as *const Tensor3D<C, H, W> as *const Tensor1D<N>) }
+}
+
+/// Mutable [`flatten`], e.g. for [`relu`](super::relu) after a convolution.
+pub fn flatten_mut<const C: usize, const H: usize, const W: usize, const N: usize>(
+    input: &mut Tensor3D<C, H, W>,
+) -> &mut Tensor1D<N> {
+    assert_eq!(C * H * W, N, "flattened size");
+    unsafe { &mut *(input as *mut Tensor3D<C, H, W> as *mut Tensor1D<N>) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn input() -> Tensor3D<2, 4, 5> {
+        let mut t = Tensor3D::zeros();
+        for c in 0..2 {
+            for y in 0..4 {
+                for x in 0..5 {
+                    *t.mut_at(c, y, x) = ((c * 31 + y * 7 + x * 13) % 17) as Numeric - 8;
+                }
+            }
+        }
+        t
+    }
+
+    // Pooling by brute force: every output looks at all inputs and keeps the
+    // ones inside its window.
+    fn reference(
+        input: &Tensor3D<2, 4, 5>,
+        size: usize,
+        stride: usize,
+        (oh, ow): (usize, usize),
+        average: bool,
+    ) -> Vec<Numeric> {
+        let mut out = Vec::new();
+        for c in 0..2 {
+            for oy in 0..oh {
+                for ox in 0..ow {
+                    let mut window = Vec::new();
+                    for y in 0..4 {
+                        for x in 0..5 {
+                            let (dy, dx) = (
+                                y as isize - (oy * stride) as isize,
+                                x as isize - (ox * stride) as isize,
+                            );
+                            if dy >= 0 && dx >= 0 && (dy as usize) < size && (dx as usize) < size {
+                                window.push(*input.at(c, y, x) as f64);
+                            }
+                        }
+                    }
+                    let v = if average {
+                        (window.iter().sum::<f64>() / window.len() as f64 + 0.5).floor()
+                    } else {
+                        window.iter().cloned().fold(f64::MIN, f64::max)
+                    };
+                    out.push(v as Numeric);
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn max_pool_matches_reference() {
+        let input = input();
+        let mut out = Tensor3D::<2, 2, 2>::zeros();
+        max_pool2d(&input, 2, 2, &mut out);
+        let flat: &Tensor1D<8> = flatten(&out);
+        assert_eq!(
+            &flat.as_array()[..],
+            &reference(&input, 2, 2, (2, 2), false)[..]
+        );
+
+        let mut out = Tensor3D::<2, 2, 3>::zeros();
+        max_pool2d(&input, 3, 1, &mut out);
+        let flat: &Tensor1D<12> = flatten(&out);
+        assert_eq!(
+            &flat.as_array()[..],
+            &reference(&input, 3, 1, (2, 3), false)[..]
+        );
+    }
+
+    #[test]
+    fn avg_pool_matches_reference() {
+        let input = input();
+        let mut out = Tensor3D::<2, 2, 2>::zeros();
+        avg_pool2d(&input, 2, 2, &mut out);
+        let flat: &Tensor1D<8> = flatten(&out);
+        assert_eq!(
+            &flat.as_array()[..],
+            &reference(&input, 2, 2, (2, 2), true)[..]
+        );
+
+        let mut out = Tensor3D::<2, 2, 3>::zeros();
+        avg_pool2d(&input, 3, 1, &mut out);
+        let flat: &Tensor1D<12> = flatten(&out);
+        assert_eq!(
+            &flat.as_array()[..],
+            &reference(&input, 3, 1, (2, 3), true)[..]
+        );
+    }
+
+    #[test]
+    fn flatten_is_chw_order() {
+        let mut input = input();
+        let flat: &Tensor1D<40> = flatten(&input);
+        assert_eq!(*flat.at(0), *input.at(0, 0, 0));
+        assert_eq!(*flat.at(7), *input.at(0, 1, 2));
+        assert_eq!(*flat.at(39), *input.at(1, 3, 4));
+
+        crate::nn::relu(flatten_mut::<2, 4, 5, 40>(&mut input));
+        assert!(flatten::<2, 4, 5, 40>(&input)
+            .as_array()
+            .iter()
+            .all(|&v| v >= 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "flattened size")]
+    fn flatten_checks_size() {
+        let _: &Tensor1D<41> = flatten(&input());
+    }
+}
@@ -0,0 +1,338 @@
+//! Kernels that survive power failures by keeping their progress in F-RAM.
+//!
+//! Each output element is one dot product. Every `granularity` multiply-adds
+//! the kernel commits the element it is on, how far along it is and the
+//! partial sum; after a reboot the same call picks up from there. Outputs are
+//! written to F-RAM as they are finished. Weights and inputs are only read,
+//! so they must still be there after a reboot: in F-RAM or in flash.
+//!
+//! The sums are computed in exactly the same order as without interruptions,
+//! so the output is the same as [`nn::dense`](super::dense) would give.
+//!
+//! Progress layout, double buffered:
+//!
+//! ```text
+//! | selector: u16 | pad: u16 | slot 0 | slot 1 |
+//! slot: | index: u32 | k: u32 | acc: i64 |
+//! ```
+//!
+//! A slot is written in full before `selector` is switched to it; both
+//! selector values differ in the low byte only, so even a torn store selects
+//! one of the two slots.
+
+use core::mem;
+
+use super::{mac, Acc, Dense, Requant};
+use crate::fram::{bytes_of, read_value, FramDevice, FramError, Region};
+use crate::tensor::{Numeric, Tensor1D, Tensor2D};
+
+const SLOT_0: u16 = 0x9e00;
+const SLOT_1: u16 = 0x9e01;
+const SLOT_LEN: usize = 16;
+
+/// Bytes of F-RAM a [`Resumable`] needs for its progress.
+pub const PROGRESS_LEN: usize = 4 + 2 * SLOT_LEN;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Progress {
+    /// Output element being computed.
+    index: u32,
+    /// Multiply-adds of it already in `acc`.
+    k: u32,
+    acc: Acc,
+}
+
+/// One dot product per output element.
+trait Kernel {
+    fn outputs(&self) -> usize;
+    fn inner(&self) -> usize;
+    /// Accumulator before the first multiply-add of `index`.
+    fn init(&self, index: usize) -> Acc;
+    fn mac(&self, index: usize, k: usize, acc: Acc) -> Acc;
+    fn finish(&self, acc: Acc) -> Numeric;
+}
+
+/// Runs kernels with their progress in an F-RAM region of [`PROGRESS_LEN`]
+/// bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resumable {
+    region: Region,
+    granularity: usize,
+}
+
+impl Resumable {
+    /// Commits progress every `granularity` multiply-adds (and after every
+    /// output element). An all-zero region starts from the beginning.
+    pub const fn new(region: Region, granularity: usize) -> Self {
+        Resumable {
+            region,
+            granularity: if granularity == 0 { 1 } else { granularity },
+        }
+    }
+
+    /// Makes the next kernel start from the beginning instead of returning
+    /// straight away because the last one finished.
+    pub fn reset<D: FramDevice>(&self, fram: &mut D) -> Result<(), FramError> {
+        self.store(fram, &Progress::default())
+    }
+
+    fn load<D: FramDevice>(&self, fram: &D) -> Result<Progress, FramError> {
+        let slot = match fram.read_u16(self.region.offset)? {
+            SLOT_0 => 0,
+            SLOT_1 => 1,
+            _ => return Ok(Progress::default()),
+        };
+        let at = self.slot(slot);
+        Ok(Progress {
+            index: fram.read_u32(at)?,
+            k: fram.read_u32(at + 4)?,
+            acc: read_value(fram, at + 8)?,
+        })
+    }
+
+    fn store<D: FramDevice>(&self, fram: &mut D, progress: &Progress) -> Result<(), FramError> {
+        // write the slot not in use
+        let (slot, selector) = match fram.read_u16(self.region.offset)? {
+            SLOT_0 => (1, SLOT_1),
+            _ => (0, SLOT_0),
+        };
+        let at = self.slot(slot);
+        fram.write_u32(at, progress.index)?;
+        fram.write_u32(at + 4, progress.k)?;
+        fram.write(at + 8, bytes_of(&progress.acc))?;
+        fram.flush();
+        fram.write_u16(self.region.offset, selector)?;
+        fram.flush();
+        Ok(())
+    }
+
+    fn slot(&self, slot: usize) -> usize {
+        self.region.offset + 4 + slot * SLOT_LEN
+    }
+
+    fn run<D: FramDevice, K: Kernel>(
+        &self,
+        fram: &mut D,
+        kernel: &K,
+        out: usize,
+    ) -> Result<(), FramError> {
+        let mut p = self.load(fram)?;
+        while (p.index as usize) < kernel.outputs() {
+            let index = p.index as usize;
+            if p.k == 0 {
+                p.acc = kernel.init(index);
+            }
+            while (p.k as usize) < kernel.inner() {
+                let end = kernel.inner().min(p.k as usize + self.granularity);
+                for k in p.k as usize..end {
+                    p.acc = kernel.mac(index, k, p.acc);
+                }
+                p.k = end as u32;
+                if end < kernel.inner() {
+                    self.store(fram, &p)?;
+                }
+            }
+
+            let value = kernel.finish(p.acc);
+            fram.write(out + index * mem::size_of::<Numeric>(), bytes_of(&value))?;
+            p = Progress {
+                index: p.index + 1,
+                k: 0,
+                acc: 0,
+            };
+            self.store(fram, &p)?;
+        }
+        Ok(())
+    }
+
+    /// `layer.forward(input, out)` with `out` a `Tensor1D<O>` at F-RAM
+    /// offset `out`.
+    pub fn matvec<D: FramDevice, const I: usize, const O: usize>(
+        &self,
+        fram: &mut D,
+        layer: &Dense<'_, I, O>,
+        input: &Tensor1D<I>,
+        out: usize,
+    ) -> Result<(), FramError> {
+        self.run(fram, &MatVec { layer, input }, out)
+    }
+
+    /// `out = requant(a * b)` with `out` a `Tensor2D<M, N>` at F-RAM offset
+    /// `out`.
+    pub fn matmul<D: FramDevice, const M: usize, const K: usize, const N: usize>(
+        &self,
+        fram: &mut D,
+        a: &Tensor2D<M, K>,
+        b: &Tensor2D<K, N>,
+        requant: Requant,
+        out: usize,
+    ) -> Result<(), FramError> {
+        self.run(fram, &MatMul { a, b, requant }, out)
+    }
+}
+
+struct MatVec<'a, 'l, const I: usize, const O: usize> {
+    layer: &'a Dense<'l, I, O>,
+    input: &'a Tensor1D<I>,
+}
+
+impl<'a, 'l, const I: usize, const O: usize> Kernel for MatVec<'a, 'l, I, O> {
+    fn outputs(&self) -> usize {
+        O
+    }
+
+    fn inner(&self) -> usize {
+        I
+    }
+
+    fn init(&self, index: usize) -> Acc {
+        self.layer.bias.map_or(0, |b| *b.at(index) as Acc)
+    }
+
+    fn mac(&self, index: usize, k: usize, acc: Acc) -> Acc {
+        mac(acc, *self.layer.weights.at(index, k), *self.input.at(k))
+    }
+
+    fn finish(&self, acc: Acc) -> Numeric {
+        self.layer.requant.apply(acc)
+    }
+}
+
+struct MatMul<'a, const M: usize, const K: usize, const N: usize> {
+    a: &'a Tensor2D<M, K>,
+    b: &'a Tensor2D<K, N>,
+    requant: Requant,
+}
+
+impl<'a, const M: usize, const K: usize, const N: usize> Kernel for MatMul<'a, M, K, N> {
+    fn outputs(&self) -> usize {
+        M * N
+    }
+
+    fn inner(&self) -> usize {
+        K
+    }
+
+    fn init(&self, _index: usize) -> Acc {
+        0
+    }
+
+    fn mac(&self, index: usize, k: usize, acc: Acc) -> Acc {
+        mac(acc, *self.a.at(index / N, k), *self.b.at(k, index % N))
+    }
+
+    fn finish(&self, acc: Acc) -> Numeric {
+        self.requant.apply(acc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fram::sim::{self, SimFram, Tear};
+    use crate::nn::dense;
+
+    const OUT: usize = 64;
+    const PROGRESS: Region = Region::new(0, PROGRESS_LEN);
+
+    fn weights() -> Tensor2D<4, 7> {
+        let mut w = Tensor2D::zeros();
+        for r in 0..4 {
+            for (c, v) in w.row_mut(r).iter_mut().enumerate() {
+                *v = (r as Numeric * 37 + c as Numeric * 11) % 23 - 11;
+            }
+        }
+        w
+    }
+
+    fn matvec(fram: &mut SimFram, granularity: usize) -> Result<(), FramError> {
+        let weights = weights();
+        let bias = Tensor1D::new([100, -50, 0, 7]);
+        let layer = Dense {
+            weights: &weights,
+            bias: Some(&bias),
+            requant: Requant::new(3, 2),
+        };
+        let input = Tensor1D::new([5, -3, 8, 1, 0, -9, 4]);
+        Resumable::new(PROGRESS, granularity).matvec(fram, &layer, &input, OUT)
+    }
+
+    fn matvec_expected() -> Tensor1D<4> {
+        let weights = weights();
+        let mut out = Tensor1D::zeros();
+        dense(
+            &weights,
+            Some(&Tensor1D::new([100, -50, 0, 7])),
+            Requant::new(3, 2),
+            &Tensor1D::new([5, -3, 8, 1, 0, -9, 4]),
+            &mut out,
+        );
+        out
+    }
+
+    #[test]
+    fn matvec_matches_dense() {
+        for granularity in 0..9 {
+            let mut fram = SimFram::new(128);
+            matvec(&mut fram, granularity).unwrap();
+            assert_eq!(read_value(&fram, OUT), Ok(*matvec_expected().as_array()));
+        }
+    }
+
+    #[test]
+    fn finished_kernel_is_not_rerun() {
+        let mut fram = SimFram::new(128);
+        matvec(&mut fram, 2).unwrap();
+        let writes = fram.writes();
+        matvec(&mut fram, 2).unwrap();
+        assert_eq!(fram.writes(), writes);
+
+        let resumable = Resumable::new(PROGRESS, 2);
+        resumable.reset(&mut fram).unwrap();
+        assert_eq!(resumable.load(&fram), Ok(Progress::default()));
+    }
+
+    #[test]
+    fn matvec_resumes_after_every_failure() {
+        for &granularity in [1, 3, 7].iter() {
+            for &tear in [Tear::Lost, Tear::LowByte, Tear::Complete].iter() {
+                sim::for_each_failure(
+                    &[0; 128],
+                    tear,
+                    |fram| matvec(fram, granularity),
+                    |fram, n| {
+                        assert_eq!(
+                            read_value(fram, OUT),
+                            Ok(*matvec_expected().as_array()),
+                            "failure at write {}",
+                            n
+                        )
+                    },
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matvec_under_random_failures() {
+        for seed in 0..20 {
+            let mut fram = SimFram::new(128);
+            fram.seed(seed);
+            sim::run_intermittently(&mut fram, 30, |fram| matvec(fram, 2));
+            assert_eq!(read_value(&fram, OUT), Ok(*matvec_expected().as_array()));
+        }
+    }
+
+    #[test]
+    fn matmul_resumes_after_every_failure() {
+        let a = Tensor2D::new([[1, -2, 3], [4, 5, -6]]);
+        let b = Tensor2D::new([[7, 8], [-9, 10], [11, 12]]);
+        let expected: [[Numeric; 2]; 2] = [[29, 12], [-41, 5]];
+        let run = |fram: &mut SimFram| {
+            Resumable::new(PROGRESS, 2).matmul(fram, &a, &b, Requant::new(1, 1), OUT)
+        };
+        sim::for_each_failure(&[0; 128], Tear::LowByte, run, |fram, _| {
+            assert_eq!(read_value(fram, OUT), Ok(expected))
+        });
+    }
+}
@@ -0,0 +1,930 @@
+//! Static execution plans for models that do not fit in SRAM.
+//!
+//! Given the layers of a model and the SRAM and F-RAM scratch set aside for
+//! it, [`plan`] decides for every layer where its input and output
+//! activations live and whether its weights are read in place from F-RAM or
+//! copied to SRAM by DMA, whole or in double-buffered tiles. It is a
+//! `const fn`, so the schedule is fixed at build time and a model that does
+//! not fit fails the build:
+//!
+//! ```ignore
+//! const LAYERS: [Layer; 2] = [
+//!     Layer::new("fc1", Op::Dense { inputs: 50, outputs: 10 }, false),
+//!     Layer::new("fc2", Op::Dense { inputs: 10, outputs: 2 }, false),
+//! ];
+//! static PLAN: Plan<2> = match plan::plan(&LAYERS, Memory { sram: 1280, fram: 0 }) {
+//!     Ok(plan) => plan,
+//!     Err(e) => e.panic(),
+//! };
+//!
+//! PLAN.run(&mut dma, [FC1.as_slice(), FC2.as_slice()], arenas, &input, &mut output, |step, ops| {
+//!     LAYERS[step]... // compute `ops.units` of the output
+//! })?;
+//! ```
+//!
+//! Activations go to one arena in SRAM and one in F-RAM, those of even
+//! layers at the bottom and those of odd layers at the top, so the input and
+//! output of a layer never overlap; in SRAM the space between them holds the
+//! weight tiles. The choices are greedy, layer by layer: the output goes to
+//! SRAM if it fits next to the input, the weights are copied whole if they
+//! fit in what is left, else in tiles of as many units (rows, filters or
+//! channels) as fit twice, else they are read in place.
+//!
+//! `tools/fram-plan` prints the plan of a model description along with the
+//! [`Traffic`] it causes on the FMC.
+
+use core::mem;
+use core::ops::Range;
+
+use crate::dma::{self, Dma, DmaError};
+use crate::tensor::Numeric;
+
+/// Bytes per element of every tensor.
+pub const ELEM: usize = mem::size_of::<Numeric>();
+
+/// What a layer computes, with the shapes the planner needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Dense {
+        inputs: usize,
+        outputs: usize,
+    },
+    /// `input` and `kernel` are `[height, width]`.
+    Conv2D {
+        filters: usize,
+        channels: usize,
+        kernel: [usize; 2],
+        input: [usize; 2],
+        stride: usize,
+        padding: usize,
+    },
+    Depthwise {
+        channels: usize,
+        kernel: [usize; 2],
+        input: [usize; 2],
+        stride: usize,
+        padding: usize,
+    },
+}
+
+// Output height or width of a convolution; 0 if there is none.
+const fn conv_out(input: usize, kernel: usize, stride: usize, padding: usize) -> usize {
+    if stride == 0 || kernel > input + 2 * padding {
+        0
+    } else {
+        (input + 2 * padding - kernel) / stride + 1
+    }
+}
+
+impl Op {
+    /// Elements of the input activation.
+    pub const fn input_len(&self) -> usize {
+        match *self {
+            Op::Dense { inputs, .. } => inputs,
+            Op::Conv2D {
+                channels, input, ..
+            }
+            | Op::Depthwise {
+                channels, input, ..
+            } => channels * input[0] * input[1],
+        }
+    }
+
+    /// `[height, width]` of the output of a convolution, `[1, 1]` for dense.
+    pub const fn output_size(&self) -> [usize; 2] {
+        match *self {
+            Op::Dense { .. } => [1, 1],
+            Op::Conv2D {
+                kernel,
+                input,
+                stride,
+                padding,
+                ..
+            }
+            | Op::Depthwise {
+                kernel,
+                input,
+                stride,
+                padding,
+                ..
+            } => [
+                conv_out(input[0], kernel[0], stride, padding),
+                conv_out(input[1], kernel[1], stride, padding),
+            ],
+        }
+    }
+
+    /// Elements of the output activation.
+    pub const fn output_len(&self) -> usize {
+        let [h, w] = self.output_size();
+        self.units() * h * w
+    }
+
+    /// Weight slices one output channel (or dense output) needs: rows,
+    /// filters or channels. Tiles are made of whole units.
+    pub const fn units(&self) -> usize {
+        match *self {
+            Op::Dense { outputs, .. } => outputs,
+            Op::Conv2D { filters, .. } => filters,
+            Op::Depthwise { channels, .. } => channels,
+        }
+    }
+
+    /// Elements of the weights per unit.
+    pub const fn unit_len(&self) -> usize {
+        match *self {
+            Op::Dense { inputs, .. } => inputs,
+            Op::Conv2D {
+                channels, kernel, ..
+            } => channels * kernel[0] * kernel[1],
+            Op::Depthwise { kernel, .. } => kernel[0] * kernel[1],
+        }
+    }
+
+    pub const fn weight_len(&self) -> usize {
+        self.units() * self.unit_len()
+    }
+
+    /// Multiply-adds; each reads one weight and one input element.
+    pub const fn macs(&self) -> usize {
+        let [h, w] = self.output_size();
+        self.weight_len() * h * w
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layer {
+    pub name: &'static str,
+    pub op: Op,
+    /// Whether the layer adds a bias, which is read from F-RAM.
+    pub bias: bool,
+}
+
+impl Layer {
+    pub const fn new(name: &'static str, op: Op, bias: bool) -> Self {
+        Layer { name, op, bias }
+    }
+}
+
+/// Scratch set aside for activations and weight tiles, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Memory {
+    pub sram: usize,
+    pub fram: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Place {
+    Sram,
+    Fram,
+}
+
+/// `len` elements at `offset` of the arena in `place`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Buffer {
+    pub place: Place,
+    pub offset: usize,
+    pub len: usize,
+}
+
+impl Buffer {
+    const fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.len
+    }
+}
+
+/// Where the kernel of a step reads its weights.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Weights {
+    /// From F-RAM, with CPU loads.
+    InPlace,
+    /// From SRAM at `offset`, copied there by DMA `units` at a time: into
+    /// one buffer if that is all of them, else alternately into two.
+    Tiled { offset: usize, units: usize },
+}
+
+/// Estimated bytes moved over the FMC.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Traffic {
+    /// Loads by the kernels: in-place weights, biases and F-RAM inputs, once
+    /// per multiply-add (ignoring padding).
+    pub cpu_reads: usize,
+    /// Weights copied to SRAM, once each.
+    pub dma_reads: usize,
+    /// Outputs stored to F-RAM.
+    pub writes: usize,
+}
+
+impl Traffic {
+    pub const fn total(&self) -> usize {
+        self.cpu_reads + self.dma_reads + self.writes
+    }
+
+    pub const fn plus(self, other: Traffic) -> Traffic {
+        Traffic {
+            cpu_reads: self.cpu_reads + other.cpu_reads,
+            dma_reads: self.dma_reads + other.dma_reads,
+            writes: self.writes + other.writes,
+        }
+    }
+}
+
+/// One layer of a [`Plan`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Step {
+    pub layer: Layer,
+    pub input: Buffer,
+    pub output: Buffer,
+    pub weights: Weights,
+    pub traffic: Traffic,
+}
+
+impl Step {
+    const EMPTY: Step = Step {
+        layer: Layer::new(
+            "",
+            Op::Dense {
+                inputs: 0,
+                outputs: 0,
+            },
+            false,
+        ),
+        input: Buffer {
+            place: Place::Sram,
+            offset: 0,
+            len: 0,
+        },
+        output: Buffer {
+            place: Place::Sram,
+            offset: 0,
+            len: 0,
+        },
+        weights: Weights::InPlace,
+        traffic: Traffic {
+            cpu_reads: 0,
+            dma_reads: 0,
+            writes: 0,
+        },
+    };
+
+    /// Elements of each weight tile buffer, 0 for in-place weights.
+    pub const fn tile_len(&self) -> usize {
+        match self.weights {
+            Weights::InPlace => 0,
+            Weights::Tiled { units, .. } => units * self.layer.op.unit_len(),
+        }
+    }
+
+    /// Number of weight tiles, 1 for in-place weights.
+    pub const fn tiles(&self) -> usize {
+        match self.weights {
+            Weights::InPlace => 1,
+            Weights::Tiled { units, .. } => self.layer.op.units().div_ceil(units),
+        }
+    }
+
+    /// Elements of SRAM the step uses.
+    pub const fn sram_len(&self) -> usize {
+        let mut len = self.tile_len() * if self.tiles() > 1 { 2 } else { 1 };
+        if let Place::Sram = self.input.place {
+            len += self.input.len;
+        }
+        if let Place::Sram = self.output.place {
+            len += self.output.len;
+        }
+        len
+    }
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Step::EMPTY
+    }
+}
+
+/// What is wrong with a layer list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlanError {
+    /// Layer `layer` has no inputs, outputs or weights, e.g. a kernel larger
+    /// than its padded input.
+    Shape { layer: usize },
+    /// The input of layer `layer` differs in size from the output before.
+    Mismatch { layer: usize },
+    /// An activation of layer `layer` fits in neither arena.
+    OutOfMemory { layer: usize },
+    /// The two weight tiles of layer `layer` do not fit in the SRAM left
+    /// free, because a single DMA transfer cannot copy its weights whole.
+    Tiles { layer: usize },
+    /// Fewer steps than layers were given to [`schedule`].
+    Steps,
+}
+
+impl PlanError {
+    /// Panics with a message naming the problem; `const` friendly.
+    pub const fn panic(self) -> ! {
+        match self {
+            PlanError::Shape { .. } => panic!("plan: layer with an empty shape"),
+            PlanError::Mismatch { .. } => {
+                panic!("plan: layer input does not match the output before")
+            }
+            PlanError::OutOfMemory { .. } => {
+                panic!("plan: activations do not fit in SRAM or F-RAM")
+            }
+            PlanError::Tiles { .. } => panic!("plan: weight tiles do not fit in SRAM"),
+            PlanError::Steps => panic!("plan: not enough steps"),
+        }
+    }
+}
+
+/// A schedule of `L` layers, see the [module docs](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Plan<const L: usize> {
+    pub steps: [Step; L],
+    /// Elements of the SRAM arena.
+    pub sram: usize,
+    /// Elements of the F-RAM arena.
+    pub fram: usize,
+}
+
+/// Plans `layers` to run in `memory`.
+pub const fn plan<const L: usize>(
+    layers: &[Layer; L],
+    memory: Memory,
+) -> Result<Plan<L>, PlanError> {
+    let mut steps = [Step::EMPTY; L];
+    match schedule(layers, memory, &mut steps) {
+        Ok(()) => Ok(Plan {
+            steps,
+            sram: memory.sram / ELEM,
+            fram: memory.fram / ELEM,
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+// Activation `index` (the input of layer `index`) of `len` elements in the
+// arena of `place`, of `arena` elements.
+const fn activation(index: usize, place: Place, len: usize, arena: usize) -> Buffer {
+    let offset = if index.is_multiple_of(2) {
+        0
+    } else {
+        arena - len
+    };
+    Buffer { place, offset, len }
+}
+
+/// [`plan`] for a layer list whose length is only known at run time.
+/// Fills the first `layers.len()` of `steps`.
+pub const fn schedule(
+    layers: &[Layer],
+    memory: Memory,
+    steps: &mut [Step],
+) -> Result<(), PlanError> {
+    if steps.len() < layers.len() {
+        return Err(PlanError::Steps);
+    }
+    let sram = memory.sram / ELEM;
+    let fram = memory.fram / ELEM;
+    if layers.is_empty() {
+        return Ok(());
+    }
+
+    let first = layers[0].op.input_len();
+    let mut input = if first <= sram {
+        activation(0, Place::Sram, first, sram)
+    } else if first <= fram {
+        activation(0, Place::Fram, first, fram)
+    } else {
+        return Err(PlanError::OutOfMemory { layer: 0 });
+    };
+
+    let mut i = 0;
+    while i < layers.len() {
+        let op = layers[i].op;
+        let out = op.output_len();
+        if op.input_len() == 0 || out == 0 || op.weight_len() == 0 {
+            return Err(PlanError::Shape { layer: i });
+        }
+        if op.input_len() != input.len {
+            return Err(PlanError::Mismatch { layer: i });
+        }
+
+        let (in_sram, in_fram) = match input.place {
+            Place::Sram => (input.len, 0),
+            Place::Fram => (0, input.len),
+        };
+        let output = if in_sram + out <= sram {
+            activation(i + 1, Place::Sram, out, sram)
+        } else if in_fram + out <= fram {
+            activation(i + 1, Place::Fram, out, fram)
+        } else {
+            return Err(PlanError::OutOfMemory { layer: i });
+        };
+
+        // SRAM between the activation at the bottom and the one at the top
+        let (bottom, top) = if i.is_multiple_of(2) {
+            (input, output)
+        } else {
+            (output, input)
+        };
+        let free_start = match bottom.place {
+            Place::Sram => bottom.len,
+            Place::Fram => 0,
+        };
+        let free_end = match top.place {
+            Place::Sram => top.offset,
+            Place::Fram => sram,
+        };
+        let free = free_end - free_start;
+        let unit_len = op.unit_len();
+        let mut units = if op.weight_len() <= free {
+            op.units()
+        } else {
+            free / (2 * unit_len)
+        };
+        if units > dma::MAX_LEN / unit_len {
+            units = dma::MAX_LEN / unit_len;
+            // split after all, so both tiles must fit
+            if 2 * units * unit_len > free {
+                return Err(PlanError::Tiles { layer: i });
+            }
+        }
+        let weights = if units == 0 {
+            Weights::InPlace
+        } else {
+            Weights::Tiled {
+                offset: free_start,
+                units,
+            }
+        };
+
+        let mut traffic = Traffic {
+            cpu_reads: 0,
+            dma_reads: 0,
+            writes: 0,
+        };
+        match weights {
+            Weights::InPlace => traffic.cpu_reads += op.macs() * ELEM,
+            Weights::Tiled { .. } => traffic.dma_reads += op.weight_len() * ELEM,
+        }
+        if layers[i].bias {
+            traffic.cpu_reads += out * ELEM;
+        }
+        if let Place::Fram = input.place {
+            traffic.cpu_reads += op.macs() * ELEM;
+        }
+        if let Place::Fram = output.place {
+            traffic.writes += out * ELEM;
+        }
+
+        steps[i] = Step {
+            layer: layers[i],
+            input,
+            output,
+            weights,
+            traffic,
+        };
+        input = output;
+        i += 1;
+    }
+    Ok(())
+}
+
+/// The memory a [`Plan`] runs in. `sram` and `fram` must have at least
+/// [`Plan::sram`] and [`Plan::fram`] elements; `fram` should be an F-RAM
+/// static, e.g. a [`FramArea`](crate::fram::FramArea).
+pub struct Arenas<'a> {
+    pub sram: &'a mut [Numeric],
+    pub fram: &'a mut [Numeric],
+}
+
+/// What the kernel of a step gets for one tile.
+pub struct Operands<'a> {
+    /// Units (rows, filters or channels) of the output to compute.
+    pub units: Range<usize>,
+    /// The weights of those units, in SRAM or F-RAM.
+    pub weights: &'a [Numeric],
+    pub input: &'a [Numeric],
+    /// All of the output of the layer.
+    pub output: &'a mut [Numeric],
+}
+
+// Disjoint `ranges` of `arena`, in the order given. Empty ranges may be
+// anywhere.
+fn carve<const N: usize>(arena: &mut [Numeric], ranges: [Range<usize>; N]) -> [&mut [Numeric]; N] {
+    let mut order = [0; N];
+    for (i, o) in order.iter_mut().enumerate() {
+        *o = i;
+    }
+    order.sort_unstable_by_key(|&i| (ranges[i].start, ranges[i].end));
+    let mut parts: [Option<&mut [Numeric]>; N] = [const { None }; N];
+    let mut rest = arena;
+    let mut at = 0;
+    for &i in order.iter() {
+        let range = &ranges[i];
+        if range.is_empty() {
+            parts[i] = Some(&mut []);
+            continue;
+        }
+        assert!(range.start >= at, "overlapping buffers");
+        let (_, tail) = rest.split_at_mut(range.start - at);
+        let (part, tail) = tail.split_at_mut(range.len());
+        parts[i] = Some(part);
+        rest = tail;
+        at = range.end;
+    }
+    parts.map(Option::unwrap)
+}
+
+impl<const L: usize> Plan<L> {
+    pub fn traffic(&self) -> Traffic {
+        self.steps
+            .iter()
+            .fold(Traffic::default(), |sum, step| sum.plus(step.traffic))
+    }
+
+    /// Runs the steps in order. `input` is copied to the first activation and
+    /// the last one to `output`; in between, `kernel(step, operands)` is
+    /// called once per weight tile of each step to compute those units.
+    /// `weights` are those of the layers, in F-RAM or flash.
+    pub fn run<E, F>(
+        &self,
+        dma: &mut Dma,
+        weights: [&[Numeric]; L],
+        arenas: Arenas<'_>,
+        input: &[Numeric],
+        output: &mut [Numeric],
+        mut kernel: F,
+    ) -> Result<(), E>
+    where
+        E: From<DmaError>,
+        F: FnMut(usize, Operands<'_>) -> Result<(), E>,
+    {
+        assert!(
+            arenas.sram.len() >= self.sram && arenas.fram.len() >= self.fram,
+            "arena size"
+        );
+        let (first, last) = match (self.steps.first(), self.steps.last()) {
+            (Some(first), Some(last)) => (first.input, last.output),
+            _ => {
+                output.copy_from_slice(input);
+                return Ok(());
+            }
+        };
+        let arena = match first.place {
+            Place::Sram => &mut *arenas.sram,
+            Place::Fram => &mut *arenas.fram,
+        };
+        dma.copy(input, &mut arena[first.range()])?;
+
+        for (i, step) in self.steps.iter().enumerate() {
+            let op = step.layer.op;
+            assert_eq!(weights[i].len(), op.weight_len(), "weights of step {}", i);
+            let in_sram = |b: Buffer| {
+                if b.place == Place::Sram {
+                    b.range()
+                } else {
+                    0..0
+                }
+            };
+            let in_fram = |b: Buffer| {
+                if b.place == Place::Fram {
+                    b.range()
+                } else {
+                    0..0
+                }
+            };
+            let tiles = match step.weights {
+                Weights::InPlace => 0..0,
+                Weights::Tiled { offset, .. } => {
+                    offset..offset + step.tile_len() * if step.tiles() > 1 { 2 } else { 1 }
+                }
+            };
+            let [s_in, s_out, tiles] = carve(
+                &mut *arenas.sram,
+                [in_sram(step.input), in_sram(step.output), tiles],
+            );
+            let [f_in, f_out] = carve(
+                &mut *arenas.fram,
+                [in_fram(step.input), in_fram(step.output)],
+            );
+            let (from, to) = match (step.input.place, step.output.place) {
+                (Place::Sram, Place::Sram) => (&*s_in, s_out),
+                (Place::Sram, Place::Fram) => (&*s_in, f_out),
+                (Place::Fram, Place::Sram) => (&*f_in, s_out),
+                (Place::Fram, Place::Fram) => (&*f_in, f_out),
+            };
+
+            match step.weights {
+                Weights::InPlace => kernel(
+                    i,
+                    Operands {
+                        units: 0..op.units(),
+                        weights: weights[i],
+                        input: from,
+                        output: to,
+                    },
+                )?,
+                Weights::Tiled { .. } => {
+                    let (buffer, other) = tiles.split_at_mut(step.tile_len());
+                    let unit_len = op.unit_len();
+                    dma::stream(dma, weights[i], buffer, other, |at, tile| {
+                        let start = at / unit_len;
+                        kernel(
+                            i,
+                            Operands {
+                                units: start..start + tile.len() / unit_len,
+                                weights: tile,
+                                input: from,
+                                output: &mut *to,
+                            },
+                        )
+                    })?
+                }
+            }
+        }
+
+        let arena = match last.place {
+            Place::Sram => &*arenas.sram,
+            Place::Fram => &*arenas.fram,
+        };
+        dma.copy(&arena[last.range()], output)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "arm")))]
+mod test {
+    use super::*;
+    use crate::dma::Channel;
+    use crate::nn::{Dense, Requant};
+    use crate::tensor::{Tensor1D, Tensor2D};
+
+    const fn dense(inputs: usize, outputs: usize) -> Op {
+        Op::Dense { inputs, outputs }
+    }
+
+    const LAYERS: [Layer; 2] = [
+        Layer::new("fc1", dense(4, 6), true),
+        Layer::new("fc2", dense(6, 3), false),
+    ];
+
+    // fails the build if it does not fit
+    const SMALL: Plan<2> = match plan(
+        &LAYERS,
+        Memory {
+            sram: 4 * 20,
+            fram: 4 * 8,
+        },
+    ) {
+        Ok(plan) => plan,
+        Err(e) => e.panic(),
+    };
+
+    #[test]
+    fn shapes() {
+        let conv = Op::Conv2D {
+            filters: 4,
+            channels: 3,
+            kernel: [3, 3],
+            input: [8, 10],
+            stride: 2,
+            padding: 1,
+        };
+        assert_eq!(conv.input_len(), 3 * 8 * 10);
+        assert_eq!(conv.output_size(), [4, 5]);
+        assert_eq!((conv.units(), conv.unit_len()), (4, 27));
+        assert_eq!(conv.macs(), 4 * 27 * 20);
+        let dw = Op::Depthwise {
+            channels: 2,
+            kernel: [5, 5],
+            input: [4, 4],
+            stride: 1,
+            padding: 0,
+        };
+        assert_eq!(dw.output_len(), 0);
+        assert_eq!(
+            plan(
+                &[Layer::new("dw", dw, false)],
+                Memory {
+                    sram: 1024,
+                    fram: 0
+                }
+            ),
+            Err(PlanError::Shape { layer: 0 })
+        );
+        assert_eq!(
+            plan(
+                &[LAYERS[1], LAYERS[0]],
+                Memory {
+                    sram: 1024,
+                    fram: 0
+                }
+            ),
+            Err(PlanError::Mismatch { layer: 1 })
+        );
+    }
+
+    #[test]
+    fn places_what_fits() {
+        // everything fits: whole weights between the activations
+        let roomy = plan(
+            &LAYERS,
+            Memory {
+                sram: 4 * 64,
+                fram: 0,
+            },
+        )
+        .unwrap();
+        let step = roomy.steps[0];
+        assert_eq!(
+            step.input,
+            Buffer {
+                place: Place::Sram,
+                offset: 0,
+                len: 4
+            }
+        );
+        assert_eq!(
+            step.output,
+            Buffer {
+                place: Place::Sram,
+                offset: 58,
+                len: 6
+            }
+        );
+        assert_eq!(
+            step.weights,
+            Weights::Tiled {
+                offset: 4,
+                units: 6
+            }
+        );
+        assert_eq!(step.tiles(), 1);
+        assert_eq!(roomy.steps[1].output.offset, 0);
+        assert_eq!(
+            roomy.steps[1].weights,
+            Weights::Tiled {
+                offset: 3,
+                units: 3
+            }
+        );
+        assert_eq!(
+            roomy.traffic(),
+            Traffic {
+                cpu_reads: 6 * 4,
+                dma_reads: (24 + 18) * 4,
+                writes: 0
+            }
+        );
+
+        // 10 free elements: fc1 in tiles of one row, fc2 in place
+        let step = SMALL.steps[0];
+        assert_eq!(
+            step.weights,
+            Weights::Tiled {
+                offset: 4,
+                units: 1
+            }
+        );
+        assert_eq!((step.tile_len(), step.tiles(), step.sram_len()), (4, 6, 18));
+        let step = SMALL.steps[1];
+        assert_eq!(step.weights, Weights::InPlace);
+        assert_eq!(step.traffic.cpu_reads, 18 * 4);
+
+        // no room for the output next to the input
+        let tight = plan(
+            &LAYERS,
+            Memory {
+                sram: 4 * 8,
+                fram: 4 * 8,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            tight.steps[0].output,
+            Buffer {
+                place: Place::Fram,
+                offset: 2,
+                len: 6
+            }
+        );
+        assert_eq!(tight.steps[0].traffic.writes, 6 * 4);
+        // its input is read from F-RAM once per multiply-add
+        assert_eq!(tight.steps[1].input.place, Place::Fram);
+        assert_eq!(tight.steps[1].traffic.cpu_reads, 2 * 18 * 4);
+        assert_eq!(
+            plan(
+                &LAYERS,
+                Memory {
+                    sram: 4 * 8,
+                    fram: 4 * 4
+                }
+            ),
+            Err(PlanError::OutOfMemory { layer: 0 })
+        );
+
+        // room for the weights whole but not in two DMA-sized tiles
+        let big = [Layer::new(
+            "big",
+            Op::Dense {
+                inputs: 1,
+                outputs: 70000,
+            },
+            false,
+        )];
+        assert_eq!(
+            plan(
+                &big,
+                Memory {
+                    sram: 4 * (1 + 2 * 70000),
+                    fram: 0
+                }
+            ),
+            Err(PlanError::Tiles { layer: 0 })
+        );
+    }
+
+    #[test]
+    fn runs_like_dense() {
+        let w1 = Tensor2D::<6, 4>::new([
+            [3, -1, 2, 0],
+            [1, 1, 1, 1],
+            [-2, 4, 0, 1],
+            [0, 0, 5, -5],
+            [7, 1, -1, 2],
+            [1, 2, 3, 4],
+        ]);
+        let b1 = Tensor1D::new([4, 0, -8, 1, 2, 3]);
+        let w2 =
+            Tensor2D::<3, 6>::new([[1, -1, 2, 0, 1, 1], [-3, 2, 1, 1, 0, 0], [0, 0, 0, 1, 1, 1]]);
+        let fc1 = Dense {
+            weights: &w1,
+            bias: Some(&b1),
+            requant: Requant::new(5, 3),
+        };
+        let fc2 = Dense {
+            weights: &w2,
+            bias: None,
+            requant: Requant::new(1, 1),
+        };
+        let input = Tensor1D::new([120, -7, 33, 90]);
+        let mut hidden = Tensor1D::zeros();
+        fc1.forward(&input, &mut hidden);
+        let mut expected = Tensor1D::zeros();
+        fc2.forward(&hidden, &mut expected);
+
+        let mut dma = Dma::new(Channel::C1);
+        let memories = [
+            Memory {
+                sram: 4 * 64,
+                fram: 0,
+            },
+            Memory {
+                sram: 4 * 20,
+                fram: 4 * 8,
+            },
+            Memory {
+                sram: 4 * 8,
+                fram: 4 * 8,
+            },
+        ];
+        for memory in memories.iter() {
+            let plan = plan(&LAYERS, *memory).unwrap();
+            let mut sram = vec![0; plan.sram];
+            let mut fram = vec![0; plan.fram];
+            let mut output = [0; 3];
+            let mut calls = 0;
+            plan.run(
+                &mut dma,
+                [w1.as_slice(), w2.as_slice()],
+                Arenas {
+                    sram: &mut sram,
+                    fram: &mut fram,
+                },
+                input.as_array(),
+                &mut output,
+                |step, ops| {
+                    calls += 1;
+                    let layer: &dyn Rows = if step == 0 { &fc1 } else { &fc2 };
+                    layer.rows(ops.units.start, ops.weights, ops.input, ops.output);
+                    Ok::<_, DmaError>(())
+                },
+            )
+            .unwrap();
+            assert_eq!(&output, expected.as_array(), "{:?}", memory);
+            let tiles: usize = plan.steps.iter().map(Step::tiles).sum();
+            assert_eq!(calls, tiles);
+        }
+    }
+
+    trait Rows {
+        fn rows(&self, first: usize, rows: &[Numeric], input: &[Numeric], output: &mut [Numeric]);
+    }
+
+    impl<const I: usize, const O: usize> Rows for Dense<'_, I, O> {
+        fn rows(&self, first: usize, rows: &[Numeric], input: &[Numeric], output: &mut [Numeric]) {
+            self.forward_rows(first, rows, input, output)
+        }
+    }
+}
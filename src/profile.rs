@@ -0,0 +1,340 @@
+//! Per-object access profiling, compiled in with the `profile` feature.
+//!
+//! Each object of interest gets a static [`Object`] that is [`register`]ed
+//! with the tensor it describes. Every element access through a tensor
+//! accessor (`at`, `mut_at`, [`Matrix`](crate::tensor::Matrix)/
+//! [`Vector`](crate::tensor::Vector) `get`, and one per element for those
+//! that hand out a whole row or array) is then counted against the object
+//! that contains it, and so is every read or write of a
+//! [`MappedFram`](crate::fram::MappedFram). Their cost in core cycles is
+//! added up:
+//!
+//! * on the board, the element is read once more between two reads of the
+//!   DWT cycle counter, so the cost is the measured FMC (or SRAM) latency;
+//! * on the host, a modeled cost per access from [`HOST_COST`] is used.
+//!
+//! [`report`] writes a table of the counts and cycles, and on the board the
+//! share of all cycles since [`start`] that went to the accesses.
+//!
+//! ```ignore
+//! static PARAM_1_PROFILE: Object = Object::new("PARAM_1", Location::Fram);
+//!
+//! profile::register(&PARAM_1_PROFILE, &PARAM_1);
+//! profile::start();
+//! // ... inference ...
+//! profile::report(&mut hstdout).unwrap();
+//! ```
+
+use core::fmt;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+
+#[cfg(target_arch = "arm")]
+use cortex_m::peripheral::{DCB, DWT};
+
+/// Number of objects that can be registered.
+pub const MAX_OBJECTS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    Fram,
+    Sram,
+}
+
+/// Modeled cycles per access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cost {
+    /// Per 16-bit FMC transaction.
+    pub fram: u32,
+    /// Per element.
+    pub sram: u32,
+}
+
+/// What the host assumes: a mode A transaction with the board's F-RAM
+/// timings at 16 MHz (no address setup, 2 cycles data setup, 2 more for the
+/// AHB) and single-cycle SRAM.
+pub const HOST_COST: Cost = Cost { fram: 4, sram: 1 };
+
+/// Access statistics of one named object. Counters are 32 bits wide; the
+/// cycle count wraps after 2^32 cycles.
+pub struct Object {
+    name: &'static str,
+    location: Location,
+    start: AtomicUsize,
+    len: AtomicUsize,
+    reads: AtomicU32,
+    writes: AtomicU32,
+    cycles: AtomicU32,
+}
+
+impl Object {
+    pub const fn new(name: &'static str, location: Location) -> Self {
+        Object {
+            name,
+            location,
+            start: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            reads: AtomicU32::new(0),
+            writes: AtomicU32::new(0),
+            cycles: AtomicU32::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn reads(&self) -> u32 {
+        self.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn writes(&self) -> u32 {
+        self.writes.load(Ordering::Relaxed)
+    }
+
+    pub fn cycles(&self) -> u32 {
+        self.cycles.load(Ordering::Relaxed)
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        let start = self.start.load(Ordering::Relaxed);
+        addr >= start && addr - start < self.len.load(Ordering::Relaxed)
+    }
+
+    fn clear(&self) {
+        self.reads.store(0, Ordering::Relaxed);
+        self.writes.store(0, Ordering::Relaxed);
+        self.cycles.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Accesses outside every registered object.
+pub static OTHER: Object = Object::new("(other)", Location::Sram);
+
+static OBJECTS: [AtomicPtr<Object>; MAX_OBJECTS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_OBJECTS];
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Attributes accesses to `value` to `object` from now on. Returns `false`
+/// if all [`MAX_OBJECTS`] slots are taken.
+pub fn register<T>(object: &'static Object, value: &T) -> bool {
+    object
+        .start
+        .store(value as *const T as usize, Ordering::Relaxed);
+    object
+        .len
+        .store(core::mem::size_of::<T>(), Ordering::Relaxed);
+    let slot = COUNT.fetch_add(1, Ordering::AcqRel);
+    if slot >= MAX_OBJECTS {
+        COUNT.fetch_sub(1, Ordering::AcqRel);
+        return false;
+    }
+    OBJECTS[slot].store(object as *const Object as *mut Object, Ordering::Release);
+    true
+}
+
+fn objects() -> impl Iterator<Item = &'static Object> {
+    let count = COUNT.load(Ordering::Acquire).min(MAX_OBJECTS);
+    OBJECTS[..count].iter().filter_map(|slot| {
+        // only `&'static Object`s are stored
+        unsafe { slot.load(Ordering::Acquire).as_ref() }
+    })
+}
+
+fn object_at(addr: usize) -> &'static Object {
+    objects().find(|o| o.contains(addr)).unwrap_or(&OTHER)
+}
+
+fn count(object: &Object, write: bool, cycles: u32) {
+    let counter = if write { &object.writes } else { &object.reads };
+    counter.fetch_add(1, Ordering::Relaxed);
+    object.cycles.fetch_add(cycles, Ordering::Relaxed);
+}
+
+pub(crate) fn memory<T>(write: bool, value: *const T) {
+    let object = object_at(value as usize);
+    count(object, write, cost(object, value));
+}
+
+/// One access of `len` bytes at `addr` made through a device rather than a
+/// tensor, e.g. a [`MappedFram`](crate::fram::MappedFram) write of a
+/// kernel's output. Only `MappedFram` calls it, so only on the board.
+#[cfg(any(target_arch = "arm", test))]
+pub(crate) fn bytes(write: bool, addr: *const u8, len: usize) {
+    let object = object_at(addr as usize);
+    count(object, write, bytes_cost(object, addr, len));
+}
+
+// Half-words `addr..addr + len` spans on the 16-bit bus.
+fn halves(addr: *const u8, len: usize) -> usize {
+    let first = addr as usize & !1;
+    (addr as usize + len - first).div_ceil(2)
+}
+
+#[cfg(target_arch = "arm")]
+fn cost<T>(_object: &Object, value: *const T) -> u32 {
+    let before = DWT::cycle_count();
+    let copy = unsafe { ptr::read_volatile(value) };
+    let cycles = DWT::cycle_count().wrapping_sub(before);
+    core::mem::forget(copy);
+    cycles.saturating_sub(OVERHEAD.load(Ordering::Relaxed))
+}
+
+#[cfg(target_arch = "arm")]
+fn bytes_cost(_object: &Object, addr: *const u8, len: usize) -> u32 {
+    let first = (addr as usize & !1) as *const u16;
+    let before = DWT::cycle_count();
+    for i in 0..halves(addr, len) {
+        unsafe { ptr::read_volatile(first.add(i)) };
+    }
+    let cycles = DWT::cycle_count().wrapping_sub(before);
+    cycles.saturating_sub(OVERHEAD.load(Ordering::Relaxed))
+}
+
+#[cfg(not(target_arch = "arm"))]
+fn cost<T>(object: &Object, value: *const T) -> u32 {
+    bytes_cost(object, value as *const u8, core::mem::size_of::<T>())
+}
+
+#[cfg(not(target_arch = "arm"))]
+fn bytes_cost(object: &Object, addr: *const u8, len: usize) -> u32 {
+    match object.location {
+        Location::Fram => HOST_COST.fram * halves(addr, len) as u32,
+        Location::Sram => HOST_COST.sram,
+    }
+}
+
+// Cycles the two counter reads in `cost` take by themselves.
+#[cfg(target_arch = "arm")]
+static OVERHEAD: AtomicU32 = AtomicU32::new(0);
+#[cfg(target_arch = "arm")]
+static STARTED_AT: AtomicU32 = AtomicU32::new(0);
+
+/// Turns on the cycle counter.
+#[cfg(target_arch = "arm")]
+pub fn enable(dcb: &mut DCB, dwt: &mut DWT) {
+    dcb.enable_trace();
+    dwt.enable_cycle_counter();
+    let before = DWT::cycle_count();
+    let overhead = DWT::cycle_count().wrapping_sub(before);
+    OVERHEAD.store(overhead, Ordering::Relaxed);
+}
+
+/// Clears all counters and starts the period [`report`] covers.
+pub fn start() {
+    OTHER.clear();
+    for object in objects() {
+        object.clear();
+    }
+    #[cfg(target_arch = "arm")]
+    STARTED_AT.store(DWT::cycle_count(), Ordering::Relaxed);
+}
+
+/// Writes one line per object with accesses, then the totals.
+pub fn report<W: fmt::Write>(out: &mut W) -> fmt::Result {
+    writeln!(
+        out,
+        "{:<16} {:>4} {:>10} {:>10} {:>12}",
+        "object", "mem", "reads", "writes", "cycles"
+    )?;
+    let mut accesses: u64 = 0;
+    for object in objects().chain(Some(&OTHER)) {
+        if object.reads() == 0 && object.writes() == 0 {
+            continue;
+        }
+        let location = match object.location {
+            Location::Fram => "fram",
+            Location::Sram => "sram",
+        };
+        writeln!(
+            out,
+            "{:<16} {:>4} {:>10} {:>10} {:>12}",
+            object.name,
+            location,
+            object.reads(),
+            object.writes(),
+            object.cycles()
+        )?;
+        accesses += object.cycles() as u64;
+    }
+
+    #[cfg(target_arch = "arm")]
+    {
+        let total = DWT::cycle_count().wrapping_sub(STARTED_AT.load(Ordering::Relaxed));
+        let percent = (accesses * 100).checked_div(total as u64).unwrap_or(0);
+        writeln!(
+            out,
+            "{} of {} cycles in accesses ({}%)",
+            accesses, total, percent
+        )
+    }
+    #[cfg(not(target_arch = "arm"))]
+    writeln!(out, "{} modeled cycles in accesses", accesses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fmc::timing::DeviceTimings;
+    use crate::nn::{dense, Requant};
+    use crate::tensor::{Tensor1D, Tensor2D};
+
+    static WEIGHTS: Object = Object::new("weights", Location::Fram);
+    static OUTPUT: Object = Object::new("output", Location::Sram);
+    static HIDDEN: Object = Object::new("hidden", Location::Fram);
+
+    #[test]
+    fn host_cost_follows_the_board_timings() {
+        // `FRAM_TIMINGS` in main.rs, the FM22L16, at the 16 MHz HCLK
+        let fm22l16 = DeviceTimings {
+            address_setup_ns: 0,
+            address_hold_ns: 20,
+            data_setup_ns: 55,
+            cycle_time_ns: 110,
+            bus_turnaround_ns: 15,
+        };
+        let t = fm22l16.to_timing(16_000_000).unwrap();
+        assert_eq!(HOST_COST.fram, t.addset as u32 + t.datast as u32 + 2);
+    }
+
+    // The only test that registers objects or resets counters.
+    #[test]
+    fn counts_accesses_per_object() {
+        let weights = Tensor2D::<2, 3>::new([[1, 2, 3], [-1, 0, 1]]);
+        let input = Tensor1D::new([4, 5, 6]);
+        let mut output = Tensor1D::<2>::zeros();
+        let hidden = Tensor1D::<2>::zeros();
+        assert!(register(&WEIGHTS, &weights));
+        assert!(register(&OUTPUT, &output));
+        assert!(register(&HIDDEN, &hidden));
+        start();
+
+        dense(&weights, None, Requant::IDENTITY, &input, &mut output);
+        *output.mut_at(1) = 0;
+        output.at(0);
+        // what a `MappedFram` write of the second element reports
+        let at = (&hidden as *const Tensor1D<2>).cast::<u8>();
+        bytes(true, at.wrapping_add(4), 4);
+
+        assert_eq!((WEIGHTS.reads(), WEIGHTS.writes()), (6, 0));
+        // two half-words per element
+        assert_eq!(WEIGHTS.cycles(), 6 * 2 * HOST_COST.fram);
+        // dense writes both outputs
+        assert_eq!((OUTPUT.reads(), OUTPUT.writes()), (1, 2 + 1));
+        assert_eq!(OUTPUT.cycles(), 4 * HOST_COST.sram);
+        assert_eq!((HIDDEN.reads(), HIDDEN.writes()), (0, 1));
+        assert_eq!(HIDDEN.cycles(), 2 * HOST_COST.fram);
+
+        let mut text = String::new();
+        report(&mut text).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[1].split_whitespace().collect::<Vec<_>>(),
+            ["weights", "fram", "6", "0", "48"]
+        );
+        assert_eq!(
+            lines[2].split_whitespace().collect::<Vec<_>>(),
+            ["output", "sram", "1", "3", "4"]
+        );
+    }
+}
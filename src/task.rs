@@ -0,0 +1,422 @@
+//! Task-based intermittent execution.
+//!
+//! Instead of snapshotting the whole machine like [`checkpoint`], the program
+//! is split into tasks: plain functions that run to completion and return the
+//! task to run next. Everything a task shares with other tasks or with its own
+//! next run lives in F-RAM and is accessed through a [`Var`], via the
+//! [`TaskCtx`] the task is given.
+//!
+//! A task's writes to `Var`s are privatized: they go to a redo log and only
+//! reach their variables, together with the next task id, when the task
+//! returns. A power failure before that throws them away and the task starts
+//! over on the next boot, seeing exactly the values it saw the first time; so
+//! every task is idempotent no matter in which order it reads and writes.
+//!
+//! State layout:
+//!
+//! ```text
+//! | state: u16 | task: u16 | next: u16 | count: u16 | entry 0 | entry 1 | ...
+//! entry: | offset: u32 | len: u16 | pad: u16 | new bytes (len, padded to 2) |
+//! ```
+//!
+//! Setting `state` to `COMMITTING` is the commit point of a task. From then on
+//! the entries are replayed until `task` has been advanced to `next` and
+//! `state` is back to `IDLE`, across as many reboots as it takes. Like the
+//! undo log this relies on a half-word store landing whole or byte by byte.
+//!
+//! [`checkpoint`]: crate::checkpoint
+
+use core::marker::PhantomData;
+use core::mem;
+
+use crate::fram::{bytes_of, check_access, read_value, FramDevice, FramError, Region};
+
+const IDLE: u16 = 0;
+const COMMITTING: u16 = 0x7a5c;
+const HEADER_LEN: usize = 8;
+const ENTRY_HEADER_LEN: usize = 8;
+
+/// Index of a task in the task table.
+pub type TaskId = u16;
+
+/// Task id recorded once the graph has returned [`Next::Done`].
+const DONE: TaskId = u16::MAX;
+
+/// A task: runs to completion and names its successor.
+pub type Task<D> = fn(&mut TaskCtx<'_, D>) -> Result<Next, TaskError>;
+
+/// Where control goes when a task returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Next {
+    Task(TaskId),
+    /// The graph is finished; [`Scheduler::run`] returns.
+    Done,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskError {
+    /// A task wrote more than fits in the redo log.
+    LogFull,
+    /// The recorded or returned task id is not in the task table.
+    UnknownTask(TaskId),
+    /// The variable is not in F-RAM.
+    NotInFram,
+    Fram(FramError),
+}
+
+impl From<FramError> for TaskError {
+    fn from(e: FramError) -> Self {
+        TaskError::Fram(e)
+    }
+}
+
+/// A `T` at a fixed F-RAM offset, shared between tasks.
+///
+/// A `Var` written by one task and read by the next is the channel between
+/// them. `T` should have no padding, see [`bytes_of`].
+pub struct Var<T> {
+    offset: usize,
+    _type: PhantomData<T>,
+}
+
+impl<T> Clone for Var<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Var<T> {}
+
+impl<T: Copy> Var<T> {
+    pub const fn at(offset: usize) -> Self {
+        Var {
+            offset,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The variable stored in `place`, a `.fram_data` static.
+    #[cfg(target_arch = "arm")]
+    pub fn of(fram: &crate::fram::MappedFram, place: *const T) -> Result<Self, TaskError> {
+        fram.offset_of(place)
+            .map(Var::at)
+            .ok_or(TaskError::NotInFram)
+    }
+}
+
+/// Runs task graphs, keeping its state and redo log in an F-RAM region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scheduler {
+    region: Region,
+}
+
+impl Scheduler {
+    /// An all-zero region starts the graph at task 0.
+    pub const fn new(region: Region) -> Self {
+        Scheduler { region }
+    }
+
+    /// The task that runs next, `None` once the graph is done. Only
+    /// meaningful after [`recover`](Self::recover).
+    pub fn current<D: FramDevice>(&self, fram: &D) -> Result<Option<TaskId>, FramError> {
+        match fram.read_u16(self.region.offset + 2)? {
+            DONE => Ok(None),
+            task => Ok(Some(task)),
+        }
+    }
+
+    /// Finishes committing a task that was cut short after its commit point.
+    /// Safe to interrupt and run again.
+    pub fn recover<D: FramDevice>(&self, fram: &mut D) -> Result<(), FramError> {
+        if fram.read_u16(self.region.offset)? != COMMITTING {
+            return Ok(());
+        }
+        let count = fram.read_u16(self.region.offset + 6)?;
+        let mut at = self.region.offset + HEADER_LEN;
+        let mut buf = [0u8; 32];
+        for _ in 0..count {
+            let target = fram.read_u32(at)? as usize;
+            let len = fram.read_u16(at + 4)? as usize;
+            let mut done = 0;
+            while done < len {
+                let n = buf.len().min(len - done);
+                fram.read(at + ENTRY_HEADER_LEN + done, &mut buf[..n])?;
+                fram.write(target + done, &buf[..n])?;
+                done += n;
+            }
+            at += entry_len(len);
+        }
+        let next = fram.read_u16(self.region.offset + 4)?;
+        fram.write_u16(self.region.offset + 2, next)?;
+        fram.flush();
+        fram.write_u16(self.region.offset, IDLE)?;
+        fram.flush();
+        Ok(())
+    }
+
+    /// Runs tasks from the recorded one until one returns [`Next::Done`].
+    ///
+    /// A task that returns an error has its writes discarded and stays the
+    /// current task; the error is returned.
+    pub fn run<D: FramDevice>(&self, fram: &mut D, tasks: &[Task<D>]) -> Result<(), TaskError> {
+        self.recover(fram)?;
+        while let Some(id) = self.current(fram)? {
+            let task = *tasks.get(id as usize).ok_or(TaskError::UnknownTask(id))?;
+            let mut ctx = TaskCtx {
+                sched: self,
+                fram: &mut *fram,
+                task: id,
+                count: 0,
+                tail: self.region.offset + HEADER_LEN,
+            };
+            let next = match task(&mut ctx)? {
+                Next::Task(next) if next as usize >= tasks.len() => {
+                    return Err(TaskError::UnknownTask(next))
+                }
+                Next::Task(next) => next,
+                Next::Done => DONE,
+            };
+            ctx.commit(next)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the next [`run`](Self::run) start over at task 0. `Var`s keep
+    /// their values.
+    pub fn reset<D: FramDevice>(&self, fram: &mut D) -> Result<(), FramError> {
+        self.recover(fram)?;
+        fram.write_u16(self.region.offset + 2, 0)?;
+        fram.flush();
+        Ok(())
+    }
+}
+
+fn entry_len(len: usize) -> usize {
+    ENTRY_HEADER_LEN + len.div_ceil(2) * 2
+}
+
+/// What a running task sees of the F-RAM.
+pub struct TaskCtx<'a, D: FramDevice> {
+    sched: &'a Scheduler,
+    fram: &'a mut D,
+    task: TaskId,
+    count: u16,
+    tail: usize,
+}
+
+impl<'a, D: FramDevice> TaskCtx<'a, D> {
+    /// The running task.
+    pub fn task(&self) -> TaskId {
+        self.task
+    }
+
+    /// Direct read access, for data no task writes (model parameters, say).
+    pub fn fram(&self) -> &D {
+        self.fram
+    }
+
+    // Log entry holding this task's value of `var`, if it has set it.
+    fn find<T>(&self, var: Var<T>) -> Result<Option<usize>, FramError> {
+        let mut at = self.sched.region.offset + HEADER_LEN;
+        for _ in 0..self.count {
+            let len = self.fram.read_u16(at + 4)? as usize;
+            if self.fram.read_u32(at)? as usize == var.offset && len == mem::size_of::<T>() {
+                return Ok(Some(at + ENTRY_HEADER_LEN));
+            }
+            at += entry_len(len);
+        }
+        Ok(None)
+    }
+
+    /// The value of `var`, including what this task has set it to.
+    pub fn get<T: Copy>(&self, var: Var<T>) -> Result<T, TaskError> {
+        let at = self.find(var)?.unwrap_or(var.offset);
+        Ok(read_value(self.fram, at)?)
+    }
+
+    /// Sets `var` once the task returns.
+    pub fn set<T: Copy>(&mut self, var: Var<T>, value: T) -> Result<(), TaskError> {
+        let data = bytes_of(&value);
+        if let Some(at) = self.find(var)? {
+            self.fram.write(at, data)?;
+            return Ok(());
+        }
+
+        let entry = entry_len(data.len());
+        if self.tail + entry > self.sched.region.end() || data.len() > u16::MAX as usize {
+            return Err(TaskError::LogFull);
+        }
+        // bounds-check the target now rather than while committing
+        check_access(self.fram.size(), var.offset, data.len(), 1)?;
+
+        self.fram.write_u32(self.tail, var.offset as u32)?;
+        self.fram.write_u32(self.tail + 4, data.len() as u32)?;
+        self.fram.write(self.tail + ENTRY_HEADER_LEN, data)?;
+        self.tail += entry;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn commit(self, next: TaskId) -> Result<(), FramError> {
+        let header = self.sched.region.offset;
+        self.fram.write_u16(header + 4, next)?;
+        self.fram.write_u16(header + 6, self.count)?;
+        self.fram.flush();
+        self.fram.write_u16(header, COMMITTING)?;
+        self.fram.flush();
+        self.sched.recover(self.fram)
+    }
+}
+
+#[cfg(target_arch = "arm")]
+mod global {
+    use core::ptr;
+
+    use super::{Scheduler, Task, TaskError};
+    use crate::fram::{FramArea, MappedFram};
+
+    /// Size of the state and redo log used by [`run`].
+    pub const TASK_LOG_SIZE: usize = 512;
+
+    #[link_section = ".fram_data"]
+    static mut TASK_AREA: FramArea<TASK_LOG_SIZE> = FramArea::new();
+
+    /// Runs `tasks` on the F-RAM, resuming at the task that was running when
+    /// power last failed:
+    ///
+    /// ```ignore
+    /// static TASKS: [Task<MappedFram>; 2] = [init, step];
+    /// task::run(&TASKS)?;
+    /// ```
+    pub fn run(tasks: &[Task<MappedFram>]) -> Result<(), TaskError> {
+        let mut fram = unsafe { MappedFram::new() };
+        let region = fram.region_of(ptr::addr_of!(TASK_AREA)).unwrap();
+        Scheduler::new(region).run(&mut fram, tasks)
+    }
+}
+
+#[cfg(target_arch = "arm")]
+pub use self::global::{run, TASK_LOG_SIZE};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fram::sim::{self, SimFram, Tear};
+
+    const SCHED: Scheduler = Scheduler::new(Region::new(0, 128));
+    const I: Var<u16> = Var::at(128);
+    const SUM: Var<u32> = Var::at(132);
+    const HISTORY: Var<[u16; 4]> = Var::at(136);
+
+    // sum += i for i in 0..10, swapping HISTORY around on every step: both are
+    // read-then-written, so they would be wrong if a step ran twice.
+    fn step(ctx: &mut TaskCtx<'_, SimFram>) -> Result<Next, TaskError> {
+        let i = ctx.get(I)?;
+        let sum = ctx.get(SUM)?;
+        ctx.set(SUM, sum + i as u32)?;
+        let mut h = ctx.get(HISTORY)?;
+        h.rotate_left(1);
+        h[3] = i;
+        ctx.set(HISTORY, h)?;
+        ctx.set(I, i + 1)?;
+        assert_eq!(ctx.get(I)?, i + 1);
+        if i + 1 == 10 {
+            Ok(Next::Task(1))
+        } else {
+            Ok(Next::Task(0))
+        }
+    }
+
+    fn finish(ctx: &mut TaskCtx<'_, SimFram>) -> Result<Next, TaskError> {
+        let sum = ctx.get(SUM)?;
+        ctx.set(SUM, sum * 2)?;
+        Ok(Next::Done)
+    }
+
+    const TASKS: [Task<SimFram>; 2] = [step, finish];
+
+    fn run(fram: &mut SimFram) -> Result<(), FramError> {
+        match SCHED.run(fram, &TASKS) {
+            Ok(()) => Ok(()),
+            Err(TaskError::Fram(e)) => Err(e),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    fn check(fram: &SimFram) {
+        assert_eq!(read_value::<u32, _>(fram, SUM.offset()), Ok(90));
+        assert_eq!(
+            read_value::<[u16; 4], _>(fram, HISTORY.offset()),
+            Ok([6, 7, 8, 9])
+        );
+        assert_eq!(SCHED.current(fram), Ok(None));
+    }
+
+    #[test]
+    fn runs_to_completion() {
+        let mut fram = SimFram::new(256);
+        run(&mut fram).unwrap();
+        check(&fram);
+
+        // done graphs stay done until reset
+        run(&mut fram).unwrap();
+        check(&fram);
+        SCHED.reset(&mut fram).unwrap();
+        assert_eq!(SCHED.current(&fram), Ok(Some(0)));
+    }
+
+    #[test]
+    fn failed_task_is_not_committed() {
+        fn fail(ctx: &mut TaskCtx<'_, SimFram>) -> Result<Next, TaskError> {
+            ctx.set(SUM, 5)?;
+            Err(TaskError::NotInFram)
+        }
+        let mut fram = SimFram::new(256);
+        assert_eq!(SCHED.run(&mut fram, &[fail]), Err(TaskError::NotInFram));
+        assert_eq!(read_value::<u32, _>(&fram, SUM.offset()), Ok(0));
+        assert_eq!(SCHED.current(&fram), Ok(Some(0)));
+    }
+
+    #[test]
+    fn unknown_successor() {
+        fn jump(_: &mut TaskCtx<'_, SimFram>) -> Result<Next, TaskError> {
+            Ok(Next::Task(3))
+        }
+        let mut fram = SimFram::new(256);
+        assert_eq!(
+            SCHED.run(&mut fram, &[jump]),
+            Err(TaskError::UnknownTask(3))
+        );
+    }
+
+    #[test]
+    fn log_overflow() {
+        fn big(ctx: &mut TaskCtx<'_, SimFram>) -> Result<Next, TaskError> {
+            ctx.set(Var::<[u8; 128]>::at(128), [1; 128])?;
+            Ok(Next::Done)
+        }
+        let mut fram = SimFram::new(256);
+        assert_eq!(SCHED.run(&mut fram, &[big]), Err(TaskError::LogFull));
+    }
+
+    #[test]
+    fn every_power_failure_point() {
+        for &tear in [Tear::Lost, Tear::LowByte, Tear::Complete].iter() {
+            sim::for_each_failure(&[0; 256], tear, run, |fram, _| check(fram));
+        }
+    }
+
+    #[test]
+    fn random_power_failures() {
+        for seed in 0..20 {
+            let mut fram = SimFram::new(256);
+            fram.seed(seed);
+            sim::run_intermittently(&mut fram, 60, run);
+            check(&fram);
+        }
+    }
+}
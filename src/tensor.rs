@@ -0,0 +1,400 @@
+//! Fixed-size tensors, usually placed in F-RAM:
+//!
+//! ```ignore
+//! #[link_section = ".fram_rodata"]
+//! static WEIGHTS: Tensor2D<2, 10> = Tensor2D::new([[0; 10]; 2]);
+//! ```
+//!
+//! The elements are [`Numeric`] unless given, e.g. Q7.8 numbers:
+//!
+//! ```ignore
+//! static SCALES: Tensor1D<2, Fixed<8>> = Tensor1D::new([Fixed::from_f32(0.5); 2]);
+//! ```
+//!
+//! Kernels that should not care where their operands live take a [`Matrix`]
+//! or [`Vector`] instead. Those are implemented by the in-memory tensors (in
+//! SRAM or mapped F-RAM), by [`FramTensor2D`]/[`FramTensor1D`], which read
+//! through a [`FramDevice`](crate::fram::FramDevice), and by the
+//! [`Transposed`], [`Reshaped`] and [`Row`] views over any of them. Of the
+//! layers in [`nn`](crate::nn) only [`matvec`](crate::nn::matvec) is such a
+//! kernel; the others index in-memory tensors directly.
+
+use core::fmt;
+
+use crate::fram::FramError;
+
+mod device;
+mod view;
+
+// Element access hooks, empty unless the `trace` or `profile` feature is on.
+mod hook {
+    #[inline(always)]
+    pub fn read<T>(_value: &T) {
+        #[cfg(feature = "trace")]
+        crate::trace::memory(crate::trace::Kind::Read, _value);
+        #[cfg(feature = "profile")]
+        crate::profile::memory(false, _value);
+    }
+
+    #[inline(always)]
+    pub fn write<T>(_value: &T) {
+        #[cfg(feature = "trace")]
+        crate::trace::memory(crate::trace::Kind::Write, _value);
+        #[cfg(feature = "profile")]
+        crate::profile::memory(true, _value);
+    }
+
+    // A read (or write) of every element, for the accessors that hand out
+    // a whole row or array at once.
+    #[inline(always)]
+    pub fn read_all<T>(_values: &[T]) {
+        #[cfg(any(feature = "trace", feature = "profile"))]
+        for value in _values {
+            read(value);
+        }
+    }
+
+    #[inline(always)]
+    pub fn write_all<T>(_values: &[T]) {
+        #[cfg(any(feature = "trace", feature = "profile"))]
+        for value in _values {
+            write(value);
+        }
+    }
+
+    #[inline(always)]
+    pub fn device<T>(_offset: usize) {
+        #[cfg(feature = "trace")]
+        crate::trace::device(crate::trace::Kind::Read, _offset, core::mem::size_of::<T>());
+    }
+}
+
+pub use self::device::{FramTensor1D, FramTensor2D};
+pub use self::view::{Elements, Reshaped, Row, Transposed};
+
+/// The default element type, the integers the [`nn`](crate::nn) layers work
+/// on. The same on the host and on the board, so results match bit for bit;
+/// use [`Fixed`](crate::fixed::Fixed) elements for values with a scale.
+pub type Numeric = i32;
+
+/// Element types with a zero, for `zeros()` in constants.
+pub trait Zero: Copy {
+    const ZERO: Self;
+}
+
+macro_rules! zero {
+    ($($t:ty),*) => {$(
+        impl Zero for $t {
+            const ZERO: Self = 0;
+        }
+    )*};
+}
+
+zero!(i8, i16, i32, i64);
+
+/// Element types kernels can compute with.
+pub trait Element: Zero + PartialEq + fmt::Debug {
+    /// The value in accumulator units: integers as they are, fixed-point
+    /// numbers as their raw bits.
+    fn widen(self) -> i64;
+}
+
+macro_rules! element {
+    ($($t:ty),*) => {$(
+        impl Element for $t {
+            #[inline(always)]
+            fn widen(self) -> i64 {
+                self as i64
+            }
+        }
+    )*};
+}
+
+element!(i8, i16, i32);
+
+/// Read access to an `ROWS` x `COLS` matrix, wherever it is stored.
+///
+/// Reads only fail for backends that go through a device.
+pub trait Matrix {
+    type Elem: Element;
+    const ROWS: usize;
+    const COLS: usize;
+
+    fn get(&self, row: usize, col: usize) -> Result<Self::Elem, FramError>;
+
+    /// Row `row` as a [`Vector`].
+    fn row_view(&self, row: usize) -> Row<'_, Self>
+    where
+        Self: Sized,
+    {
+        Row::new(self, row)
+    }
+
+    /// The transpose, without copying.
+    fn transposed(&self) -> Transposed<'_, Self>
+    where
+        Self: Sized,
+    {
+        Transposed::new(self)
+    }
+
+    /// The same elements in row-major order as an `H` x `W` matrix, without
+    /// copying. Panics unless `H * W == ROWS * COLS`.
+    fn reshaped<const H: usize, const W: usize>(&self) -> Reshaped<'_, Self, H, W>
+    where
+        Self: Sized,
+    {
+        Reshaped::new(self)
+    }
+}
+
+/// Read access to a vector of `LEN` elements, wherever it is stored.
+pub trait Vector {
+    type Elem: Element;
+    const LEN: usize;
+
+    fn get(&self, index: usize) -> Result<Self::Elem, FramError>;
+
+    fn elements(&self) -> Elements<'_, Self>
+    where
+        Self: Sized,
+    {
+        Elements::new(self)
+    }
+}
+
+/// An `H` x `W` matrix, stored row by row.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Tensor2D<const H: usize, const W: usize, T = Numeric> {
+    tensor: [[T; W]; H],
+}
+
+impl<const H: usize, const W: usize, T: Zero> Tensor2D<H, W, T> {
+    pub const fn new(tensor: [[T; W]; H]) -> Self {
+        Self { tensor }
+    }
+
+    pub const fn zeros() -> Self {
+        Self {
+            tensor: [[T::ZERO; W]; H],
+        }
+    }
+
+    #[inline(always)]
+    pub fn at(&self, rol: usize, col: usize) -> &T {
+        hook::read(&self.tensor[rol][col]);
+        &self.tensor[rol][col]
+    }
+
+    #[inline(always)]
+    pub fn mut_at(&mut self, rol: usize, col: usize) -> &mut T {
+        hook::write(&self.tensor[rol][col]);
+        &mut self.tensor[rol][col]
+    }
+
+    /// Row `row`, counted by the access hooks as a read of every element.
+    #[inline(always)]
+    pub fn row(&self, row: usize) -> &[T; W] {
+        hook::read_all(&self.tensor[row]);
+        &self.tensor[row]
+    }
+
+    /// Row `row`, counted as a write of every element.
+    #[inline(always)]
+    pub fn row_mut(&mut self, row: usize) -> &mut [T; W] {
+        hook::write_all(&self.tensor[row]);
+        &mut self.tensor[row]
+    }
+
+    /// All elements, row by row, counted as a read of each.
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+
+    /// All elements, row by row, e.g. for a [`dma`](crate::dma) copy.
+    /// Counted as a read of each.
+    pub fn as_slice(&self) -> &[T] {
+        hook::read_all(self.tensor.as_flattened());
+        self.tensor.as_flattened()
+    }
+
+    /// All elements, row by row, counted as a write of each.
+    pub fn iter_mut(&mut self) -> core::slice::IterMut<'_, T> {
+        hook::write_all(self.tensor.as_flattened());
+        self.tensor.as_flattened_mut().iter_mut()
+    }
+
+    /// The same elements as an `RH` x `RW` matrix, without copying.
+    pub fn reshape<const RH: usize, const RW: usize>(&self) -> &Tensor2D<RH, RW, T> {
+        assert_eq!(RH * RW, H * W, "reshaped size");
+        // both are `repr(C)` arrays of `T`
+        unsafe { &*(self as *const Self as *const Tensor2D<RH, RW, T>) }
+    }
+
+    pub fn reshape_mut<const RH: usize, const RW: usize>(&mut self) -> &mut Tensor2D<RH, RW, T> {
+        assert_eq!(RH * RW, H * W, "reshaped size");
+        unsafe { &mut *(self as *mut Self as *mut Tensor2D<RH, RW, T>) }
+    }
+}
+
+impl<const H: usize, const W: usize, T: Element> Matrix for Tensor2D<H, W, T> {
+    type Elem = T;
+    const ROWS: usize = H;
+    const COLS: usize = W;
+
+    #[inline(always)]
+    fn get(&self, row: usize, col: usize) -> Result<T, FramError> {
+        hook::read(&self.tensor[row][col]);
+        Ok(self.tensor[row][col])
+    }
+}
+
+/// A vector of `W` elements.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Tensor1D<const W: usize, T = Numeric> {
+    tensor: [T; W],
+}
+
+impl<const W: usize, T: Zero> Tensor1D<W, T> {
+    pub const fn new(tensor: [T; W]) -> Self {
+        Self { tensor }
+    }
+
+    pub const fn zeros() -> Self {
+        Self {
+            tensor: [T::ZERO; W],
+        }
+    }
+
+    #[inline(always)]
+    pub fn at(&self, col: usize) -> &T {
+        hook::read(&self.tensor[col]);
+        &self.tensor[col]
+    }
+
+    #[inline(always)]
+    pub fn mut_at(&mut self, col: usize) -> &mut T {
+        hook::write(&self.tensor[col]);
+        &mut self.tensor[col]
+    }
+
+    /// All elements, counted by the access hooks as a read of each.
+    #[inline(always)]
+    pub fn as_array(&self) -> &[T; W] {
+        hook::read_all(&self.tensor);
+        &self.tensor
+    }
+
+    /// All elements, counted as a write of each.
+    #[inline(always)]
+    pub fn as_mut_array(&mut self) -> &mut [T; W] {
+        hook::write_all(&self.tensor);
+        &mut self.tensor
+    }
+
+    pub fn iter(&self) -> core::slice::Iter<'_, T> {
+        self.as_array().iter()
+    }
+
+    pub fn iter_mut(&mut self) -> core::slice::IterMut<'_, T> {
+        self.as_mut_array().iter_mut()
+    }
+}
+
+impl<const W: usize, T: Element> Vector for Tensor1D<W, T> {
+    type Elem = T;
+    const LEN: usize = W;
+
+    #[inline(always)]
+    fn get(&self, index: usize) -> Result<T, FramError> {
+        hook::read(&self.tensor[index]);
+        Ok(self.tensor[index])
+    }
+}
+
+/// `C` channels of `H` x `W`, stored channel by channel, then row by row.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Tensor3D<const C: usize, const H: usize, const W: usize, T = Numeric> {
+    tensor: [[[T; W]; H]; C],
+}
+
+impl<const C: usize, const H: usize, const W: usize, T: Zero> Tensor3D<C, H, W, T> {
+    pub const fn new(tensor: [[[T; W]; H]; C]) -> Self {
+        Self { tensor }
+    }
+
+    pub const fn zeros() -> Self {
+        Self {
+            tensor: [[[T::ZERO; W]; H]; C],
+        }
+    }
+
+    #[inline(always)]
+    pub fn at(&self, c: usize, row: usize, col: usize) -> &T {
+        hook::read(&self.tensor[c][row][col]);
+        &self.tensor[c][row][col]
+    }
+
+    #[inline(always)]
+    pub fn mut_at(&mut self, c: usize, row: usize, col: usize) -> &mut T {
+        hook::write(&self.tensor[c][row][col]);
+        &mut self.tensor[c][row][col]
+    }
+
+    /// All elements, counted by the access hooks as a read of each.
+    #[inline(always)]
+    pub fn as_array(&self) -> &[[[T; W]; H]; C] {
+        hook::read_all(self.tensor.as_flattened().as_flattened());
+        &self.tensor
+    }
+
+    /// All elements, counted as a write of each.
+    #[inline(always)]
+    pub fn as_mut_array(&mut self) -> &mut [[[T; W]; H]; C] {
+        hook::write_all(self.tensor.as_flattened().as_flattened());
+        &mut self.tensor
+    }
+}
+
+/// `N` filters of `C` x `H` x `W`, the weights of a convolution.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Tensor4D<const N: usize, const C: usize, const H: usize, const W: usize, T = Numeric> {
+    tensor: [[[[T; W]; H]; C]; N],
+}
+
+impl<const N: usize, const C: usize, const H: usize, const W: usize, T: Zero>
+    Tensor4D<N, C, H, W, T>
+{
+    pub const fn new(tensor: [[[[T; W]; H]; C]; N]) -> Self {
+        Self { tensor }
+    }
+
+    pub const fn zeros() -> Self {
+        Self {
+            tensor: [[[[T::ZERO; W]; H]; C]; N],
+        }
+    }
+
+    #[inline(always)]
+    pub fn at(&self, n: usize, c: usize, row: usize, col: usize) -> &T {
+        hook::read(&self.tensor[n][c][row][col]);
+        &self.tensor[n][c][row][col]
+    }
+
+    #[inline(always)]
+    pub fn mut_at(&mut self, n: usize, c: usize, row: usize, col: usize) -> &mut T {
+        hook::write(&self.tensor[n][c][row][col]);
+        &mut self.tensor[n][c][row][col]
+    }
+
+    /// All elements, counted by the access hooks as a read of each.
+    #[inline(always)]
+    pub fn as_array(&self) -> &[[[[T; W]; H]; C]; N] {
+        hook::read_all(self.tensor.as_flattened().as_flattened().as_flattened());
+        &self.tensor
+    }
+}
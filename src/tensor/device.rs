@@ -0,0 +1,116 @@
+//! Tensors read through a [`FramDevice`] rather than through memory, e.g.
+//! weights in a [`SimFram`](crate::fram::sim::SimFram) image on the host or
+//! in F-RAM that is not mapped.
+
+use core::marker::PhantomData;
+use core::mem;
+
+use super::{hook, Element, Matrix, Numeric, Vector};
+use crate::fram::{check_access, read_value, FramDevice, FramError};
+
+/// An `H` x `W` matrix of `T` stored row by row at `offset` in `fram`.
+pub struct FramTensor2D<'a, D, const H: usize, const W: usize, T = Numeric> {
+    fram: &'a D,
+    offset: usize,
+    elem: PhantomData<T>,
+}
+
+impl<'a, D: FramDevice, const H: usize, const W: usize, T: Element> FramTensor2D<'a, D, H, W, T> {
+    /// Fails if the tensor does not fit in the device.
+    pub fn new(fram: &'a D, offset: usize) -> Result<Self, FramError> {
+        check_access(fram.size(), offset, H * W * mem::size_of::<T>(), 1)?;
+        Ok(FramTensor2D {
+            fram,
+            offset,
+            elem: PhantomData,
+        })
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<D: FramDevice, const H: usize, const W: usize, T: Element> Matrix
+    for FramTensor2D<'_, D, H, W, T>
+{
+    type Elem = T;
+    const ROWS: usize = H;
+    const COLS: usize = W;
+
+    fn get(&self, row: usize, col: usize) -> Result<T, FramError> {
+        assert!(row < H && col < W, "index out of bounds");
+        let offset = self.offset + (row * W + col) * mem::size_of::<T>();
+        hook::device::<T>(offset);
+        read_value(self.fram, offset)
+    }
+}
+
+/// A vector of `W` elements of `T` at `offset` in `fram`.
+pub struct FramTensor1D<'a, D, const W: usize, T = Numeric> {
+    fram: &'a D,
+    offset: usize,
+    elem: PhantomData<T>,
+}
+
+impl<'a, D: FramDevice, const W: usize, T: Element> FramTensor1D<'a, D, W, T> {
+    /// Fails if the tensor does not fit in the device.
+    pub fn new(fram: &'a D, offset: usize) -> Result<Self, FramError> {
+        check_access(fram.size(), offset, W * mem::size_of::<T>(), 1)?;
+        Ok(FramTensor1D {
+            fram,
+            offset,
+            elem: PhantomData,
+        })
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<D: FramDevice, const W: usize, T: Element> Vector for FramTensor1D<'_, D, W, T> {
+    type Elem = T;
+    const LEN: usize = W;
+
+    fn get(&self, index: usize) -> Result<T, FramError> {
+        assert!(index < W, "index out of bounds");
+        let offset = self.offset + index * mem::size_of::<T>();
+        hook::device::<T>(offset);
+        read_value(self.fram, offset)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "arm")))]
+mod test {
+    use super::*;
+    use crate::fixed::Fixed;
+    use crate::fram::sim::SimFram;
+    use crate::fram::MemFram;
+
+    #[test]
+    fn reads_through_the_device() {
+        let mut fram = MemFram::new(64);
+        for (i, v) in [1i16, -2, 3, -4, 5, -6].iter().enumerate() {
+            fram.write(8 + 2 * i, &v.to_le_bytes()).unwrap();
+        }
+        let m = FramTensor2D::<_, 2, 3, i16>::new(&fram, 8).unwrap();
+        assert_eq!(m.get(1, 2), Ok(-6));
+        assert_eq!(m.transposed().get(2, 0), Ok(3));
+        let v = FramTensor1D::<_, 6, Fixed<1>>::new(&fram, 8).unwrap();
+        assert_eq!(v.get(3).map(Fixed::to_f32), Ok(-2.0));
+
+        assert!(FramTensor2D::<_, 8, 4, i16>::new(&fram, 8).is_err());
+    }
+
+    #[test]
+    fn reads_fail_without_power() {
+        let mut fram = SimFram::new(16);
+        let v = FramTensor1D::<_, 4>::new(&fram, 0).unwrap();
+        assert_eq!(v.get(0), Ok(0));
+        fram.fail_after(0);
+        assert!(fram.write_u16(0, 1).is_err());
+        let v = FramTensor1D::<_, 4>::new(&fram, 0).unwrap();
+        assert_eq!(v.get(0), Err(FramError::PowerLost));
+    }
+}
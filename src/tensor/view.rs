@@ -0,0 +1,138 @@
+//! Views over a [`Matrix`] or [`Vector`] that only change the indexing.
+
+use super::{Matrix, Vector};
+use crate::fram::FramError;
+
+/// The transpose of `M`.
+pub struct Transposed<'a, M> {
+    inner: &'a M,
+}
+
+impl<'a, M: Matrix> Transposed<'a, M> {
+    pub fn new(inner: &'a M) -> Self {
+        Transposed { inner }
+    }
+}
+
+impl<M: Matrix> Matrix for Transposed<'_, M> {
+    type Elem = M::Elem;
+    const ROWS: usize = M::COLS;
+    const COLS: usize = M::ROWS;
+
+    #[inline(always)]
+    fn get(&self, row: usize, col: usize) -> Result<M::Elem, FramError> {
+        self.inner.get(col, row)
+    }
+}
+
+/// `M` read in row-major order as an `H` x `W` matrix.
+pub struct Reshaped<'a, M, const H: usize, const W: usize> {
+    inner: &'a M,
+}
+
+impl<'a, M: Matrix, const H: usize, const W: usize> Reshaped<'a, M, H, W> {
+    pub fn new(inner: &'a M) -> Self {
+        assert_eq!(H * W, M::ROWS * M::COLS, "reshaped size");
+        Reshaped { inner }
+    }
+}
+
+impl<M: Matrix, const H: usize, const W: usize> Matrix for Reshaped<'_, M, H, W> {
+    type Elem = M::Elem;
+    const ROWS: usize = H;
+    const COLS: usize = W;
+
+    #[inline(always)]
+    fn get(&self, row: usize, col: usize) -> Result<M::Elem, FramError> {
+        assert!(row < H && col < W, "index out of bounds");
+        let index = row * W + col;
+        self.inner.get(index / M::COLS, index % M::COLS)
+    }
+}
+
+/// One row of `M`.
+pub struct Row<'a, M> {
+    inner: &'a M,
+    row: usize,
+}
+
+impl<'a, M: Matrix> Row<'a, M> {
+    pub fn new(inner: &'a M, row: usize) -> Self {
+        assert!(row < M::ROWS, "index out of bounds");
+        Row { inner, row }
+    }
+}
+
+impl<M: Matrix> Vector for Row<'_, M> {
+    type Elem = M::Elem;
+    const LEN: usize = M::COLS;
+
+    #[inline(always)]
+    fn get(&self, index: usize) -> Result<M::Elem, FramError> {
+        self.inner.get(self.row, index)
+    }
+}
+
+/// Iterator over the elements of a [`Vector`].
+pub struct Elements<'a, V> {
+    inner: &'a V,
+    next: usize,
+}
+
+impl<'a, V: Vector> Elements<'a, V> {
+    pub fn new(inner: &'a V) -> Self {
+        Elements { inner, next: 0 }
+    }
+}
+
+impl<V: Vector> Iterator for Elements<'_, V> {
+    type Item = Result<V::Elem, FramError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == V::LEN {
+            return None;
+        }
+        self.next += 1;
+        Some(self.inner.get(self.next - 1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = V::LEN - self.next;
+        (left, Some(left))
+    }
+}
+
+impl<V: Vector> ExactSizeIterator for Elements<'_, V> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tensor::{Tensor1D, Tensor2D};
+
+    #[test]
+    fn views() {
+        let m = Tensor2D::<2, 3>::new([[1, 2, 3], [4, 5, 6]]);
+        let t = m.transposed();
+        assert_eq!(<Transposed<'_, Tensor2D<2, 3>> as Matrix>::ROWS, 3);
+        assert_eq!(t.get(2, 1), Ok(6));
+        assert_eq!(t.transposed().get(1, 2), Ok(6));
+
+        let r = m.reshaped::<3, 2>();
+        assert_eq!(r.get(1, 0), Ok(3));
+        assert_eq!(r.get(2, 1), Ok(6));
+        let rt = r.transposed();
+        let col: Result<Vec<_>, _> = rt.row_view(1).elements().collect();
+        assert_eq!(col, Ok(vec![2, 4, 6]));
+
+        let v = Tensor1D::<3, i8>::new([7, -8, 9]);
+        assert_eq!(v.elements().len(), 3);
+        assert_eq!(v.elements().nth(1), Some(Ok(-8)));
+    }
+
+    #[test]
+    #[should_panic(expected = "reshaped size")]
+    fn reshape_checks_the_size() {
+        let m = Tensor2D::<2, 3>::zeros();
+        m.reshaped::<2, 2>();
+    }
+}
@@ -0,0 +1,265 @@
+//! Tensor access tracing, compiled in with the `trace` feature.
+//!
+//! Every element read or write through a tensor (`at`, `mut_at`, the
+//! [`Matrix`](crate::tensor::Matrix)/[`Vector`](crate::tensor::Vector)
+//! accessors and the device-backed tensors) is recorded as an [`Access`].
+//! Records go to a ring buffer of the last [`TRACE_LEN`] accesses, which can
+//! be [`drain`]ed, e.g. from a debugger-attached idle loop, or to a sink
+//! installed with [`set_sink`]. On the host, [`save`] writes the buffer to a
+//! file.
+//!
+//! Recording is lock-free and may be done from interrupts, but a record that
+//! is overwritten while being drained can come out torn.
+
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+/// Number of accesses the ring buffer keeps.
+pub const TRACE_LEN: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Read,
+    Write,
+}
+
+/// What `addr` is relative to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Space {
+    /// An address in the memory map (SRAM or mapped F-RAM).
+    Memory,
+    /// An offset in a [`FramDevice`](crate::fram::FramDevice).
+    Device,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub kind: Kind,
+    pub space: Space,
+    pub addr: usize,
+    /// Bytes accessed.
+    pub len: usize,
+}
+
+impl Access {
+    // kind in bit 0, space in bit 1, len above
+    fn pack(self) -> usize {
+        (self.len << 2) | ((self.space as usize) << 1) | self.kind as usize
+    }
+
+    fn unpack(addr: usize, meta: usize) -> Self {
+        Access {
+            kind: if meta & 1 == 0 {
+                Kind::Read
+            } else {
+                Kind::Write
+            },
+            space: if meta & 2 == 0 {
+                Space::Memory
+            } else {
+                Space::Device
+            },
+            addr,
+            len: meta >> 2,
+        }
+    }
+}
+
+struct Ring {
+    addr: [AtomicUsize; TRACE_LEN],
+    meta: [AtomicUsize; TRACE_LEN],
+    /// Records ever written.
+    head: AtomicUsize,
+    /// Records ever drained or skipped.
+    tail: AtomicUsize,
+}
+
+static RING: Ring = Ring {
+    addr: [const { AtomicUsize::new(0) }; TRACE_LEN],
+    meta: [const { AtomicUsize::new(0) }; TRACE_LEN],
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+};
+
+static SINK: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Sends every access to `sink` instead of the ring buffer, or back to the
+/// ring buffer with `None`.
+pub fn set_sink(sink: Option<fn(Access)>) {
+    let sink = sink.map_or(ptr::null_mut(), |f| f as *mut ());
+    SINK.store(sink, Ordering::Release);
+}
+
+pub fn record(access: Access) {
+    let sink = SINK.load(Ordering::Acquire);
+    if !sink.is_null() {
+        // only ever set from a `fn(Access)` in `set_sink`
+        let sink = unsafe { mem::transmute::<*mut (), fn(Access)>(sink) };
+        return sink(access);
+    }
+    let slot = RING.head.fetch_add(1, Ordering::AcqRel) % TRACE_LEN;
+    RING.addr[slot].store(access.addr, Ordering::Relaxed);
+    RING.meta[slot].store(access.pack(), Ordering::Relaxed);
+}
+
+#[inline(always)]
+pub(crate) fn memory<T>(kind: Kind, value: *const T) {
+    record(Access {
+        kind,
+        space: Space::Memory,
+        addr: value as usize,
+        len: mem::size_of::<T>(),
+    })
+}
+
+#[inline(always)]
+pub(crate) fn device(kind: Kind, offset: usize, len: usize) {
+    record(Access {
+        kind,
+        space: Space::Device,
+        addr: offset,
+        len,
+    })
+}
+
+/// Passes the buffered accesses to `f`, oldest first, and empties the
+/// buffer.
+pub fn drain<F: FnMut(Access)>(mut f: F) {
+    let head = RING.head.load(Ordering::Acquire);
+    let mut tail = RING.tail.load(Ordering::Relaxed);
+    let buffered = head.wrapping_sub(tail);
+    if buffered > TRACE_LEN {
+        DROPPED.fetch_add(buffered - TRACE_LEN, Ordering::Relaxed);
+        tail = head.wrapping_sub(TRACE_LEN);
+    }
+    while tail != head {
+        let slot = tail % TRACE_LEN;
+        f(Access::unpack(
+            RING.addr[slot].load(Ordering::Relaxed),
+            RING.meta[slot].load(Ordering::Relaxed),
+        ));
+        tail = tail.wrapping_add(1);
+    }
+    RING.tail.store(tail, Ordering::Relaxed);
+}
+
+/// Accesses overwritten before they were drained.
+pub fn dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Drains the buffer into a text file, one access per line:
+/// `R mem 0x20000010 4` or `W dev 0x00000120 2`. Returns the number of
+/// accesses written.
+#[cfg(not(target_arch = "arm"))]
+pub fn save(path: &std::path::Path) -> std::io::Result<usize> {
+    use std::io::Write;
+
+    let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut result = Ok(());
+    let mut count = 0;
+    drain(|a| {
+        if result.is_ok() {
+            result = writeln!(out, "{}", line(a));
+            count += 1;
+        }
+    });
+    result?;
+    out.flush()?;
+    Ok(count)
+}
+
+#[cfg(not(target_arch = "arm"))]
+fn line(a: Access) -> String {
+    let kind = match a.kind {
+        Kind::Read => 'R',
+        Kind::Write => 'W',
+    };
+    let space = match a.space {
+        Space::Memory => "mem",
+        Space::Device => "dev",
+    };
+    format!("{} {} {:#010x} {}", kind, space, a.addr, a.len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nn::{self, Requant};
+    use crate::tensor::{Tensor1D, Tensor2D, Vector};
+    use std::cell::RefCell;
+
+    thread_local! {
+        static SEEN: RefCell<Vec<Access>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // Other tests record accesses from their own threads at the same time,
+    // so this one only checks the ring buffer loosely, and everything that
+    // uses the global state is in this one test.
+    #[test]
+    fn records_tensor_accesses() {
+        let mut m = Tensor2D::<2, 3, i16>::zeros();
+        let v = Tensor1D::<4>::new([1, 2, 3, 4]);
+        let base = &m as *const _ as usize;
+
+        set_sink(Some(|a| SEEN.with(|seen| seen.borrow_mut().push(a))));
+        *m.mut_at(1, 2) = 5;
+        m.at(0, 1);
+        v.get(3).unwrap();
+        set_sink(None);
+        let access = |kind, addr, len| Access {
+            kind,
+            space: Space::Memory,
+            addr,
+            len,
+        };
+        assert_eq!(
+            SEEN.with(|seen| seen.take()),
+            vec![
+                access(Kind::Write, base + 10, 2),
+                access(Kind::Read, base + 2, 2),
+                access(Kind::Read, &v as *const _ as usize + 12, 4),
+            ]
+        );
+
+        // a kernel's output writes, through the whole-array accessors
+        let weights = Tensor2D::<2, 4>::new([[1, 0, 0, 0], [0, 1, 0, 0]]);
+        let mut output = Tensor1D::<2>::zeros();
+        let out = &output as *const _ as usize;
+        set_sink(Some(|a| SEEN.with(|seen| seen.borrow_mut().push(a))));
+        nn::dense(&weights, None, Requant::IDENTITY, &v, &mut output);
+        set_sink(None);
+        let seen = SEEN.with(|seen| seen.take());
+        assert_eq!(
+            seen.iter()
+                .filter(|a| a.kind == Kind::Write)
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![access(Kind::Write, out, 4), access(Kind::Write, out + 4, 4)]
+        );
+        assert_eq!(
+            seen.iter().filter(|a| a.kind == Kind::Read).count(),
+            4 + 2 * 4
+        );
+        assert_eq!(output, Tensor1D::new([1, 2]));
+
+        for _ in 0..TRACE_LEN + 3 {
+            m.at(0, 0);
+        }
+        let mut count = 0;
+        drain(|_| count += 1);
+        assert_eq!(count, TRACE_LEN);
+        assert!(dropped() >= 3);
+
+        m.at(1, 1);
+        let path = std::env::temp_dir().join(format!("trace-{}.txt", std::process::id()));
+        assert!(save(&path).unwrap() >= 1);
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(text
+            .lines()
+            .any(|l| l == format!("R mem {:#010x} 2", base + 8)));
+    }
+}
@@ -0,0 +1,163 @@
+//! Record layouts given on the command line, e.g. `time:u32,value:i16,_:x2`.
+
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Type {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+    /// Skipped bytes, such as padding.
+    Skip(usize),
+}
+
+impl Type {
+    fn parse(s: &str) -> Result<Type, String> {
+        Ok(match s {
+            "u8" => Type::U8,
+            "i8" => Type::I8,
+            "u16" => Type::U16,
+            "i16" => Type::I16,
+            "u32" => Type::U32,
+            "i32" => Type::I32,
+            "u64" => Type::U64,
+            "i64" => Type::I64,
+            "f32" => Type::F32,
+            "f64" => Type::F64,
+            _ => match s.strip_prefix('x').map(str::parse) {
+                Some(Ok(n)) => Type::Skip(n),
+                _ => return Err(format!("unknown field type `{}`", s)),
+            },
+        })
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            Type::U8 | Type::I8 => 1,
+            Type::U16 | Type::I16 => 2,
+            Type::U32 | Type::I32 | Type::F32 => 4,
+            Type::U64 | Type::I64 | Type::F64 => 8,
+            Type::Skip(n) => n,
+        }
+    }
+
+    // `bytes` is exactly `size()` long, little-endian
+    fn format(self, bytes: &[u8], out: &mut String) {
+        let mut raw = [0u8; 8];
+        raw[..bytes.len()].copy_from_slice(bytes);
+        let unsigned = u64::from_le_bytes(raw);
+        // sign-extend
+        let shift = 64 - 8 * bytes.len() as u32;
+        let signed = ((unsigned << shift) as i64) >> shift;
+        let _ = match self {
+            Type::U8 | Type::U16 | Type::U32 | Type::U64 => write!(out, "{}", unsigned),
+            Type::I8 | Type::I16 | Type::I32 | Type::I64 => write!(out, "{}", signed),
+            Type::F32 => write!(out, "{}", f32::from_bits(unsigned as u32)),
+            Type::F64 => write!(out, "{}", f64::from_bits(unsigned)),
+            Type::Skip(_) => Ok(()),
+        };
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    pub ty: Type,
+}
+
+/// How to print records: named fields, or the bytes in hex if there are
+/// none. Bytes after the last field are ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Fields(Vec<Field>);
+
+impl Fields {
+    /// `name:type` or just `type`, separated by commas. Fields named `_` and
+    /// `xN` fields are not printed.
+    pub fn parse(s: &str) -> Result<Fields, String> {
+        let mut fields = Vec::new();
+        for (i, field) in s.split(',').map(str::trim).enumerate() {
+            let (name, ty) = match field.split_once(':') {
+                Some((name, ty)) => (name.trim().to_owned(), ty.trim()),
+                None => (format!("f{}", i), field),
+            };
+            fields.push(Field {
+                name,
+                ty: Type::parse(ty)?,
+            });
+        }
+        Ok(Fields(fields))
+    }
+
+    pub fn size(&self) -> usize {
+        self.0.iter().map(|f| f.ty.size()).sum()
+    }
+
+    fn printed(&self) -> impl Iterator<Item = &Field> {
+        self.0
+            .iter()
+            .filter(|f| f.name != "_" && !matches!(f.ty, Type::Skip(_)))
+    }
+
+    /// The CSV header line.
+    pub fn header(&self) -> String {
+        let mut line = String::from("seq");
+        if self.0.is_empty() {
+            line.push_str(",data");
+        }
+        for field in self.printed() {
+            line.push(',');
+            line.push_str(&field.name);
+        }
+        line
+    }
+
+    /// The CSV line of record `seq`.
+    pub fn line(&self, seq: u32, record: &[u8]) -> String {
+        let mut line = seq.to_string();
+        if self.0.is_empty() {
+            line.push(',');
+            for b in record {
+                let _ = write!(line, "{:02x}", b);
+            }
+        }
+        let mut at = 0;
+        for field in &self.0 {
+            let bytes = &record[at..at + field.ty.size()];
+            at += field.ty.size();
+            if field.name != "_" && !matches!(field.ty, Type::Skip(_)) {
+                line.push(',');
+                field.ty.format(bytes, &mut line);
+            }
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn formats_fields() {
+        let fields = Fields::parse("time:u32, value:i16, _:u8, x1, f32").unwrap();
+        assert_eq!(fields.size(), 12);
+        assert_eq!(fields.header(), "seq,time,value,f4");
+        let mut record = Vec::new();
+        record.extend_from_slice(&1000u32.to_le_bytes());
+        record.extend_from_slice(&(-2i16).to_le_bytes());
+        record.extend_from_slice(&[9, 9]);
+        record.extend_from_slice(&1.5f32.to_le_bytes());
+        assert_eq!(fields.line(7, &record), "7,1000,-2,1.5");
+
+        assert_eq!(Fields::default().header(), "seq,data");
+        assert_eq!(Fields::default().line(3, &[0xab, 1]), "3,ab01");
+        assert!(Fields::parse("u24").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! `fram-drain <image> [--offset <n>] [--fields <layout>]`
+//!
+//! Prints the records of a `FramLog` in a dumped F-RAM image as CSV, oldest
+//! first: the sequence number, then one column per field of `--fields`
+//! (e.g. `time:u32,value:i16,channel:u16`, see `fields.rs`), or the record
+//! bytes in hex without it. Records that fail their CRC are reported on
+//! stderr and skipped.
+//!
+//! The image is the `FRAM` region as dumped by a debugger, e.g. with
+//! `dump binary memory fram.bin 0x60000000 0x60008000` in gdb. Without
+//! `--offset`, the image is searched for a log header.
+
+use std::fs;
+use std::process;
+
+use parallel_fram::fram::log::{LogError, RawLog};
+use parallel_fram::fram::MemFram;
+
+mod fields;
+
+use self::fields::Fields;
+
+struct Args {
+    image: String,
+    offset: Option<usize>,
+    fields: Fields,
+}
+
+fn parse_offset(s: &str) -> Result<usize, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("bad offset `{}`", s))
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut image = None;
+    let mut offset = None;
+    let mut fields = Fields::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--offset" => {
+                let value = args.next().ok_or("--offset needs a value")?;
+                offset = Some(parse_offset(value)?);
+            }
+            "--fields" => {
+                fields = Fields::parse(args.next().ok_or("--fields needs a value")?)?;
+            }
+            _ if image.is_none() && !arg.starts_with("--") => image = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+    Ok(Args {
+        image: image.ok_or("no image given")?,
+        offset,
+        fields,
+    })
+}
+
+// The log at `offset`, or the only one in the image.
+fn find(fram: &MemFram, offset: Option<usize>) -> Result<RawLog, String> {
+    let fram_error = |e| format!("{:?}", e);
+    if let Some(offset) = offset {
+        return RawLog::at(fram, offset)
+            .map_err(fram_error)?
+            .ok_or_else(|| format!("no log at {:#x}", offset));
+    }
+    let mut logs = Vec::new();
+    for offset in (0..fram.image().len()).step_by(4) {
+        if let Some(log) = RawLog::at(fram, offset).map_err(fram_error)? {
+            logs.push(log);
+        }
+    }
+    match logs.len() {
+        0 => Err("no log in the image".to_owned()),
+        1 => Ok(logs[0]),
+        _ => {
+            let offsets: Vec<String> = logs
+                .iter()
+                .map(|l| format!("{:#x}", l.region().offset))
+                .collect();
+            Err(format!(
+                "logs at {}, pick one with --offset",
+                offsets.join(", ")
+            ))
+        }
+    }
+}
+
+fn drain(args: &Args) -> Result<(), String> {
+    let image = fs::read(&args.image).map_err(|e| format!("{}: {}", args.image, e))?;
+    let fram = MemFram::from_image(image);
+    let log = find(&fram, args.offset)?;
+    if args.fields.size() > log.record_len() {
+        return Err(format!(
+            "fields take {} bytes, records are {}",
+            args.fields.size(),
+            log.record_len()
+        ));
+    }
+
+    println!("{}", args.fields.header());
+    let mut record = vec![0; log.record_len()];
+    for seq in log.seqs(&fram).map_err(|e| format!("{:?}", e))? {
+        match log.read(&fram, seq, &mut record) {
+            Ok(()) => println!("{}", args.fields.line(seq, &record)),
+            Err(LogError::Corrupt { seq }) => eprintln!("record {} is damaged", seq),
+            Err(e) => return Err(format!("{:?}", e)),
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let parsed = match parse_args(&args[1..]) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!(
+                "usage: {} <image> [--offset <n>] [--fields <name:type,...>]",
+                args[0]
+            );
+            process::exit(2);
+        }
+    };
+    if let Err(e) = drain(&parsed) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parallel_fram::fram::log::FramLog;
+    use parallel_fram::fram::Region;
+
+    #[test]
+    fn finds_logs() {
+        let mut fram = MemFram::new(1024);
+        assert!(find(&fram, None).is_err());
+        let log = FramLog::<[u16; 3]>::new(Region::new(200, 300));
+        log.format(&mut fram).unwrap();
+        log.push(&mut fram, &[1, 2, 3]).unwrap();
+        let found = find(&fram, None).unwrap();
+        assert_eq!(found.region().offset, 200);
+        assert_eq!(found.record_len(), 6);
+        assert_eq!(found.capacity(), log.capacity());
+        assert!(find(&fram, Some(204)).is_err());
+
+        FramLog::<u32>::new(Region::new(600, 100))
+            .format(&mut fram)
+            .unwrap();
+        assert!(find(&fram, None).unwrap_err().contains("0xc8, 0x258"));
+        assert_eq!(find(&fram, Some(0x258)).unwrap().record_len(), 4);
+        assert_eq!(parse_offset("0x258"), Ok(600));
+    }
+}
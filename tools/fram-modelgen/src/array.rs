@@ -0,0 +1,213 @@
+//! Weight files: NumPy `.npy` and plain CSV.
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use crate::Error;
+
+/// An n-dimensional array of weights, row-major.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Array {
+    pub shape: Vec<usize>,
+    pub data: Vec<f64>,
+}
+
+impl Array {
+    /// Changes the shape, keeping the data in the same order.
+    pub fn reshape(self, shape: Vec<usize>) -> Result<Array, Error> {
+        if shape.iter().product::<usize>() != self.data.len() {
+            return Err(Error::Shape(format!(
+                "cannot reshape {:?} to {:?}",
+                self.shape, shape
+            )));
+        }
+        Ok(Array {
+            shape,
+            data: self.data,
+        })
+    }
+}
+
+/// Reads a `.npy` or `.csv` file, going by the extension.
+pub fn load(path: &Path) -> Result<Array, Error> {
+    let bytes = std::fs::read(path).map_err(|e| Error::Io(path.to_owned(), e))?;
+    let parsed = match path.extension().and_then(|e| e.to_str()) {
+        Some("npy") => parse_npy(&bytes),
+        Some("csv") => parse_csv(&String::from_utf8_lossy(&bytes)),
+        _ => Err(Error::Format("expected a .npy or .csv file".into())),
+    };
+    parsed.map_err(|e| e.in_file(path))
+}
+
+/// Parses CSV: one row per line, `#` starts a comment. A single row gives a
+/// 1-D array, several rows a 2-D one.
+pub fn parse_csv(text: &str) -> Result<Array, Error> {
+    let mut rows: Vec<Vec<f64>> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let row = line
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| Error::Format(format!("line {}: {}", n + 1, e)))?;
+        if !rows.is_empty() && row.len() != rows[0].len() {
+            return Err(Error::Format(format!("line {}: ragged row", n + 1)));
+        }
+        rows.push(row);
+    }
+
+    match rows.len() {
+        0 => Err(Error::Format("no data".into())),
+        1 => Ok(Array {
+            shape: vec![rows[0].len()],
+            data: rows.remove(0),
+        }),
+        n => Ok(Array {
+            shape: vec![n, rows[0].len()],
+            data: rows.concat(),
+        }),
+    }
+}
+
+/// Parses the NumPy format (version 1 to 3) for little-endian integer and
+/// float arrays in C order.
+pub fn parse_npy(bytes: &[u8]) -> Result<Array, Error> {
+    let bad = |what: &str| Error::Format(format!("npy: {}", what));
+    if bytes.len() < 10 || &bytes[..6] != b"\x93NUMPY" {
+        return Err(bad("bad magic"));
+    }
+    let (header_len, start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            12,
+        ),
+        _ => return Err(bad("unsupported version")),
+    };
+    let header = bytes
+        .get(start..start + header_len)
+        .ok_or_else(|| bad("truncated header"))?;
+    let header = std::str::from_utf8(header).map_err(|_| bad("header is not text"))?;
+
+    let descr = dict_value(header, "descr").ok_or_else(|| bad("no descr"))?;
+    let descr = descr.trim_matches(|c| c == '\'' || c == '"');
+    if dict_value(header, "fortran_order") != Some("False") {
+        return Err(bad("only C order is supported"));
+    }
+    let shape = dict_value(header, "shape").ok_or_else(|| bad("no shape"))?;
+    let shape = shape
+        .trim_matches(|c| c == '(' || c == ')')
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<usize>().map_err(|_| bad("bad shape")))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (kind, size) = match descr.as_bytes() {
+        [b'<' | b'|', kind, size @ ..] => (*kind, std::str::from_utf8(size).unwrap()),
+        _ => return Err(bad("only little-endian data is supported")),
+    };
+    let size: usize = size.parse().map_err(|_| bad("bad descr"))?;
+    let decode: fn(&[u8]) -> f64 = match (kind, size) {
+        (b'i', 1) => |b| b[0] as i8 as f64,
+        (b'u', 1) => |b| b[0] as f64,
+        (b'i', 2) => |b| i16::from_le_bytes([b[0], b[1]]) as f64,
+        (b'u', 2) => |b| u16::from_le_bytes([b[0], b[1]]) as f64,
+        (b'i', 4) => |b| i32::from_le_bytes(b.try_into().unwrap()) as f64,
+        (b'u', 4) => |b| u32::from_le_bytes(b.try_into().unwrap()) as f64,
+        (b'i', 8) => |b| i64::from_le_bytes(b.try_into().unwrap()) as f64,
+        (b'u', 8) => |b| u64::from_le_bytes(b.try_into().unwrap()) as f64,
+        (b'f', 4) => |b| f32::from_le_bytes(b.try_into().unwrap()) as f64,
+        (b'f', 8) => |b| f64::from_le_bytes(b.try_into().unwrap()),
+        _ => return Err(bad("unsupported dtype")),
+    };
+
+    let len = shape.iter().product::<usize>();
+    let data = &bytes[start + header_len..];
+    if data.len() < len * size {
+        return Err(bad("truncated data"));
+    }
+    Ok(Array {
+        shape,
+        data: data[..len * size].chunks(size).map(decode).collect(),
+    })
+}
+
+// The text of `key`'s value in the header dict, e.g. `(10, 50)` for `shape`.
+fn dict_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let rest = &header[header.find(&format!("'{}'", key))? + key.len() + 2..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find([',', '}'])?
+    };
+    Some(rest[..end].trim())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn npy(descr: &str, shape: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = format!(
+            "{{'descr': '{}', 'fortran_order': False, 'shape': {}, }}",
+            descr, shape
+        );
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+        header.push('\n');
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(header.as_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn npy_integers() {
+        let data: Vec<u8> = [1i16, -2, 300, 4, 5, -6]
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        let a = parse_npy(&npy("<i2", "(2, 3)", &data)).unwrap();
+        assert_eq!(a.shape, vec![2, 3]);
+        assert_eq!(a.data, vec![1.0, -2.0, 300.0, 4.0, 5.0, -6.0]);
+    }
+
+    #[test]
+    fn npy_floats_and_1d() {
+        let data: Vec<u8> = [0.5f32, -1.25]
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        let a = parse_npy(&npy("<f4", "(2,)", &data)).unwrap();
+        assert_eq!(a.shape, vec![2]);
+        assert_eq!(a.data, vec![0.5, -1.25]);
+    }
+
+    #[test]
+    fn npy_rejects() {
+        assert!(parse_npy(b"not numpy").is_err());
+        assert!(parse_npy(&npy(">i2", "(1,)", &[0, 1])).is_err());
+        assert!(parse_npy(&npy("<i2", "(3,)", &[0, 1])).is_err());
+        assert!(parse_npy(&npy("<c8", "(1,)", &[0; 8])).is_err());
+    }
+
+    #[test]
+    fn csv() {
+        let a = parse_csv("# weights\n1, 2,3\n\n-4,5,6 # last\n").unwrap();
+        assert_eq!(a.shape, vec![2, 3]);
+        assert_eq!(a.data, vec![1.0, 2.0, 3.0, -4.0, 5.0, 6.0]);
+        assert_eq!(parse_csv("7,8").unwrap().shape, vec![2]);
+        assert!(parse_csv("1,2\n3").is_err());
+        assert!(parse_csv("1,x").is_err());
+
+        let a = parse_csv("1,2,3,4\n5,6,7,8").unwrap();
+        assert_eq!(a.reshape(vec![2, 2, 2]).unwrap().shape, vec![2, 2, 2]);
+    }
+}
@@ -0,0 +1,237 @@
+//! Rust source for a [`Model`].
+
+use std::fmt::Write;
+
+use crate::{Kind, Layer, Model};
+
+pub fn module(model: &Model, source: &str, krate: &str) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "// Generated by fram-modelgen from {}. Do not edit.",
+        source
+    )
+    .unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "#[allow(unused_imports)]").unwrap();
+    writeln!(
+        out,
+        "use {}::nn::{{Conv2D, Dense, DepthwiseConv2D, LayerInfo, LayerKind, Requant}};",
+        krate
+    )
+    .unwrap();
+    writeln!(out, "#[allow(unused_imports)]").unwrap();
+    writeln!(
+        out,
+        "use {}::tensor::{{Tensor1D, Tensor2D, Tensor3D, Tensor4D}};",
+        krate
+    )
+    .unwrap();
+
+    for layer in &model.layers {
+        writeln!(out).unwrap();
+        layer_statics(&mut out, layer);
+    }
+
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "pub static LAYERS: [LayerInfo; {}] = [",
+        model.layers.len()
+    )
+    .unwrap();
+    for layer in &model.layers {
+        let kind = match layer.kind {
+            Kind::Dense => "Dense",
+            Kind::Conv2D => "Conv2D",
+            Kind::Depthwise => "DepthwiseConv2D",
+        };
+        writeln!(out, "    LayerInfo {{").unwrap();
+        writeln!(out, "        name: {:?},", layer.name).unwrap();
+        writeln!(out, "        kind: LayerKind::{},", kind).unwrap();
+        writeln!(out, "        shape: &{:?},", layer.shape).unwrap();
+        writeln!(out, "        has_bias: {},", layer.bias.is_some()).unwrap();
+        writeln!(out, "        requant: {},", requant(layer)).unwrap();
+        writeln!(out, "    }},").unwrap();
+    }
+    writeln!(out, "];").unwrap();
+    out
+}
+
+fn requant(layer: &Layer) -> String {
+    format!("Requant::new({}, {})", layer.requant.0, layer.requant.1)
+}
+
+fn dims(shape: &[usize]) -> String {
+    shape
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn layer_statics(out: &mut String, layer: &Layer) {
+    let name = layer.name.to_uppercase();
+    let tensor = match layer.shape.len() {
+        2 => "Tensor2D",
+        3 => "Tensor3D",
+        _ => "Tensor4D",
+    };
+    let ty = format!("{}<{}>", tensor, dims(&layer.shape));
+    writeln!(out, "#[link_section = \".fram_rodata\"]").unwrap();
+    write!(out, "pub static {}: {} = {}::new(", name, ty, tensor).unwrap();
+    nested(out, &layer.shape, &layer.weights, 0);
+    writeln!(out, ");").unwrap();
+
+    let bias = match &layer.bias {
+        Some(bias) => {
+            writeln!(out, "#[link_section = \".fram_rodata\"]").unwrap();
+            write!(
+                out,
+                "pub static {}_BIAS: Tensor1D<{}> = Tensor1D::new(",
+                name,
+                bias.len()
+            )
+            .unwrap();
+            nested(out, &[bias.len()], bias, 0);
+            writeln!(out, ");").unwrap();
+            format!("Some(&{}_BIAS)", name)
+        }
+        None => "None".to_owned(),
+    };
+
+    let s = &layer.shape;
+    match layer.kind {
+        Kind::Dense => {
+            writeln!(
+                out,
+                "pub static {}_LAYER: Dense<'static, {}, {}> = Dense {{",
+                name, s[1], s[0]
+            )
+            .unwrap();
+        }
+        Kind::Conv2D => {
+            writeln!(
+                out,
+                "pub static {}_LAYER: Conv2D<'static, {}> = Conv2D {{",
+                name,
+                dims(s)
+            )
+            .unwrap();
+        }
+        Kind::Depthwise => {
+            writeln!(
+                out,
+                "pub static {}_LAYER: DepthwiseConv2D<'static, {}> = DepthwiseConv2D {{",
+                name,
+                dims(s)
+            )
+            .unwrap();
+        }
+    }
+    writeln!(out, "    weights: &{},", name).unwrap();
+    writeln!(out, "    bias: {},", bias).unwrap();
+    if layer.kind != Kind::Dense {
+        writeln!(out, "    stride: {},", layer.stride).unwrap();
+        writeln!(out, "    padding: {},", layer.padding).unwrap();
+    }
+    writeln!(out, "    requant: {},", requant(layer)).unwrap();
+    writeln!(out, "}};").unwrap();
+}
+
+// Nested array literal, innermost rows on one line each.
+fn nested(out: &mut String, shape: &[usize], data: &[i64], depth: usize) {
+    if shape.len() == 1 {
+        let values = data.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        write!(out, "[{}]", values.join(", ")).unwrap();
+        return;
+    }
+    let indent = "    ".repeat(depth + 1);
+    let step = data.len() / shape[0];
+    writeln!(out, "[").unwrap();
+    for chunk in data.chunks(step) {
+        out.push_str(&indent);
+        nested(out, &shape[1..], chunk, depth + 1);
+        writeln!(out, ",").unwrap();
+    }
+    write!(out, "{}]", "    ".repeat(depth)).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{array, Model};
+
+    #[test]
+    fn generated_module() {
+        let model = Model::parse(
+            "fc dense weights=w.csv bias=b.csv multiplier=3 shift=2\n\
+             conv conv2d weights=k.csv shape=1x2x2x2 stride=2",
+            |name| match name {
+                "w.csv" => array::parse_csv("1,2,3\n4,5,6"),
+                "b.csv" => array::parse_csv("10,-10"),
+                _ => array::parse_csv("1,2,3,4,5,6,7,8"),
+            },
+        )
+        .unwrap();
+        let code = model.to_rust("model.txt", "crate");
+        let expected = r#"// Generated by fram-modelgen from model.txt. Do not edit.
+
+#[allow(unused_imports)]
+use crate::nn::{Conv2D, Dense, DepthwiseConv2D, LayerInfo, LayerKind, Requant};
+#[allow(unused_imports)]
+use crate::tensor::{Tensor1D, Tensor2D, Tensor3D, Tensor4D};
+
+#[link_section = ".fram_rodata"]
+pub static FC: Tensor2D<2, 3> = Tensor2D::new([
+    [1, 2, 3],
+    [4, 5, 6],
+]);
+#[link_section = ".fram_rodata"]
+pub static FC_BIAS: Tensor1D<2> = Tensor1D::new([10, -10]);
+pub static FC_LAYER: Dense<'static, 3, 2> = Dense {
+    weights: &FC,
+    bias: Some(&FC_BIAS),
+    requant: Requant::new(3, 2),
+};
+
+#[link_section = ".fram_rodata"]
+pub static CONV: Tensor4D<1, 2, 2, 2> = Tensor4D::new([
+    [
+        [
+            [1, 2],
+            [3, 4],
+        ],
+        [
+            [5, 6],
+            [7, 8],
+        ],
+    ],
+]);
+pub static CONV_LAYER: Conv2D<'static, 1, 2, 2, 2> = Conv2D {
+    weights: &CONV,
+    bias: None,
+    stride: 2,
+    padding: 0,
+    requant: Requant::new(1, 0),
+};
+
+pub static LAYERS: [LayerInfo; 2] = [
+    LayerInfo {
+        name: "fc",
+        kind: LayerKind::Dense,
+        shape: &[2, 3],
+        has_bias: true,
+        requant: Requant::new(3, 2),
+    },
+    LayerInfo {
+        name: "conv",
+        kind: LayerKind::Conv2D,
+        shape: &[1, 2, 2, 2],
+        has_bias: false,
+        requant: Requant::new(1, 0),
+    },
+];
+"#;
+        assert_eq!(code, expected);
+    }
+}
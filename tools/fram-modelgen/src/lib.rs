@@ -0,0 +1,445 @@
+//! Turns trained model weights into a Rust module of `.fram_rodata`
+//! statics for the `parallel_fram` inference layers.
+//!
+//! A model is described by a text file with one layer per line:
+//!
+//! ```text
+//! # name   kind     options
+//! conv1    conv2d   weights=conv1.npy bias=conv1_b.npy stride=2 padding=1 scale=0.0123
+//! fc1      dense    weights=fc1.csv weight_scale=0.01 multiplier=3 shift=8
+//! ```
+//!
+//! Kinds and the weight shapes they take:
+//!
+//! - `dense`: `[outputs, inputs]`
+//! - `conv2d`: `[filters, channels, height, width]`
+//! - `depthwise`: `[channels, height, width]`
+//!
+//! Options:
+//!
+//! - `weights=<file>` (required), `bias=<file>`: `.npy` or `.csv`, relative to
+//!   the description
+//! - `shape=AxBxC`: reshape the weights, e.g. for 4-D weights from CSV
+//! - `weight_scale=<f>`, `bias_scale=<f>`: quantize float data as
+//!   `round(x / scale)`; without them the data must be integers
+//! - `scale=<f>` or `multiplier=<m> shift=<s>`: the output `Requant` of the
+//!   layer, `scale` being converted to a multiplier and shift
+//! - `stride=<n>`, `padding=<n>`: for convolutions, default 1 and 0
+//!
+//! From `build.rs`:
+//!
+//! ```ignore
+//! let out = PathBuf::from(env::var_os("OUT_DIR").unwrap()).join("model.rs");
+//! for input in fram_modelgen::generate(Path::new("model/model.txt"), &out).unwrap() {
+//!     println!("cargo:rerun-if-changed={}", input.display());
+//! }
+//! ```
+//!
+//! and in the firmware:
+//!
+//! ```ignore
+//! mod model {
+//!     include!(concat!(env!("OUT_DIR"), "/model.rs"));
+//! }
+//! ```
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub mod array;
+mod codegen;
+
+pub use self::array::Array;
+
+/// Largest magnitude of a weight: `Numeric` is `i32`.
+pub const WEIGHT_MAX: i64 = i32::MAX as i64;
+
+/// Requant multipliers are kept below this so that `acc * multiplier` does
+/// not saturate for accumulators up to 2^48.
+pub const MULTIPLIER_LIMIT: i64 = 1 << 15;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, io::Error),
+    /// A malformed weight file.
+    Format(String),
+    /// A weight file in the wrong shape for its layer.
+    Shape(String),
+    /// A problem with the model description, at a line.
+    Description(usize, String),
+    /// A value that does not fit, or a float without a scale.
+    Value(String),
+    /// Any of the above, in a named file.
+    InFile(PathBuf, Box<Error>),
+}
+
+impl Error {
+    fn in_file(self, path: &Path) -> Error {
+        Error::InFile(path.to_owned(), Box::new(self))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Format(msg) | Error::Shape(msg) | Error::Value(msg) => f.write_str(msg),
+            Error::Description(line, msg) => write!(f, "line {}: {}", line, msg),
+            Error::InFile(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Dense,
+    Conv2D,
+    Depthwise,
+}
+
+impl Kind {
+    fn weight_dims(self) -> usize {
+        match self {
+            Kind::Dense => 2,
+            Kind::Conv2D => 4,
+            Kind::Depthwise => 3,
+        }
+    }
+}
+
+/// One quantized layer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layer {
+    pub name: String,
+    pub kind: Kind,
+    pub shape: Vec<usize>,
+    pub weights: Vec<i64>,
+    pub bias: Option<Vec<i64>>,
+    /// `(multiplier, shift)` of the output requantization.
+    pub requant: (i32, u8),
+    pub stride: usize,
+    pub padding: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Model {
+    pub layers: Vec<Layer>,
+}
+
+impl Model {
+    /// Reads a model description and the weight files it names.
+    pub fn load(description: &Path) -> Result<Model, Error> {
+        Ok(Model::load_with_inputs(description)?.0)
+    }
+
+    fn load_with_inputs(description: &Path) -> Result<(Model, Vec<PathBuf>), Error> {
+        let text = std::fs::read_to_string(description)
+            .map_err(|e| Error::Io(description.to_owned(), e))?;
+        let dir = description.parent().unwrap_or_else(|| Path::new(""));
+        let mut inputs = vec![description.to_owned()];
+        let model = Model::parse(&text, |file| {
+            let path = dir.join(file);
+            inputs.push(path.clone());
+            array::load(&path)
+        })
+        .map_err(|e| e.in_file(description))?;
+        Ok((model, inputs))
+    }
+
+    /// Parses a model description, loading weight files with `load`.
+    pub fn parse<F>(text: &str, mut load: F) -> Result<Model, Error>
+    where
+        F: FnMut(&str) -> Result<Array, Error>,
+    {
+        let mut model = Model::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let layer = parse_layer(line, &mut load).map_err(|e| match e {
+                Error::Description(_, msg) => Error::Description(n + 1, msg),
+                e => Error::Description(n + 1, e.to_string()),
+            })?;
+            if model.layers.iter().any(|l| l.name == layer.name) {
+                return Err(Error::Description(n + 1, "duplicate layer name".into()));
+            }
+            model.layers.push(layer);
+        }
+        Ok(model)
+    }
+
+    /// The generated Rust module. `krate` is the path of the `parallel_fram`
+    /// crate as seen from where the module is included.
+    pub fn to_rust(&self, source: &str, krate: &str) -> String {
+        codegen::module(self, source, krate)
+    }
+}
+
+fn parse_layer<F>(line: &str, load: &mut F) -> Result<Layer, Error>
+where
+    F: FnMut(&str) -> Result<Array, Error>,
+{
+    let bad = |msg: String| Error::Description(0, msg);
+    let mut words = line.split_whitespace();
+    let name = words.next().unwrap();
+    if !is_identifier(name) {
+        return Err(bad(format!("`{}` is not a valid layer name", name)));
+    }
+    let kind = match words.next() {
+        Some("dense") => Kind::Dense,
+        Some("conv2d") => Kind::Conv2D,
+        Some("depthwise") => Kind::Depthwise,
+        Some(other) => return Err(bad(format!("unknown layer kind `{}`", other))),
+        None => return Err(bad("missing layer kind".into())),
+    };
+
+    let mut options = Vec::new();
+    for word in words {
+        let mut kv = word.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(key), Some(value)) => options.push((key, value)),
+            _ => return Err(bad(format!("expected key=value, got `{}`", word))),
+        }
+    }
+    let option = |key: &str| options.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+    for (key, _) in &options {
+        const KNOWN: &[&str] = &[
+            "weights",
+            "bias",
+            "shape",
+            "weight_scale",
+            "bias_scale",
+            "scale",
+            "multiplier",
+            "shift",
+            "stride",
+            "padding",
+        ];
+        if !KNOWN.contains(key) {
+            return Err(bad(format!("unknown option `{}`", key)));
+        }
+    }
+    fn number<T: std::str::FromStr>(key: &str, value: Option<&str>) -> Result<Option<T>, Error> {
+        value
+            .map(|v| {
+                v.parse()
+                    .map_err(|_| Error::Description(0, format!("bad value for `{}`", key)))
+            })
+            .transpose()
+    }
+
+    let mut weights = load(option("weights").ok_or_else(|| bad("missing weights=".into()))?)?;
+    if let Some(shape) = option("shape") {
+        let shape = shape
+            .split('x')
+            .map(|d| d.parse().map_err(|_| bad("bad shape".into())))
+            .collect::<Result<Vec<usize>, _>>()?;
+        weights = weights.reshape(shape)?;
+    }
+    if weights.shape.len() != kind.weight_dims() {
+        return Err(Error::Shape(format!(
+            "{:?} layer needs {}-D weights, got shape {:?}",
+            kind,
+            kind.weight_dims(),
+            weights.shape
+        )));
+    }
+    if weights.shape.contains(&0) {
+        return Err(Error::Shape("weights are empty".into()));
+    }
+    let weight_scale = number::<f64>("weight_scale", option("weight_scale"))?;
+    let quantized_weights = quantize(&weights.data, weight_scale)?;
+
+    let bias = match option("bias") {
+        Some(file) => {
+            let bias = load(file)?;
+            if bias.data.len() != weights.shape[0] {
+                return Err(Error::Shape(format!(
+                    "bias has {} values for {} outputs",
+                    bias.data.len(),
+                    weights.shape[0]
+                )));
+            }
+            let bias_scale = number::<f64>("bias_scale", option("bias_scale"))?;
+            Some(quantize(&bias.data, bias_scale)?)
+        }
+        None => None,
+    };
+
+    let requant = match (
+        number::<f64>("scale", option("scale"))?,
+        number::<i32>("multiplier", option("multiplier"))?,
+        number::<u8>("shift", option("shift"))?,
+    ) {
+        (Some(scale), None, None) => requant_of_scale(scale)?,
+        (None, multiplier, shift) => (multiplier.unwrap_or(1), shift.unwrap_or(0)),
+        _ => return Err(bad("give either scale= or multiplier=/shift=".into())),
+    };
+    if requant.1 > 62 {
+        return Err(bad("shift is at most 62".into()));
+    }
+
+    let stride = number("stride", option("stride"))?.unwrap_or(1);
+    let padding = number("padding", option("padding"))?.unwrap_or(0);
+    if kind == Kind::Dense && (option("stride").is_some() || option("padding").is_some()) {
+        return Err(bad("dense layers have no stride or padding".into()));
+    }
+    if stride == 0 {
+        return Err(bad("stride must be at least 1".into()));
+    }
+
+    Ok(Layer {
+        name: name.to_owned(),
+        kind,
+        shape: weights.shape,
+        weights: quantized_weights,
+        bias,
+        requant,
+        stride,
+        padding,
+    })
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `round(x / scale)` for every value, or the values as they are if they are
+/// integers and there is no scale.
+pub fn quantize(data: &[f64], scale: Option<f64>) -> Result<Vec<i64>, Error> {
+    data.iter()
+        .map(|&x| {
+            let q = match scale {
+                Some(scale) if scale > 0.0 => (x / scale).round(),
+                Some(_) => return Err(Error::Value("scales must be positive".into())),
+                None if x.fract() == 0.0 => x,
+                None => {
+                    return Err(Error::Value(format!(
+                        "{} is not an integer; give a scale to quantize floats",
+                        x
+                    )))
+                }
+            };
+            if q.abs() > WEIGHT_MAX as f64 {
+                return Err(Error::Value(format!("{} is out of range", x)));
+            }
+            Ok(q as i64)
+        })
+        .collect()
+}
+
+/// The most precise `(multiplier, shift)` with `multiplier / 2^shift` close
+/// to `scale` and `multiplier` below [`MULTIPLIER_LIMIT`].
+pub fn requant_of_scale(scale: f64) -> Result<(i32, u8), Error> {
+    if scale.is_nan() || scale <= 0.0 || scale >= MULTIPLIER_LIMIT as f64 {
+        return Err(Error::Value(format!(
+            "requant scale {} is out of range",
+            scale
+        )));
+    }
+    let mut shift = 0;
+    while shift < 62 && (scale * (2u64 << shift) as f64).round() < MULTIPLIER_LIMIT as f64 {
+        shift += 1;
+    }
+    let multiplier = (scale * (1u64 << shift) as f64).round() as i32;
+    if multiplier == 0 {
+        return Err(Error::Value(format!(
+            "requant scale {} is too small",
+            scale
+        )));
+    }
+    Ok((multiplier, shift as u8))
+}
+
+/// Generates `out` from the model `description`, leaving it alone if it is
+/// already up to date. Returns the files read, for `cargo:rerun-if-changed`.
+pub fn generate(description: &Path, out: &Path) -> Result<Vec<PathBuf>, Error> {
+    let (model, inputs) = Model::load_with_inputs(description)?;
+    let code = model.to_rust(&description.display().to_string(), "parallel_fram");
+    if std::fs::read_to_string(out).ok().as_deref() != Some(code.as_str()) {
+        std::fs::write(out, code).map_err(|e| Error::Io(out.to_owned(), e))?;
+    }
+    Ok(inputs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn files(name: &str) -> Result<Array, Error> {
+        match name {
+            "w.csv" => array::parse_csv("1,2,3\n4,5,6"),
+            "b.csv" => array::parse_csv("10,-10"),
+            "f.csv" => array::parse_csv("0.5,-0.25,0.125,1"),
+            "k.csv" => array::parse_csv("1,2,3,4,5,6,7,8"),
+            _ => Err(Error::Format("no such file".into())),
+        }
+    }
+
+    #[test]
+    fn parses_layers() {
+        let model = Model::parse(
+            "# a model\n\
+             fc dense weights=w.csv bias=b.csv multiplier=3 shift=2\n\
+             \n\
+             dw depthwise weights=k.csv shape=2x2x2 padding=1 # comment\n",
+            files,
+        )
+        .unwrap();
+        assert_eq!(model.layers.len(), 2);
+        let fc = &model.layers[0];
+        assert_eq!((fc.kind, &fc.shape[..]), (Kind::Dense, &[2, 3][..]));
+        assert_eq!(fc.weights, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(fc.bias, Some(vec![10, -10]));
+        assert_eq!(fc.requant, (3, 2));
+        let dw = &model.layers[1];
+        assert_eq!((dw.kind, &dw.shape[..]), (Kind::Depthwise, &[2, 2, 2][..]));
+        assert_eq!((dw.stride, dw.padding, dw.requant), (1, 1, (1, 0)));
+    }
+
+    #[test]
+    fn quantizes_floats() {
+        let model = Model::parse(
+            "fc dense weights=f.csv shape=2x2 weight_scale=0.125 scale=0.75",
+            files,
+        )
+        .unwrap();
+        assert_eq!(model.layers[0].weights, vec![4, -2, 1, 8]);
+        assert_eq!(model.layers[0].requant, (24576, 15));
+    }
+
+    #[test]
+    fn description_errors() {
+        let line_of = |text: &str| match Model::parse(text, files) {
+            Err(Error::Description(line, _)) => line,
+            r => panic!("{:?}", r),
+        };
+        assert_eq!(line_of("\nfc dense"), 2);
+        assert_eq!(line_of("fc pool weights=w.csv"), 1);
+        assert_eq!(line_of("9fc dense weights=w.csv"), 1);
+        assert_eq!(line_of("fc dense weights=w.csv colour=red"), 1);
+        assert_eq!(line_of("fc dense weights=f.csv"), 1);
+        assert_eq!(line_of("fc dense weights=k.csv"), 1);
+        assert_eq!(line_of("fc dense weights=w.csv bias=k.csv"), 1);
+        assert_eq!(line_of("fc dense weights=w.csv scale=0.5 shift=1"), 1);
+        assert_eq!(line_of("fc dense weights=w.csv stride=2"), 1);
+        assert_eq!(line_of("fc dense weights=w.csv\nfc dense weights=w.csv"), 2);
+    }
+
+    #[test]
+    fn scales() {
+        assert_eq!(requant_of_scale(1.0).unwrap(), (16384, 14));
+        assert_eq!(requant_of_scale(0.0625).unwrap(), (16384, 18));
+        let (m, s) = requant_of_scale(0.3).unwrap();
+        assert!(m < MULTIPLIER_LIMIT as i32 && (m as f64 / (1u64 << s) as f64 - 0.3).abs() < 1e-5);
+        assert!(requant_of_scale(0.0).is_err());
+        assert!(requant_of_scale(1e-30).is_err());
+        assert!(quantize(&[3e10], None).is_err());
+    }
+}
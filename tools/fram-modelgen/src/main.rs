@@ -0,0 +1,22 @@
+//! `fram-modelgen <model description> <output.rs>`
+//!
+//! Writes the Rust module for a model description, see the library docs for
+//! the format. Usually run from `build.rs` instead.
+
+use std::path::Path;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <model description> <output.rs>", args[0]);
+        process::exit(2);
+    }
+    match fram_modelgen::generate(Path::new(&args[1]), Path::new(&args[2])) {
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
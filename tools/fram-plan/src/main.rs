@@ -0,0 +1,318 @@
+//! `fram-plan <model description> [--sram <bytes>] [--fram <bytes>] [--input <CxHxW>]`
+//!
+//! Prints the `parallel_fram::plan` schedule of a model description (the
+//! format `fram-modelgen` reads) for the given SRAM and F-RAM scratch, by
+//! default all of `RAM` and `FRAM` in `memory.x`: where each activation
+//! goes, how the weights are read, and the estimated bytes moved over the
+//! FMC. Sizes take a `K` suffix. `--input` gives the input shape when the
+//! first layer is a convolution.
+
+use std::process;
+
+use fram_modelgen::{Kind, Model};
+use parallel_fram::plan::{self, Buffer, Layer, Memory, Op, Place, Step, Traffic, Weights};
+
+struct Args {
+    model: String,
+    memory: Memory,
+    input: Option<Vec<usize>>,
+}
+
+fn parse_size(s: &str) -> Result<usize, String> {
+    let bad = || format!("bad size `{}`", s);
+    let (digits, unit) = match s.strip_suffix(&['K', 'k'][..]) {
+        Some(digits) => (digits, 1024),
+        None => (s, 1),
+    };
+    let n: usize = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).map_err(|_| bad())?,
+        None => digits.parse().map_err(|_| bad())?,
+    };
+    Ok(n * unit)
+}
+
+fn parse_dims(s: &str) -> Result<Vec<usize>, String> {
+    s.split('x')
+        .map(|d| d.parse().map_err(|_| format!("bad shape `{}`", s)))
+        .collect()
+}
+
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut model = None;
+    let mut memory = Memory {
+        sram: 64 * 1024,
+        fram: 32 * 1024,
+    };
+    let mut input = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--sram" => memory.sram = parse_size(value()?)?,
+            "--fram" => memory.fram = parse_size(value()?)?,
+            "--input" => input = Some(parse_dims(value()?)?),
+            _ if model.is_none() && !arg.starts_with("--") => model = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+    Ok(Args {
+        model: model.ok_or("no model description given")?,
+        memory,
+        input,
+    })
+}
+
+/// The planner's view of `model`, following the activation shape from
+/// `input` (or the inputs of a leading dense layer) through the layers.
+fn layers(model: &Model, input: Option<Vec<usize>>) -> Result<Vec<Layer>, String> {
+    let mut shape = input;
+    let mut layers = Vec::new();
+    for layer in &model.layers {
+        let s = &layer.shape;
+        let image = |shape: &Option<Vec<usize>>| match shape.as_deref() {
+            Some(&[c, h, w]) => Ok((c, [h, w])),
+            _ => Err(format!(
+                "{}: convolutions need a CxHxW input, give --input",
+                layer.name
+            )),
+        };
+        // channels the weights are made for against those the input has
+        let channels = match layer.kind {
+            Kind::Dense => None,
+            Kind::Conv2D => Some(s[1]),
+            Kind::Depthwise => Some(s[0]),
+        };
+        if let Some(channels) = channels {
+            let (c, _) = image(&shape)?;
+            if c != channels {
+                return Err(format!(
+                    "{}: weights take {} channels, the input has {}",
+                    layer.name, channels, c
+                ));
+            }
+        }
+        let op = match layer.kind {
+            Kind::Dense => Op::Dense {
+                inputs: s[1],
+                outputs: s[0],
+            },
+            Kind::Conv2D => Op::Conv2D {
+                filters: s[0],
+                channels: image(&shape)?.0,
+                kernel: [s[2], s[3]],
+                input: image(&shape)?.1,
+                stride: layer.stride,
+                padding: layer.padding,
+            },
+            Kind::Depthwise => Op::Depthwise {
+                channels: image(&shape)?.0,
+                kernel: [s[1], s[2]],
+                input: image(&shape)?.1,
+                stride: layer.stride,
+                padding: layer.padding,
+            },
+        };
+        if let Some(shape) = &shape {
+            let len: usize = shape.iter().product();
+            if len != op.input_len() {
+                return Err(format!(
+                    "{}: takes {} inputs, gets {}",
+                    layer.name,
+                    op.input_len(),
+                    len
+                ));
+            }
+        }
+        let [h, w] = op.output_size();
+        shape = Some(match layer.kind {
+            Kind::Dense => vec![op.units()],
+            _ => vec![op.units(), h, w],
+        });
+        // the tool plans one model and exits
+        let name: &'static str = Box::leak(layer.name.clone().into_boxed_str());
+        layers.push(Layer::new(name, op, layer.bias.is_some()));
+    }
+    Ok(layers)
+}
+
+fn describe_op(op: &Op) -> String {
+    match *op {
+        Op::Dense { inputs, outputs } => format!("dense {} -> {}", inputs, outputs),
+        Op::Conv2D {
+            filters,
+            channels,
+            input,
+            ..
+        } => {
+            let [h, w] = op.output_size();
+            format!(
+                "conv2d {}x{}x{} -> {}x{}x{}",
+                channels, input[0], input[1], filters, h, w
+            )
+        }
+        Op::Depthwise {
+            channels, input, ..
+        } => {
+            let [h, w] = op.output_size();
+            format!(
+                "depthwise {}x{}x{} -> {}x{}x{}",
+                channels, input[0], input[1], channels, h, w
+            )
+        }
+    }
+}
+
+fn describe_buffer(buffer: &Buffer) -> String {
+    let place = match buffer.place {
+        Place::Sram => "SRAM",
+        Place::Fram => "F-RAM",
+    };
+    format!("{} +{}", place, buffer.offset * plan::ELEM)
+}
+
+fn describe_weights(step: &Step) -> String {
+    let op = &step.layer.op;
+    match step.weights {
+        Weights::InPlace => "read in place".to_owned(),
+        Weights::Tiled { units, .. } if units == op.units() => "copied whole".to_owned(),
+        Weights::Tiled { units, .. } => {
+            let unit = match op {
+                Op::Dense { .. } => "rows",
+                Op::Conv2D { .. } => "filters",
+                Op::Depthwise { .. } => "channels",
+            };
+            format!("{} tiles of {} {}", step.tiles(), units, unit)
+        }
+    }
+}
+
+fn report(steps: &[Step], memory: Memory) -> String {
+    let mut out = format!(
+        "SRAM {} B, F-RAM {} B\n\n{:<4} {:<12} {:<26} {:<12} {:<12} {:<22} {:>8} {:>10} {:>10} {:>10}\n",
+        memory.sram,
+        memory.fram,
+        "step",
+        "layer",
+        "op",
+        "input",
+        "output",
+        "weights",
+        "SRAM B",
+        "CPU rd B",
+        "DMA rd B",
+        "write B"
+    );
+    let mut total = Traffic::default();
+    for (i, step) in steps.iter().enumerate() {
+        let t = step.traffic;
+        out.push_str(&format!(
+            "{:<4} {:<12} {:<26} {:<12} {:<12} {:<22} {:>8} {:>10} {:>10} {:>10}\n",
+            i,
+            step.layer.name,
+            describe_op(&step.layer.op),
+            describe_buffer(&step.input),
+            describe_buffer(&step.output),
+            describe_weights(step),
+            step.sram_len() * plan::ELEM,
+            t.cpu_reads,
+            t.dma_reads,
+            t.writes
+        ));
+        total = total.plus(t);
+    }
+    out.push_str(&format!(
+        "\nFMC traffic per inference: {} B ({} B CPU reads, {} B DMA reads, {} B writes)\n",
+        total.total(),
+        total.cpu_reads,
+        total.dma_reads,
+        total.writes
+    ));
+    out
+}
+
+fn run(args: Args) -> Result<String, String> {
+    let model = Model::load(args.model.as_ref()).map_err(|e| e.to_string())?;
+    let layers = layers(&model, args.input)?;
+    let mut steps = vec![Step::default(); layers.len()];
+    plan::schedule(&layers, args.memory, &mut steps).map_err(|e| match e {
+        plan::PlanError::Shape { layer }
+        | plan::PlanError::Mismatch { layer }
+        | plan::PlanError::OutOfMemory { layer }
+        | plan::PlanError::Tiles { layer } => format!("{:?} at {}", e, layers[layer].name),
+        e => format!("{:?}", e),
+    })?;
+    Ok(report(&steps, args.memory))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let parsed = match parse_args(&args[1..]) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            eprintln!(
+                "usage: {} <model description> [--sram <bytes>] [--fram <bytes>] [--input <CxHxW>]",
+                args[0]
+            );
+            process::exit(2);
+        }
+    };
+    match run(parsed) {
+        Ok(report) => print!("{}", report),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fram_modelgen::array;
+
+    #[test]
+    fn plans_a_model() {
+        assert_eq!(parse_size("16K"), Ok(16384));
+        assert_eq!(parse_size("0x400"), Ok(1024));
+        assert!(parse_size("1M").is_err());
+
+        let model = Model::parse(
+            "conv conv2d weights=k.csv shape=2x1x2x2 stride=2\n\
+             fc dense weights=w.csv bias=b.csv",
+            |name| match name {
+                "k.csv" => array::parse_csv("1,2,3,4,5,6,7,8"),
+                "w.csv" => array::parse_csv(&["1,1,1,1,1,1,1,1"; 3].join("\n")),
+                _ => array::parse_csv("1,2,3"),
+            },
+        )
+        .unwrap();
+        assert!(layers(&model, None).is_err());
+        assert!(layers(&model, Some(vec![1, 4, 6])).is_err());
+        assert!(layers(&model, Some(vec![2, 4, 2])).is_err());
+        let layers = layers(&model, Some(vec![1, 4, 4])).unwrap();
+        assert_eq!(layers[0].op.output_len(), 8);
+        assert_eq!(
+            layers[1].op,
+            Op::Dense {
+                inputs: 8,
+                outputs: 3
+            }
+        );
+
+        let memory = Memory {
+            sram: 4 * 32,
+            fram: 0,
+        };
+        let mut steps = vec![Step::default(); 2];
+        plan::schedule(&layers, memory, &mut steps).unwrap();
+        let report = report(&steps, memory);
+        assert!(report.contains("conv2d 1x4x4 -> 2x2x2"), "{}", report);
+        assert!(report.contains("3 tiles of 1 rows"), "{}", report);
+        assert!(
+            report.contains("FMC traffic per inference: 140 B"),
+            "{}",
+            report
+        );
+    }
+}